@@ -0,0 +1,52 @@
+use chrono::{Duration, TimeZone, Utc};
+use simwatch_grpc::{moving::pilot::Pilot, types::Point};
+
+// Pilots spread over the globe in a fixed pseudo-random pattern, every
+// cycle moving them a little further east
+pub fn pilots(count: usize, cycle: usize) -> Vec<Pilot> {
+  let logon_time = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+  let mut seed: u64 = 0x5DEECE66D;
+  let mut next = move || {
+    seed = seed
+      .wrapping_mul(6364136223846793005)
+      .wrapping_add(1442695040888963407);
+    (seed >> 11) as f64 / (1u64 << 53) as f64
+  };
+  (0..count)
+    .map(|idx| {
+      let lat = next() * 140.0 - 70.0;
+      let lng = next() * 360.0 - 180.0 + cycle as f64 * 0.01;
+      Pilot {
+        cid: 800000 + idx as u32,
+        name: format!("Pilot {idx}"),
+        callsign: format!("BENCH{idx}"),
+        server: "GERMANY".into(),
+        pilot_rating: 0,
+        position: Point { lat, lng },
+        altitude: 35000,
+        groundspeed: 450,
+        transponder: "2000".into(),
+        heading: 90,
+        qnh_i_hg: 2992,
+        qnh_mb: 1013,
+        flight_plan: None,
+        logon_time,
+        last_updated: logon_time + Duration::seconds(15 * cycle as i64),
+        aircraft_type: None,
+        source: "vatsim".into(),
+        emergency: false,
+        stale: false,
+        corrected_altitude: None,
+        departure_utc_offset: None,
+        arrival_utc_offset: None,
+        current_fir: None,
+      }
+    })
+    .collect()
+}
+
+// Sorted samples' percentile
+pub fn percentile<T: Copy>(sorted: &[T], pct: f64) -> T {
+  let idx = ((sorted.len() - 1) as f64 * pct / 100.0).round() as usize;
+  sorted[idx]
+}
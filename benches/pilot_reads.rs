@@ -0,0 +1,223 @@
+// Latency of the pilot reads every map_updates stream does on its tick,
+// with 200 streams reading while the ingestion installs fresh pilots.
+// Compares the ways the manager has kept its pilot index:
+//
+//   cargo bench --bench pilot_reads
+use arc_swap::ArcSwap;
+use rstar::{RTree, AABB};
+use simwatch_grpc::{manager::spatial::PointObject, moving::pilot::Pilot, types::Point};
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::{sync::RwLock, task::block_in_place};
+
+mod common;
+
+const STREAMS: usize = 200;
+const PILOTS: usize = 2000;
+// ingestion cycles come this often, sped up from 15 seconds
+const CYCLE: Duration = Duration::from_millis(50);
+// and stream ticks, sped up from a second
+const TICK: Duration = Duration::from_millis(5);
+const RUN: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Snapshot {
+  pilots: HashMap<String, Arc<Pilot>>,
+  pilots2d: RTree<PointObject>,
+}
+
+impl Snapshot {
+  fn build(fresh: Vec<Pilot>) -> Self {
+    let pilots2d = RTree::bulk_load(fresh.iter().map(PointObject::from).collect());
+    let pilots = fresh
+      .into_iter()
+      .map(|pilot| (pilot.callsign.clone(), Arc::new(pilot)))
+      .collect();
+    Self { pilots, pilots2d }
+  }
+}
+
+enum Index {
+  // pilots written one by one under the write locks
+  PerPilot {
+    pilots: RwLock<HashMap<String, Arc<Pilot>>>,
+    pilots2d: RwLock<RTree<PointObject>>,
+    pilots_po: RwLock<HashMap<String, PointObject>>,
+  },
+  // built off the locks, swapped in under the write locks
+  Locked {
+    pilots: RwLock<HashMap<String, Arc<Pilot>>>,
+    pilots2d: RwLock<RTree<PointObject>>,
+  },
+  // built off the locks, swapped in as one snapshot
+  Swapped(ArcSwap<Snapshot>),
+}
+
+impl Index {
+  fn name(&self) -> &'static str {
+    match self {
+      Index::PerPilot { .. } => "per-pilot write locks",
+      Index::Locked { .. } => "RwLock swap",
+      Index::Swapped(_) => "ArcSwap snapshot",
+    }
+  }
+
+  async fn read(&self, env: &AABB<Point>) -> Vec<Arc<Pilot>> {
+    let locate = |pilots2d: &RTree<PointObject>, pilots: &HashMap<String, Arc<Pilot>>| {
+      pilots2d
+        .locate_in_envelope(env)
+        .filter_map(|po| pilots.get(&po.id).cloned())
+        .collect()
+    };
+    match self {
+      Index::PerPilot {
+        pilots, pilots2d, ..
+      }
+      | Index::Locked { pilots, pilots2d } => {
+        let pilots2d = pilots2d.read().await;
+        let pilots = pilots.read().await;
+        locate(&pilots2d, &pilots)
+      }
+      Index::Swapped(snapshot) => {
+        let snapshot = snapshot.load();
+        locate(&snapshot.pilots2d, &snapshot.pilots)
+      }
+    }
+  }
+
+  async fn ingest(&self, fresh: Vec<Pilot>) {
+    match self {
+      Index::PerPilot {
+        pilots,
+        pilots2d,
+        pilots_po,
+      } => {
+        for pilot in fresh {
+          let po = pilots_po.write().await.remove(&pilot.callsign);
+          if let Some(po) = po {
+            pilots2d.write().await.remove(&po);
+            pilots.write().await.remove(&pilot.callsign);
+          }
+          let po = PointObject::from(&pilot);
+          let mut pilots2d = pilots2d.write().await;
+          let mut pilots_po = pilots_po.write().await;
+          let mut pilots = pilots.write().await;
+          pilots2d.insert(po.clone());
+          pilots_po.insert(pilot.callsign.clone(), po);
+          pilots.insert(pilot.callsign.clone(), Arc::new(pilot));
+        }
+      }
+      Index::Locked { pilots, pilots2d } => {
+        let snapshot = block_in_place(|| Snapshot::build(fresh));
+        let mut current2d = pilots2d.write().await;
+        let mut current = pilots.write().await;
+        *current2d = snapshot.pilots2d;
+        *current = snapshot.pilots;
+      }
+      Index::Swapped(current) => {
+        let snapshot = block_in_place(|| Snapshot::build(fresh));
+        current.store(Arc::new(snapshot));
+      }
+    }
+  }
+}
+
+// a 20 by 10 degrees viewport, a few dozen pilots within
+fn viewport(stream: usize, tick: usize) -> AABB<Point> {
+  let lat = ((stream * 7 + tick) % 12) as f64 * 10.0 - 60.0;
+  let lng = ((stream * 13 + tick) % 16) as f64 * 20.0 - 160.0;
+  AABB::from_corners(
+    Point { lat, lng },
+    Point {
+      lat: lat + 10.0,
+      lng: lng + 20.0,
+    },
+  )
+}
+
+async fn run(index: Arc<Index>) -> Vec<Duration> {
+  index.ingest(common::pilots(PILOTS, 0)).await;
+  let deadline = Instant::now() + RUN;
+
+  let writer = {
+    let index = index.clone();
+    tokio::spawn(async move {
+      let mut cycle = 1;
+      while Instant::now() < deadline {
+        tokio::time::sleep(CYCLE).await;
+        let fresh = common::pilots(PILOTS, cycle);
+        index.ingest(fresh).await;
+        cycle += 1;
+      }
+    })
+  };
+
+  let readers: Vec<_> = (0..STREAMS)
+    .map(|stream| {
+      let index = index.clone();
+      tokio::spawn(async move {
+        let mut latencies = vec![];
+        let mut tick = 0;
+        while Instant::now() < deadline {
+          let env = viewport(stream, tick);
+          let t = Instant::now();
+          let pilots = index.read(&env).await;
+          latencies.push(t.elapsed());
+          std::hint::black_box(pilots);
+          tick += 1;
+          tokio::time::sleep(TICK).await;
+        }
+        latencies
+      })
+    })
+    .collect();
+
+  let mut latencies = vec![];
+  for reader in readers {
+    latencies.extend(reader.await.unwrap());
+  }
+  writer.await.unwrap();
+  latencies
+}
+
+#[tokio::main]
+async fn main() {
+  let indexes = [
+    Index::PerPilot {
+      pilots: RwLock::default(),
+      pilots2d: RwLock::default(),
+      pilots_po: RwLock::default(),
+    },
+    Index::Locked {
+      pilots: RwLock::default(),
+      pilots2d: RwLock::default(),
+    },
+    Index::Swapped(ArcSwap::default()),
+  ];
+
+  println!(
+    "{STREAMS} streams, {PILOTS} pilots, {} cpus",
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+  );
+  println!(
+    "{:<24}{:>10}{:>12}{:>12}{:>12}",
+    "index", "reads", "p50, us", "p99, us", "max, us"
+  );
+  for index in indexes {
+    let name = index.name();
+    let mut latencies = run(Arc::new(index)).await;
+    latencies.sort();
+    let us = |pct| common::percentile(&latencies, pct).as_micros();
+    println!(
+      "{:<24}{:>10}{:>12}{:>12}{:>12}",
+      name,
+      latencies.len(),
+      us(50.0),
+      us(99.0),
+      us(100.0)
+    );
+  }
+}
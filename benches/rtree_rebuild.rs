@@ -0,0 +1,88 @@
+// Time an ingestion cycle spends updating the pilot rtree of 2000 moving
+// pilots: removing and inserting every pilot, as the manager used to, or
+// bulk loading a fresh tree and swapping it in, as it does now:
+//
+//   cargo bench --bench rtree_rebuild
+use arc_swap::ArcSwap;
+use rstar::RTree;
+use simwatch_grpc::{manager::spatial::PointObject, moving::pilot::Pilot};
+use std::{
+  collections::HashMap,
+  hint::black_box,
+  time::{Duration, Instant},
+};
+
+mod common;
+
+const PILOTS: usize = 2000;
+const CYCLES: usize = 200;
+
+// the time every cycle has taken
+type Strategy = fn(&[Vec<Pilot>]) -> Vec<Duration>;
+
+// every pilot is removed by its previous point object and inserted again
+fn incremental(cycles: &[Vec<Pilot>]) -> Vec<Duration> {
+  let mut pilots2d = RTree::new();
+  let mut pilots_po: HashMap<String, PointObject> = HashMap::new();
+  cycles
+    .iter()
+    .map(|pilots| {
+      let t = Instant::now();
+      for pilot in pilots {
+        if let Some(po) = pilots_po.remove(&pilot.callsign) {
+          pilots2d.remove(&po);
+        }
+        let po = PointObject::from(pilot);
+        pilots2d.insert(po.clone());
+        pilots_po.insert(pilot.callsign.clone(), po);
+      }
+      t.elapsed()
+    })
+    .collect()
+}
+
+// a fresh tree of all the pilots replaces the previous one
+fn bulk_load(cycles: &[Vec<Pilot>]) -> Vec<Duration> {
+  let pilots2d: ArcSwap<RTree<PointObject>> = ArcSwap::default();
+  cycles
+    .iter()
+    .map(|pilots| {
+      let t = Instant::now();
+      let objects = pilots.iter().map(PointObject::from).collect();
+      let prev = pilots2d.swap(RTree::bulk_load(objects).into());
+      let elapsed = t.elapsed();
+      // the readers of the previous tree drop it, not the ingestion
+      black_box(prev);
+      elapsed
+    })
+    .collect()
+}
+
+fn main() {
+  let cycles: Vec<Vec<Pilot>> = (0..CYCLES)
+    .map(|cycle| common::pilots(PILOTS, cycle))
+    .collect();
+
+  println!("{PILOTS} pilots, {CYCLES} cycles");
+  println!(
+    "{:<24}{:>12}{:>12}{:>12}",
+    "strategy", "mean, us", "p50, us", "p99, us"
+  );
+  let strategies: [(&str, Strategy); 2] = [
+    ("remove + insert", incremental),
+    ("bulk_load + swap", bulk_load),
+  ];
+  for (name, strategy) in strategies {
+    // the first cycle fills the empty tree, it's left out
+    let mut times = strategy(&cycles)[1..].to_vec();
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    times.sort();
+    println!(
+      "{:<24}{:>12}{:>12}{:>12}",
+      name,
+      mean.as_micros(),
+      common::percentile(&times, 50.0).as_micros(),
+      common::percentile(&times, 99.0).as_micros()
+    );
+  }
+}
@@ -0,0 +1,97 @@
+use super::info::{parse_atis, AtisInfo};
+use crate::service::camden;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error};
+
+// Real world digital ATIS broadcast at US airports, used when no VATSIM
+// ATIS is online
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DigitalAtis {
+  pub code: String,
+  pub text: String,
+  pub info: AtisInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct DatisRecord {
+  airport: String,
+  // arr, dep or combined
+  #[serde(rename = "type")]
+  kind: String,
+  code: String,
+  datis: String,
+}
+
+impl From<DigitalAtis> for camden::DigitalAtis {
+  fn from(value: DigitalAtis) -> Self {
+    Self {
+      code: value.code,
+      text: value.text,
+    }
+  }
+}
+
+// D-ATIS by airport ICAO code. Airports broadcasting separate arrival and
+// departure ATIS get both texts joined, the arrival one first
+pub fn parse_datis(raw: &str) -> Result<HashMap<String, DigitalAtis>, serde_json::Error> {
+  let mut records: Vec<DatisRecord> = serde_json::from_str(raw)?;
+  records.sort_by_key(|r| r.kind != "arr");
+
+  let mut texts: HashMap<String, (String, String)> = HashMap::new();
+  for record in records {
+    match texts.get_mut(&record.airport) {
+      Some((_, text)) => {
+        text.push(' ');
+        text.push_str(&record.datis);
+      }
+      None => {
+        texts.insert(record.airport, (record.code, record.datis));
+      }
+    }
+  }
+  Ok(
+    texts
+      .into_iter()
+      .map(|(icao, (code, text))| {
+        let info = parse_atis(&text);
+        (icao, DigitalAtis { code, text, info })
+      })
+      .collect(),
+  )
+}
+
+pub async fn fetch_datis(
+  client: &Client,
+  url: &str,
+) -> Result<HashMap<String, DigitalAtis>, Box<dyn Error + Send + Sync>> {
+  let response = client.get(url).send().await?.error_for_status()?;
+  let raw = response.text().await?;
+  Ok(parse_datis(&raw)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_datis() {
+    let raw = r#"[
+      {"airport": "KATL", "type": "dep", "code": "C", "datis": "ATL DEP INFO C 1752Z. DEPG RWY 8R, 9L."},
+      {"airport": "KATL", "type": "arr", "code": "B", "datis": "ATL ARR INFO B 1752Z. 09008KT 10SM A3012 (THREE ZERO ONE TWO). SIMUL APCHS IN USE, ILS RWY 8L APCH, ILS RWY 9R APCH. LNDG RWY 8L, 9R."},
+      {"airport": "KBOS", "type": "combined", "code": "K", "datis": "BOS ATIS INFO K 1754Z. A2998. ILS RWY 4R APCH IN USE. DEPG RWY 9."}
+    ]"#;
+    let datis = parse_datis(raw).unwrap();
+    assert_eq!(datis.len(), 2);
+
+    let atl = &datis["KATL"];
+    assert_eq!(atl.code, "B");
+    assert!(atl.text.starts_with("ATL ARR INFO B"));
+    assert!(atl.text.ends_with("DEPG RWY 8R, 9L."));
+    assert_eq!(atl.info.altimeter, Some(30.12));
+    assert_eq!(atl.info.approaches, vec!["ILS"]);
+
+    assert_eq!(datis["KBOS"].code, "K");
+    assert!(parse_datis("{}").is_err());
+  }
+}
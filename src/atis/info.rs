@@ -0,0 +1,133 @@
+use super::runways::normalize_atis_text;
+use crate::service::camden;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::str::FromStr;
+
+const APPROACH_KINDS: &str = r"ILS|LOC|RNP|RNAV|GPS|VOR|NDB|VISUAL|LDA|GLS";
+
+lazy_static! {
+  static ref TRANSITION_LEVEL: Regex = Regex::from_str(
+    r"\b(?:TRANSITION LEVEL|TRANS LEVEL|TRL|TL) (?:IS )?(?:FLIGHT LEVEL |FL ?)?(\d{2,3})\b"
+  )
+  .unwrap();
+  static ref TRANSITION_ALTITUDE: Regex =
+    Regex::from_str(r"\b(?:TRANSITION ALTITUDE|TRANS ALT|TA) (?:IS )?(\d{4,5})\b").unwrap();
+  static ref QNH: Regex = Regex::from_str(r"\bQ ?N ?H ?(?:IS )?(\d{3,4})\b").unwrap();
+  static ref ALTIMETER: Regex =
+    Regex::from_str(r"\b(?:ALTIMETER SETTING|ALTIMETER|ALSTG|A) ?(\d{4})\b").unwrap();
+  // approach kinds followed by the word approach or a runway, e.g.
+  // "ILS OR VISUAL APPROACH", "ILS Z RWY 27"
+  static ref APPROACH_BEFORE: Regex = Regex::from_str(&format!(
+    r"\b((?:(?:{APPROACH_KINDS})(?: [XYZ])? (?:OR |AND )?)+)(?:APPROACHES|APPROACH|APCH|APPS|APP|RWY|RUNWAY)\b"
+  ))
+  .unwrap();
+  // approach kinds following the word approach or expect, e.g.
+  // "EXPECTED APPROACH ILS"
+  static ref APPROACH_AFTER: Regex = Regex::from_str(&format!(
+    r"\b(?:APPROACH|APCH|EXPECTED|EXPECT) ((?:(?:{APPROACH_KINDS})(?: [XYZ])?(?: OR| AND)? ?)+)"
+  ))
+  .unwrap();
+  static ref APPROACH_KIND: Regex = Regex::from_str(&format!(r"\b({APPROACH_KINDS})\b")).unwrap();
+  static ref LOW_VISIBILITY: Regex = Regex::from_str(
+    r"\b(?:LVPS|LVP|LOW VIS(?:IBILITY)? (?:PROCEDURES|PROCEDURE|PROC|OPERATIONS|OPS))\b( (?:ARE |IS )?(?:NOT|CANCELLED|TERMINATED|SUSPENDED))?"
+  )
+  .unwrap();
+}
+
+// Structured data extracted from the ATIS text
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AtisInfo {
+  // flight level
+  pub transition_level: Option<u32>,
+  // feet
+  pub transition_altitude: Option<u32>,
+  // hPa
+  pub qnh: Option<u32>,
+  // inHg
+  pub altimeter: Option<f64>,
+  // expected approach kinds, e.g. ILS, RNP or VISUAL
+  pub approaches: Vec<String>,
+  pub low_visibility: bool,
+}
+
+fn capture_u32(expr: &Regex, text: &str) -> Option<u32> {
+  expr
+    .captures(text)
+    .and_then(|cap| cap.get(1))
+    .and_then(|m| m.as_str().parse().ok())
+}
+
+fn detect_approaches(text: &str) -> Vec<String> {
+  let mut approaches: Vec<String> = vec![];
+  for expr in [&*APPROACH_BEFORE, &*APPROACH_AFTER] {
+    for cap in expr.captures_iter(text) {
+      for kind in APPROACH_KIND.find_iter(&cap[1]) {
+        if !approaches.iter().any(|a| a == kind.as_str()) {
+          approaches.push(kind.as_str().to_owned());
+        }
+      }
+    }
+  }
+  approaches
+}
+
+pub fn parse_atis(text: &str) -> AtisInfo {
+  let text = normalize_atis_text(text, true);
+  let low_visibility = LOW_VISIBILITY
+    .captures_iter(&text)
+    .any(|cap| cap.get(1).is_none());
+  AtisInfo {
+    transition_level: capture_u32(&TRANSITION_LEVEL, &text),
+    transition_altitude: capture_u32(&TRANSITION_ALTITUDE, &text),
+    qnh: capture_u32(&QNH, &text).filter(|qnh| (900..=1100).contains(qnh)),
+    // the dot is stripped from 29.92 when the text is normalised
+    altimeter: capture_u32(&ALTIMETER, &text)
+      .filter(|alt| (2500..=3200).contains(alt))
+      .map(|alt| alt as f64 / 100.0),
+    approaches: detect_approaches(&text),
+    low_visibility,
+  }
+}
+
+impl From<AtisInfo> for camden::AtisInfo {
+  fn from(value: AtisInfo) -> Self {
+    Self {
+      transition_level: value.transition_level,
+      transition_altitude: value.transition_altitude,
+      qnh: value.qnh,
+      altimeter: value.altimeter,
+      approaches: value.approaches,
+      low_visibility: value.low_visibility,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_atis() {
+    let info = parse_atis("BONJOUR. THIS IS CHARLES DE GAULLE INFORMATION YANKEE RECORDED AT 1 6 4 3 U T C. LANDING RUNWAY 26 LEFT AND 27 RIGHT, TAKEOFF RUNWAY 26 RIGHT AND 27 LEFT. EXPECTED APPROACH ILS. TRANSITION LEVEL 6 0. WIND 2 6 0 DEGREES, 9 KNOTS. TEMPERATURE 8, DEW POINT 5. Q N H 1 0 0 1, Q F E 0 9 8 7.");
+    assert_eq!(info.transition_level, Some(60));
+    assert_eq!(info.qnh, Some(1001));
+    assert_eq!(info.approaches, vec!["ILS"]);
+    assert!(!info.low_visibility);
+
+    let info = parse_atis("THIS IS KASTRUP AIRPORT DEPARTURE AND ARRIVAL INFO W METREPORT 1720 EXPECT ILS APPROACH VISUAL APPROACH ON REQUEST ARRIVAL RUNWAY 22L DEPARTURE RUNWAY 22R TRANSITION LEVEL 75 WIND 200 DEGREES 19 KNOTS QNH 974 LVP IN FORCE");
+    assert_eq!(info.transition_level, Some(75));
+    assert_eq!(info.qnh, Some(974));
+    assert_eq!(info.approaches, vec!["ILS", "VISUAL"]);
+    assert!(info.low_visibility);
+
+    let info = parse_atis("JFK ATIS INFO B 1751Z. 33012KT 10SM FEW050 07/M03 A2992 (TWO NINER NINER TWO). ILS RWY 4R APCH IN USE. RNAV Y OR VISUAL RWY 31L. TRANSITION ALTITUDE 18000. LOW VISIBILITY PROCEDURES NOT IN FORCE.");
+    assert_eq!(info.altimeter, Some(29.92));
+    assert_eq!(info.transition_altitude, Some(18000));
+    assert_eq!(info.transition_level, None);
+    assert_eq!(info.qnh, None);
+    assert_eq!(info.approaches, vec!["ILS", "RNAV", "VISUAL"]);
+    assert!(!info.low_visibility);
+  }
+}
@@ -1 +1,3 @@
+pub mod datis;
+pub mod info;
 pub mod runways;
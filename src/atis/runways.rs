@@ -3,12 +3,93 @@ use std::{collections::HashSet, str::FromStr};
 use lazy_static::lazy_static;
 use regex::Regex;
 
-const IDENT_EXPR: &str = r"(\d{2}(?:[LRC]|\s(?:LEFT|RIGHT|CENTER))?)(?:\s(?:(?:AND|OR)\s)?(\d{2}(?:[LRC]|\s(?:LEFT|RIGHT|CENTER))?))?(?:\s(?:(?:AND|OR)\s)?(\d{2}(?:[LRC]|\s(?:LEFT|RIGHT|CENTER))?))?";
+const IDENT_EXPR: &str = r"(\d{1,2}(?:\s?[LRC]\b|\s(?:LEFT|RIGHT|CENTER))?)(?:\s(?:(?:AND|OR)\s)?(\d{1,2}(?:\s?[LRC]\b|\s(?:LEFT|RIGHT|CENTER))?))?(?:\s(?:(?:AND|OR)\s)?(\d{1,2}(?:\s?[LRC]\b|\s(?:LEFT|RIGHT|CENTER))?))?";
+
+// French, German and Spanish runway phrasing translated to the English one
+// the detection expressions expect. Longer phrases go first
+const PHRASES: [(&str, &str); 51] = [
+  ("START UND LANDEBAHN", "LANDING AND TAKEOFF RUNWAY"),
+  ("L ATTERRISSAGE", "LANDING"),
+  ("LE DECOLLAGE", "TAKEOFF"),
+  ("L ARRIVEE", "ARRIVAL"),
+  ("LE DEPART", "DEPARTURE"),
+  ("EN SERVICE", "IN USE"),
+  ("EN UTILISATION", "IN USE"),
+  ("IN BENUTZUNG", "IN USE"),
+  ("IN BETRIEB", "IN USE"),
+  ("EN USO", "IN USE"),
+  ("EN SERVICIO", "IN USE"),
+  ("ATTERRISSAGES?", "LANDING"),
+  ("DECOLLAGES?", "TAKEOFF"),
+  ("ARRIVEES?", "ARRIVAL"),
+  ("DEPARTS?", "DEPARTURE"),
+  ("PISTES?", "RUNWAY"),
+  ("GAUCHE", "LEFT"),
+  ("DROITE", "RIGHT"),
+  ("CENTRE", "CENTER"),
+  ("POUR", "FOR"),
+  ("ET", "AND"),
+  ("OU", "OR"),
+  ("LANDEBAHN", "LANDING RUNWAY"),
+  ("STARTBAHN", "TAKEOFF RUNWAY"),
+  ("BAHN", "RUNWAY"),
+  ("LANDUNGEN", "LANDING"),
+  ("LANDUNG", "LANDING"),
+  ("ANFLUG", "APPROACH"),
+  ("ABFLUG", "DEPARTURE"),
+  ("LINKS", "LEFT"),
+  ("RECHTS", "RIGHT"),
+  ("MITTE", "CENTER"),
+  ("FUER", "FOR"),
+  ("FUR", "FOR"),
+  ("UND", "AND"),
+  ("ODER", "OR"),
+  ("PISTAS?", "RUNWAY"),
+  ("IZQUIERDA", "LEFT"),
+  ("DERECHA", "RIGHT"),
+  ("CENTRAL", "CENTER"),
+  ("ATERRIZAJES?", "LANDING"),
+  ("DESPEGUES?", "TAKEOFF"),
+  ("LLEGADAS?", "ARRIVAL"),
+  ("SALIDAS?", "DEPARTURE"),
+  ("PARA", "FOR"),
+  ("EL", ""),
+  ("LA", ""),
+  ("LOS", ""),
+  ("LAS", ""),
+  ("DIE", ""),
+  ("DER", ""),
+];
+
+const ACCENTS: [(char, char); 14] = [
+  ('À', 'A'),
+  ('Â', 'A'),
+  ('Á', 'A'),
+  ('Ä', 'A'),
+  ('Ç', 'C'),
+  ('É', 'E'),
+  ('È', 'E'),
+  ('Ê', 'E'),
+  ('Í', 'I'),
+  ('Ñ', 'N'),
+  ('Ó', 'O'),
+  ('Ö', 'O'),
+  ('Ú', 'U'),
+  ('Ü', 'U'),
+];
 
 lazy_static! {
   static ref WHITESPACE: Regex = Regex::from_str(r"\s+").unwrap();
   static ref SPECIAL: Regex = Regex::from_str(r"[^A-Z0-9\s]").unwrap();
   static ref COLLAPSE_NUMS: Regex = Regex::from_str(r"(\d)\s+(\d)").unwrap();
+  static ref SEPARATORS: Regex = Regex::from_str(r"[/'-]").unwrap();
+  static ref TRANSLATIONS: Vec<(Regex, &'static str)> = PHRASES
+    .iter()
+    .map(|(phrase, en)| (Regex::from_str(&format!(r"\b{phrase}\b")).unwrap(), *en))
+    .collect();
+  // spanish "Y" is only taken for "and" between runway idents, it's a
+  // letter of the ATIS code otherwise
+  static ref SPANISH_AND: Regex = Regex::from_str(r"(\d{2}(?: ?[LRC]| LEFT| RIGHT| CENTER)?) Y (\d{2})").unwrap();
   static ref ARRIVAL_EXPR: Vec<Regex> = [
     r"(?:(?:APPROACH|ARRIVAL|LANDING|LDG)\s)+(?:RUNWAY|RWY)S?\s".to_owned() + IDENT_EXPR,
     r"(?:RUNWAY|RWY)S?\s".to_owned() + IDENT_EXPR + r"\sFOR\s(?:ARRIVAL|LANDING|LDG|APPROACH)",
@@ -16,6 +97,7 @@ lazy_static! {
     r"(?:RUNWAY|RWY)S?\sIN\sUSE\s".to_owned() + IDENT_EXPR,
     r"(?:APPROACH|ARRIVAL|LANDING|LDG)\sAND\s(?:TAKEOFF|DEPARTURE|DEPARTING|DEP)\s(?:RUNWAY|RWY)S?\s".to_owned()
       + IDENT_EXPR,
+    r"(?:RUNWAY|RWY)S?\s".to_owned() + IDENT_EXPR + r"\s(?:OPS|OPERATIONS|OPERATION|CONFIGURATION|CONFIG)",
   ].into_iter().map(|expr| Regex::from_str(&expr).unwrap()).collect();
 
   static ref DEPARTURE_EXPR: Vec<Regex> = [
@@ -25,6 +107,7 @@ lazy_static! {
     r"(?:RUNWAY|RWY)S?\sIN\sUSE\s".to_owned() + IDENT_EXPR,
     r"(?:APPROACH|ARRIVAL|LANDING|LDG)\sAND\s(?:TAKEOFF|DEPARTURE|DEPARTING|DEP)\s(?:RUNWAY|RWY)S?\s".to_owned()
       + IDENT_EXPR,
+    r"(?:RUNWAY|RWY)S?\s".to_owned() + IDENT_EXPR + r"\s(?:OPS|OPERATIONS|OPERATION|CONFIGURATION|CONFIG)",
   ].into_iter().map(|expr| Regex::from_str(&expr).unwrap()).collect();
 }
 
@@ -37,15 +120,49 @@ fn normalize_runway_ident(ident: &str) -> String {
   }
 }
 
-pub fn normalize_atis_text(text: &str, collapse_nums: bool) -> String {
-  let text = text.to_uppercase();
+fn fold_accents(text: &str) -> String {
+  text
+    .chars()
+    .map(|c| {
+      ACCENTS
+        .iter()
+        .find(|(accented, _)| *accented == c)
+        .map_or(c, |(_, plain)| *plain)
+    })
+    .collect()
+}
+
+fn translate(text: &str) -> String {
+  let mut text = text.to_owned();
+  for (expr, en) in TRANSLATIONS.iter() {
+    if expr.is_match(&text) {
+      text = expr.replace_all(&text, *en).to_string();
+    }
+  }
+  let text = WHITESPACE.replace_all(&text, " ");
+  SPANISH_AND.replace_all(&text, "$1 AND $2").to_string()
+}
+
+// ATIS texts often spell numbers digit by digit, "Q N H 1 0 0 1". The
+// matches of a single pass don't overlap, so it takes a few
+fn collapse_nums(text: &str) -> String {
+  let mut text = text.to_owned();
+  loop {
+    let collapsed = COLLAPSE_NUMS.replace_all(&text, "$1$2").to_string();
+    if collapsed == text {
+      return text;
+    }
+    text = collapsed;
+  }
+}
+
+pub fn normalize_atis_text(text: &str, collapse: bool) -> String {
+  let text = fold_accents(&text.to_uppercase());
+  let text = SEPARATORS.replace_all(&text, " ");
   let text = SPECIAL.replace_all(&text, "");
   let text = WHITESPACE.replace_all(&text, " ");
-  let text = if collapse_nums {
-    COLLAPSE_NUMS.replace_all(&text, "$1$2")
-  } else {
-    text
-  };
+  let text = translate(text.trim());
+  let text = if collapse { collapse_nums(&text) } else { text };
   text.trim().to_owned()
 }
 
@@ -98,7 +215,7 @@ mod tests {
       exp: &'static str,
     }
 
-    let test_cases = vec![
+    let test_cases = [
       TC {
         src: "35L",
         exp: "35L",
@@ -127,7 +244,7 @@ mod tests {
       departures: Vec<&'static str>,
     }
 
-    let mut testcases = vec![
+    let mut testcases = [
       TC {atis: "BONJOUR. THIS IS CHARLES DE GAULLE INFORMATION YANKEE RECORDED AT 1 6 4 3 U T C. LANDING RUNWAY 26 LEFT AND 27 RIGHT, TAKEOFF RUNWAY 26 RIGHT AND 27 LEFT. EXPECTED APPROACH ILS. EXPECTED DEPARTURES 5 ALPHA , 5 BRAVO , 5 ZULU. TRANSITION LEVEL 6 0. AFTER VACATING THE OUTER RUNWAY, HOLD SHORT OF THE INNER RUNWAY. BIRD ACTIVITY REPORTED. WIND 2 6 0 DEGREES, 9 KNOTS. VISIBILITY 1 0 KILOMETERS. CLOUDS SCATTERED 1800 FEET. TEMPERATURE 8, DEW POINT 5. Q N H 1 0 0 1, Q F E 0 9 8 7. CONFIRM ON FIRST CONTACT THAT YOU HAVE RECEIVED INFORMATION YANKEE.", arrivals: vec!["26L", "27R"], departures: vec!["27L", "26R"] },
       TC {atis: "HANNOVER INFORMATION A MET REPORT TIME 1720 EXPECT ILS Z APPROACH RUNWAY 27C 27L OR 27R RUNWAYS IN USE 27C 27L AND 27R TRL 70 WIND 270 DEGREES 22 KNOTS GUSTS UP TO 33 KNOTS VISIBILITY 10 KILOMETERS IN THE VICINITY SHOWER CLOUDS BROKEN 2400 FEET TEMPERATURE 7 DEW POINT 3 QNH 985 TREND NOSIG HANNOVER INFORMATION A OUT", arrivals: vec!["27C", "27L", "27R"], departures: vec!["27C", "27L", "27R"]},
       TC {atis: "HAMBURG INFORMATION E MET REPORT TIME 1720 EXPECT ILS APPROACH RUNWAY 23 RUNWAY 23 IN USE FOR LANDING AND TAKE OFF TRL 70 WHEN PASSING 2000 FEET CONTACT BREMEN RADAR ON FREQUENCY 123.925 WIND 240 DEGREES 25 KNOTS GUSTS UP TO 37 KNOTS VARIABLE BETWEEN 210 AND 270 DEGREES VISIBILITY 10 KILOMETERS LIGHT SHOWERS OF RAIN CLOUDS BROKEN CB 1800 FEET TEMPERATURE 6 DEW POINT 3 QNH 978 TREND TEMPORARY WIND 250 DEGREES 25 KNOTS GUSTS UP TO 45 KNOTS MODERATE SHOWERS OF RAIN INFORMATION E OUT", arrivals: vec!["23"], departures: vec!["23"]},
@@ -149,4 +266,66 @@ mod tests {
       assert_eq!(departures, tc.departures);
     }
   }
+
+  #[test]
+  fn test_detect_localized() {
+    // atis text, arrivals, departures
+    let corpus: [(&str, &[&str], &[&str]); 9] = [
+      (
+        "LYON SAINT EXUPERY INFORMATION B. DÉCOLLAGE PISTE 35 DROITE, PISTE 35 GAUCHE POUR L'ATTERRISSAGE. NIVEAU DE TRANSITION 70.",
+        &["35L"],
+        &["35R"],
+      ),
+      (
+        "ATTERRISSAGE PISTE 2 6 GAUCHE ET 2 7 DROITE, DÉCOLLAGE PISTE 2 6 DROITE ET 2 7 GAUCHE",
+        &["26L", "27R"],
+        &["26R", "27L"],
+      ),
+      (
+        "FRANKFURT INFORMATION K. LANDEBAHN 25 LINKS UND 25 RECHTS, STARTBAHN 18. UEBERGANGSFLAECHE 70.",
+        &["25L", "25R"],
+        &["18"],
+      ),
+      (
+        "STUTTGART INFORMATION C. START- UND LANDEBAHN 25 IN BETRIEB.",
+        &["25"],
+        &["25"],
+      ),
+      (
+        "MADRID BARAJAS INFORMACION D. PISTAS 32 IZQUIERDA Y 32 DERECHA PARA ATERRIZAJE, DESPEGUE PISTAS 36 IZQUIERDA Y 36 DERECHA.",
+        &["32L", "32R"],
+        &["36L", "36R"],
+      ),
+      (
+        "PALMA INFORMACION A. PISTA EN USO 24 IZQUIERDA.",
+        &["24L"],
+        &["24L"],
+      ),
+      (
+        "LANDING RWY 25 R, DEPARTURE RWY 25 C",
+        &["25R"],
+        &["25C"],
+      ),
+      (
+        "SCHIPHOL ARRIVAL AND DEPARTURE INFORMATION P. RWY 09L/09R OPS. TRANSITION LEVEL 60.",
+        &["09L", "09R"],
+        &["09L", "09R"],
+      ),
+      (
+        "LANDING RUNWAY 2 7 LEFT, TAKEOFF RUNWAY 2 7 RIGHT. WIND 2 7 0 DEGREES 1 2 KNOTS. INFORMATION Y",
+        &["27L"],
+        &["27R"],
+      ),
+    ];
+
+    for (atis, exp_arrivals, exp_departures) in corpus {
+      let norm_atis = normalize_atis_text(atis, true);
+      let mut arrivals = detect_arrivals(&norm_atis);
+      let mut departures = detect_departures(&norm_atis);
+      arrivals.sort();
+      departures.sort();
+      assert_eq!(arrivals, exp_arrivals, "arrivals in {atis}");
+      assert_eq!(departures, exp_departures, "departures in {atis}");
+    }
+  }
 }
@@ -0,0 +1,290 @@
+// Load test harness: runs the service on a replayed datafeed capture along
+// with simulated map_updates clients and reports ingestion time, push
+// latency and memory use. Built with the loadtest feature:
+//
+//   cargo run --release --features loadtest --bin loadtest -- \
+//     -c simwatch-grpc.toml --capture captures/ --clients 200 --speed 3
+use clap::Parser;
+use futures_util::{stream, StreamExt};
+use log::{error, LevelFilter};
+use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
+use simwatch_grpc::{
+  config::{read_config, ConfigSource},
+  manager::Manager,
+  moving::{
+    data::Data,
+    source::{DataSource, ReplaySource},
+  },
+  service::{
+    camden::{
+      camden_client::CamdenClient, camden_server::CamdenServer, map_updates_request::Request,
+      update::ObjectUpdate, MapBounds, MapUpdatesRequest, Point,
+    },
+    quota::QuotaLayer,
+    rpc_metrics::RpcMetricsLayer,
+    CamdenService,
+  },
+};
+use std::{
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::{sync::watch, time::sleep};
+use tonic::transport::Server;
+
+// vatsim refreshes its datafeed this often, captures are taken as often
+const CAPTURE_PERIOD: Duration = Duration::from_secs(15);
+
+#[derive(Parser, Debug)]
+struct Args {
+  #[arg(short, default_value = "/etc/simwatch/simwatch-grpc.toml")]
+  config: String,
+  #[arg(short, long = "set", value_name = "KEY=VALUE")]
+  set: Vec<String>,
+  // datafeed capture in Vatsim v3 JSON format, either a file or a
+  // directory of files replayed in name order
+  #[arg(long)]
+  capture: String,
+  // replay speed relative to the capture period
+  #[arg(long, default_value_t = 1.0)]
+  speed: f64,
+  // simulated map_updates clients
+  #[arg(long, default_value_t = 200)]
+  clients: usize,
+  #[arg(long, default_value = "2m", value_parser = parse_duration)]
+  duration: Duration,
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+  duration_str::parse(value).map_err(|err| err.to_string())
+}
+
+#[derive(Default)]
+struct Stats {
+  fetch: Vec<f64>,
+  ingestion: Vec<f64>,
+  push: Vec<f64>,
+  updates: u64,
+  bytes: u64,
+}
+
+// The replayed capture, noting when every payload is in hand
+struct TimedSource {
+  inner: ReplaySource,
+  fetched: watch::Sender<Option<Instant>>,
+  stats: Arc<Mutex<Stats>>,
+}
+
+#[tonic::async_trait]
+impl DataSource for TimedSource {
+  fn tag(&self) -> &str {
+    self.inner.tag()
+  }
+
+  async fn fetch(&self) -> Option<Data> {
+    let t = Instant::now();
+    let data = self.inner.fetch().await;
+    self
+      .stats
+      .lock()
+      .unwrap()
+      .fetch
+      .push(t.elapsed().as_secs_f64());
+    self.fetched.send_replace(Some(Instant::now()));
+    data
+  }
+}
+
+// Viewports spread over the busy part of the world, every tenth client
+// watches the whole map
+fn bounds(client: usize) -> MapBounds {
+  if client.is_multiple_of(10) {
+    return MapBounds {
+      sw: Some(Point {
+        lat: -80.0,
+        lng: -180.0,
+      }),
+      ne: Some(Point {
+        lat: 80.0,
+        lng: 180.0,
+      }),
+      zoom: 2.0,
+    };
+  }
+  let lat = -40.0 + (client * 37 % 90) as f64;
+  let lng = -120.0 + (client * 53 % 240) as f64;
+  MapBounds {
+    sw: Some(Point { lat, lng }),
+    ne: Some(Point {
+      lat: lat + 10.0,
+      lng: lng + 15.0,
+    }),
+    zoom: 6.0,
+  }
+}
+
+// A map_updates client noting how long after the payload has been fetched
+// the first pilot update of every cycle arrives
+async fn run_client(
+  client: usize,
+  endpoint: String,
+  mut fetched: watch::Receiver<Option<Instant>>,
+  stats: Arc<Mutex<Stats>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut camden = CamdenClient::connect(endpoint).await?;
+  let req = MapUpdatesRequest {
+    request: Some(Request::Bounds(bounds(client))),
+  };
+  // the request stream is kept open, the server hangs up otherwise
+  let requests = stream::iter([req]).chain(stream::pending());
+  let mut updates = camden.map_updates(requests).await?.into_inner();
+
+  // the initial load isn't a push
+  let mut seen = *fetched.borrow_and_update();
+  while let Some(update) = updates.message().await? {
+    let received = Instant::now();
+    let mut stats = stats.lock().unwrap();
+    stats.updates += 1;
+    stats.bytes += prost::Message::encoded_len(&update) as u64;
+    if !matches!(update.object_update, Some(ObjectUpdate::PilotUpdate(_))) {
+      continue;
+    }
+    let last = *fetched.borrow();
+    if let Some(at) = last.filter(|_| last != seen) {
+      stats.push.push(received.duration_since(at).as_secs_f64());
+      seen = last;
+    }
+  }
+  Ok(())
+}
+
+// kB values of the given /proc/self/status field, e.g. VmRSS
+fn memory_kb(field: &str) -> Option<u64> {
+  let status = std::fs::read_to_string("/proc/self/status").ok()?;
+  status
+    .lines()
+    .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+    .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+fn percentiles(name: &str, values: &mut [f64]) {
+  if values.is_empty() {
+    println!("{name}: no samples");
+    return;
+  }
+  values.sort_by(f64::total_cmp);
+  let at = |q: f64| values[((values.len() - 1) as f64 * q).round() as usize] * 1000.0;
+  println!(
+    "{name}: n={} p50={:.1}ms p90={:.1}ms p99={:.1}ms max={:.1}ms",
+    values.len(),
+    at(0.5),
+    at(0.9),
+    at(0.99),
+    at(1.0)
+  );
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let args = Args::parse();
+  let source = ConfigSource::new(&args.config, &args.set)?;
+  let mut config = read_config(&source);
+  config.api.poll_period = CAPTURE_PERIOD.div_f64(args.speed);
+  config.validate()?;
+
+  TermLogger::init(
+    LevelFilter::Warn,
+    Config::default(),
+    TerminalMode::Stderr,
+    ColorChoice::Auto,
+  )?;
+
+  let mut addr: SocketAddr = config.grpc.listen.parse()?;
+  if addr.ip().is_unspecified() {
+    addr.set_ip([127, 0, 0, 1].into());
+  }
+  let stats = Arc::new(Mutex::new(Stats::default()));
+  let (fetched, fetched_rx) = watch::channel(None);
+  let replay = TimedSource {
+    inner: ReplaySource::new("replay", &args.capture)?,
+    fetched,
+    stats: stats.clone(),
+  };
+
+  let m = Arc::new(Manager::new(config.clone(), source).await);
+  {
+    let m = m.clone();
+    tokio::spawn(async move {
+      if let Err(err) = m.run_with_sources(vec![Arc::new(replay)]).await {
+        error!("error running manager: {err:?}");
+      }
+    });
+  }
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_track_writer().await });
+  }
+
+  // ingestion time is taken from the payload in hand to the diff published
+  {
+    let mut events = m.subscribe_events();
+    let fetched = fetched_rx.clone();
+    let stats = stats.clone();
+    tokio::spawn(async move {
+      while let Ok(batch) = events.recv().await {
+        if batch.pilots.set.is_empty() && batch.pilots.delete.is_empty() {
+          continue;
+        }
+        if let Some(at) = *fetched.borrow() {
+          let secs = at.elapsed().as_secs_f64();
+          stats.lock().unwrap().ingestion.push(secs);
+        }
+      }
+    });
+  }
+
+  let svc = CamdenServer::new(CamdenService::new(m.clone()));
+  tokio::spawn(
+    Server::builder()
+      .layer(RpcMetricsLayer)
+      .layer(QuotaLayer::new(m.clone()))
+      .add_service(svc)
+      .serve(addr),
+  );
+  // let the server come up
+  sleep(Duration::from_secs(1)).await;
+
+  let endpoint = format!("http://{addr}");
+  for client in 0..args.clients {
+    let endpoint = endpoint.clone();
+    let fetched = fetched_rx.clone();
+    let stats = stats.clone();
+    tokio::spawn(async move {
+      if let Err(err) = run_client(client, endpoint, fetched, stats).await {
+        error!("client {client}: {err}");
+      }
+    });
+  }
+
+  println!(
+    "{} clients, replaying {} every {:?} for {:?}",
+    args.clients, args.capture, config.api.poll_period, args.duration
+  );
+  sleep(args.duration).await;
+
+  let mut stats = stats.lock().unwrap();
+  percentiles("fetch", &mut stats.fetch);
+  percentiles("ingestion", &mut stats.ingestion);
+  percentiles("push latency", &mut stats.push);
+  println!(
+    "updates received: {} ({} bytes)",
+    stats.updates, stats.bytes
+  );
+  println!(
+    "memory: rss={}kB peak={}kB",
+    memory_kb("VmRSS").unwrap_or_default(),
+    memory_kb("VmHWM").unwrap_or_default()
+  );
+  Ok(())
+}
@@ -106,8 +106,7 @@ fn extract_boundaries(feat: &Feature) -> Option<Boundaries> {
   }
 }
 
-pub async fn load_boundaries(url: &str) -> Result<HashMap<String, Boundaries>, Box<dyn Error>> {
-  let raw_geojson = reqwest::get(url).await?.text().await?;
+pub fn parse_boundaries(raw_geojson: &str) -> Result<HashMap<String, Boundaries>, Box<dyn Error>> {
   let geo = raw_geojson.parse::<GeoJson>()?;
   let coll = FeatureCollection::try_from(geo)?;
   let mut res = HashMap::new();
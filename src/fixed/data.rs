@@ -1,29 +1,72 @@
 use super::{
+  frequencies::AirportFrequency,
   geonames::Geonames,
+  navaids::Navaid,
   types::{Airport, Country, GeonamesCountry, FIR, UIR},
 };
 use crate::{
+  atis::datis::DigitalAtis,
   moving::controller::{Controller, Facility},
-  types::Point,
+  service::camden,
+  types::{Point, Rect},
   weather::WeatherInfo,
 };
+use chrono::{DateTime, Utc};
+use geo::HaversineDistance;
+use geo_types::Point as GeoPoint;
 use log::error;
-use std::collections::HashMap;
+use rstar::{primitives::GeomWithData, RTree};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+// navaid position along with its index in FixedData::navaids
+type NavaidPoint = GeomWithData<Point, usize>;
+
+// Which kind of code an ambiguous airport code is resolved by first
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CodePreference {
+  #[default]
+  Iata,
+  Icao,
+}
+
+impl From<camden::CodePreference> for CodePreference {
+  fn from(value: camden::CodePreference) -> Self {
+    match value {
+      camden::CodePreference::PreferIata => Self::Iata,
+      camden::CodePreference::PreferIcao => Self::Icao,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FixedDataCounts {
+  pub airports: usize,
+  pub firs: usize,
+  pub runways: usize,
+  pub navaids: usize,
+}
 
 #[derive(Debug)]
 pub struct FixedData {
   countries: Vec<Country>,
-  airports: Vec<Airport>,
-  firs: Vec<FIR>,
+  // shared with the readers, copied on write
+  airports: Vec<Arc<Airport>>,
+  firs: Vec<Arc<FIR>>,
   uirs: Vec<UIR>,
   arpt_icao_idx: HashMap<String, Vec<usize>>,
-  arpt_iata_idx: HashMap<String, usize>,
+  arpt_iata_idx: HashMap<String, Vec<usize>>,
   arpt_compound_idx: HashMap<String, usize>,
   country_idx: HashMap<String, usize>,
   firs_icao_idx: HashMap<String, usize>,
   firs_prefix_idx: HashMap<String, usize>,
   uirs_idx: HashMap<String, usize>,
   geonames: Geonames,
+  navaids: Vec<Navaid>,
+  navaids_idx: HashMap<String, Vec<usize>>,
+  navaids2d: RTree<NavaidPoint>,
 }
 
 impl FixedData {
@@ -41,6 +84,9 @@ impl FixedData {
       firs_prefix_idx: HashMap::new(),
       uirs_idx: HashMap::new(),
       geonames: Geonames::empty(),
+      navaids: vec![],
+      navaids_idx: HashMap::new(),
+      navaids2d: RTree::new(),
     }
   }
 
@@ -57,6 +103,9 @@ impl FixedData {
     self.firs_prefix_idx = other.firs_prefix_idx;
     self.uirs_idx = other.uirs_idx;
     self.geonames = other.geonames;
+    self.navaids = other.navaids;
+    self.navaids_idx = other.navaids_idx;
+    self.navaids2d = other.navaids2d;
   }
 
   pub fn new(
@@ -67,7 +116,7 @@ impl FixedData {
     geonames: Geonames,
   ) -> Self {
     let mut arpt_icao_idx: HashMap<String, Vec<usize>> = HashMap::new();
-    let mut arpt_iata_idx: HashMap<String, usize> = HashMap::new();
+    let mut arpt_iata_idx: HashMap<String, Vec<usize>> = HashMap::new();
     let mut arpt_compound_idx: HashMap<String, usize> = HashMap::new();
     for (idx, arpt) in airports.iter().enumerate() {
       if !arpt.icao.is_empty() {
@@ -80,7 +129,10 @@ impl FixedData {
         }
       }
       if !arpt.iata.is_empty() {
-        arpt_iata_idx.insert(arpt.iata.clone(), idx);
+        arpt_iata_idx
+          .entry(arpt.iata.clone())
+          .or_default()
+          .push(idx);
       }
       arpt_compound_idx.insert(arpt.compound_id(), idx);
     }
@@ -104,8 +156,8 @@ impl FixedData {
 
     Self {
       countries,
-      airports,
-      firs,
+      airports: airports.into_iter().map(Arc::new).collect(),
+      firs: firs.into_iter().map(Arc::new).collect(),
       uirs,
       arpt_icao_idx,
       arpt_iata_idx,
@@ -115,23 +167,163 @@ impl FixedData {
       firs_prefix_idx,
       uirs_idx,
       geonames,
+      navaids: vec![],
+      navaids_idx: HashMap::new(),
+      navaids2d: RTree::new(),
+    }
+  }
+
+  pub fn set_navaids(&mut self, navaids: Vec<Navaid>) {
+    let mut navaids_idx: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut points = vec![];
+    for (idx, navaid) in navaids.iter().enumerate() {
+      navaids_idx
+        .entry(navaid.ident.clone())
+        .or_default()
+        .push(idx);
+      points.push(NavaidPoint::new(navaid.position, idx));
+    }
+    self.navaids = navaids;
+    self.navaids_idx = navaids_idx;
+    self.navaids2d = RTree::bulk_load(points);
+  }
+
+  // navaid idents are not unique worldwide
+  pub fn find_navaids(&self, ident: &str) -> Vec<Navaid> {
+    self
+      .navaids_idx
+      .get(ident)
+      .map(|indices| {
+        indices
+          .iter()
+          .map(|idx| self.navaids[*idx].clone())
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  // the navaid with the ident closest to the point
+  pub fn closest_navaid(&self, ident: &str, near: Point) -> Option<&Navaid> {
+    let near: GeoPoint = near.into();
+    self
+      .navaids_idx
+      .get(ident)?
+      .iter()
+      .map(|idx| &self.navaids[*idx])
+      .min_by(|a, b| {
+        let da = near.haversine_distance(&a.position.into());
+        let db = near.haversine_distance(&b.position.into());
+        da.total_cmp(&db)
+      })
+  }
+
+  pub fn get_navaids(&self, rect: &Rect) -> Vec<Navaid> {
+    let mut found = HashSet::new();
+    for env in rect.envelopes() {
+      for point in self.navaids2d.locate_in_envelope(&env) {
+        found.insert(point.data);
+      }
+    }
+    found
+      .into_iter()
+      .map(|idx| self.navaids[idx].clone())
+      .collect()
+  }
+
+  // Attaches the published frequencies to the airports by ICAO code
+  pub fn set_frequencies(&mut self, mut frequencies: HashMap<String, Vec<AirportFrequency>>) {
+    for arpt in self.airports.iter_mut() {
+      if let Some(freqs) = frequencies.remove(&arpt.icao) {
+        Arc::make_mut(arpt).frequencies = freqs;
+      }
+    }
+  }
+
+  // Carries the runtime state, i.e. weather and currently assigned
+  // controllers, over from the data set being replaced
+  pub fn inherit_state(&mut self, old: &FixedData, controllers: &HashMap<String, Controller>) {
+    for arpt in old.airports.iter() {
+      if let Some(wx) = &arpt.wx {
+        self.set_airport_weather(&arpt.icao, wx.clone());
+      }
+    }
+    self.set_datis(
+      old
+        .airports
+        .iter()
+        .filter_map(|arpt| arpt.datis.clone().map(|datis| (arpt.icao.clone(), datis)))
+        .collect(),
+    );
+    for ctrl in controllers.values() {
+      match ctrl.facility {
+        Facility::Reject => (),
+        Facility::Radar => {
+          self.set_fir_controller(ctrl.clone());
+        }
+        _ => {
+          self.set_airport_controller(ctrl.clone());
+        }
+      }
+    }
+  }
+
+  // ICAO codes of the airports known by the same IATA code in both the old
+  // and the new data but with a different ICAO code, both codes included
+  pub fn remapped_icao(&self, old: &FixedData) -> HashSet<String> {
+    let mut remapped = HashSet::new();
+    for iata in self.arpt_iata_idx.keys() {
+      let idx = self.find_airport_idx(iata);
+      let old_idx = old.find_airport_idx(iata);
+      let (Some(idx), Some(old_idx)) = (idx, old_idx) else {
+        continue;
+      };
+      let icao = &self.airports[idx].icao;
+      let old_icao = &old.airports[old_idx].icao;
+      if icao != old_icao {
+        remapped.insert(icao.clone());
+        remapped.insert(old_icao.clone());
+      }
+    }
+    remapped
+  }
+
+  pub fn counts(&self) -> FixedDataCounts {
+    FixedDataCounts {
+      airports: self.airports.len(),
+      firs: self.firs.len(),
+      runways: self.airports.iter().map(|arpt| arpt.runways.len()).sum(),
+      navaids: self.navaids.len(),
     }
   }
 
-  pub fn airports(&self) -> &Vec<Airport> {
+  pub fn airports(&self) -> &Vec<Arc<Airport>> {
     &self.airports
   }
 
-  pub fn firs(&self) -> &Vec<FIR> {
+  pub fn firs(&self) -> &Vec<Arc<FIR>> {
     &self.firs
   }
 
   pub fn set_airport_weather(&mut self, icao: &str, wx: WeatherInfo) {
     let idx = self.find_airport_idx(icao);
     if let Some(idx) = idx {
-      let arpt = self.airports.get_mut(idx);
+      let arpt = self.airports.get_mut(idx).map(Arc::make_mut);
       if let Some(arpt) = arpt {
         arpt.wx = Some(wx);
+        arpt.update_runway_winds();
+      }
+    }
+  }
+
+  // Replaces the digital ATIS of every airport, the ones missing from the
+  // feed get theirs cleared
+  pub fn set_datis(&mut self, mut datis: HashMap<String, DigitalAtis>) {
+    for arpt in self.airports.iter_mut() {
+      let fresh = datis.remove(&arpt.icao);
+      if arpt.datis != fresh {
+        let arpt = Arc::make_mut(arpt);
+        arpt.datis = fresh;
+        arpt.set_active_runways();
       }
     }
   }
@@ -142,7 +334,7 @@ impl FixedData {
     let code = tokens[0];
     let idx = self.find_airport_idx(code);
     if let Some(idx) = idx {
-      let arpt = self.airports.get_mut(idx);
+      let arpt = self.airports.get_mut(idx).map(Arc::make_mut);
       if let Some(arpt) = arpt {
         ctrl.human_readable = match &ctrl.facility {
           Facility::ATIS => Some(format!("{} ATIS", arpt.name)),
@@ -163,6 +355,7 @@ impl FixedData {
           Facility::Approach => arpt.controllers.approach = Some(ctrl),
           _ => unreachable!(),
         }
+        arpt.link_frequencies();
         return Some(arpt);
       } else {
         error!(
@@ -181,12 +374,13 @@ impl FixedData {
     let code = tokens[0];
     let idx = self.find_airport_idx(code);
     if let Some(idx) = idx {
-      let arpt = self.airports.get_mut(idx);
+      let arpt = self.airports.get_mut(idx).map(Arc::make_mut);
       if let Some(arpt) = arpt {
         match &ctrl.facility {
           Facility::ATIS => {
             arpt.controllers.atis = None;
-            arpt.reset_active_runways();
+            // falls back to the digital ATIS if there's one
+            arpt.set_active_runways();
           }
           Facility::Delivery => arpt.controllers.delivery = None,
           Facility::Ground => arpt.controllers.ground = None,
@@ -194,6 +388,7 @@ impl FixedData {
           Facility::Approach => arpt.controllers.approach = None,
           _ => unreachable!(),
         }
+        arpt.link_frequencies();
       } else {
         error!(
           "can't find airport for controller {} by index {}, this is deffy a bug",
@@ -205,7 +400,7 @@ impl FixedData {
     }
   }
 
-  pub fn set_fir_controller(&mut self, ctrl: Controller) -> Option<FIR> {
+  pub fn set_fir_controller(&mut self, ctrl: Controller) -> Option<Arc<FIR>> {
     let tokens: Vec<&str> = ctrl.callsign.split('_').collect();
     let code = tokens[0];
     let country = self
@@ -216,8 +411,8 @@ impl FixedData {
     let fir_ids = self.find_fir_indices(code);
     let mut fir_found = None;
     for idx in fir_ids {
-      let fir = self.firs.get_mut(idx);
-      if let Some(fir) = fir {
+      if let Some(shared) = self.firs.get_mut(idx) {
+        let fir = Arc::make_mut(shared);
         // region:set_human_readable
         let ctrl = if let Some(country) = country {
           let mut ctrl = ctrl.clone();
@@ -232,7 +427,7 @@ impl FixedData {
         };
         // endregion:set_human_readable
         fir.controllers.insert(ctrl.callsign.clone(), ctrl);
-        fir_found = Some(fir.clone());
+        fir_found = Some(shared.clone());
       }
     }
     fir_found
@@ -245,11 +440,46 @@ impl FixedData {
     for idx in fir_ids {
       let fir = self.firs.get_mut(idx);
       if let Some(fir) = fir {
-        fir.controllers.remove(&ctrl.callsign);
+        if fir.controllers.contains_key(&ctrl.callsign) {
+          Arc::make_mut(fir).controllers.remove(&ctrl.callsign);
+        }
+      }
+    }
+  }
+
+  // VATSIM top-down model: an airport with no tower of its own is
+  // covered by its approach or, failing that, by a center controller
+  // of the FIR it belongs to
+  pub fn update_coverage(&mut self) {
+    let coverage: Vec<Option<String>> = self
+      .airports
+      .iter()
+      .map(|arpt| self.covering_controller(arpt))
+      .collect();
+    for (arpt, covered_by) in self.airports.iter_mut().zip(coverage) {
+      if arpt.covered_by != covered_by {
+        Arc::make_mut(arpt).covered_by = covered_by;
       }
     }
   }
 
+  fn covering_controller(&self, arpt: &Airport) -> Option<String> {
+    if arpt.controllers.tower.is_some() {
+      return None;
+    }
+    if let Some(app) = &arpt.controllers.approach {
+      return Some(app.callsign.clone());
+    }
+    if arpt.fir_id.is_empty() {
+      return None;
+    }
+    let idx = self
+      .find_fir_idx_by_icao(&arpt.fir_id)
+      .or_else(|| self.find_fir_idx_by_prefix(&arpt.fir_id))?;
+    // several center positions may share a FIR, pick a stable one
+    self.firs[idx].controllers.keys().min().cloned()
+  }
+
   fn find_fir_idx_by_icao(&self, query: &str) -> Option<usize> {
     self.firs_icao_idx.get(query).copied()
   }
@@ -296,7 +526,7 @@ impl FixedData {
     }
   }
 
-  pub fn find_firs(&self, query: &str) -> Vec<FIR> {
+  pub fn find_firs(&self, query: &str) -> Vec<Arc<FIR>> {
     self
       .find_fir_indices(query)
       .into_iter()
@@ -311,23 +541,72 @@ impl FixedData {
       .map(|idx| self.countries[*idx].clone())
   }
 
-  pub fn find_airport_idx(&self, code: &str) -> Option<usize> {
+  // All the airports known by the code, the ones matching it by the
+  // preferred kind of code go first and real airports go before pseudo
+  // ones within each kind
+  pub fn find_airport_indices(&self, code: &str, pref: CodePreference) -> Vec<usize> {
     let code = if code.len() > 4 { &code[0..4] } else { code };
-    let idx = self.arpt_iata_idx.get(code);
-    if let Some(idx) = idx {
-      Some(*idx)
-    } else {
-      let indices = self.arpt_icao_idx.get(code);
-      indices.map(|indices| indices[0])
+    let by_iata = self.arpt_iata_idx.get(code);
+    let by_icao = self.arpt_icao_idx.get(code);
+    let groups = match pref {
+      CodePreference::Iata => [by_iata, by_icao],
+      CodePreference::Icao => [by_icao, by_iata],
+    };
+    let mut indices = vec![];
+    for group in groups.into_iter().flatten() {
+      let mut group = group.clone();
+      // stable sort, the data order is kept otherwise
+      group.sort_by_key(|idx| self.airports[*idx].is_pseudo);
+      for idx in group {
+        if !indices.contains(&idx) {
+          indices.push(idx);
+        }
+      }
     }
+    indices
+  }
+
+  pub fn find_airport_idx_by(&self, code: &str, pref: CodePreference) -> Option<usize> {
+    self.find_airport_indices(code, pref).first().copied()
+  }
+
+  // controller callsigns and flight plans use IATA codes as often as ICAO
+  // ones, IATA matches take precedence as they always have
+  pub fn find_airport_idx(&self, code: &str) -> Option<usize> {
+    self.find_airport_idx_by(code, CodePreference::Iata)
   }
 
-  pub fn find_airport(&self, code: &str) -> Option<Airport> {
+  pub fn find_airport(&self, code: &str) -> Option<Arc<Airport>> {
     let idx = self.find_airport_idx(code)?;
     Some(self.airports[idx].clone())
   }
 
-  pub fn find_airport_compound(&self, code: &str) -> Option<Airport> {
+  pub fn find_airport_by(&self, code: &str, pref: CodePreference) -> Option<Arc<Airport>> {
+    let idx = self.find_airport_idx_by(code, pref)?;
+    Some(self.airports[idx].clone())
+  }
+
+  pub fn find_airports(&self, code: &str, pref: CodePreference) -> Vec<Arc<Airport>> {
+    self
+      .find_airport_indices(code, pref)
+      .into_iter()
+      .map(|idx| self.airports[idx].clone())
+      .collect()
+  }
+
+  pub fn airport_qnh(&self, compound_id: &str) -> Option<f64> {
+    let idx = self.arpt_compound_idx.get(compound_id)?;
+    self.airports.get(*idx)?.wx.as_ref()?.qnh
+  }
+
+  // current UTC offset of the airport local time, minutes
+  pub fn airport_utc_offset(&self, code: &str, now: DateTime<Utc>) -> Option<i32> {
+    let idx = self.find_airport_idx(code)?;
+    let tz = self.airports.get(idx)?.timezone.as_ref()?;
+    Some(tz.offset_at(now))
+  }
+
+  pub fn find_airport_compound(&self, code: &str) -> Option<Arc<Airport>> {
     let idx = self.arpt_compound_idx.get(code)?;
     let arpt = self.airports.get(*idx)?;
     Some(arpt.clone())
@@ -341,3 +620,210 @@ impl FixedData {
     self.geonames.get_country_by_id(id)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixed::{ourairports::Runway, types::Boundaries},
+    moving::controller::ControllerSet,
+  };
+  use chrono::Utc;
+
+  fn controller(callsign: &str, facility: Facility) -> Controller {
+    Controller {
+      cid: 1,
+      name: "".into(),
+      callsign: callsign.into(),
+      freq: 0,
+      facility,
+      rating: 0,
+      server: "".into(),
+      visual_range: 0,
+      atis_code: "".into(),
+      text_atis: "".into(),
+      human_readable: None,
+      last_updated: Utc::now(),
+      logon_time: Utc::now(),
+      source: "".into(),
+      atis: None,
+    }
+  }
+
+  fn airport(icao: &str, fir_id: &str) -> Airport {
+    Airport {
+      icao: icao.into(),
+      iata: "".into(),
+      name: icao.into(),
+      position: Point { lat: 0.0, lng: 0.0 },
+      fir_id: fir_id.into(),
+      is_pseudo: false,
+      controllers: ControllerSet::empty(),
+      runways: HashMap::new(),
+      frequencies: vec![],
+      country: None,
+      timezone: None,
+      wx: None,
+      covered_by: None,
+      datis: None,
+    }
+  }
+
+  fn fir(icao: &str) -> FIR {
+    let p = Point { lat: 0.0, lng: 0.0 };
+    FIR {
+      icao: icao.into(),
+      name: icao.into(),
+      prefix: "".into(),
+      boundaries: Boundaries {
+        id: icao.into(),
+        region: "".into(),
+        division: "".into(),
+        is_oceanic: false,
+        min: p,
+        max: p,
+        center: p,
+        points: vec![],
+      },
+      controllers: HashMap::new(),
+      country: None,
+    }
+  }
+
+  #[test]
+  fn test_top_down_coverage() {
+    let mut fixed = FixedData::new(
+      vec![],
+      vec![
+        airport("EGLL", "EGTT"),
+        airport("EGKK", "EGTT"),
+        airport("EGCC", "EGTT"),
+      ],
+      vec![fir("EGTT")],
+      vec![],
+      Geonames::empty(),
+    );
+    fixed.set_fir_controller(controller("EGTT_CTR", Facility::Radar));
+    fixed.set_airport_controller(controller("EGLL_TWR", Facility::Tower));
+    fixed.set_airport_controller(controller("EGKK_APP", Facility::Approach));
+    fixed.update_coverage();
+
+    let covered_by = |code: &str| fixed.find_airport(code).unwrap().covered_by.clone();
+    assert_eq!(covered_by("EGLL"), None);
+    assert_eq!(covered_by("EGKK"), Some("EGKK_APP".into()));
+    assert_eq!(covered_by("EGCC"), Some("EGTT_CTR".into()));
+
+    fixed.reset_fir_controller(&controller("EGTT_CTR", Facility::Radar));
+    fixed.update_coverage();
+    assert_eq!(fixed.find_airport("EGCC").unwrap().covered_by, None);
+  }
+
+  #[test]
+  fn test_datis_fallback() {
+    let runway = |ident: &str| Runway {
+      icao: "KBOS".into(),
+      length_ft: 0,
+      width_ft: 0,
+      surface: "".into(),
+      lighted: true,
+      closed: false,
+      ident: ident.into(),
+      reciprocal: "".into(),
+      latitude: 0.0,
+      longitude: 0.0,
+      elevation_ft: 0,
+      heading: 0,
+      active_to: false,
+      active_lnd: false,
+      wind: None,
+    };
+    let mut kbos = airport("KBOS", "KZBW");
+    for ident in ["22L", "22R"] {
+      kbos.runways.insert(ident.into(), runway(ident));
+    }
+    let mut fixed = FixedData::new(vec![], vec![kbos], vec![], vec![], Geonames::empty());
+    let active = |fixed: &FixedData| {
+      let arpt = fixed.find_airport("KBOS").unwrap();
+      (
+        arpt.runways["22L"].active_lnd,
+        arpt.runways["22R"].active_lnd,
+      )
+    };
+
+    let text = "BOS ATIS INFO K. ARRIVAL RUNWAY 22L DEPARTURE RUNWAY 22R.";
+    fixed.set_datis(HashMap::from([(
+      "KBOS".to_owned(),
+      DigitalAtis {
+        code: "K".into(),
+        text: text.into(),
+        info: Default::default(),
+      },
+    )]));
+    assert_eq!(active(&fixed), (true, false));
+    assert!(!fixed.find_airport("KBOS").unwrap().is_staffed());
+
+    // the vatsim atis takes precedence while online
+    let mut atis = controller("KBOS_ATIS", Facility::ATIS);
+    atis.text_atis = "ARRIVAL RUNWAY 22R DEPARTURE RUNWAY 22L".into();
+    fixed.set_airport_controller(atis.clone());
+    assert_eq!(active(&fixed), (false, true));
+    fixed.reset_airport_controller(&atis);
+    assert_eq!(active(&fixed), (true, false));
+
+    fixed.set_datis(HashMap::new());
+    assert_eq!(active(&fixed), (false, false));
+  }
+
+  #[test]
+  fn test_remapped_icao() {
+    let with_iata = |icao: &str, iata: &str| Airport {
+      iata: iata.into(),
+      ..airport(icao, "EGTT")
+    };
+    let data = |airports| FixedData::new(vec![], airports, vec![], vec![], Geonames::empty());
+    let old = data(vec![with_iata("EGLL", "LHR"), with_iata("EGXX", "XXX")]);
+    let new = data(vec![with_iata("EGLL", "LHR"), with_iata("EGYY", "XXX")]);
+
+    let remapped = new.remapped_icao(&old);
+    assert_eq!(remapped, HashSet::from(["EGXX".into(), "EGYY".into()]));
+  }
+
+  #[test]
+  fn test_find_airport_indices() {
+    let with = |icao: &str, iata: &str, is_pseudo: bool| Airport {
+      iata: iata.into(),
+      is_pseudo,
+      ..airport(icao, "EGTT")
+    };
+    let fixed = FixedData::new(
+      vec![],
+      vec![
+        with("KXXX", "", true),
+        with("KXXX", "", false),
+        with("KYYY", "XXX", false),
+        with("XXX", "", false),
+      ],
+      vec![],
+      vec![],
+      Geonames::empty(),
+    );
+
+    // pseudo airports go last among the ICAO matches
+    assert_eq!(
+      fixed.find_airport_indices("KXXX", CodePreference::Iata),
+      vec![1, 0]
+    );
+    assert_eq!(
+      fixed.find_airport_indices("XXX", CodePreference::Iata),
+      vec![2, 3]
+    );
+    assert_eq!(
+      fixed.find_airport_indices("XXX", CodePreference::Icao),
+      vec![3, 2]
+    );
+    assert!(!fixed.find_airport("KXXX").unwrap().is_pseudo);
+    assert!(fixed
+      .find_airport_indices("ZZZZ", CodePreference::Iata)
+      .is_empty());
+  }
+}
@@ -11,3 +11,21 @@ impl Display for GeonamesParseError {
   }
 }
 impl std::error::Error for GeonamesParseError {}
+
+#[derive(Debug)]
+pub struct ChecksumError {
+  pub url: String,
+  pub expected: String,
+  pub actual: String,
+}
+
+impl Display for ChecksumError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "checksum mismatch for {}: expected sha256 {}, got {}",
+      self.url, self.expected, self.actual
+    )
+  }
+}
+impl std::error::Error for ChecksumError {}
@@ -0,0 +1,147 @@
+use crate::{
+  config::Config, fixed::cached_loader, moving::controller::ControllerSet, service::camden,
+  util::seconds_since,
+};
+use chrono::Utc;
+use log::{error, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, io::Read};
+
+// Published airport frequency from the ourairports airport-frequencies
+// dataset
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AirportFrequency {
+  // TWR, GND, ATIS, UNIC, CTAF and so on
+  pub kind: String,
+  pub description: String,
+  pub frequency_khz: u32,
+  // callsign of the online controller working the frequency
+  pub online: Option<String>,
+}
+
+impl AirportFrequency {
+  // controller frequencies are truncated to kHz when parsed, thus 118.505
+  // may come as 118504
+  fn matches(&self, freq: u32) -> bool {
+    self.frequency_khz.abs_diff(freq) <= 1
+  }
+
+  pub fn link(&mut self, controllers: &ControllerSet) {
+    self.online = controllers
+      .iter()
+      .find(|ctrl| self.matches(ctrl.freq))
+      .map(|ctrl| ctrl.callsign.clone());
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct FrequencyRecord {
+  airport_ident: String,
+  #[serde(rename = "type")]
+  kind: String,
+  description: String,
+  frequency_mhz: f64,
+}
+
+impl From<FrequencyRecord> for AirportFrequency {
+  fn from(value: FrequencyRecord) -> Self {
+    Self {
+      kind: value.kind,
+      description: value.description,
+      frequency_khz: (value.frequency_mhz * 1000.0).round() as u32,
+      online: None,
+    }
+  }
+}
+
+impl From<AirportFrequency> for camden::AirportFrequency {
+  fn from(value: AirportFrequency) -> Self {
+    Self {
+      kind: value.kind,
+      description: value.description,
+      frequency_khz: value.frequency_khz,
+      online_callsign: value.online,
+    }
+  }
+}
+
+// Frequencies by airport ident
+pub fn parse_frequencies(src: impl Read) -> HashMap<String, Vec<AirportFrequency>> {
+  let mut rdr = csv::Reader::from_reader(src);
+  let mut frequencies: HashMap<String, Vec<AirportFrequency>> = HashMap::new();
+  for res in rdr.deserialize::<FrequencyRecord>() {
+    match res {
+      Ok(record) => frequencies
+        .entry(record.airport_ident.clone())
+        .or_default()
+        .push(record.into()),
+      Err(err) => error!("error parsing airport frequency: {err}"),
+    }
+  }
+  frequencies
+}
+
+pub async fn load_frequencies(
+  cfg: &Config,
+  client: &Client,
+) -> Result<HashMap<String, Vec<AirportFrequency>>, Box<dyn Error>> {
+  let cache_file = cached_loader(
+    client,
+    &cfg.fixed.frequencies_url,
+    &cfg.cache.frequencies,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.frequencies_url),
+  )
+  .await?;
+  let t = Utc::now();
+  let frequencies = parse_frequencies(cache_file);
+  info!("airport frequencies data parsed in {}s", seconds_since(t));
+  Ok(frequencies)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::moving::controller::{Controller, Facility};
+
+  #[test]
+  fn test_parse_frequencies() {
+    let raw = r#""id","airport_ref","airport_ident","type","description","frequency_mhz"
+60641,2434,"EGLL","ATIS","ATIS",128.075
+60642,2434,"EGLL","TWR","HEATHROW TWR",118.5
+70000,9999,"K0W3","UNIC","UNICOM",122.8
+70001,9999,"K0W3","CTAF","CTAF",
+"#;
+    let frequencies = parse_frequencies(raw.as_bytes());
+    assert_eq!(frequencies["EGLL"].len(), 2);
+    assert_eq!(frequencies["EGLL"][0].frequency_khz, 128075);
+    assert_eq!(frequencies["K0W3"].len(), 1);
+    assert_eq!(frequencies["K0W3"][0].kind, "UNIC");
+
+    let mut twr = frequencies["EGLL"][1].clone();
+    let mut controllers = ControllerSet::empty();
+    controllers.tower = Some(Controller {
+      cid: 1,
+      name: "".into(),
+      callsign: "EGLL_TWR".into(),
+      freq: 118499,
+      facility: Facility::Tower,
+      rating: 0,
+      server: "".into(),
+      visual_range: 0,
+      atis_code: "".into(),
+      text_atis: "".into(),
+      human_readable: None,
+      last_updated: Utc::now(),
+      logon_time: Utc::now(),
+      source: "".into(),
+      atis: None,
+    });
+    twr.link(&controllers);
+    assert_eq!(twr.online, Some("EGLL_TWR".into()));
+
+    twr.link(&ControllerSet::empty());
+    assert_eq!(twr.online, None);
+  }
+}
@@ -1,8 +1,9 @@
-use super::types::GeonamesCountry;
+use super::{shapes_cache, types::GeonamesCountry};
 use crate::{
   config::Config,
   fixed::{
     cached_loader,
+    timezones::{load_timezones, CityPoint, TimeZone},
     types::{GeonamesShape, GeonamesShapeSet},
   },
   types::Point,
@@ -12,15 +13,23 @@ use chrono::Utc;
 use csv::StringRecord;
 use geo::Contains;
 use geojson::{FeatureCollection, GeoJson};
-use log::info;
+use log::{error, info};
+use reqwest::Client;
 use rstar::{RTree, AABB};
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{
+  collections::HashMap,
+  fs::File,
+  io::{BufReader, BufWriter, Cursor, Read},
+  path::Path,
+};
 use zip::ZipArchive;
 
 #[derive(Debug)]
 pub struct Geonames {
   countries: HashMap<String, GeonamesCountry>,
   countries2d: RTree<GeonamesShape>,
+  timezones: Vec<TimeZone>,
+  cities2d: RTree<CityPoint>,
 }
 
 impl Geonames {
@@ -28,6 +37,8 @@ impl Geonames {
     Self {
       countries: HashMap::new(),
       countries2d: RTree::new(),
+      timezones: vec![],
+      cities2d: RTree::new(),
     }
   }
 
@@ -35,17 +46,30 @@ impl Geonames {
     Self {
       countries: other.countries,
       countries2d: other.countries2d,
+      timezones: other.timezones,
+      cities2d: other.cities2d,
     }
   }
 
-  pub async fn load(cfg: &Config) -> Result<Self, Box<dyn std::error::Error>> {
-    let countries = load_countries(cfg).await?;
-    let geonames_shapes = load_shapes(cfg).await?;
+  pub async fn load(cfg: &Config, client: &Client) -> Result<Self, Box<dyn std::error::Error>> {
+    let countries = load_countries(cfg, client).await?;
+    let geonames_shapes = load_shapes(cfg, client).await?;
     let countries2d = RTree::bulk_load(geonames_shapes);
+    // time zones are informational only, airports go without them if
+    // the data can't be loaded
+    let (timezones, cities2d) = match load_timezones(cfg, client).await {
+      Ok(res) => res,
+      Err(err) => {
+        error!("error loading geonames time zones: {err}");
+        (vec![], RTree::new())
+      }
+    };
 
     Ok(Self {
       countries,
       countries2d,
+      timezones,
+      cities2d,
     })
   }
 
@@ -63,6 +87,15 @@ impl Geonames {
     }
   }
 
+  // time zone of the closest city
+  pub fn get_timezone_by_position(&self, position: Point) -> Option<TimeZone> {
+    self
+      .cities2d
+      .nearest_neighbor(&position)
+      .and_then(|city| self.timezones.get(city.data))
+      .cloned()
+  }
+
   pub fn get_country_by_id(&self, id: &str) -> Option<GeonamesCountry> {
     self.countries.get(id).cloned()
   }
@@ -114,10 +147,14 @@ fn parse_countries(
 
 async fn load_countries(
   cfg: &Config,
+  client: &Client,
 ) -> Result<HashMap<String, GeonamesCountry>, Box<dyn std::error::Error>> {
   let cache_file = cached_loader(
+    client,
     &cfg.fixed.geonames_countries_url,
     &cfg.cache.geonames_countries,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.geonames_countries_url),
   )
   .await?;
 
@@ -127,18 +164,52 @@ async fn load_countries(
   Ok(countries)
 }
 
-async fn load_shapes(cfg: &Config) -> Result<Vec<GeonamesShape>, Box<dyn std::error::Error>> {
-  let cache_file =
-    cached_loader(&cfg.fixed.geonames_shapes_url, &cfg.cache.geonames_shapes).await?;
+async fn load_shapes(
+  cfg: &Config,
+  client: &Client,
+) -> Result<Vec<GeonamesShape>, Box<dyn std::error::Error>> {
+  let mut cache_file = cached_loader(
+    client,
+    &cfg.fixed.geonames_shapes_url,
+    &cfg.cache.geonames_shapes,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.geonames_shapes_url),
+  )
+  .await?;
   let t = Utc::now();
-  let mut z = ZipArchive::new(cache_file)?;
+  let mut raw = vec![];
+  cache_file.read_to_end(&mut raw)?;
+
+  // the parsed shapes are reused as long as the archive stays the same
+  let checksum = crc32fast::hash(&raw);
+  let bin_path = shapes_cache::path(Path::new(&cfg.cache.geonames_shapes));
+  let cached = File::open(&bin_path)
+    .and_then(|f| shapes_cache::read(BufReader::new(f), checksum))
+    .ok()
+    .flatten();
+  if let Some(shapes) = cached {
+    info!("geonames shapes read from cache in {}s", seconds_since(t));
+    return Ok(shapes);
+  }
+
+  let shapes = parse_shapes(raw)?;
+  info!("geonames shapes parsed in {}s", seconds_since(t));
+  let res =
+    File::create(&bin_path).and_then(|f| shapes_cache::write(BufWriter::new(f), checksum, &shapes));
+  if let Err(err) = res {
+    error!("error storing geonames shapes cache: {err}");
+  }
+  Ok(shapes)
+}
+
+fn parse_shapes(raw: Vec<u8>) -> Result<Vec<GeonamesShape>, Box<dyn std::error::Error>> {
+  let mut z = ZipArchive::new(Cursor::new(raw))?;
   let mut raw_data = String::new();
 
   let mut file = z.by_name("shapes_simplified_low.json")?;
   file.read_to_string(&mut raw_data)?;
 
   let geodata = raw_data.parse::<GeoJson>()?;
-  info!("geonames geojson parsed in {}s", seconds_since(t));
 
   let mut shapes = vec![];
   let fc = FeatureCollection::try_from(geodata)?;
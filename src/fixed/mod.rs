@@ -1,35 +1,332 @@
 /// Fixed data provider
 /// This includes vatspy-data-project's items like Countries, Airports,
-/// FIRs and UIRs as well as ourairports' data on runways
+/// FIRs and UIRs as well as ourairports' data on runways, navaids and
+/// airport frequencies
 mod boundaries;
 pub mod data;
 pub mod errors;
+pub mod frequencies;
 pub mod geonames;
+pub mod navaids;
 pub mod ourairports;
 pub mod parser;
+mod shapes_cache;
+pub mod timezones;
 pub mod types;
 
 use crate::util::seconds_since;
 use chrono::Utc;
-use log::info;
-use std::{error::Error, fs::File, io::Write, path::Path};
+use errors::ChecksumError;
+use log::{error, info};
+use reqwest::{
+  header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+  Client, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+  error::Error,
+  fs::File,
+  io::Write,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+use tracing::instrument;
+
+// Validators of a cached copy, stored next to it to revalidate it with
+// a conditional request
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct CacheMeta {
+  etag: Option<String>,
+  last_modified: Option<String>,
+}
+
+impl CacheMeta {
+  fn path(cache_path: &Path) -> PathBuf {
+    let mut path = cache_path.as_os_str().to_owned();
+    path.push(".meta");
+    PathBuf::from(path)
+  }
+
+  fn load(cache_path: &Path) -> Self {
+    std::fs::read_to_string(Self::path(cache_path))
+      .ok()
+      .and_then(|raw| serde_json::from_str(&raw).ok())
+      .unwrap_or_default()
+  }
+
+  fn store(&self, cache_path: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(Self::path(cache_path), serde_json::to_string(self)?)?;
+    Ok(())
+  }
+
+  fn from_headers(headers: &HeaderMap) -> Self {
+    let value = |name| {
+      headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+    };
+    Self {
+      etag: value(ETAG),
+      last_modified: value(LAST_MODIFIED),
+    }
+  }
+
+  fn conditional_headers(&self) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(value) = self.etag.as_ref().and_then(|v| v.parse().ok()) {
+      headers.insert(IF_NONE_MATCH, value);
+    }
+    if let Some(value) = self.last_modified.as_ref().and_then(|v| v.parse().ok()) {
+      headers.insert(IF_MODIFIED_SINCE, value);
+    }
+    headers
+  }
+}
+
+// file:// urls point at local mirrors which are read in place, bypassing
+// the cache
+fn local_path(url: &str) -> Option<&Path> {
+  url.strip_prefix("file://").map(Path::new)
+}
+
+// Checks the data against the sha256 hex digest pinned for the url, if any
+fn verify_checksum(url: &str, data: &[u8], checksum: Option<&str>) -> Result<(), ChecksumError> {
+  let Some(expected) = checksum else {
+    return Ok(());
+  };
+  let actual = hex::encode(Sha256::digest(data));
+  if actual.eq_ignore_ascii_case(expected) {
+    Ok(())
+  } else {
+    Err(ChecksumError {
+      url: url.to_owned(),
+      expected: expected.to_owned(),
+      actual,
+    })
+  }
+}
+
+fn verify_file(url: &str, path: &Path, checksum: Option<&str>) -> Result<(), Box<dyn Error>> {
+  if checksum.is_some() {
+    let data = std::fs::read(path)?;
+    verify_checksum(url, &data, checksum)?;
+  }
+  Ok(())
+}
+
+// Fetches the url and stores the result in cache_filename. If fetching fails and
+// stale data is allowed, the previously stored copy is used instead. Data not
+// matching the pinned checksum is treated as a failed fetch.
+// Returns the data along with the flag showing if it came from the stale cache
+#[instrument(skip_all, fields(url = url))]
+async fn fallback_loader(
+  client: &Client,
+  url: &str,
+  cache_filename: &str,
+  allow_stale: bool,
+  checksum: Option<&str>,
+) -> Result<(String, bool), Box<dyn Error>> {
+  if let Some(path) = local_path(url) {
+    info!("reading {url}");
+    let text = std::fs::read_to_string(path)?;
+    verify_checksum(url, text.as_bytes(), checksum)?;
+    return Ok((text, false));
+  }
+
+  info!("fetching {url} from web");
+  let res: Result<String, Box<dyn Error>> = async {
+    let text = client
+      .get(url)
+      .send()
+      .await?
+      .error_for_status()?
+      .text()
+      .await?;
+    verify_checksum(url, text.as_bytes(), checksum)?;
+    Ok(text)
+  }
+  .await;
+  match res {
+    Ok(text) => {
+      let res = std::fs::write(cache_filename, &text);
+      if let Err(err) = res {
+        error!("error storing {url} in {cache_filename}: {err}");
+      }
+      Ok((text, false))
+    }
+    Err(err) => {
+      let path = Path::new(cache_filename);
+      if allow_stale && path.is_file() {
+        error!("error fetching {url}: {err}, falling back to {cache_filename}");
+        let text = std::fs::read_to_string(path)?;
+        verify_checksum(url, text.as_bytes(), checksum)?;
+        Ok((text, true))
+      } else {
+        Err(err)
+      }
+    }
+  }
+}
+
+fn cache_expired(path: &Path, max_age: Option<Duration>) -> bool {
+  let Some(max_age) = max_age else {
+    return false;
+  };
+  path
+    .metadata()
+    .and_then(|meta| meta.modified())
+    .ok()
+    .and_then(|modified| modified.elapsed().ok())
+    .map(|age| age > max_age)
+    .unwrap_or(true)
+}
+
+// Fetches the url into path. If there's a copy at path already, the request
+// is conditional and an unchanged copy is only touched to reset its age.
+// Returns false if the copy has not been changed
+async fn fetch_to_file(client: &Client, url: &str, path: &Path) -> Result<bool, Box<dyn Error>> {
+  let meta = if path.is_file() {
+    CacheMeta::load(path)
+  } else {
+    CacheMeta::default()
+  };
+  let response = client
+    .get(url)
+    .headers(meta.conditional_headers())
+    .send()
+    .await?;
+  if response.status() == StatusCode::NOT_MODIFIED {
+    File::options()
+      .write(true)
+      .open(path)?
+      .set_modified(SystemTime::now())?;
+    return Ok(false);
+  }
+
+  let response = response.error_for_status()?;
+  let meta = CacheMeta::from_headers(response.headers());
+  let data = response.bytes().await?;
+  let mut cache_file = File::create(path)?;
+  cache_file.write_all(&data)?;
+  if let Err(err) = meta.store(path) {
+    error!("error storing {url} cache validators: {err}");
+  }
+  Ok(true)
+}
+
+// Returns the cached copy of the url contents fetching it first if there's
+// no cache yet or the cache is older than max_age. An expired cache is
+// revalidated with a conditional request and still used if refetching fails.
+// A cached copy not matching the pinned checksum is dropped and fetched anew
+#[instrument(skip_all, fields(url = url))]
+async fn cached_loader(
+  client: &Client,
+  url: &str,
+  cache_filename: &str,
+  max_age: Option<Duration>,
+  checksum: Option<&str>,
+) -> Result<File, Box<dyn Error>> {
+  if let Some(path) = local_path(url) {
+    info!("reading {url}");
+    verify_file(url, path, checksum)?;
+    return Ok(File::open(path)?);
+  }
 
-async fn cached_loader(url: &str, cache_filename: &str) -> Result<File, Box<dyn Error>> {
   let path = Path::new(&cache_filename);
   if !path.is_file() {
     info!("fetching {url} from web");
     let t = Utc::now();
-    let data = reqwest::get(url).await?.bytes().await?;
-    let mut cache_file = File::create(path)?;
-    cache_file.write_all(&data)?;
+    fetch_to_file(client, url, path).await?;
     info!(
       "data loaded from web in {}s and stored in {cache_filename}",
       seconds_since(t)
     );
+  } else if cache_expired(path, max_age) {
+    info!("{cache_filename} expired, revalidating {url}");
+    let res = fetch_to_file(client, url, path).await;
+    match res {
+      Ok(true) => info!("{cache_filename} refetched"),
+      Ok(false) => info!("{url} not modified, keeping {cache_filename}"),
+      Err(err) => error!("error refetching {url}: {err}, using expired {cache_filename}"),
+    }
   } else {
     info!("{cache_filename} found, skipping fetching")
   }
 
+  let verified = verify_file(url, path, checksum)
+    .map_err(|err| error!("{err}, refetching {url}"))
+    .is_ok();
+  if !verified {
+    std::fs::remove_file(path)?;
+    fetch_to_file(client, url, path).await?;
+    verify_file(url, path, checksum)?;
+  }
+
   let f = File::open(path)?;
   Ok(f)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{cache_expired, local_path, verify_checksum, CacheMeta};
+  use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+  use std::{path::Path, time::Duration};
+
+  #[test]
+  fn test_cache_meta() {
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, "\"5f1e-6a\"".parse().unwrap());
+    headers.insert(
+      LAST_MODIFIED,
+      "Sat, 02 Mar 2024 11:50:00 GMT".parse().unwrap(),
+    );
+    let meta = CacheMeta::from_headers(&headers);
+    assert_eq!(meta.etag.as_deref(), Some("\"5f1e-6a\""));
+
+    let headers = meta.conditional_headers();
+    assert_eq!(headers.get(IF_NONE_MATCH).unwrap(), "\"5f1e-6a\"");
+    assert_eq!(
+      headers.get(IF_MODIFIED_SINCE).unwrap(),
+      "Sat, 02 Mar 2024 11:50:00 GMT"
+    );
+    assert!(CacheMeta::default().conditional_headers().is_empty());
+
+    assert_eq!(
+      CacheMeta::path(Path::new("/tmp/runways.csv.cache")),
+      Path::new("/tmp/runways.csv.cache.meta")
+    );
+  }
+
+  #[test]
+  fn test_cache_expired() {
+    let path = Path::new("Cargo.toml");
+    assert!(!cache_expired(path, None));
+    assert!(!cache_expired(
+      path,
+      Some(Duration::from_secs(86400 * 365 * 100))
+    ));
+    assert!(cache_expired(path, Some(Duration::ZERO)));
+    assert!(cache_expired(
+      Path::new("/nonexistent/cache"),
+      Some(Duration::from_secs(60))
+    ));
+  }
+
+  #[test]
+  fn test_verify_checksum() {
+    let sha = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    assert!(verify_checksum("u", b"hello", None).is_ok());
+    assert!(verify_checksum("u", b"hello", Some(sha)).is_ok());
+    assert!(verify_checksum("u", b"hello", Some(&sha.to_uppercase())).is_ok());
+    let err = verify_checksum("u", b"hello!", Some(sha)).unwrap_err();
+    assert_eq!(err.expected, sha);
+
+    assert_eq!(
+      local_path("file:///srv/mirror/VATSpy.dat"),
+      Some(Path::new("/srv/mirror/VATSpy.dat"))
+    );
+    assert_eq!(local_path("https://example.com/VATSpy.dat"), None);
+  }
+}
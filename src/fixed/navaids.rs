@@ -0,0 +1,118 @@
+use crate::{
+  config::Config, fixed::cached_loader, service::camden, types::Point, util::seconds_since,
+};
+use chrono::Utc;
+use log::{error, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, io::Read};
+
+// Radio navigation aids from the ourairports navaids dataset
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Navaid {
+  pub ident: String,
+  pub name: String,
+  // VOR, VOR-DME, VORTAC, DME, NDB, NDB-DME or TACAN
+  pub kind: String,
+  pub frequency_khz: Option<u32>,
+  pub position: Point,
+  pub elevation_ft: Option<i32>,
+  pub country: String,
+  pub associated_airport: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NavaidRecord {
+  ident: String,
+  name: String,
+  #[serde(rename = "type")]
+  kind: String,
+  frequency_khz: Option<u32>,
+  latitude_deg: f64,
+  longitude_deg: f64,
+  elevation_ft: Option<i32>,
+  iso_country: String,
+  associated_airport: String,
+}
+
+impl From<NavaidRecord> for Navaid {
+  fn from(value: NavaidRecord) -> Self {
+    Self {
+      ident: value.ident,
+      name: value.name,
+      kind: value.kind,
+      frequency_khz: value.frequency_khz,
+      position: Point {
+        lat: value.latitude_deg,
+        lng: value.longitude_deg,
+      },
+      elevation_ft: value.elevation_ft,
+      country: value.iso_country,
+      associated_airport: value.associated_airport,
+    }
+  }
+}
+
+impl From<Navaid> for camden::Navaid {
+  fn from(value: Navaid) -> Self {
+    Self {
+      ident: value.ident,
+      name: value.name,
+      kind: value.kind,
+      frequency_khz: value.frequency_khz,
+      position: Some(value.position.into()),
+      elevation_ft: value.elevation_ft,
+      country: value.country,
+      associated_airport: value.associated_airport,
+    }
+  }
+}
+
+pub fn parse_navaids(src: impl Read) -> Vec<Navaid> {
+  let mut rdr = csv::Reader::from_reader(src);
+  let mut navaids = vec![];
+  for res in rdr.deserialize::<NavaidRecord>() {
+    match res {
+      Ok(record) => navaids.push(record.into()),
+      Err(err) => error!("error parsing navaid: {err}"),
+    }
+  }
+  navaids
+}
+
+pub async fn load_navaids(cfg: &Config, client: &Client) -> Result<Vec<Navaid>, Box<dyn Error>> {
+  let cache_file = cached_loader(
+    client,
+    &cfg.fixed.navaids_url,
+    &cfg.cache.navaids,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.navaids_url),
+  )
+  .await?;
+  let t = Utc::now();
+  let navaids = parse_navaids(cache_file);
+  info!("navaids data parsed in {}s", seconds_since(t));
+  Ok(navaids)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_navaids() {
+    let raw = r#""id","filename","ident","name","type","frequency_khz","latitude_deg","longitude_deg","elevation_ft","iso_country","dme_frequency_khz","dme_channel","dme_latitude_deg","dme_longitude_deg","dme_elevation_ft","slaved_variation_deg","magnetic_variation_deg","usageType","power","associated_airport"
+85943,"London_VOR-DME_GB","LON","London","VOR-DME",113600,51.4871,-0.46706,77,"GB",113600,"083X",51.4871,-0.46706,77,,-0.152,"BOTH","HIGH","EGLL"
+91027,"Detling_VOR-DME_GB","DET","Detling","VOR-DME",117300,51.304,0.597334,,"GB",,,,,,,,"BOTH","HIGH",
+broken,"line"
+"#;
+    let navaids = parse_navaids(raw.as_bytes());
+    assert_eq!(navaids.len(), 2);
+    assert_eq!(navaids[0].ident, "LON");
+    assert_eq!(navaids[0].kind, "VOR-DME");
+    assert_eq!(navaids[0].frequency_khz, Some(113600));
+    assert_eq!(navaids[0].associated_airport, "EGLL");
+    assert_eq!(navaids[1].elevation_ft, None);
+    assert_eq!(navaids[1].position.lng, 0.597334);
+  }
+}
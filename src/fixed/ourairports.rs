@@ -1,6 +1,7 @@
 use chrono::Utc;
 use csv::StringRecord;
 use log::{error, info};
+use reqwest::Client;
 use serde::Serialize;
 use std::{
   collections::HashMap,
@@ -19,19 +20,83 @@ pub struct Runway {
   pub width_ft: u32,
   pub surface: String,
   pub lighted: bool,
+  // shared by both ends
   pub closed: bool,
   pub ident: String,
+  // ident of the opposite end
+  pub reciprocal: String,
   pub latitude: f64,
   pub longitude: f64,
   pub elevation_ft: i32,
   pub heading: u16,
   pub active_to: bool,
   pub active_lnd: bool,
+  // current wind relative to the runway, if the airport weather is known
+  pub wind: Option<WindComponents>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Clone, Copy)]
+pub struct WindComponents {
+  // knots, negative for a tailwind
+  pub headwind: f64,
+  // knots, positive for the wind from the right
+  pub crosswind: f64,
+}
+
+impl Runway {
+  // direction is the true direction the wind blows from, the runway
+  // headings are true as well
+  pub fn wind_components(&self, direction: u64, speed: u64) -> WindComponents {
+    let angle = (direction as f64 - self.heading as f64).to_radians();
+    let speed = speed as f64;
+    // adding zero turns -0.0 into 0.0
+    let round = |v: f64| (v * 10.0).round() / 10.0 + 0.0;
+    WindComponents {
+      headwind: round(speed * angle.cos()),
+      crosswind: round(speed * angle.sin()),
+    }
+  }
+
+  // the tailwind is only worth a warning on a runway in use
+  pub fn active_tailwind(&self) -> bool {
+    (self.active_to || self.active_lnd) && self.wind.is_some_and(|w| w.headwind < 0.0)
+  }
+}
+
+// Opposite end ident, e.g. 16R for 34L. None for idents not starting with
+// a runway number, e.g. helipads
+pub fn reciprocal_ident(ident: &str) -> Option<String> {
+  let digits = ident.chars().take_while(|c| c.is_ascii_digit()).count();
+  if digits == 0 || digits > 2 {
+    return None;
+  }
+  let num = ident[..digits].parse::<u8>().ok()?;
+  if !(1..=36).contains(&num) {
+    return None;
+  }
+  let num = (num + 17) % 36 + 1;
+  let suffix = match &ident[digits..] {
+    "L" => "R",
+    "R" => "L",
+    other => other,
+  };
+  Some(format!("{num:02}{suffix}"))
+}
+
+impl From<WindComponents> for camden::RunwayWind {
+  fn from(value: WindComponents) -> Self {
+    Self {
+      headwind: value.headwind,
+      crosswind: value.crosswind,
+    }
+  }
 }
 
 impl From<Runway> for camden::Runway {
   fn from(value: Runway) -> Self {
     Self {
+      active_tailwind: value.active_tailwind(),
+      wind: value.wind.map(|w| w.into()),
       icao: value.icao,
       length_ft: value.length_ft,
       width_ft: value.width_ft,
@@ -39,12 +104,13 @@ impl From<Runway> for camden::Runway {
       lighted: value.lighted,
       closed: value.closed,
       ident: value.ident,
+      reciprocal: value.reciprocal,
       latitude: value.latitude,
       longitude: value.longitude,
       elevation_ft: value.elevation_ft,
       heading: value.heading as i32,
       active_to: value.active_to,
-      active_lnd: value.active_to,
+      active_lnd: value.active_lnd,
     }
   }
 }
@@ -95,7 +161,11 @@ fn parse_runway(tokens: &StringRecord) -> Result<(Runway, Runway), ParseError> {
   let le_lng = parse_f64(&tokens[10]).unwrap_or(0.0);
   let le_elev = parse_i32(&tokens[11]).unwrap_or(0);
   let le_hdg = parse_f64(&tokens[12]).unwrap_or(0.0);
-  let he_ident = &tokens[14];
+  // single ended runways have the other end computed
+  let he_ident = match &tokens[14] {
+    "" => reciprocal_ident(le_ident).unwrap_or_default(),
+    ident => ident.to_owned(),
+  };
   let he_lat = parse_f64(&tokens[15]).unwrap_or(0.0);
   let he_lng = parse_f64(&tokens[16]).unwrap_or(0.0);
   let he_elev = parse_i32(&tokens[17]).unwrap_or(0);
@@ -109,12 +179,14 @@ fn parse_runway(tokens: &StringRecord) -> Result<(Runway, Runway), ParseError> {
     lighted,
     closed,
     ident: le_ident.into(),
+    reciprocal: he_ident.clone(),
     latitude: le_lat,
     longitude: le_lng,
     elevation_ft: le_elev,
     heading: le_hdg as u16,
     active_to: false,
     active_lnd: false,
+    wind: None,
   };
   let rwy2 = Runway {
     icao: icao.into(),
@@ -123,13 +195,15 @@ fn parse_runway(tokens: &StringRecord) -> Result<(Runway, Runway), ParseError> {
     surface: surface.into(),
     lighted,
     closed,
-    ident: he_ident.into(),
+    reciprocal: le_ident.into(),
+    ident: he_ident,
     latitude: he_lat,
     longitude: he_lng,
     elevation_ft: he_elev,
     heading: he_hdg as u16,
     active_to: false,
     active_lnd: false,
+    wind: None,
   };
   Ok((rwy1, rwy2))
 }
@@ -148,19 +222,29 @@ async fn parse(src: File) -> Result<HashMap<String, Vec<Runway>>, Box<dyn Error>
 
     let (rwy1, rwy2) = res.unwrap();
 
-    let rwys = runways.get_mut(&rwy1.icao);
-    if let Some(rwys) = rwys {
-      rwys.push(rwy1);
-      rwys.push(rwy2);
-    } else {
-      runways.insert(rwy1.icao.clone(), vec![rwy1, rwy2]);
+    let rwys = runways.entry(rwy1.icao.clone()).or_default();
+    // ends with no ident, e.g. of helipads, can't be referred to
+    for rwy in [rwy1, rwy2] {
+      if !rwy.ident.is_empty() {
+        rwys.push(rwy);
+      }
     }
   }
   Ok(runways)
 }
 
-pub async fn load_runways(cfg: &Config) -> Result<HashMap<String, Vec<Runway>>, Box<dyn Error>> {
-  let cache_file = cached_loader(&cfg.fixed.runways_url, &cfg.cache.runways).await?;
+pub async fn load_runways(
+  cfg: &Config,
+  client: &Client,
+) -> Result<HashMap<String, Vec<Runway>>, Box<dyn Error>> {
+  let cache_file = cached_loader(
+    client,
+    &cfg.fixed.runways_url,
+    &cfg.cache.runways,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.runways_url),
+  )
+  .await?;
   let t = Utc::now();
   let res = parse(cache_file).await;
   info!("runways data parsed in {}s", seconds_since(t));
@@ -169,7 +253,7 @@ pub async fn load_runways(cfg: &Config) -> Result<HashMap<String, Vec<Runway>>,
 
 #[cfg(test)]
 mod tests {
-  use super::{parse_runway, Runway};
+  use super::{parse_runway, reciprocal_ident, Runway};
   use csv::StringRecord;
 
   const TEST_RUNWAY: &str = "239398,2434,EGLL,12001,148,ASP,1,0,09R,51.464900970458984,-0.48677200078964233,75,90,1013,27L,51.46500015258789,-0.4340749979019165,77,270,";
@@ -191,12 +275,14 @@ mod tests {
         lighted: true,
         closed: false,
         ident: "09R".into(),
+        reciprocal: "27L".into(),
         latitude: 51.464900970458984,
         longitude: -0.48677200078964233,
         elevation_ft: 75,
         heading: 90,
         active_to: false,
-        active_lnd: false
+        active_lnd: false,
+        wind: None,
       }
     );
     assert_eq!(
@@ -209,13 +295,53 @@ mod tests {
         lighted: true,
         closed: false,
         ident: "27L".into(),
+        reciprocal: "09R".into(),
         latitude: 51.46500015258789,
         longitude: -0.4340749979019165,
         elevation_ft: 77,
         heading: 270,
         active_to: false,
-        active_lnd: false
+        active_lnd: false,
+        wind: None,
       }
     );
   }
+
+  #[test]
+  fn test_reciprocal_ident() {
+    assert_eq!(reciprocal_ident("34L"), Some("16R".into()));
+    assert_eq!(reciprocal_ident("09"), Some("27".into()));
+    assert_eq!(reciprocal_ident("18C"), Some("36C".into()));
+    assert_eq!(reciprocal_ident("36"), Some("18".into()));
+    assert_eq!(reciprocal_ident("4R"), Some("22L".into()));
+    assert_eq!(reciprocal_ident("H1"), None);
+    assert_eq!(reciprocal_ident("40"), None);
+
+    let raw = "1,1,KXYZ,3000,60,TURF,0,1,18,,,,,,,,,,,";
+    let tokens: Vec<&str> = raw.split(",").collect();
+    let (rwy18, rwy36) = parse_runway(&StringRecord::from(tokens)).unwrap();
+    assert_eq!(rwy18.reciprocal, "36");
+    assert_eq!(rwy36.ident, "36");
+    assert!(rwy18.closed && rwy36.closed);
+  }
+
+  #[test]
+  fn test_wind_components() {
+    let tokens: Vec<&str> = TEST_RUNWAY.split(",").collect();
+    let (mut rwy09, rwy27) = parse_runway(&StringRecord::from(tokens)).unwrap();
+
+    let wind = rwy09.wind_components(90, 10);
+    assert_eq!((wind.headwind, wind.crosswind), (10.0, 0.0));
+    let wind = rwy27.wind_components(90, 10);
+    assert_eq!((wind.headwind, wind.crosswind), (-10.0, 0.0));
+    let wind = rwy09.wind_components(180, 10);
+    assert_eq!((wind.headwind, wind.crosswind), (0.0, 10.0));
+    let wind = rwy09.wind_components(30, 20);
+    assert_eq!((wind.headwind, wind.crosswind), (10.0, -17.3));
+
+    rwy09.wind = Some(rwy09.wind_components(270, 5));
+    assert!(!rwy09.active_tailwind());
+    rwy09.active_lnd = true;
+    assert!(rwy09.active_tailwind());
+  }
 }
@@ -1,13 +1,18 @@
 use super::{
-  boundaries::load_boundaries,
+  boundaries::parse_boundaries,
   data::FixedData,
+  fallback_loader,
+  frequencies::load_frequencies,
   geonames::Geonames,
+  navaids::load_navaids,
   ourairports::{load_runways, Runway},
   types::{Airport, Boundaries, Country, FIR, UIR},
 };
 use crate::{config::Config, moving::controller::ControllerSet, types::Point};
 use log::error;
+use reqwest::Client;
 use std::{collections::HashMap, error::Error, fmt::Display};
+use tracing::instrument;
 
 enum ParserState {
   Idle,
@@ -113,6 +118,7 @@ fn parse(
               lng: lng.unwrap(),
             };
             let country = geonames.get_country_by_position(position);
+            let timezone = geonames.get_timezone_by_position(position);
 
             let a = Airport {
               icao,
@@ -123,8 +129,12 @@ fn parse(
               is_pseudo: tokens[6] == "1",
               controllers: ControllerSet::empty(),
               runways,
+              frequencies: vec![],
               country,
+              timezone,
               wx: None,
+              covered_by: None,
+              datis: None,
             };
 
             airports.push(a);
@@ -185,11 +195,44 @@ fn parse(
   Ok(FixedData::new(countries, airports, firs, uirs, geonames))
 }
 
-pub async fn load_fixed(cfg: &Config) -> Result<FixedData, Box<dyn Error>> {
-  let boundaries = load_boundaries(&cfg.fixed.boundaries_url).await?;
-  let text = reqwest::get(&cfg.fixed.data_url).await?.text().await?;
-  let runways = load_runways(cfg).await?;
-  let geonames = Geonames::load(cfg).await?;
-  let data = parse(&text, boundaries, runways, geonames)?;
-  Ok(data)
+// Loads and parses fixed data. With allow_stale set, previously cached copies
+// are used for the sources which can't be fetched; the returned flag shows
+// if any of them has been used
+#[instrument(skip_all)]
+pub async fn load_fixed(
+  cfg: &Config,
+  client: &Client,
+  allow_stale: bool,
+) -> Result<(FixedData, bool), Box<dyn Error>> {
+  let (raw_boundaries, boundaries_stale) = fallback_loader(
+    client,
+    &cfg.fixed.boundaries_url,
+    &cfg.cache.boundaries,
+    allow_stale,
+    cfg.fixed.checksum(&cfg.fixed.boundaries_url),
+  )
+  .await?;
+  let boundaries = parse_boundaries(&raw_boundaries)?;
+  let (text, data_stale) = fallback_loader(
+    client,
+    &cfg.fixed.data_url,
+    &cfg.cache.data,
+    allow_stale,
+    cfg.fixed.checksum(&cfg.fixed.data_url),
+  )
+  .await?;
+  let runways = load_runways(cfg, client).await?;
+  let geonames = Geonames::load(cfg, client).await?;
+  let mut data = parse(&text, boundaries, runways, geonames)?;
+  // route expansion is the only thing relying on navaids and frequencies
+  // are informational, no reason to fail the whole fixed data loading
+  match load_navaids(cfg, client).await {
+    Ok(navaids) => data.set_navaids(navaids),
+    Err(err) => error!("error loading navaids: {err}"),
+  }
+  match load_frequencies(cfg, client).await {
+    Ok(frequencies) => data.set_frequencies(frequencies),
+    Err(err) => error!("error loading airport frequencies: {err}"),
+  }
+  Ok((data, boundaries_stale || data_stale))
 }
@@ -0,0 +1,166 @@
+use super::types::GeonamesShape;
+use geo_types::{Coord, LineString, Polygon};
+use std::{
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+};
+
+// Parsed geonames shapes stored in a compact binary form next to the
+// source archive so that the geojson is only parsed when the archive
+// changes. All numbers are little endian:
+//
+//   magic, version, source checksum, shape count
+//   per shape: ref_id length, ref_id, ring count
+//   per ring: point count, (x, y) f64 pairs
+//
+// the first ring is the exterior one
+const MAGIC: &[u8; 4] = b"SWGS";
+const VERSION: u32 = 1;
+
+pub fn path(cache_path: &Path) -> PathBuf {
+  let mut path = cache_path.as_os_str().to_owned();
+  path.push(".bin");
+  PathBuf::from(path)
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+  w.write_all(&v.to_le_bytes())
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+  let mut buf = [0; 4];
+  r.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+  let mut buf = [0; 8];
+  r.read_exact(&mut buf)?;
+  Ok(f64::from_le_bytes(buf))
+}
+
+fn write_ring(w: &mut impl Write, ring: &LineString) -> io::Result<()> {
+  write_u32(w, ring.0.len() as u32)?;
+  for c in ring.0.iter() {
+    w.write_all(&c.x.to_le_bytes())?;
+    w.write_all(&c.y.to_le_bytes())?;
+  }
+  Ok(())
+}
+
+fn read_ring(r: &mut impl Read) -> io::Result<LineString> {
+  let count = read_u32(r)?;
+  let mut coords = Vec::with_capacity(count as usize);
+  for _ in 0..count {
+    let x = read_f64(r)?;
+    let y = read_f64(r)?;
+    coords.push(Coord { x, y });
+  }
+  Ok(LineString::from(coords))
+}
+
+pub fn write(mut w: impl Write, checksum: u32, shapes: &[GeonamesShape]) -> io::Result<()> {
+  w.write_all(MAGIC)?;
+  write_u32(&mut w, VERSION)?;
+  write_u32(&mut w, checksum)?;
+  write_u32(&mut w, shapes.len() as u32)?;
+  for shape in shapes.iter() {
+    write_u32(&mut w, shape.ref_id.len() as u32)?;
+    w.write_all(shape.ref_id.as_bytes())?;
+    write_u32(&mut w, shape.poly.interiors().len() as u32 + 1)?;
+    write_ring(&mut w, shape.poly.exterior())?;
+    for ring in shape.poly.interiors() {
+      write_ring(&mut w, ring)?;
+    }
+  }
+  w.flush()
+}
+
+// None if the cache has been built by another version or from another
+// source archive
+pub fn read(mut r: impl Read, checksum: u32) -> io::Result<Option<Vec<GeonamesShape>>> {
+  let mut magic = [0; 4];
+  r.read_exact(&mut magic)?;
+  if &magic != MAGIC || read_u32(&mut r)? != VERSION || read_u32(&mut r)? != checksum {
+    return Ok(None);
+  }
+
+  let count = read_u32(&mut r)?;
+  let mut shapes = Vec::with_capacity(count as usize);
+  for _ in 0..count {
+    let len = read_u32(&mut r)?;
+    let mut ref_id = vec![0; len as usize];
+    r.read_exact(&mut ref_id)?;
+    let ref_id =
+      String::from_utf8(ref_id).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let rings = read_u32(&mut r)?;
+    if rings == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("shape {ref_id} has no rings"),
+      ));
+    }
+    let exterior = read_ring(&mut r)?;
+    let interiors = (1..rings)
+      .map(|_| read_ring(&mut r))
+      .collect::<io::Result<Vec<_>>>()?;
+    shapes.push(GeonamesShape {
+      poly: Polygon::new(exterior, interiors),
+      ref_id,
+    });
+  }
+  Ok(Some(shapes))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_roundtrip() {
+    let shapes = vec![
+      GeonamesShape::from_vec(
+        "2635167",
+        vec![
+          vec![
+            vec![0.0, 0.0],
+            vec![4.0, 0.0],
+            vec![4.0, 4.0],
+            vec![0.0, 0.0],
+          ],
+          vec![
+            vec![1.0, 1.0],
+            vec![2.0, 1.0],
+            vec![2.0, 2.0],
+            vec![1.0, 1.0],
+          ],
+        ],
+      ),
+      GeonamesShape::from_vec(
+        "3017382",
+        vec![vec![
+          vec![-1.5, 43.0],
+          vec![7.5, 43.0],
+          vec![2.0, 51.0],
+          vec![-1.5, 43.0],
+        ]],
+      ),
+    ];
+
+    let mut raw = vec![];
+    write(&mut raw, 42, &shapes).unwrap();
+
+    let restored = read(raw.as_slice(), 42).unwrap().unwrap();
+    assert_eq!(restored.len(), 2);
+    for (a, b) in shapes.iter().zip(restored.iter()) {
+      assert_eq!(a.ref_id, b.ref_id);
+      assert_eq!(a.poly, b.poly);
+    }
+
+    // built from another archive
+    assert!(read(raw.as_slice(), 43).unwrap().is_none());
+    // truncated
+    assert!(read(&raw[..raw.len() - 4], 42).is_err());
+  }
+}
@@ -0,0 +1,223 @@
+use crate::{config::Config, fixed::cached_loader, types::Point, util::seconds_since};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone as _, Utc};
+use csv::StringRecord;
+use log::{error, info};
+use reqwest::Client;
+use rstar::{primitives::GeomWithData, RTree};
+use serde::Serialize;
+use std::{
+  collections::HashMap,
+  error::Error,
+  fs::File,
+  io::{Cursor, Read},
+};
+use zip::ZipArchive;
+
+pub type CityPoint = GeomWithData<Point, usize>;
+
+// An IANA time zone with the UTC offsets geonames publishes for the 1st of
+// January and the 1st of July, minutes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeZone {
+  pub id: String,
+  pub jan_offset: i32,
+  pub jul_offset: i32,
+}
+
+fn last_sunday(year: i32, month: u32) -> NaiveDate {
+  let next = if month == 12 {
+    NaiveDate::from_ymd_opt(year + 1, 1, 1)
+  } else {
+    NaiveDate::from_ymd_opt(year, month + 1, 1)
+  }
+  .unwrap();
+  let last = next.pred_opt().unwrap();
+  let back = last.weekday().num_days_from_sunday() as i64;
+  last - Duration::days(back)
+}
+
+impl TimeZone {
+  // There are no transition rules in the geonames data, so the July offset
+  // is taken between the last Sundays of March and October, which is exact
+  // for Europe and within a few weeks elsewhere
+  pub fn offset_at(&self, t: DateTime<Utc>) -> i32 {
+    if self.jan_offset == self.jul_offset {
+      return self.jan_offset;
+    }
+    let year = t.year();
+    let switch = |month| {
+      let day = last_sunday(year, month);
+      Utc.from_utc_datetime(&day.and_hms_opt(1, 0, 0).unwrap())
+    };
+    if t >= switch(3) && t < switch(10) {
+      self.jul_offset
+    } else {
+      self.jan_offset
+    }
+  }
+}
+
+fn hours_to_minutes(raw: &str) -> Option<i32> {
+  raw
+    .trim()
+    .parse::<f64>()
+    .ok()
+    .map(|h| (h * 60.0).round() as i32)
+}
+
+fn tsv_reader<R: Read>(src: R) -> csv::Reader<R> {
+  csv::ReaderBuilder::new()
+    .has_headers(false)
+    .delimiter(b'\t')
+    .flexible(true)
+    .quoting(false)
+    .from_reader(src)
+}
+
+// geonames timeZones.txt: country code, time zone id, January offset,
+// July offset and raw offset, hours
+pub fn parse_timezones(src: impl Read) -> Vec<TimeZone> {
+  let mut timezones = vec![];
+  for record in tsv_reader(src).records() {
+    let record = match record {
+      Ok(record) => record,
+      Err(err) => {
+        error!("error parsing time zone: {err}");
+        continue;
+      }
+    };
+    let offsets = (
+      record.get(2).and_then(hours_to_minutes),
+      record.get(3).and_then(hours_to_minutes),
+    );
+    // the header line has no numeric offsets
+    if let (Some(id), (Some(jan_offset), Some(jul_offset))) = (record.get(1), offsets) {
+      timezones.push(TimeZone {
+        id: id.to_owned(),
+        jan_offset,
+        jul_offset,
+      });
+    }
+  }
+  timezones
+}
+
+fn parse_city(record: &StringRecord) -> Option<(Point, &str)> {
+  let lat = record.get(4)?.parse::<f64>().ok()?;
+  let lng = record.get(5)?.parse::<f64>().ok()?;
+  let tz = record.get(17)?;
+  Some((Point { lat, lng }, tz))
+}
+
+// geonames cities dump, every city becomes a point carrying the index of its
+// time zone
+pub fn parse_cities(src: impl Read, timezones: &[TimeZone]) -> Vec<CityPoint> {
+  let tz_idx: HashMap<&str, usize> = timezones
+    .iter()
+    .enumerate()
+    .map(|(idx, tz)| (tz.id.as_str(), idx))
+    .collect();
+  let mut cities = vec![];
+  for record in tsv_reader(src).records() {
+    let Ok(record) = record else {
+      continue;
+    };
+    match parse_city(&record) {
+      Some((point, tz)) => {
+        if let Some(idx) = tz_idx.get(tz) {
+          cities.push(CityPoint::new(point, *idx));
+        }
+      }
+      None => error!("error parsing city {:?}", record.get(0)),
+    }
+  }
+  cities
+}
+
+fn read_cities(file: File, timezones: &[TimeZone]) -> Result<Vec<CityPoint>, Box<dyn Error>> {
+  let mut z = ZipArchive::new(file)?;
+  // the archive holds a single text file named after it
+  let mut raw = vec![];
+  z.by_index(0)?.read_to_end(&mut raw)?;
+  Ok(parse_cities(Cursor::new(raw), timezones))
+}
+
+pub async fn load_timezones(
+  cfg: &Config,
+  client: &Client,
+) -> Result<(Vec<TimeZone>, RTree<CityPoint>), Box<dyn Error>> {
+  let tz_file = cached_loader(
+    client,
+    &cfg.fixed.geonames_timezones_url,
+    &cfg.cache.geonames_timezones,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.geonames_timezones_url),
+  )
+  .await?;
+  let cities_file = cached_loader(
+    client,
+    &cfg.fixed.geonames_cities_url,
+    &cfg.cache.geonames_cities,
+    Some(cfg.fixed.cache_max_age),
+    cfg.fixed.checksum(&cfg.fixed.geonames_cities_url),
+  )
+  .await?;
+  let t = Utc::now();
+  let timezones = parse_timezones(tz_file);
+  let cities = read_cities(cities_file, &timezones)?;
+  info!("geonames time zones parsed in {}s", seconds_since(t));
+  Ok((timezones, RTree::bulk_load(cities)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_offset_at() {
+    let tz = TimeZone {
+      id: "Europe/London".into(),
+      jan_offset: 0,
+      jul_offset: 60,
+    };
+    let at = |y, m, d, h| Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap();
+    assert_eq!(
+      last_sunday(2024, 3),
+      NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()
+    );
+    assert_eq!(
+      last_sunday(2024, 10),
+      NaiveDate::from_ymd_opt(2024, 10, 27).unwrap()
+    );
+    assert_eq!(tz.offset_at(at(2024, 3, 31, 0)), 0);
+    assert_eq!(tz.offset_at(at(2024, 3, 31, 1)), 60);
+    assert_eq!(tz.offset_at(at(2024, 10, 27, 0)), 60);
+    assert_eq!(tz.offset_at(at(2024, 10, 27, 1)), 0);
+
+    // southern hemisphere zones have the summer offset in January
+    let tz = TimeZone {
+      id: "Australia/Sydney".into(),
+      jan_offset: 660,
+      jul_offset: 600,
+    };
+    assert_eq!(tz.offset_at(at(2024, 1, 15, 0)), 660);
+    assert_eq!(tz.offset_at(at(2024, 7, 15, 0)), 600);
+  }
+
+  #[test]
+  fn test_parse() {
+    let raw = "CountryCode\tTimeZoneId\tGMT offset 1. Jan 2024\tDST offset 1. Jul 2024\trawOffset (independant of DST)\n\
+      GB\tEurope/London\t0.0\t1.0\t0.0\n\
+      IN\tAsia/Kolkata\t5.5\t5.5\t5.5\n";
+    let timezones = parse_timezones(raw.as_bytes());
+    assert_eq!(timezones.len(), 2);
+    assert_eq!(timezones[1].jul_offset, 330);
+
+    let raw = "2643743\tLondon\tLondon\tLondres\t51.50853\t-0.12574\tP\tPPLC\tGB\t\tENG\tGLA\t\t\t8961989\t\t25\tEurope/London\t2023-09-08\n\
+      1275339\tMumbai\tMumbai\tBombay\t19.07283\t72.88261\tP\tPPLA\tIN\t\t16\t\t\t\t12691836\t\t8\tAsia/Kolkata\t2023-11-29\n\
+      1\tNowhere\tNowhere\t\t0.0\t0.0\tP\tPPL\tXX\t\t\t\t\t\t0\t\t0\tMars/Olympus\t2023-01-01\n";
+    let cities = parse_cities(raw.as_bytes(), &timezones);
+    assert_eq!(cities.len(), 2);
+    assert_eq!(cities[1].data, 1);
+  }
+}
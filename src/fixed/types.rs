@@ -1,17 +1,25 @@
-use super::{errors::GeonamesParseError, ourairports::Runway};
+use super::{
+  errors::GeonamesParseError, frequencies::AirportFrequency, ourairports::Runway,
+  timezones::TimeZone,
+};
 use crate::{
-  atis::runways::{detect_arrivals, detect_departures, normalize_atis_text},
+  atis::{
+    datis::DigitalAtis,
+    runways::{detect_arrivals, detect_departures, normalize_atis_text},
+  },
   moving::controller::{Controller, ControllerSet},
   service::camden,
   types::Point,
   weather::WeatherInfo,
 };
+use chrono::Utc;
+use geo::Simplify;
 use geo_types::Polygon;
 use geo_types::{geometry::Coord, LineString};
 use geojson::{Feature, Value};
 use rstar::{RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 #[derive(Debug, Clone)]
 pub struct Country {
@@ -30,9 +38,13 @@ pub struct Airport {
   pub is_pseudo: bool,
   pub controllers: ControllerSet,
   pub runways: HashMap<String, Runway>,
+  pub frequencies: Vec<AirportFrequency>,
   #[serde(skip_serializing)]
   pub country: Option<GeonamesCountry>,
+  pub timezone: Option<TimeZone>,
   pub wx: Option<WeatherInfo>,
+  pub covered_by: Option<String>,
+  pub datis: Option<DigitalAtis>,
 }
 
 impl Airport {
@@ -40,6 +52,28 @@ impl Airport {
     format!("{}:{}", self.icao, self.iata)
   }
 
+  // recalculates the runway wind components, called on every metar refresh
+  pub fn update_runway_winds(&mut self) {
+    let wind = self.wx.as_ref().and_then(|wx| wx.wind());
+    for rwy in self.runways.values_mut() {
+      rwy.wind = wind.map(|(direction, speed)| rwy.wind_components(direction, speed));
+    }
+  }
+
+  // marks the published frequencies worked by the online controllers,
+  // called whenever the airport controllers change
+  pub fn link_frequencies(&mut self) {
+    for freq in self.frequencies.iter_mut() {
+      freq.link(&self.controllers);
+    }
+  }
+
+  // airports with controllers of their own or covered top-down while
+  // broadcasting a real world digital ATIS
+  pub fn is_staffed(&self) -> bool {
+    !self.controllers.is_empty() || (self.covered_by.is_some() && self.datis.is_some())
+  }
+
   pub fn reset_active_runways(&mut self) {
     for (_, rwy) in self.runways.iter_mut() {
       rwy.active_lnd = false;
@@ -47,30 +81,59 @@ impl Airport {
     }
   }
 
+  // the digital ATIS is only used when there's no ATIS controller online
   pub fn set_active_runways(&mut self) {
     self.reset_active_runways();
-    if let Some(atis) = &self.controllers.atis {
-      let norm_atis = normalize_atis_text(&atis.text_atis, true);
+    let text = match (&self.controllers.atis, &self.datis) {
+      (Some(atis), _) => Some(&atis.text_atis),
+      (None, Some(datis)) => Some(&datis.text),
+      (None, None) => None,
+    };
+    if let Some(text) = text {
+      let norm_atis = normalize_atis_text(text, true);
       let arrivals = detect_arrivals(&norm_atis);
       let departures = detect_departures(&norm_atis);
       for ident in arrivals.iter() {
-        let rwy = self.runways.get_mut(ident);
-        if let Some(rwy) = rwy {
+        if let Some(rwy) = self.open_runway_mut(ident) {
           rwy.active_lnd = true
         }
       }
       for ident in departures.iter() {
-        let rwy = self.runways.get_mut(ident);
-        if let Some(rwy) = rwy {
+        if let Some(rwy) = self.open_runway_mut(ident) {
           rwy.active_to = true
         }
       }
     }
   }
+
+  // ATIS texts may omit the leading zero, e.g. runway 4R for 04R. Closed
+  // runways are never in use whatever the ATIS says
+  fn open_runway_mut(&mut self, ident: &str) -> Option<&mut Runway> {
+    let key = if self.runways.contains_key(ident) {
+      ident.to_owned()
+    } else {
+      format!("0{ident}")
+    };
+    self.runways.get_mut(&key).filter(|rwy| !rwy.closed)
+  }
+}
+
+impl From<Arc<Airport>> for camden::Airport {
+  fn from(value: Arc<Airport>) -> Self {
+    Arc::unwrap_or_clone(value).into()
+  }
 }
 
 impl From<Airport> for camden::Airport {
   fn from(value: Airport) -> Self {
+    let atis = value
+      .controllers
+      .atis
+      .as_ref()
+      .and_then(|ctrl| ctrl.atis.clone())
+      .or_else(|| value.datis.as_ref().map(|datis| datis.info.clone()))
+      .map(|v| v.into());
+    let utc_offset = value.timezone.as_ref().map(|tz| tz.offset_at(Utc::now()));
     Self {
       icao: value.icao,
       iata: value.iata,
@@ -85,6 +148,13 @@ impl From<Airport> for camden::Airport {
         .collect(),
       wx: value.wx.map(|v| v.into()),
       controllers: Some(value.controllers.into()),
+      covered_by: value.covered_by,
+      frequencies: value.frequencies.into_iter().map(|f| f.into()).collect(),
+      country: value.country.map(|c| c.iso).unwrap_or_default(),
+      timezone: value.timezone.map(|tz| tz.id),
+      utc_offset,
+      atis,
+      datis: value.datis.map(|v| v.into()),
     }
   }
 }
@@ -106,6 +176,12 @@ impl FIR {
   }
 }
 
+impl From<Arc<FIR>> for camden::Fir {
+  fn from(value: Arc<FIR>) -> Self {
+    Arc::unwrap_or_clone(value).into()
+  }
+}
+
 impl From<FIR> for camden::Fir {
   fn from(value: FIR) -> Self {
     Self {
@@ -141,6 +217,53 @@ pub struct Boundaries {
   pub points: Vec<Vec<Point>>,
 }
 
+fn simplify_rings(rings: &[Vec<Point>], tolerance: f64) -> Vec<Vec<Point>> {
+  if tolerance <= 0.0 {
+    return rings.to_vec();
+  }
+  rings
+    .iter()
+    .map(|ring| {
+      let line: LineString = ring.iter().map(|pt| (pt.lng, pt.lat)).collect();
+      line
+        .simplify(&tolerance)
+        .0
+        .into_iter()
+        .map(|c| Point { lat: c.y, lng: c.x })
+        .collect::<Vec<_>>()
+    })
+    // rings collapsed into a line are dropped
+    .filter(|ring| ring.len() >= 4)
+    .collect()
+}
+
+impl Boundaries {
+  // Douglas-Peucker simplified copy, tolerance in degrees. The tolerance is
+  // doubled until the rings fit into max_points in total, unless that
+  // collapses all of them. Zero max_points means no limit
+  pub fn simplified(&self, tolerance: f64, max_points: usize) -> Self {
+    let count = |rings: &[Vec<Point>]| rings.iter().map(|ring| ring.len()).sum::<usize>();
+    let mut tolerance = tolerance;
+    let mut points = simplify_rings(&self.points, tolerance);
+    while max_points > 0 && count(&points) > max_points {
+      tolerance = if tolerance > 0.0 {
+        tolerance * 2.0
+      } else {
+        0.01
+      };
+      let next = simplify_rings(&self.points, tolerance);
+      if next.is_empty() {
+        break;
+      }
+      points = next;
+    }
+    Self {
+      points,
+      ..self.clone()
+    }
+  }
+}
+
 impl PartialEq for Boundaries {
   // simplify partial eq as boundaries don't change within a single app run
   fn eq(&self, other: &Self) -> bool {
@@ -171,7 +294,7 @@ impl From<Boundaries> for camden::Boundaries {
   }
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
 pub struct GeonamesCountry {
   pub iso: String,
   pub iso3: String,
@@ -290,3 +413,99 @@ impl TryFrom<Feature> for GeonamesShapeSet {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_set_active_runways() {
+    let runway = |ident: &str, closed: bool| Runway {
+      icao: "KBOS".into(),
+      length_ft: 0,
+      width_ft: 0,
+      surface: "".into(),
+      lighted: true,
+      closed,
+      ident: ident.into(),
+      reciprocal: "".into(),
+      latitude: 0.0,
+      longitude: 0.0,
+      elevation_ft: 0,
+      heading: 0,
+      active_to: false,
+      active_lnd: false,
+      wind: None,
+    };
+    let mut arpt = Airport {
+      icao: "KBOS".into(),
+      iata: "BOS".into(),
+      name: "Boston".into(),
+      position: Point { lat: 0.0, lng: 0.0 },
+      fir_id: "KZBW".into(),
+      is_pseudo: false,
+      controllers: ControllerSet::empty(),
+      runways: HashMap::new(),
+      frequencies: vec![],
+      country: None,
+      timezone: None,
+      wx: None,
+      covered_by: None,
+      datis: Some(DigitalAtis {
+        code: "K".into(),
+        text: "ARRIVAL RUNWAY 4R DEPARTURE RUNWAY 9".into(),
+        info: Default::default(),
+      }),
+    };
+    arpt.runways.insert("04R".into(), runway("04R", false));
+    arpt.runways.insert("09".into(), runway("09", true));
+    arpt.set_active_runways();
+    assert!(arpt.runways["04R"].active_lnd);
+    assert!(!arpt.runways["09"].active_to);
+  }
+
+  #[test]
+  fn test_simplified_boundaries() {
+    let pt = |lat, lng| Point { lat, lng };
+    // a square with a point every tenth of a degree along its sides and
+    // a small island
+    let mut square = vec![];
+    for i in 0..10 {
+      square.push(pt(0.0, i as f64 * 0.1));
+    }
+    for i in 0..10 {
+      square.push(pt(i as f64 * 0.1, 1.0));
+    }
+    for i in 0..10 {
+      square.push(pt(1.0, 1.0 - i as f64 * 0.1));
+    }
+    for i in 0..10 {
+      square.push(pt(1.0 - i as f64 * 0.1, 0.0));
+    }
+    square.push(pt(0.0, 0.0));
+    let island = vec![pt(2.0, 2.0), pt(2.0, 2.01), pt(2.01, 2.01), pt(2.0, 2.0)];
+    let bounds = Boundaries {
+      id: "TEST".into(),
+      region: "".into(),
+      division: "".into(),
+      is_oceanic: false,
+      min: pt(0.0, 0.0),
+      max: pt(2.01, 2.01),
+      center: pt(1.0, 1.0),
+      points: vec![square, island],
+    };
+
+    let full = bounds.simplified(0.0, 0);
+    assert_eq!(full.points, bounds.points);
+
+    let simple = bounds.simplified(0.001, 0);
+    assert_eq!(simple.points[0].len(), 5);
+    assert_eq!(simple.points[1].len(), 4);
+
+    // the island collapses once the tolerance grows
+    let limited = bounds.simplified(0.0, 10);
+    assert_eq!(limited.points.len(), 1);
+    assert_eq!(limited.points[0].len(), 5);
+    assert_eq!(limited.id, "TEST");
+  }
+}
@@ -97,6 +97,25 @@ impl<'a> Lexer<'a> {
         line,
         pos,
       },
+      "now" => self.read_time_offset(lower, line, pos),
+      "contains" => Token {
+        src: literal,
+        kind: TokenKind::Contains,
+        line,
+        pos,
+      },
+      "startswith" => Token {
+        src: literal,
+        kind: TokenKind::StartsWith,
+        line,
+        pos,
+      },
+      "endswith" => Token {
+        src: literal,
+        kind: TokenKind::EndsWith,
+        line,
+        pos,
+      },
       _ => Token {
         src: literal,
         kind: TokenKind::Ident,
@@ -106,6 +125,49 @@ impl<'a> Lexer<'a> {
     }
   }
 
+  // Reads the optional offset of a relative time literal, i.e. the "-2h" part of "now-2h"
+  fn read_time_offset(&mut self, mut literal: String, line: usize, pos: usize) -> Token {
+    let sign = self.src.peek();
+    if let Some(sign @ ('-' | '+')) = sign {
+      literal.push(sign);
+      self.src.advance();
+    } else {
+      return Token {
+        src: literal,
+        kind: TokenKind::Time,
+        line,
+        pos,
+      };
+    }
+
+    let mut digits = false;
+    while let Some(sym) = self.src.peek() {
+      if !sym.is_ascii_digit() {
+        break;
+      }
+      digits = true;
+      literal.push(sym);
+      self.src.advance();
+    }
+
+    let unit = self.src.peek();
+    let kind = match unit {
+      Some(unit @ ('s' | 'm' | 'h' | 'd')) if digits => {
+        literal.push(unit);
+        self.src.advance();
+        TokenKind::Time
+      }
+      _ => TokenKind::Illegal,
+    };
+
+    Token {
+      src: literal,
+      kind,
+      line,
+      pos,
+    }
+  }
+
   fn read_equals_or_matches(&mut self) -> Token {
     let (line, pos) = self.src.position();
     self.src.advance();
@@ -250,10 +312,16 @@ impl<'a> Lexer<'a> {
     }
   }
 
-  fn read_string(&mut self) -> Token {
+  // Reads a string literal enclosed in either double or single quotes.
+  // Raw strings (r"..." or r'...') skip escape processing which is handy for regexes
+  fn read_string(&mut self, raw: bool) -> Token {
     let (line, pos) = self.src.position();
     let mut literal = String::new();
     let mut escape = false;
+    if raw {
+      self.src.advance();
+    }
+    let quote = self.src.peek().unwrap_or('"');
     self.src.advance();
 
     loop {
@@ -280,8 +348,8 @@ impl<'a> Lexer<'a> {
               escape = false
             } else {
               match sym {
-                '\\' => escape = true,
-                '"' => break,
+                '\\' if !raw => escape = true,
+                _ if sym == quote => break,
                 _ => literal.push(sym),
               }
             }
@@ -304,13 +372,15 @@ impl<'a> Lexer<'a> {
     }
   }
 
-  pub fn parse(&mut self) -> TokenFlow {
+  pub fn parse(&mut self) -> TokenFlow<'_> {
     loop {
       let sym = self.src.peek();
       if let Some(sym) = sym {
         let s = String::from(sym);
         let token = if sym.is_ascii_digit() {
           self.read_number()
+        } else if sym == 'r' && matches!(self.src.peek_next(), Some('"' | '\'')) {
+          self.read_string(true)
         } else if IDENT_START.is_match(&s) {
           self.read_identifier()
         } else if sym == '=' {
@@ -321,8 +391,8 @@ impl<'a> Lexer<'a> {
           self.read_less()
         } else if sym == '>' {
           self.read_greater()
-        } else if sym == '"' {
-          self.read_string()
+        } else if sym == '"' || sym == '\'' {
+          self.read_string(false)
         } else if sym == '(' {
           let (line, pos) = self.src.position();
           self.src.advance();
@@ -378,3 +448,56 @@ impl<'a> Lexer<'a> {
     TokenFlow::new(&self.tokens)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tokens(src: &str) -> Vec<Token> {
+    let mut l = Lexer::new(src);
+    l.parse();
+    l.tokens
+  }
+
+  #[test]
+  fn test_double_quoted_string() {
+    let t = tokens(r#"callsign == "AFL\"1\t""#);
+    assert_eq!(t[2].kind, TokenKind::String);
+    assert_eq!(t[2].src, "AFL\"1\t");
+    assert_eq!(t[3].kind, TokenKind::EOF);
+  }
+
+  #[test]
+  fn test_single_quoted_string() {
+    let t = tokens(r#"name == 'John "Doe"' and callsign == 'it\'s'"#);
+    assert_eq!(t[2].kind, TokenKind::String);
+    assert_eq!(t[2].src, "John \"Doe\"");
+    assert_eq!(t[6].kind, TokenKind::String);
+    assert_eq!(t[6].src, "it's");
+  }
+
+  #[test]
+  fn test_raw_string() {
+    let t = tokens(r#"callsign =~ r"^AFL\d+$" or name =~ r'\s'"#);
+    assert_eq!(t[2].kind, TokenKind::String);
+    assert_eq!(t[2].src, r"^AFL\d+$");
+    assert_eq!(t[6].kind, TokenKind::String);
+    assert_eq!(t[6].src, r"\s");
+  }
+
+  #[test]
+  fn test_ident_starting_with_r() {
+    let t = tokens("rules == 'ifr'");
+    assert_eq!(t[0].kind, TokenKind::Ident);
+    assert_eq!(t[0].src, "rules");
+  }
+
+  #[test]
+  fn test_unterminated_string() {
+    let t = tokens("callsign == 'AFL");
+    assert_eq!(t[2].kind, TokenKind::Illegal);
+
+    let t = tokens("callsign == r\"AFL'");
+    assert_eq!(t[2].kind, TokenKind::Illegal);
+  }
+}
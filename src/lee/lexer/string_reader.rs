@@ -24,6 +24,10 @@ impl<'a> StringReader<'a> {
     self.curr
   }
 
+  pub fn peek_next(&self) -> Option<char> {
+    self.curr.and_then(|_| self.src.clone().next())
+  }
+
   pub fn advance(&mut self) {
     if let Some(sym) = self.curr {
       if sym == '\n' {
@@ -50,6 +54,7 @@ pub mod tests {
     let mut s = StringReader::new("hello");
     assert!(s.peek() == Some('h'));
     assert!(s.peek() == Some('h'));
+    assert!(s.peek_next() == Some('e'));
     s.advance();
     assert!(s.peek() == Some('e'));
     s.advance();
@@ -58,9 +63,10 @@ pub mod tests {
     assert!(s.peek() == Some('l'));
     s.advance();
     assert!(s.peek() == Some('o'));
+    assert!(s.peek_next().is_none());
     s.advance();
-    assert!(s.peek() == None);
+    assert!(s.peek().is_none());
     s.advance();
-    assert!(s.peek() == None);
+    assert!(s.peek().is_none());
   }
 }
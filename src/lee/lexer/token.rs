@@ -9,6 +9,7 @@ pub enum TokenKind {
   Integer,
   Float,
   String,
+  Time,
 
   NotEquals,
   Equals,
@@ -18,6 +19,9 @@ pub enum TokenKind {
   Greater,
   LessOrEqual,
   GreaterOrEqual,
+  Contains,
+  StartsWith,
+  EndsWith,
 
   LeftBrace,
   RightBrace,
@@ -35,6 +39,7 @@ impl Display for TokenKind {
       TokenKind::Integer => write!(f, "Integer"),
       TokenKind::Float => write!(f, "Float"),
       TokenKind::String => write!(f, "String"),
+      TokenKind::Time => write!(f, "Time"),
       TokenKind::NotEquals => write!(f, "NotEquals"),
       TokenKind::Equals => write!(f, "Equals"),
       TokenKind::Matches => write!(f, "Matches"),
@@ -43,6 +48,9 @@ impl Display for TokenKind {
       TokenKind::Greater => write!(f, "Greater"),
       TokenKind::LessOrEqual => write!(f, "LessOrEqual"),
       TokenKind::GreaterOrEqual => write!(f, "GreaterOrEqual"),
+      TokenKind::Contains => write!(f, "Contains"),
+      TokenKind::StartsWith => write!(f, "StartsWith"),
+      TokenKind::EndsWith => write!(f, "EndsWith"),
       TokenKind::LeftBrace => write!(f, "LeftBrace"),
       TokenKind::RightBrace => write!(f, "RightBrace"),
       TokenKind::Or => write!(f, "Or"),
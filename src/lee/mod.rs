@@ -2,14 +2,31 @@
 ///
 use self::{
   lexer::Lexer,
-  parser::{error::ParseError, expression::Expression, parse},
+  parser::{
+    error::ParseError,
+    expression::{Expression, Limits},
+    parse,
+  },
+  usage::{record_failure, record_usage, QueryFailure},
 };
 
 pub mod lexer;
 pub mod parser;
+pub mod usage;
 
 pub fn make_expr<T>(query: &str) -> Result<Expression<T>, ParseError> {
   let mut l = Lexer::new(query);
   let mut tf = l.parse();
   parse(&mut tf)
 }
+
+// Parses a client query, the only way client queries come in, thus the
+// place to record the query usage at
+pub fn make_expr_limited<T>(query: &str, limits: &Limits) -> Result<Expression<T>, ParseError> {
+  let expr = make_expr(query).inspect_err(|_| record_failure(QueryFailure::Parse))?;
+  expr
+    .check_limits(limits)
+    .inspect_err(|_| record_failure(QueryFailure::Limits))?;
+  record_usage(&expr);
+  Ok(expr)
+}
@@ -1,5 +1,7 @@
+use super::error::CompileError;
+use chrono::{DateTime, Utc};
 use regex::Regex;
-use std::{fmt::Display, str::FromStr};
+use std::{collections::HashSet, fmt::Display, str::FromStr};
 
 #[derive(Debug, Clone)]
 pub enum Operator {
@@ -11,6 +13,9 @@ pub enum Operator {
   LessOrEqual,
   Greater,
   GreaterOrEqual,
+  Contains,
+  StartsWith,
+  EndsWith,
 }
 
 impl Operator {
@@ -24,6 +29,33 @@ impl Operator {
       Operator::LessOrEqual => "<=",
       Operator::Greater => ">",
       Operator::GreaterOrEqual => ">=",
+      Operator::Contains => "contains",
+      Operator::StartsWith => "startswith",
+      Operator::EndsWith => "endswith",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+  Len,
+}
+
+impl Function {
+  pub fn literal(&self) -> &'static str {
+    match self {
+      Function::Len => "len",
+    }
+  }
+}
+
+impl FromStr for Function {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "len" => Ok(Function::Len),
+      _ => Err(()),
     }
   }
 }
@@ -33,6 +65,25 @@ pub enum Value {
   Integer(i64),
   Float(f64),
   String(String),
+  Regex(Regex),
+  // relative time literal, i.e. now-2h, stored as offset from now in seconds
+  Time(i64),
+  // set of strings produced by folding `field == "A" or field == "B"` chains
+  Set(HashSet<String>),
+}
+
+impl PartialEq for Value {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Value::Integer(a), Value::Integer(b)) => a == b,
+      (Value::Float(a), Value::Float(b)) => a == b,
+      (Value::String(a), Value::String(b)) => a == b,
+      (Value::Regex(a), Value::Regex(b)) => a.as_str() == b.as_str(),
+      (Value::Time(a), Value::Time(b)) => a == b,
+      (Value::Set(a), Value::Set(b)) => a == b,
+      _ => false,
+    }
+  }
 }
 
 impl Value {
@@ -41,6 +92,24 @@ impl Value {
       Value::Integer(_) => "integer",
       Value::Float(_) => "float",
       Value::String(_) => "string",
+      Value::Regex(_) => "regex",
+      Value::Time(_) => "time",
+      Value::Set(_) => "set",
+    }
+  }
+
+  pub fn raw(&self) -> String {
+    match self {
+      Value::Integer(v) => format!("{}", v),
+      Value::Float(v) => format!("{}", v),
+      Value::String(v) => v.clone(),
+      Value::Regex(v) => v.as_str().to_owned(),
+      Value::Time(v) => format!("now{:+}s", v),
+      Value::Set(v) => {
+        let mut items = v.iter().cloned().collect::<Vec<_>>();
+        items.sort();
+        items.join(", ")
+      }
     }
   }
 
@@ -49,6 +118,9 @@ impl Value {
       Value::Integer(v) => format!("int({})", v),
       Value::Float(v) => format!("float({})", v),
       Value::String(v) => format!("string({})", v),
+      Value::Regex(v) => format!("regex({})", v.as_str()),
+      Value::Time(_) => format!("time({})", self.raw()),
+      Value::Set(_) => format!("set({})", self.raw()),
     }
   }
 
@@ -75,7 +147,7 @@ impl Value {
           _ => false,
         }
       }
-      Value::String(_) => false,
+      Value::String(_) | Value::Regex(_) | Value::Time(_) | Value::Set(_) => false,
     }
   }
 
@@ -102,7 +174,7 @@ impl Value {
         Operator::GreaterOrEqual => ext_val >= v,
         _ => false,
       },
-      Value::String(_) => false,
+      Value::String(_) | Value::Regex(_) | Value::Time(_) | Value::Set(_) => false,
     }
   }
 
@@ -110,45 +182,89 @@ impl Value {
     match self {
       Value::Integer(_) => false,
       Value::Float(_) => false,
+      Value::Time(_) => false,
+      Value::Set(v) => match operator {
+        Operator::Equals => v.contains(ext_val),
+        Operator::NotEquals => !v.contains(ext_val),
+        _ => false,
+      },
       Value::String(v) => match operator {
-        Operator::Matches => {
-          let re = Regex::from_str(v);
-          if let Ok(re) = re {
-            re.is_match(ext_val)
-          } else {
-            false
-          }
-        }
-        Operator::NotMatches => {
-          // TODO: evaluation errors
-          let re = Regex::from_str(v);
-          if let Ok(re) = re {
-            !re.is_match(ext_val)
-          } else {
-            true
-          }
-        }
         Operator::Equals => ext_val == v,
         Operator::NotEquals => ext_val != v,
+        Operator::Contains => ext_val.contains(v.as_str()),
+        Operator::StartsWith => ext_val.starts_with(v.as_str()),
+        Operator::EndsWith => ext_val.ends_with(v.as_str()),
+        _ => false,
+      },
+      Value::Regex(re) => match operator {
+        Operator::Matches => re.is_match(ext_val),
+        Operator::NotMatches => !re.is_match(ext_val),
         _ => false,
       },
     }
   }
+
+  // Evaluates a time field value. Relative literals are resolved against
+  // the current time on every evaluation, integers are treated as unix timestamps
+  pub fn eval_time(&self, ext_val: DateTime<Utc>, operator: Operator) -> bool {
+    match *self {
+      Value::Time(offset) => {
+        let v = Utc::now().timestamp() + offset;
+        Value::Integer(v).eval_i64(ext_val.timestamp(), operator)
+      }
+      _ => self.eval_i64(ext_val.timestamp(), operator),
+    }
+  }
+
+  // Evaluates a string field value, applying the condition function if any
+  pub fn eval_str_func(&self, ext_val: &str, func: Option<Function>, operator: Operator) -> bool {
+    match func {
+      Some(Function::Len) => self.eval_i64(ext_val.chars().count() as i64, operator),
+      None => self.eval_str(ext_val, operator),
+    }
+  }
 }
 
 #[derive(Clone)]
 pub struct Condition {
   pub ident: String,
+  pub func: Option<Function>,
   pub operator: Operator,
   pub value: Value,
 }
 
+impl Condition {
+  pub fn field(&self) -> String {
+    match self.func {
+      Some(func) => format!("{}({})", func.literal(), self.ident),
+      None => self.ident.clone(),
+    }
+  }
+
+  // Match operators are evaluated against a regex value which is compiled
+  // once here instead of being compiled on every evaluation
+  pub fn precompile(self) -> Result<Self, CompileError> {
+    match (&self.operator, &self.value) {
+      (Operator::Matches | Operator::NotMatches, Value::String(v)) => {
+        let re = Regex::from_str(v).map_err(|err| CompileError {
+          msg: format!("invalid regular expression \"{v}\": {err}"),
+        })?;
+        Ok(Self {
+          value: Value::Regex(re),
+          ..self
+        })
+      }
+      _ => Ok(self),
+    }
+  }
+}
+
 impl Display for Condition {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
       f,
       "Condition<({} {} {})>",
-      self.ident,
+      self.field(),
       self.operator.literal(),
       self.value.as_string()
     )
@@ -10,6 +10,8 @@ pub enum ParseError {
   UnexpectedEOS(Vec<TokenKind>),
   ConvertError(Token, Box<dyn Error>),
   InvalidValueType(Token, Vec<&'static str>),
+  UnknownFunction(Token),
+  LimitExceeded(String),
 }
 
 impl Display for ParseError {
@@ -53,6 +55,14 @@ impl Display for ParseError {
           exp.join(", ")
         )
       }
+      ParseError::LimitExceeded(msg) => write!(f, "query limit exceeded: {}", msg),
+      ParseError::UnknownFunction(t) => {
+        write!(
+          f,
+          "unknown function {} at line={} pos={}",
+          t.src, t.line, t.pos
+        )
+      }
     }
   }
 }
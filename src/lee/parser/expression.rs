@@ -1,4 +1,52 @@
-use super::{condition::Condition, error::CompileError};
+use super::{
+  condition::{Condition, Operator, Value},
+  error::{CompileError, ParseError},
+};
+use crate::lee::usage::{record_failure, QueryFailure};
+use std::{
+  cell::Cell,
+  collections::HashSet,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+// Total number of compiled conditions evaluated, exposed via metrics
+static EVALUATIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+  // evaluations not yet added to the total, kept off the shared counter
+  // while a batch of objects is being filtered
+  static LOCAL_EVALUATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn evaluations_count() -> u64 {
+  EVALUATIONS.load(Ordering::Relaxed)
+}
+
+// Adds the evaluations counted on this thread to the total, called once
+// an evaluated batch is done
+pub fn flush_evaluations() {
+  let count = LOCAL_EVALUATIONS.replace(0);
+  if count > 0 {
+    EVALUATIONS.fetch_add(count, Ordering::Relaxed);
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+  pub max_depth: usize,
+  pub max_conditions: usize,
+  pub max_regex_len: usize,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      max_depth: 8,
+      max_conditions: 32,
+      max_regex_len: 256,
+    }
+  }
+}
 
 #[derive(Debug)]
 pub enum CombineOperator {
@@ -22,14 +70,164 @@ pub struct Expression<T> {
 }
 
 impl<T> Expression<T> {
+  // Checks the parsed expression against the given limits, returns
+  // the number of conditions met so far
+  fn check_limits_rec(
+    &self,
+    limits: &Limits,
+    depth: usize,
+    conditions: usize,
+  ) -> Result<usize, ParseError> {
+    if depth > limits.max_depth {
+      return Err(ParseError::LimitExceeded(format!(
+        "expression nesting depth exceeds {}",
+        limits.max_depth
+      )));
+    }
+
+    // a foldable chain counts once, it's compiled into a single set lookup
+    let chain = self.or_equals_chain();
+    if chain > 1 {
+      let mut last = self;
+      for _ in 1..chain {
+        last = last.right.as_deref().unwrap();
+      }
+      return last.check_limits_tail(limits, depth, conditions + 1);
+    }
+
+    let conditions = match self.left.as_ref() {
+      LeftExpression::Expression(expr) => expr.check_limits_rec(limits, depth + 1, conditions)?,
+      LeftExpression::Condition(cond) => {
+        if let (Operator::Matches | Operator::NotMatches, Value::String(re)) =
+          (&cond.operator, &cond.value)
+        {
+          if re.len() > limits.max_regex_len {
+            return Err(ParseError::LimitExceeded(format!(
+              "regular expression length exceeds {}",
+              limits.max_regex_len
+            )));
+          }
+        }
+        conditions + 1
+      }
+      LeftExpression::CompiledFilter(_) => conditions + 1,
+    };
+    self.check_limits_tail(limits, depth, conditions)
+  }
+
+  // Checks the condition count met with the left side and goes on to the right one
+  fn check_limits_tail(
+    &self,
+    limits: &Limits,
+    depth: usize,
+    mut conditions: usize,
+  ) -> Result<usize, ParseError> {
+    if conditions > limits.max_conditions {
+      return Err(ParseError::LimitExceeded(format!(
+        "number of conditions exceeds {}",
+        limits.max_conditions
+      )));
+    }
+
+    if let Some(right) = self.right.as_ref() {
+      conditions = right.check_limits_rec(limits, depth, conditions)?;
+    }
+    Ok(conditions)
+  }
+
+  pub fn check_limits(&self, limits: &Limits) -> Result<(), ParseError> {
+    self.check_limits_rec(limits, 0, 0).map(|_| ())
+  }
+
+  // Returns the string value if the left side is a plain `ident == "value"` condition
+  fn equals_str(&self, ident: &str) -> Option<&str> {
+    match self.left.as_ref() {
+      LeftExpression::Condition(Condition {
+        ident: cond_ident,
+        func: None,
+        operator: Operator::Equals,
+        value: Value::String(v),
+      }) if cond_ident == ident => Some(v),
+      _ => None,
+    }
+  }
+
+  // Returns the length of the leading `field == "A" or field == "B" or ...`
+  // chain over the same field
+  fn or_equals_chain(&self) -> usize {
+    let ident = match self.left.as_ref() {
+      LeftExpression::Condition(cond) => &cond.ident,
+      _ => return 0,
+    };
+
+    // operators are right associative, so a chain member followed by
+    // `and` binds to the rest of the expression and can't be folded
+    let mut count = 0;
+    let mut curr = Some(self);
+    while let Some(expr) = curr {
+      if expr.equals_str(ident).is_none() {
+        break;
+      }
+      match expr.operator {
+        Some(CombineOperator::Or) => {
+          count += 1;
+          curr = expr.right.as_deref();
+        }
+        None => {
+          count += 1;
+          break;
+        }
+        Some(CombineOperator::And) => break,
+      }
+    }
+    count
+  }
+
+  // Folds a leading OR-of-equals chain over the same field into a single set
+  // membership condition, so that long OR filters are evaluated with one hash
+  // lookup instead of a condition per value. Returns the number of conditions
+  // folded, 0 if there's no chain
+  pub fn fold_or_equals(&mut self) -> usize {
+    let count = self.or_equals_chain();
+    if count < 2 {
+      return 0;
+    }
+    let ident = match self.left.as_ref() {
+      LeftExpression::Condition(cond) => cond.ident.clone(),
+      _ => return 0,
+    };
+
+    let mut values = HashSet::new();
+    values.insert(self.equals_str(&ident).unwrap().to_owned());
+    for _ in 1..count {
+      let next = *self.right.take().unwrap();
+      values.insert(next.equals_str(&ident).unwrap().to_owned());
+      self.operator = next.operator;
+      self.right = next.right;
+    }
+
+    *self.left = LeftExpression::Condition(Condition {
+      ident,
+      func: None,
+      operator: Operator::Equals,
+      value: Value::Set(values),
+    });
+    count
+  }
+
   pub fn compile(&mut self, cb: &CompileFunc<T>) -> Result<(), CompileError> {
+    self.fold_or_equals();
     match self.left.as_mut() {
       LeftExpression::Expression(expr) => {
         expr.compile(cb)?;
       }
       LeftExpression::Condition(cond) => {
-        let compiled = cb(cond.clone())?;
-        self.left = Box::new(LeftExpression::CompiledFilter(compiled));
+        let compiled = cond
+          .clone()
+          .precompile()
+          .and_then(cb)
+          .inspect_err(|_| record_failure(QueryFailure::Compile))?;
+        *self.left = LeftExpression::CompiledFilter(compiled);
       }
       _ => (), // TODO: already compiled error
     }
@@ -43,18 +241,22 @@ impl<T> Expression<T> {
 
   pub fn evaluate(&self, model: &T) -> bool {
     let left_result = match self.left.as_ref() {
-      LeftExpression::CompiledFilter(filter) => filter(model),
+      LeftExpression::CompiledFilter(filter) => {
+        LOCAL_EVALUATIONS.set(LOCAL_EVALUATIONS.get() + 1);
+        filter(model)
+      }
       LeftExpression::Expression(e) => e.evaluate(model),
       _ => false, // TODO: partially compiled error
     };
 
-    if self.operator.is_none() {
-      left_result
-    } else {
-      let right = self.right.as_ref().unwrap();
-      match self.operator.as_ref().unwrap() {
-        CombineOperator::And => left_result && right.evaluate(model),
-        CombineOperator::Or => left_result || right.evaluate(model),
+    match self.operator.as_ref() {
+      None => left_result,
+      Some(operator) => {
+        let right = self.right.as_ref().unwrap();
+        match operator {
+          CombineOperator::And => left_result && right.evaluate(model),
+          CombineOperator::Or => left_result || right.evaluate(model),
+        }
       }
     }
   }
@@ -1,9 +1,10 @@
 use self::{
-  condition::{Condition, Operator, Value},
+  condition::{Condition, Function, Operator, Value},
   error::ParseError,
   expression::{CombineOperator, Expression, LeftExpression},
 };
 use super::lexer::token::{TokenFlow, TokenKind};
+use std::num::ParseIntError;
 
 pub mod condition;
 pub mod error;
@@ -20,6 +21,9 @@ fn parse_operator(tf: &mut TokenFlow) -> Result<Operator, ParseError> {
       TokenKind::Greater,
       TokenKind::LessOrEqual,
       TokenKind::GreaterOrEqual,
+      TokenKind::Contains,
+      TokenKind::StartsWith,
+      TokenKind::EndsWith,
     ])
   })?;
 
@@ -32,6 +36,9 @@ fn parse_operator(tf: &mut TokenFlow) -> Result<Operator, ParseError> {
     TokenKind::Greater => Operator::Greater,
     TokenKind::LessOrEqual => Operator::LessOrEqual,
     TokenKind::GreaterOrEqual => Operator::GreaterOrEqual,
+    TokenKind::Contains => Operator::Contains,
+    TokenKind::StartsWith => Operator::StartsWith,
+    TokenKind::EndsWith => Operator::EndsWith,
     _ => {
       return Err(ParseError::UnexpectedTokenType(
         token.clone(),
@@ -44,6 +51,9 @@ fn parse_operator(tf: &mut TokenFlow) -> Result<Operator, ParseError> {
           TokenKind::Greater,
           TokenKind::LessOrEqual,
           TokenKind::GreaterOrEqual,
+          TokenKind::Contains,
+          TokenKind::StartsWith,
+          TokenKind::EndsWith,
         ],
       ))
     }
@@ -52,12 +62,30 @@ fn parse_operator(tf: &mut TokenFlow) -> Result<Operator, ParseError> {
   Ok(operator)
 }
 
+// Converts a relative time literal like now-2h into an offset in seconds
+fn parse_relative_time(src: &str) -> Result<i64, ParseIntError> {
+  let offset = &src[3..];
+  if offset.is_empty() {
+    return Ok(0);
+  }
+  let (num, unit) = offset.split_at(offset.len() - 1);
+  let num = num.parse::<i64>()?;
+  let mult = match unit {
+    "m" => 60,
+    "h" => 3600,
+    "d" => 86400,
+    _ => 1,
+  };
+  Ok(num * mult)
+}
+
 fn parse_value(tf: &mut TokenFlow) -> Result<Value, ParseError> {
   let token = tf.current().ok_or_else(|| {
     ParseError::UnexpectedEOS(vec![
       TokenKind::Integer,
       TokenKind::Float,
       TokenKind::String,
+      TokenKind::Time,
     ])
   })?;
 
@@ -77,11 +105,17 @@ fn parse_value(tf: &mut TokenFlow) -> Result<Value, ParseError> {
       Value::Float(val)
     }
     TokenKind::String => Value::String(token.src.clone()),
+    TokenKind::Time => {
+      let val = parse_relative_time(&token.src)
+        .map_err(|err| ParseError::ConvertError(token.clone(), Box::new(err)))?;
+      Value::Time(val)
+    }
     _ => {
       return Err(ParseError::UnexpectedEOS(vec![
         TokenKind::Integer,
         TokenKind::Float,
         TokenKind::String,
+        TokenKind::Time,
       ]))
     }
   };
@@ -89,7 +123,7 @@ fn parse_value(tf: &mut TokenFlow) -> Result<Value, ParseError> {
   Ok(value)
 }
 
-fn parse_condition(tf: &mut TokenFlow) -> Result<Condition, ParseError> {
+fn parse_ident(tf: &mut TokenFlow) -> Result<String, ParseError> {
   let token = tf
     .current()
     .ok_or_else(|| ParseError::UnexpectedEOS(vec![TokenKind::Ident]))?;
@@ -103,6 +137,37 @@ fn parse_condition(tf: &mut TokenFlow) -> Result<Condition, ParseError> {
     }
   };
   tf.advance();
+  Ok(ident)
+}
+
+fn expect_token(tf: &mut TokenFlow, kind: TokenKind) -> Result<(), ParseError> {
+  let token = tf
+    .current()
+    .ok_or_else(|| ParseError::UnexpectedEOS(vec![kind.clone()]))?;
+  if token.kind != kind {
+    return Err(ParseError::UnexpectedTokenType(token.clone(), vec![kind]));
+  }
+  tf.advance();
+  Ok(())
+}
+
+fn parse_condition(tf: &mut TokenFlow) -> Result<Condition, ParseError> {
+  let func_t = tf.current();
+  let ident = parse_ident(tf)?;
+
+  // function call on a field, i.e. len(remarks)
+  let (ident, func) = match tf.current() {
+    Some(token) if token.kind == TokenKind::LeftBrace => {
+      let func = ident
+        .parse::<Function>()
+        .map_err(|_| ParseError::UnknownFunction(func_t.unwrap().clone()))?;
+      tf.advance();
+      let ident = parse_ident(tf)?;
+      expect_token(tf, TokenKind::RightBrace)?;
+      (ident, Some(func))
+    }
+    _ => (ident, None),
+  };
 
   let op_t = tf.current();
   let operator = parse_operator(tf)?;
@@ -110,26 +175,57 @@ fn parse_condition(tf: &mut TokenFlow) -> Result<Condition, ParseError> {
 
   let op_t = op_t.unwrap();
 
+  // functions produce numbers, thus only numeric comparisons are allowed
+  if func.is_some() {
+    match (&operator, &value) {
+      (
+        Operator::Matches
+        | Operator::NotMatches
+        | Operator::Contains
+        | Operator::StartsWith
+        | Operator::EndsWith,
+        _,
+      )
+      | (_, Value::String(_) | Value::Regex(_) | Value::Time(_)) => {
+        return Err(ParseError::InvalidValueType(
+          op_t.clone(),
+          vec!["int", "float"],
+        ))
+      }
+      _ => (),
+    }
+  }
+
   match operator {
     Operator::Matches => match value {
       Value::Integer(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
       Value::Float(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
-      Value::String(_) => (),
+      Value::Time(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
+      Value::String(_) | Value::Regex(_) | Value::Set(_) => (),
     },
     Operator::NotMatches => match value {
       Value::Integer(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
       Value::Float(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
+      Value::Time(_) => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
+      Value::String(_) | Value::Regex(_) | Value::Set(_) => (),
+    },
+    Operator::Contains | Operator::StartsWith | Operator::EndsWith => match value {
       Value::String(_) => (),
+      _ => return Err(ParseError::InvalidValueType(op_t.clone(), vec!["string"])),
     },
     Operator::Equals => (),
     Operator::NotEquals => (),
+    // strings are allowed for ordered named values such as ratings, the
+    // fields not supporting them are rejected on compilation
     _ => match value {
       Value::Integer(_) => (),
       Value::Float(_) => (),
-      Value::String(_) => {
+      Value::Time(_) => (),
+      Value::String(_) => (),
+      Value::Regex(_) | Value::Set(_) => {
         return Err(ParseError::InvalidValueType(
           op_t.clone(),
-          vec!["int", "float"],
+          vec!["int", "float", "time", "string"],
         ))
       }
     },
@@ -137,6 +233,7 @@ fn parse_condition(tf: &mut TokenFlow) -> Result<Condition, ParseError> {
 
   Ok(Condition {
     ident,
+    func,
     operator,
     value,
   })
@@ -276,4 +373,181 @@ mod tests {
     });
     assert!(!res);
   }
+
+  #[test]
+  fn test_precompile_regex() {
+    let mut l = Lexer::new("callsign !~ \"^AER\"");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap().precompile();
+    assert!(cond.is_ok());
+    let cond = cond.ok().unwrap();
+    assert!(matches!(cond.value, Value::Regex(_)));
+    assert!(cond.value.eval_str("SBI123", cond.operator.clone()));
+    assert!(!cond.value.eval_str("AER384", cond.operator.clone()));
+
+    let mut l = Lexer::new("callsign =~ \"[AER\"");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap().precompile();
+    assert!(cond.is_err());
+  }
+
+  #[test]
+  fn test_string_functions() {
+    let mut l = Lexer::new("route contains \"UL620\"");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap();
+    assert!(matches!(cond.operator, Operator::Contains));
+    assert!(cond.func.is_none());
+    assert!(cond.value.eval_str("DCT UL620 DCT", cond.operator.clone()));
+    assert!(!cond.value.eval_str("DCT UN869 DCT", cond.operator.clone()));
+
+    let mut l = Lexer::new("callsign startswith \"AFL\"");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap();
+    assert!(cond.value.eval_str("AFL123", cond.operator.clone()));
+    assert!(!cond.value.eval_str("SBI123", cond.operator.clone()));
+
+    let mut l = Lexer::new("len(remarks) > 0");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap();
+    assert_eq!(cond.func, Some(Function::Len));
+    assert_eq!(cond.field(), "len(remarks)");
+    assert!(cond
+      .value
+      .eval_str_func("/v/", cond.func, cond.operator.clone()));
+    assert!(!cond
+      .value
+      .eval_str_func("", cond.func, cond.operator.clone()));
+
+    let mut l = Lexer::new("callsign endswith 5");
+    let mut tf = l.parse();
+    assert!(parse_condition(&mut tf).is_err());
+
+    let mut l = Lexer::new("len(remarks) == \"x\"");
+    let mut tf = l.parse();
+    assert!(parse_condition(&mut tf).is_err());
+
+    let mut l = Lexer::new("size(remarks) > 0");
+    let mut tf = l.parse();
+    assert!(parse_condition(&mut tf).is_err());
+  }
+
+  #[test]
+  fn test_relative_time() {
+    let mut l = Lexer::new("logon_time < now-2h");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap();
+    assert!(cond.value == Value::Time(-7200));
+
+    let mut l = Lexer::new("last_updated >= NOW");
+    let mut tf = l.parse();
+    let cond = parse_condition(&mut tf).unwrap();
+    assert!(cond.value == Value::Time(0));
+
+    let now = chrono::Utc::now();
+    assert!(Value::Time(-60).eval_time(now, Operator::Greater));
+    assert!(!Value::Time(60).eval_time(now, Operator::Greater));
+
+    let mut l = Lexer::new("logon_time < now-2y");
+    let mut tf = l.parse();
+    assert!(parse_condition(&mut tf).is_err());
+
+    let mut l = Lexer::new("logon_time =~ now-2h");
+    let mut tf = l.parse();
+    assert!(parse_condition(&mut tf).is_err());
+  }
+
+  #[test]
+  fn test_limits() {
+    use crate::lee::{make_expr_limited, parser::expression::Limits};
+
+    let limits = Limits {
+      max_depth: 1,
+      max_conditions: 3,
+      max_regex_len: 8,
+    };
+    assert!(make_expr_limited::<Model>("(x > 1 or y > 1) and x < 5", &limits).is_ok());
+    assert!(make_expr_limited::<Model>("x > 1 and y > 1 and x < 5 and y < 5", &limits).is_err());
+    assert!(make_expr_limited::<Model>("((x > 1 or y > 1)) and x < 5", &limits).is_err());
+    assert!(make_expr_limited::<Model>("callsign =~ \"^(AFL|SBI)\"", &limits).is_err());
+    assert!(make_expr_limited::<Model>("callsign == \"^(AFL|SBI)\"", &limits).is_ok());
+
+    // a foldable chain counts as one condition
+    let query = "callsign == \"A\" or callsign == \"B\" or callsign == \"C\" or callsign == \"D\"";
+    assert!(make_expr_limited::<Model>(query, &limits).is_ok());
+    let query = "callsign == \"A\" or callsign == \"B\" and x > 1 and y > 1 and x < 5";
+    assert!(make_expr_limited::<Model>(query, &limits).is_err());
+  }
+
+  #[test]
+  fn test_evaluations_flush() {
+    use crate::lee::parser::expression::{evaluations_count, flush_evaluations};
+
+    let cb: Box<CompileFunc<Model>> = Box::new(|cond| {
+      Ok(Box::new(move |model: &Model| {
+        cond.value.eval_i64(model.x, cond.operator.clone())
+      }))
+    });
+    let mut exp = crate::lee::make_expr::<Model>("x > 1 and x < 5").unwrap();
+    exp.compile(&cb).ok().unwrap();
+
+    let before = evaluations_count();
+    for x in 0..10 {
+      exp.evaluate(&Model {
+        x,
+        y: 0,
+        callsign: "".into(),
+      });
+    }
+    flush_evaluations();
+    // 0 and 1 stop at the first condition
+    assert!(evaluations_count() >= before + 18);
+  }
+
+  #[test]
+  fn test_or_equals_folding() {
+    use std::{cell::Cell, rc::Rc};
+
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    let cb: Box<CompileFunc<Model>> = Box::new(move |cond| {
+      counter.set(counter.get() + 1);
+      let evalfunc: Box<EvaluateFunc<Model>> = match cond.ident.as_str() {
+        "x" => Box::new(move |model| cond.value.eval_i64(model.x, cond.operator.clone())),
+        _ => Box::new(move |model| cond.value.eval_str(&model.callsign, cond.operator.clone())),
+      };
+      Ok(evalfunc)
+    });
+
+    let query = "callsign == \"AFL1\" or callsign == \"AFL2\" or callsign == \"AFL3\"";
+    let mut exp = crate::lee::make_expr::<Model>(query).unwrap();
+    exp.compile(&cb).ok().unwrap();
+    assert_eq!(calls.get(), 1);
+    assert!(exp.right.is_none());
+
+    let model = |x, callsign: &str| Model {
+      x,
+      y: 0,
+      callsign: callsign.into(),
+    };
+    assert!(exp.evaluate(&model(0, "AFL2")));
+    assert!(!exp.evaluate(&model(0, "AFL4")));
+
+    // the last member binds to the `and` and must stay out of the set
+    calls.set(0);
+    let query = "callsign == \"AFL1\" or callsign == \"AFL2\" and x > 5";
+    let mut exp = crate::lee::make_expr::<Model>(query).unwrap();
+    exp.compile(&cb).ok().unwrap();
+    assert_eq!(calls.get(), 3);
+    assert!(exp.evaluate(&model(0, "AFL1")));
+    assert!(!exp.evaluate(&model(0, "AFL2")));
+    assert!(exp.evaluate(&model(6, "AFL2")));
+
+    // different fields are not folded
+    calls.set(0);
+    let query = "callsign == \"AFL1\" or name == \"AFL2\"";
+    let mut exp = crate::lee::make_expr::<Model>(query).unwrap();
+    exp.compile(&cb).ok().unwrap();
+    assert_eq!(calls.get(), 2);
+  }
 }
@@ -0,0 +1,116 @@
+use super::parser::{
+  condition::Condition,
+  expression::{Expression, LeftExpression},
+};
+use lazy_static::lazy_static;
+use std::{collections::HashMap, sync::Mutex};
+
+// distinct field and operator pairs tracked at most, unknown fields are
+// counted too as they show what clients would like to filter by
+const MAX_USAGE_KEYS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryFailure {
+  Parse,
+  Limits,
+  Compile,
+}
+
+impl QueryFailure {
+  pub fn name(&self) -> &'static str {
+    match self {
+      QueryFailure::Parse => "parse",
+      QueryFailure::Limits => "limits",
+      QueryFailure::Compile => "compile",
+    }
+  }
+}
+
+lazy_static! {
+  static ref FIELD_USAGE: Mutex<HashMap<(String, &'static str), u64>> = Mutex::new(HashMap::new());
+  static ref FAILURES: Mutex<HashMap<QueryFailure, u64>> = Mutex::new(HashMap::new());
+}
+
+fn collect_conditions<'a, T>(expr: &'a Expression<T>, conditions: &mut Vec<&'a Condition>) {
+  match expr.left.as_ref() {
+    LeftExpression::Expression(expr) => collect_conditions(expr, conditions),
+    LeftExpression::Condition(cond) => conditions.push(cond),
+    LeftExpression::CompiledFilter(_) => (),
+  }
+  if let Some(right) = expr.right.as_ref() {
+    collect_conditions(right, conditions);
+  }
+}
+
+// Counts the fields and operators of a parsed client query
+pub fn record_usage<T>(expr: &Expression<T>) {
+  let mut conditions = vec![];
+  collect_conditions(expr, &mut conditions);
+  let mut usage = FIELD_USAGE.lock().unwrap();
+  for cond in conditions {
+    let key = (cond.field(), cond.operator.literal());
+    if let Some(count) = usage.get_mut(&key) {
+      *count += 1;
+    } else if usage.len() < MAX_USAGE_KEYS {
+      usage.insert(key, 1);
+    }
+  }
+}
+
+pub fn record_failure(failure: QueryFailure) {
+  *FAILURES.lock().unwrap().entry(failure).or_default() += 1;
+}
+
+// (field, operator, count), exposed via metrics
+pub fn field_usage_counters() -> Vec<(String, &'static str, u64)> {
+  let usage = FIELD_USAGE.lock().unwrap();
+  usage
+    .iter()
+    .map(|((field, op), count)| (field.clone(), *op, *count))
+    .collect()
+}
+
+pub fn failure_counters() -> Vec<(&'static str, u64)> {
+  let failures = FAILURES.lock().unwrap();
+  failures
+    .iter()
+    .map(|(failure, count)| (failure.name(), *count))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    lee::{make_expr, make_expr_limited, parser::expression::Limits},
+    moving::pilot::Pilot,
+  };
+
+  fn usage(field: &str, op: &str) -> u64 {
+    field_usage_counters()
+      .into_iter()
+      .find(|(f, o, _)| f == field && *o == op)
+      .map(|(_, _, count)| count)
+      .unwrap_or_default()
+  }
+
+  #[test]
+  fn test_record_usage() {
+    let before = usage("usage_test_field", ">");
+    let expr =
+      make_expr::<Pilot>("(usage_test_field > 1 or alt < 2) and usage_test_field > 3").unwrap();
+    record_usage(&expr);
+    assert_eq!(usage("usage_test_field", ">"), before + 2);
+
+    let failures = |name| {
+      failure_counters()
+        .into_iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, count)| count)
+        .unwrap_or_default()
+    };
+    let before = failures("parse");
+    assert!(make_expr_limited::<Pilot>("alt >", &Limits::default()).is_err());
+    assert!(failures("parse") > before);
+  }
+}
@@ -5,6 +5,8 @@ pub mod lee;
 pub mod manager;
 pub mod moving;
 pub mod service;
+pub mod session;
+pub mod telemetry;
 pub mod track;
 pub mod trackfile;
 pub mod types;
@@ -1,13 +1,26 @@
-use clap::Parser;
-use log::{error, info};
+use clap::{Parser, Subcommand};
+use log::{error, info, set_max_level, LevelFilter};
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
 use simwatch_grpc::{
-  config::read_config,
+  config::{read_config, ConfigSource, SAMPLE_CONFIG},
   manager::Manager,
-  service::{camden::camden_server::CamdenServer, CamdenService},
+  service::{
+    admin::CamdenAdminService,
+    camden::{camden_admin_server::CamdenAdminServer, camden_server::CamdenServer},
+    quota::QuotaLayer,
+    rpc_metrics::RpcMetricsLayer,
+    web::WebService,
+    CamdenService,
+  },
+  telemetry::{init_tracing, shutdown_tracing},
 };
 use std::sync::Arc;
+use tokio::signal::{
+  ctrl_c,
+  unix::{signal, SignalKind},
+};
 use tonic::transport::Server;
+use tracing::{info_span, Span};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -15,26 +28,105 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Args {
   #[arg(short, default_value = "/etc/simwatch/simwatch-grpc.toml")]
   config: String,
+  // overrides the config file and SIMWATCH_* environment variables,
+  // e.g. --set api.poll_period=30s
+  #[arg(short, long = "set", value_name = "KEY=VALUE")]
+  set: Vec<String>,
+  // validate the config and exit
+  #[arg(long)]
+  check_config: bool,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Print the sample config with all the options documented
+  PrintDefaultConfig,
+  /// Print the config in effect, overrides applied and secrets redacted
+  DumpEffectiveConfig,
+}
+
+fn grpc_span(req: &http::Request<()>) -> Span {
+  info_span!("grpc", method = %req.uri().path())
+}
+
+async fn shutdown_signal() {
+  let mut terminate = signal(SignalKind::terminate()).unwrap();
+  tokio::select! {
+    _ = ctrl_c() => {},
+    _ = terminate.recv() => {},
+  }
+  info!("shutting down");
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
   let args = Args::parse();
-  let config = read_config(&args.config);
+  if let Some(Command::PrintDefaultConfig) = args.command {
+    print!("{SAMPLE_CONFIG}");
+    return Ok(());
+  }
+  let source = match ConfigSource::new(&args.config, &args.set) {
+    Ok(source) => source,
+    Err(err) => {
+      println!("{err}");
+      std::process::exit(1);
+    }
+  };
+  let config = read_config(&source);
+  // dumped before validation, it helps to find out what's wrong
+  if let Some(Command::DumpEffectiveConfig) = args.command {
+    print!("{}", config.dump()?);
+    return Ok(());
+  }
+  if let Err(err) = config.validate() {
+    println!("{err}");
+    std::process::exit(1);
+  }
+  if args.check_config {
+    println!("config is valid");
+    return Ok(());
+  }
   let addr = config.grpc.listen.parse().unwrap();
 
+  // the logger lets everything through so that the level can be changed
+  // on config reload
   TermLogger::init(
-    config.log.level,
+    LevelFilter::Trace,
     Config::default(),
     TerminalMode::Stdout,
     ColorChoice::Always,
   )
   .unwrap();
+  set_max_level(config.log.level);
+
+  match init_tracing(&config.tracing) {
+    Ok(true) => info!(
+      "exporting traces to {}",
+      config.tracing.otlp_endpoint.as_deref().unwrap_or_default()
+    ),
+    Ok(false) => {}
+    Err(err) => error!("error setting up tracing: {err}"),
+  }
 
   info!("starting camden server version {}", VERSION);
-  let m = Manager::new(config.clone()).await;
+  let m = Manager::new(config.clone(), source).await;
   let m = Arc::new(m);
 
+  {
+    let m = m.clone();
+    tokio::spawn(async move {
+      let mut hangup = signal(SignalKind::hangup()).unwrap();
+      while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        if let Err(err) = m.reload_config().await {
+          error!("config not reloaded: {err}");
+        }
+      }
+    });
+  }
+
   {
     let m = m.clone();
     tokio::spawn(async move {
@@ -45,9 +137,76 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
   }
 
-  let svc = CamdenService::new(m);
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_fixed_refresh().await });
+  }
+
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_transceivers().await });
+  }
+
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_track_writer().await });
+  }
+
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_viewport_weather().await });
+  }
+
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_hazards().await });
+  }
+
+  {
+    let m = m.clone();
+    tokio::spawn(async move { m.run_datis().await });
+  }
+
+  if let Some(admin_listen) = &config.grpc.admin_listen {
+    let admin_addr = admin_listen.parse().unwrap();
+    let svc = CamdenAdminServer::new(CamdenAdminService::new(m.clone()));
+    info!("starting admin service on {}", admin_listen);
+    tokio::spawn(async move {
+      let res = Server::builder()
+        .trace_fn(grpc_span)
+        .layer(RpcMetricsLayer)
+        .add_service(svc)
+        .serve(admin_addr)
+        .await;
+      if let Err(err) = res {
+        error!("error running admin service: {err:?}");
+      }
+    });
+  }
+
+  if let Some(web_listen) = &config.web.listen {
+    let web_addr = web_listen.parse().unwrap();
+    let svc = WebService::new(m.clone());
+    info!("starting web service on {}", web_listen);
+    tokio::spawn(async move {
+      let res = svc.serve(web_addr).await;
+      if let Err(err) = res {
+        error!("error running web service: {err:?}");
+      }
+    });
+  }
+
+  let svc = CamdenService::new(m.clone());
   let svc = CamdenServer::new(svc);
 
-  Server::builder().add_service(svc).serve(addr).await?;
+  Server::builder()
+    .trace_fn(grpc_span)
+    .layer(RpcMetricsLayer)
+    .layer(QuotaLayer::new(m.clone()))
+    .add_service(svc)
+    .serve_with_shutdown(addr, shutdown_signal())
+    .await?;
+  m.shutdown().await;
+  shutdown_tracing();
   Ok(())
 }
@@ -0,0 +1,76 @@
+use super::spatial::PointObject;
+use crate::{fixed::data::FixedData, moving::pilot::Pilot, types::Point};
+use geo::HaversineDistance;
+use geo_types::Point as GeoPoint;
+use rstar::{RTree, AABB};
+
+const STANDARD_PRESSURE_HPA: f64 = 1013.25;
+// altitude change per hPa in the lower atmosphere
+const FEET_PER_HPA: f64 = 27.0;
+const METERS_PER_NM: f64 = 1852.0;
+
+// QNH of the closest airport with the weather known within max_distance
+// nautical miles
+pub fn local_qnh(
+  fixed: &FixedData,
+  airports2d: &RTree<PointObject>,
+  position: Point,
+  max_distance: f64,
+) -> Option<f64> {
+  let lat_deg = max_distance / 60.0;
+  let lng_deg = (lat_deg / position.lat.to_radians().cos().max(0.01)).min(180.0);
+  let env = AABB::from_corners(
+    Point {
+      lat: position.lat - lat_deg,
+      lng: position.lng - lng_deg,
+    },
+    Point {
+      lat: position.lat + lat_deg,
+      lng: position.lng + lng_deg,
+    },
+  );
+  let pos: GeoPoint = position.into();
+  let max_distance = max_distance * METERS_PER_NM;
+
+  let mut closest: Option<(f64, f64)> = None;
+  for po in airports2d.locate_in_envelope(&env) {
+    let Some(qnh) = fixed.airport_qnh(&po.id) else {
+      continue;
+    };
+    let dist = pos.haversine_distance(&po.point().into());
+    if dist <= max_distance && closest.is_none_or(|(d, _)| dist < d) {
+      closest = Some((dist, qnh));
+    }
+  }
+  closest.map(|(_, qnh)| qnh)
+}
+
+// Vatsim reports the pressure altitude, i.e. the altitude over the standard
+// pressure level, which puts aircraft underground at high pressure airports
+pub fn corrected_altitude(altitude: i32, qnh: f64) -> i32 {
+  altitude + ((qnh - STANDARD_PRESSURE_HPA) * FEET_PER_HPA).round() as i32
+}
+
+pub fn correct_altitude(
+  pilot: &mut Pilot,
+  transition_altitude: i32,
+  qnh: impl FnOnce(Point) -> Option<f64>,
+) {
+  pilot.corrected_altitude = if pilot.altitude < transition_altitude {
+    qnh(pilot.position).map(|qnh| corrected_altitude(pilot.altitude, qnh))
+  } else {
+    None
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_corrected_altitude() {
+    assert_eq!(corrected_altitude(1000, 1013.25), 1000);
+    assert_eq!(corrected_altitude(1000, 1033.0), 1533);
+    assert_eq!(corrected_altitude(500, 990.0), -128);
+  }
+}
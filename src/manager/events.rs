@@ -0,0 +1,150 @@
+use crate::{
+  fixed::types::{Airport, FIR},
+  moving::{controller::Controller, pilot::Pilot},
+  weather::{sigmet::Hazard, WeatherChange},
+};
+use std::{collections::VecDeque, sync::Arc};
+
+// how many cycles a slow subscriber may fall behind before it has to catch up
+pub const EVENT_BUS_CAPACITY: usize = 16;
+// how many cycles a subscriber may catch up on before it has to resync
+pub const DIFF_HISTORY_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Diff<T> {
+  // new or changed objects, in their current state
+  pub set: Vec<T>,
+  // objects gone or not worth showing to anyone anymore
+  pub delete: Vec<T>,
+}
+
+impl<T> Default for Diff<T> {
+  fn default() -> Self {
+    Self {
+      set: vec![],
+      delete: vec![],
+    }
+  }
+}
+
+impl<T> Diff<T> {
+  pub fn len(&self) -> usize {
+    self.set.len() + self.delete.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.set.is_empty() && self.delete.is_empty()
+  }
+}
+
+pub type PilotDiff = Diff<Arc<Pilot>>;
+pub type ControllerDiff = Diff<Controller>;
+pub type AirportDiff = Diff<Arc<Airport>>;
+pub type FirDiff = Diff<Arc<FIR>>;
+pub type HazardDiff = Diff<Hazard>;
+
+// everything that has changed during a single ingestion cycle
+#[derive(Debug, Clone, Default)]
+pub struct CycleDiff {
+  pub seq: u64,
+  pub pilots: PilotDiff,
+  pub controllers: ControllerDiff,
+  pub airports: AirportDiff,
+  pub firs: FirDiff,
+  pub hazards: HazardDiff,
+  // metars refreshed, sent along with the airports changed
+  pub weather: Vec<WeatherChange>,
+}
+
+impl CycleDiff {
+  pub fn len(&self) -> usize {
+    self.pilots.len()
+      + self.controllers.len()
+      + self.airports.len()
+      + self.firs.len()
+      + self.hazards.len()
+      + self.weather.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+pub type EventBatch = Arc<CycleDiff>;
+
+#[derive(Debug)]
+pub struct DiffHistory {
+  capacity: usize,
+  last_seq: u64,
+  diffs: VecDeque<EventBatch>,
+}
+
+impl DiffHistory {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      last_seq: 0,
+      diffs: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  pub fn last_seq(&self) -> u64 {
+    self.last_seq
+  }
+
+  // assigns the next sequence number to the diff and stores it
+  pub fn push(&mut self, mut diff: CycleDiff) -> EventBatch {
+    self.last_seq += 1;
+    diff.seq = self.last_seq;
+    let diff = Arc::new(diff);
+    if self.diffs.len() == self.capacity {
+      self.diffs.pop_front();
+    }
+    self.diffs.push_back(diff.clone());
+    diff
+  }
+
+  // diffs following the given sequence number, None if some of them
+  // have already been evicted
+  pub fn since(&self, seq: u64) -> Option<Vec<EventBatch>> {
+    if seq >= self.last_seq {
+      return Some(vec![]);
+    }
+    let first = self.diffs.front()?;
+    if first.seq > seq + 1 {
+      return None;
+    }
+    Some(
+      self
+        .diffs
+        .iter()
+        .filter(|diff| diff.seq > seq)
+        .cloned()
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_history() {
+    let mut history = DiffHistory::new(3);
+    assert_eq!(history.since(0).map(|d| d.len()), Some(0));
+
+    for _ in 0..5 {
+      history.push(CycleDiff::default());
+    }
+    assert_eq!(history.last_seq(), 5);
+
+    let diffs = history.since(3).unwrap();
+    let seqs: Vec<u64> = diffs.iter().map(|d| d.seq).collect();
+    assert_eq!(seqs, vec![4, 5]);
+    assert_eq!(history.since(2).map(|d| d.len()), Some(3));
+    assert!(history.since(1).is_none());
+    assert_eq!(history.since(5).map(|d| d.len()), Some(0));
+  }
+}
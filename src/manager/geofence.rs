@@ -0,0 +1,279 @@
+use super::events::PilotDiff;
+use crate::{
+  lee::parser::expression::{flush_evaluations, Expression},
+  moving::pilot::Pilot,
+  service::camden,
+  types::Point,
+};
+use geo::{Contains, HaversineDistance};
+use geo_types::{LineString, Point as GeoPoint, Polygon};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+pub const GEOFENCE_BUS_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+  Polygon(Polygon),
+  // radius in meters
+  Circle { center: Point, radius: f64 },
+}
+
+impl Shape {
+  pub fn polygon(points: &[Point]) -> Option<Self> {
+    if points.len() < 3 {
+      return None;
+    }
+    let exterior: LineString = points.iter().map(|p| (p.lng, p.lat)).collect();
+    Some(Self::Polygon(Polygon::new(exterior, vec![])))
+  }
+
+  pub fn circle(center: Point, radius: f64) -> Option<Self> {
+    if radius > 0.0 {
+      Some(Self::Circle { center, radius })
+    } else {
+      None
+    }
+  }
+
+  pub fn contains(&self, point: Point) -> bool {
+    let point: GeoPoint = point.into();
+    match self {
+      Self::Polygon(poly) => poly.contains(&point),
+      Self::Circle { center, radius } => {
+        let center: GeoPoint = (*center).into();
+        center.haversine_distance(&point) <= *radius
+      }
+    }
+  }
+}
+
+pub struct Geofence {
+  pub id: String,
+  pub name: String,
+  pub shape: Shape,
+  pub query: String,
+  filter: Option<Expression<Pilot>>,
+  // callsigns currently inside
+  inside: HashSet<String>,
+}
+
+impl std::fmt::Debug for Geofence {
+  // compiled filters can't be printed, the query they're made of is enough
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Geofence")
+      .field("id", &self.id)
+      .field("name", &self.name)
+      .field("shape", &self.shape)
+      .field("query", &self.query)
+      .field("inside", &self.inside)
+      .finish()
+  }
+}
+
+impl Geofence {
+  fn matches(&self, pilot: &Pilot) -> bool {
+    self.shape.contains(pilot.position) && self.filter.as_ref().is_none_or(|f| f.evaluate(pilot))
+  }
+}
+
+impl From<&Geofence> for camden::Geofence {
+  fn from(value: &Geofence) -> Self {
+    let shape = match &value.shape {
+      Shape::Polygon(poly) => camden::geofence::Shape::Polygon(camden::PointList {
+        points: poly
+          .exterior()
+          .points()
+          .map(|p| camden::Point {
+            lat: p.y(),
+            lng: p.x(),
+          })
+          .collect(),
+      }),
+      Shape::Circle { center, radius } => camden::geofence::Shape::Circle(camden::Circle {
+        center: Some((*center).into()),
+        radius: *radius,
+      }),
+    };
+    Self {
+      id: value.id.clone(),
+      name: value.name.clone(),
+      shape: Some(shape),
+      query: value.query.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeofenceEventKind {
+  Enter,
+  Exit,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeofenceEvent {
+  pub geofence_id: String,
+  pub kind: GeofenceEventKind,
+  pub pilot: Arc<Pilot>,
+}
+
+impl From<GeofenceEvent> for camden::GeofenceEvent {
+  fn from(value: GeofenceEvent) -> Self {
+    let event_type = match value.kind {
+      GeofenceEventKind::Enter => camden::GeofenceEventType::GeofenceEnter,
+      GeofenceEventKind::Exit => camden::GeofenceEventType::GeofenceExit,
+    };
+    Self {
+      geofence_id: value.geofence_id,
+      event_type: event_type as i32,
+      pilot: Some(value.pilot.into()),
+    }
+  }
+}
+
+pub type GeofenceEvents = Arc<Vec<GeofenceEvent>>;
+
+#[derive(Debug, Default)]
+pub struct GeofenceRegistry {
+  next_id: u64,
+  fences: HashMap<String, Geofence>,
+}
+
+impl GeofenceRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // Registers a new geofence, pilots already inside are not reported
+  pub fn create<'a>(
+    &mut self,
+    name: &str,
+    shape: Shape,
+    query: &str,
+    filter: Option<Expression<Pilot>>,
+    pilots: impl Iterator<Item = &'a Pilot>,
+  ) -> camden::Geofence {
+    self.next_id += 1;
+    let mut fence = Geofence {
+      id: format!("gf{}", self.next_id),
+      name: name.to_owned(),
+      shape,
+      query: query.to_owned(),
+      filter,
+      inside: HashSet::new(),
+    };
+    fence.inside = pilots
+      .filter(|pilot| fence.matches(pilot))
+      .map(|pilot| pilot.callsign.clone())
+      .collect();
+    flush_evaluations();
+    let res = (&fence).into();
+    self.fences.insert(fence.id.clone(), fence);
+    res
+  }
+
+  pub fn delete(&mut self, id: &str) -> bool {
+    self.fences.remove(id).is_some()
+  }
+
+  pub fn list(&self) -> Vec<camden::Geofence> {
+    let mut fences: Vec<camden::Geofence> = self.fences.values().map(|f| f.into()).collect();
+    fences.sort_by(|a, b| a.id.cmp(&b.id));
+    fences
+  }
+
+  pub fn update(&mut self, diff: &PilotDiff) -> Vec<GeofenceEvent> {
+    let mut events = vec![];
+    for fence in self.fences.values_mut() {
+      for pilot in diff.set.iter() {
+        let inside = fence.matches(pilot);
+        let kind = if inside && !fence.inside.contains(&pilot.callsign) {
+          fence.inside.insert(pilot.callsign.clone());
+          GeofenceEventKind::Enter
+        } else if !inside && fence.inside.remove(&pilot.callsign) {
+          GeofenceEventKind::Exit
+        } else {
+          continue;
+        };
+        events.push(GeofenceEvent {
+          geofence_id: fence.id.clone(),
+          kind,
+          pilot: pilot.clone(),
+        });
+      }
+      for pilot in diff.delete.iter() {
+        if fence.inside.remove(&pilot.callsign) {
+          events.push(GeofenceEvent {
+            geofence_id: fence.id.clone(),
+            kind: GeofenceEventKind::Exit,
+            pilot: pilot.clone(),
+          });
+        }
+      }
+    }
+    flush_evaluations();
+    events
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pilot(callsign: &str, lat: f64, lng: f64) -> Arc<Pilot> {
+    Arc::new(Pilot {
+      position: Point { lat, lng },
+      ..Pilot::test_default(callsign)
+    })
+  }
+
+  #[test]
+  fn test_shapes() {
+    let square = Shape::polygon(&[
+      Point { lat: 0.0, lng: 0.0 },
+      Point { lat: 0.0, lng: 2.0 },
+      Point { lat: 2.0, lng: 2.0 },
+      Point { lat: 2.0, lng: 0.0 },
+    ])
+    .unwrap();
+    assert!(square.contains(Point { lat: 1.0, lng: 1.0 }));
+    assert!(!square.contains(Point { lat: 3.0, lng: 1.0 }));
+    assert!(Shape::polygon(&[Point { lat: 0.0, lng: 0.0 }]).is_none());
+
+    let circle = Shape::circle(Point { lat: 0.0, lng: 0.0 }, 200_000.0).unwrap();
+    assert!(circle.contains(Point { lat: 1.0, lng: 1.0 }));
+    assert!(!circle.contains(Point { lat: 2.0, lng: 2.0 }));
+  }
+
+  #[test]
+  fn test_update() {
+    let mut reg = GeofenceRegistry::new();
+    let shape = Shape::circle(Point { lat: 0.0, lng: 0.0 }, 200_000.0).unwrap();
+    let inside = pilot("AAA", 1.0, 0.0);
+    let fence = reg.create("test", shape, "", None, [inside.as_ref()].into_iter());
+    assert_eq!(fence.id, "gf1");
+
+    // already inside when created, moving within the fence is not reported
+    let diff = PilotDiff {
+      set: vec![pilot("AAA", 0.5, 0.0), pilot("BBB", 1.0, 1.0)],
+      delete: vec![],
+    };
+    let events = reg.update(&diff);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].pilot.callsign, "BBB");
+    assert_eq!(events[0].kind, GeofenceEventKind::Enter);
+
+    let diff = PilotDiff {
+      set: vec![pilot("BBB", 5.0, 5.0)],
+      delete: vec![inside],
+    };
+    let events = reg.update(&diff);
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|e| e.kind == GeofenceEventKind::Exit));
+
+    assert!(reg.delete("gf1"));
+    assert!(reg.list().is_empty());
+  }
+}
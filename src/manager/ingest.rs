@@ -0,0 +1,275 @@
+use super::{events::PilotDiff, spatial::PointObject};
+use crate::{moving::pilot::Pilot, service::camden};
+use chrono::{DateTime, Duration, Utc};
+use rayon::prelude::*;
+use rstar::RTree;
+use std::{collections::HashMap, sync::Arc};
+
+// Pilots along with their rtree, swapped in together so that readers
+// always see the two matching
+#[derive(Debug, Default)]
+pub struct PilotSnapshot {
+  pub pilots: HashMap<String, Arc<Pilot>>,
+  pub pilots2d: RTree<PointObject>,
+  // pilots converted for the clients once per ingestion cycle
+  pub protos: HashMap<String, Arc<camden::Pilot>>,
+}
+
+// Pilots of a snapshot as sent to the clients. Every stream sending a pilot
+// still current clones its shared conversion, the others are converted
+#[derive(Debug, Default)]
+pub struct PilotProtos(Arc<PilotSnapshot>);
+
+impl From<Arc<PilotSnapshot>> for PilotProtos {
+  fn from(value: Arc<PilotSnapshot>) -> Self {
+    Self(value)
+  }
+}
+
+impl PilotProtos {
+  pub fn get(&self, pilot: Arc<Pilot>) -> camden::Pilot {
+    let current = self
+      .0
+      .pilots
+      .get(&pilot.callsign)
+      .is_some_and(|cur| Arc::ptr_eq(cur, &pilot));
+    match self.0.protos.get(&pilot.callsign) {
+      Some(proto) if current => camden::Pilot::clone(proto),
+      _ => pilot.into(),
+    }
+  }
+}
+
+// Pilot indexes built from a fresh payload, ready to be swapped in
+pub struct PilotIndex {
+  pub pilots: HashMap<String, Arc<Pilot>>,
+  pub pilots2d: RTree<PointObject>,
+  pub diff: PilotDiff,
+  // sessions over since the previous payload, either gone offline
+  // or reconnected under the same callsign
+  pub finished: Vec<Arc<Pilot>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IndexOptions {
+  // pilots missing from the payload are kept, flagged as stale, as long
+  // as they've been updated since then
+  pub keep_since: Option<DateTime<Utc>>,
+  // logging on again within this period after the last update continues
+  // the previous session instead of starting a new one
+  pub reconnect_grace: Duration,
+}
+
+// pilot, its rtree object, whether it has changed and the session it has
+// finished by reconnecting
+type IndexEntry = (Arc<Pilot>, PointObject, bool, Option<Arc<Pilot>>);
+
+// Builds the indexes off the lock, comparing against the previous pilots.
+// Unchanged pilots keep sharing their previous instances with the readers.
+// Meant to run on a blocking thread, the work is spread over rayon's pool
+pub fn build_pilot_index(
+  fresh: Vec<Pilot>,
+  prev: &HashMap<String, Arc<Pilot>>,
+  opts: IndexOptions,
+) -> PilotIndex {
+  let entries: Vec<IndexEntry> = fresh
+    .into_par_iter()
+    .map(|mut pilot| {
+      let existing = prev.get(&pilot.callsign);
+      let mut reconnected = None;
+      if let Some(existing) = existing {
+        if existing.logon_time != pilot.logon_time {
+          if pilot.is_reconnection_of(existing, opts.reconnect_grace) {
+            // keeps the track file and the session going
+            pilot.logon_time = existing.logon_time;
+          } else {
+            reconnected = Some(existing.clone());
+          }
+        }
+      }
+      let po: PointObject = (&pilot).into();
+      match existing {
+        Some(existing) if **existing == pilot => (existing.clone(), po, false, reconnected),
+        _ => (Arc::new(pilot), po, true, reconnected),
+      }
+    })
+    .collect();
+
+  let mut pilots = HashMap::with_capacity(entries.len());
+  let mut objects = Vec::with_capacity(entries.len());
+  let mut diff = PilotDiff::default();
+  let mut finished = vec![];
+
+  for (pilot, po, changed, reconnected) in entries.into_iter() {
+    if changed {
+      diff.set.push(pilot.clone());
+    }
+    if let Some(reconnected) = reconnected {
+      finished.push(reconnected);
+    }
+    objects.push(po);
+    pilots.insert(pilot.callsign.clone(), pilot);
+  }
+
+  for (cs, pilot) in prev.iter() {
+    if pilots.contains_key(cs) {
+      continue;
+    }
+    let keep = opts
+      .keep_since
+      .is_some_and(|keep_since| pilot.last_updated >= keep_since);
+    if keep {
+      let mut pilot = pilot.clone();
+      if !pilot.stale {
+        Arc::make_mut(&mut pilot).stale = true;
+        diff.set.push(pilot.clone());
+      }
+      objects.push(pilot.as_ref().into());
+      pilots.insert(cs.clone(), pilot);
+    } else {
+      diff.delete.push(pilot.clone());
+      finished.push(pilot.clone());
+    }
+  }
+
+  PilotIndex {
+    pilots,
+    pilots2d: RTree::bulk_load(objects),
+    diff,
+    finished,
+  }
+}
+
+// Converts the pilots for the clients, the ones sharing their previous
+// instances keep their previous conversions
+pub fn build_protos(
+  pilots: &HashMap<String, Arc<Pilot>>,
+  prev: &PilotSnapshot,
+) -> HashMap<String, Arc<camden::Pilot>> {
+  pilots
+    .par_iter()
+    .map(|(cs, pilot)| {
+      let unchanged = prev
+        .pilots
+        .get(cs)
+        .is_some_and(|existing| Arc::ptr_eq(existing, pilot));
+      let proto = match prev.protos.get(cs) {
+        Some(proto) if unchanged => proto.clone(),
+        _ => Arc::new(pilot.clone().into()),
+      };
+      (cs.clone(), proto)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::Point;
+
+  fn pilot(callsign: &str, lat: f64) -> Pilot {
+    Pilot {
+      position: Point { lat, lng: 0.0 },
+      ..Pilot::test_default(callsign)
+    }
+  }
+
+  const NO_CARRY: IndexOptions = IndexOptions {
+    keep_since: None,
+    reconnect_grace: Duration::zero(),
+  };
+
+  #[test]
+  fn test_build_pilot_index() {
+    let idx = build_pilot_index(
+      vec![pilot("AAA", 1.0), pilot("BBB", 2.0)],
+      &HashMap::new(),
+      NO_CARRY,
+    );
+    assert_eq!(idx.pilots.len(), 2);
+    assert_eq!(idx.pilots2d.size(), 2);
+    assert_eq!(idx.diff.set.len(), 2);
+    assert!(idx.finished.is_empty());
+
+    let prev = idx.pilots;
+    let mut reconnected = Pilot::clone(prev.get("BBB").unwrap());
+    reconnected.logon_time += Duration::minutes(1);
+    let fresh = vec![reconnected, pilot("CCC", 3.0)];
+
+    let idx = build_pilot_index(fresh, &prev, NO_CARRY);
+    assert_eq!(idx.pilots.len(), 2);
+    assert_eq!(idx.diff.set.len(), 2);
+    assert_eq!(idx.diff.delete.len(), 1);
+    assert_eq!(idx.diff.delete[0].callsign, "AAA");
+    // AAA went offline, BBB has reconnected
+    assert_eq!(idx.finished.len(), 2);
+  }
+
+  #[test]
+  fn test_reconnection() {
+    let opts = IndexOptions {
+      keep_since: Some(Utc::now() - Duration::minutes(3)),
+      reconnect_grace: Duration::minutes(3),
+    };
+    let idx = build_pilot_index(
+      vec![pilot("AAA", 1.0), pilot("BBB", 2.0)],
+      &HashMap::new(),
+      opts,
+    );
+    let prev = idx.pilots;
+
+    // BBB drops out of the feed for a while
+    let idx = build_pilot_index(vec![Pilot::clone(prev.get("AAA").unwrap())], &prev, opts);
+    // unchanged pilots are shared with the previous index
+    assert!(Arc::ptr_eq(&idx.pilots["AAA"], &prev["AAA"]));
+    assert!(idx.diff.delete.is_empty());
+    assert!(idx.finished.is_empty());
+    assert!(idx.pilots.get("BBB").unwrap().stale);
+    assert_eq!(idx.diff.set.len(), 1);
+    let prev = idx.pilots;
+
+    // and comes back with a new logon time
+    let mut reconnected = pilot("BBB", 2.5);
+    reconnected.logon_time = prev.get("BBB").unwrap().logon_time + Duration::minutes(2);
+    let idx = build_pilot_index(vec![reconnected], &prev, opts);
+    assert!(idx.finished.is_empty());
+    let bbb = idx.pilots.get("BBB").unwrap();
+    assert_eq!(bbb.logon_time, prev.get("BBB").unwrap().logon_time);
+    assert!(!bbb.stale);
+  }
+
+  #[test]
+  fn test_build_protos() {
+    let idx = build_pilot_index(
+      vec![pilot("AAA", 1.0), pilot("BBB", 2.0)],
+      &HashMap::new(),
+      NO_CARRY,
+    );
+    let protos = build_protos(&idx.pilots, &PilotSnapshot::default());
+    assert_eq!(protos.len(), 2);
+    assert_eq!(protos["AAA"].callsign, "AAA");
+    let prev = PilotSnapshot {
+      pilots: idx.pilots,
+      pilots2d: idx.pilots2d,
+      protos,
+    };
+
+    let fresh = vec![Pilot::clone(&prev.pilots["AAA"]), pilot("BBB", 2.5)];
+    let idx = build_pilot_index(fresh, &prev.pilots, NO_CARRY);
+    let protos = build_protos(&idx.pilots, &prev);
+    // the unchanged pilot keeps its conversion, the moved one is converted
+    assert!(Arc::ptr_eq(&protos["AAA"], &prev.protos["AAA"]));
+    assert!(!Arc::ptr_eq(&protos["BBB"], &prev.protos["BBB"]));
+    assert_eq!(protos["BBB"].position.as_ref().unwrap().lat, 2.5);
+
+    let old_bbb = prev.pilots["BBB"].clone();
+    let snapshot = PilotProtos::from(Arc::new(PilotSnapshot {
+      pilots: idx.pilots,
+      pilots2d: idx.pilots2d,
+      protos,
+    }));
+    assert_eq!(snapshot.get(old_bbb).position.unwrap().lat, 2.0);
+    let bbb = snapshot.0.pilots["BBB"].clone();
+    assert_eq!(snapshot.get(bbb).position.unwrap().lat, 2.5);
+  }
+}
@@ -1,6 +1,23 @@
-use crate::{service::camden, util::seconds_since};
+use crate::{
+  lee::{
+    parser::expression::evaluations_count,
+    usage::{failure_counters, field_usage_counters},
+  },
+  moving::aircraft::unresolved_counters,
+  service::{
+    camden,
+    quota::key_usage,
+    rpc_metrics::{rpc_stats, LATENCY_BUCKETS},
+    streams::{active_streams, sent_totals},
+  },
+  trackfile::corruption_counters,
+  util::seconds_since,
+};
 use chrono::{DateTime, Utc};
-use std::{collections::HashMap, fmt::Display};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::Display,
+};
 
 #[macro_export]
 macro_rules! labels {
@@ -43,6 +60,15 @@ impl Display for MetricType {
   }
 }
 
+fn label_string(labels: HashMap<&'static str, String>) -> String {
+  let mut labels = labels
+    .iter()
+    .map(|(k, v)| format!("{}=\"{}\"", k, v))
+    .collect::<Vec<String>>();
+  labels.sort();
+  labels.join(",")
+}
+
 #[derive(Debug, Clone)]
 pub struct Metric<T: Display + Clone + Default> {
   name: String,
@@ -83,13 +109,7 @@ impl<T: Display + Clone + Default> Metric<T> {
 
   pub fn set(&mut self, labels: HashMap<&'static str, String>, value: T) {
     self.single = false;
-    let mut labels = labels
-      .iter()
-      .map(|(k, v)| format!("{}=\"{}\"", k, v))
-      .collect::<Vec<String>>();
-    labels.sort();
-    let label_str = labels.join(",");
-    self.values.insert(label_str, value);
+    self.values.insert(label_string(labels), value);
   }
 
   pub fn set_single(&mut self, value: T) {
@@ -187,6 +207,345 @@ impl From<Metric<f32>> for camden::Metric {
   }
 }
 
+// Histogram by label set, rendered as prometheus _bucket, _sum and _count
+// series
+#[derive(Debug, Clone)]
+pub struct Histogram {
+  name: String,
+  help: String,
+  bounds: Vec<f64>,
+  // cumulative bucket counts, the last one being +Inf, and the sum of
+  // the observed values
+  values: HashMap<String, (Vec<u64>, f64)>,
+}
+
+impl Histogram {
+  pub fn new(name: &str, help: &str, bounds: &[f64]) -> Self {
+    Self {
+      name: name.into(),
+      help: help.into(),
+      bounds: bounds.to_vec(),
+      values: HashMap::new(),
+    }
+  }
+
+  // buckets hold non-cumulative counts, one per bound plus +Inf
+  pub fn set(&mut self, labels: HashMap<&'static str, String>, buckets: &[u64], sum: f64) {
+    let counts = buckets
+      .iter()
+      .scan(0, |total, count| {
+        *total += count;
+        Some(*total)
+      })
+      .collect();
+    self.values.insert(label_string(labels), (counts, sum));
+  }
+
+  fn bucket_labels(&self, labels: &str) -> Vec<String> {
+    let prefix = if labels.is_empty() {
+      "".to_owned()
+    } else {
+      format!("{labels},")
+    };
+    self
+      .bounds
+      .iter()
+      .map(|bound| bound.to_string())
+      .chain(["+Inf".to_owned()])
+      .map(|le| format!("{prefix}le=\"{le}\""))
+      .collect()
+  }
+
+  pub fn render(&self) -> String {
+    if self.values.is_empty() {
+      return "".into();
+    }
+
+    let mut lines = vec![
+      format!("# HELP {} {}", self.name, self.help),
+      format!("# TYPE {} {}", self.name, MetricType::Histogram),
+    ];
+    let mut keys: Vec<&String> = self.values.keys().collect();
+    keys.sort();
+    for labels in keys {
+      let (counts, sum) = &self.values[labels];
+      for (le, count) in self.bucket_labels(labels).iter().zip(counts) {
+        lines.push(format!("{}_bucket{{{le}}} {count}", self.name));
+      }
+      let labels = if labels.is_empty() {
+        "".to_owned()
+      } else {
+        format!("{{{labels}}}")
+      };
+      let count = counts.last().copied().unwrap_or_default();
+      lines.push(format!("{}_sum{labels} {sum}", self.name));
+      lines.push(format!("{}_count{labels} {count}", self.name));
+    }
+    lines.join("\n") + "\n"
+  }
+}
+
+// cumulative bucket counts go to int_values keyed by labels along with le,
+// the sums to float_values
+impl From<Histogram> for camden::Metric {
+  fn from(value: Histogram) -> Self {
+    let mut int_values = HashMap::new();
+    let mut float_values = HashMap::new();
+    for (labels, (counts, sum)) in value.values.iter() {
+      for (le, count) in value.bucket_labels(labels).into_iter().zip(counts) {
+        int_values.insert(le, *count);
+      }
+      float_values.insert(labels.clone(), *sum);
+    }
+    Self {
+      name: value.name,
+      help: value.help,
+      metric_type: MetricType::Histogram as i32,
+      single: false,
+      is_float: false,
+      float_values,
+      int_values,
+    }
+  }
+}
+
+// Caps the distinct values of a label: the values with the largest totals
+// are kept as is, the others are folded into "other" so the number of
+// series stays bounded whatever the data is
+#[derive(Debug)]
+pub struct LabelLimiter {
+  label: &'static str,
+  top: usize,
+  rows: Vec<(HashMap<&'static str, String>, usize)>,
+}
+
+impl LabelLimiter {
+  pub fn new(label: &'static str, top: usize) -> Self {
+    Self {
+      label,
+      top,
+      rows: vec![],
+    }
+  }
+
+  pub fn add(&mut self, labels: HashMap<&'static str, String>, value: usize) {
+    self.rows.push((labels, value));
+  }
+
+  // Sets the limited values to the metric and returns the number of the
+  // label sets folded
+  pub fn apply(self, metric: &mut Metric<usize>) -> usize {
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for (labels, value) in self.rows.iter() {
+      if let Some(v) = labels.get(self.label) {
+        *totals.entry(v.as_str()).or_default() += value;
+      }
+    }
+    let mut totals: Vec<(&str, usize)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let kept: HashSet<String> = totals
+      .into_iter()
+      .take(self.top)
+      .map(|(v, _)| v.to_owned())
+      .collect();
+
+    let mut folded = 0;
+    let mut values: HashMap<String, (HashMap<&'static str, String>, usize)> = HashMap::new();
+    for (mut labels, value) in self.rows.into_iter() {
+      if let Some(v) = labels.get_mut(self.label) {
+        if !kept.contains(v) {
+          *v = "other".to_owned();
+          folded += 1;
+        }
+      }
+      let key = label_string(labels.clone());
+      values.entry(key).or_insert((labels, 0)).1 += value;
+    }
+    for (labels, value) in values.into_values() {
+      metric.set(labels, value);
+    }
+    folded
+  }
+}
+
+fn rpc_request_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "rpc_request_count",
+    "gRPC requests by method",
+    MetricType::Counter,
+  );
+  for (method, stats) in rpc_stats() {
+    metric.set(labels!("method" = method), stats.requests);
+  }
+  metric
+}
+
+fn rpc_error_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "rpc_error_count",
+    "gRPC requests finished with an error by method and status code",
+    MetricType::Counter,
+  );
+  for (method, stats) in rpc_stats() {
+    for (code, count) in stats.errors {
+      metric.set(labels!("method" = method.clone(), "code" = code), count);
+    }
+  }
+  metric
+}
+
+fn rpc_in_flight() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "rpc_in_flight",
+    "gRPC requests in progress by method, open streams included",
+    MetricType::Gauge,
+  );
+  for (method, stats) in rpc_stats() {
+    metric.set(labels!("method" = method), stats.in_flight);
+  }
+  metric
+}
+
+fn rpc_latency_sec() -> Histogram {
+  let mut metric = Histogram::new(
+    "rpc_latency_sec",
+    "gRPC request duration by method, streams are observed when they end",
+    LATENCY_BUCKETS,
+  );
+  for (method, stats) in rpc_stats() {
+    if !stats.latency_buckets.is_empty() {
+      metric.set(
+        labels!("method" = method),
+        &stats.latency_buckets,
+        stats.latency_sum,
+      );
+    }
+  }
+  metric
+}
+
+fn streams_open() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "streams_open",
+    "Open map_updates and subscribe_query streams by kind",
+    MetricType::Gauge,
+  );
+  let mut counts: HashMap<&str, u64> = HashMap::new();
+  for stream in active_streams() {
+    *counts.entry(stream.kind).or_default() += 1;
+  }
+  for (kind, count) in counts {
+    metric.set(labels!("kind" = kind), count);
+  }
+  metric
+}
+
+fn stream_messages_sent() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "stream_messages_sent",
+    "Messages sent to streaming clients by stream kind",
+    MetricType::Counter,
+  );
+  for (kind, totals) in sent_totals() {
+    metric.set(labels!("kind" = kind), totals.messages);
+  }
+  metric
+}
+
+fn stream_bytes_sent() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "stream_bytes_sent",
+    "Encoded size of the messages sent to streaming clients by stream kind",
+    MetricType::Counter,
+  );
+  for (kind, totals) in sent_totals() {
+    metric.set(labels!("kind" = kind), totals.bytes);
+  }
+  metric
+}
+
+fn api_key_bytes_sent() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "api_key_bytes_sent",
+    "Bytes sent to the clients by api key name",
+    MetricType::Counter,
+  );
+  for (key, usage) in key_usage() {
+    metric.set(labels!("key" = key), usage.bytes_sent);
+  }
+  metric
+}
+
+fn api_key_quota_rejected() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "api_key_quota_rejected",
+    "Calls refused for exceeding the daily quota by api key name",
+    MetricType::Counter,
+  );
+  for (key, usage) in key_usage() {
+    metric.set(labels!("key" = key), usage.rejected);
+  }
+  metric
+}
+
+fn query_evaluation_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "query_evaluation_count",
+    "Number of query conditions evaluated",
+    MetricType::Counter,
+  );
+  metric.set_single(evaluations_count());
+  metric
+}
+
+fn query_field_usage() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "query_field_usage",
+    "Client query conditions by field and operator, unknown fields included",
+    MetricType::Counter,
+  );
+  for (field, operator, count) in field_usage_counters() {
+    metric.set(labels!("field" = field, "operator" = operator), count);
+  }
+  metric
+}
+
+fn query_failure_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "query_failure_count",
+    "Client queries rejected by stage: parse, limits or compile",
+    MetricType::Counter,
+  );
+  for (stage, count) in failure_counters() {
+    metric.set(labels!("stage" = stage), count);
+  }
+  metric
+}
+
+fn trackfile_corruption_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "trackfile_corruption_count",
+    "Corrupted track and session files recovered on open",
+    MetricType::Counter,
+  );
+  for (kind, count) in corruption_counters() {
+    metric.set(labels!("kind" = kind), count);
+  }
+  metric
+}
+
+fn unresolved_aircraft_count() -> Metric<u64> {
+  let mut metric = Metric::new(
+    "unresolved_aircraft_count",
+    "Flight plan aircraft designators not found in the aircraft database",
+    MetricType::Counter,
+  );
+  for (designator, count) in unresolved_counters() {
+    metric.set(labels!("designator" = designator), count);
+  }
+  metric
+}
+
 #[derive(Debug, Clone)]
 pub struct Metrics {
   pub vatsim_objects_online: Metric<usize>,
@@ -198,6 +557,22 @@ pub struct Metrics {
   pub vatsim_data_request_error_count: Metric<u64>,
   pub processing_time_sec: Metric<f32>,
   pub db_cleanup_time_sec: Metric<f32>,
+  pub fixed_data_stale: Metric<u64>,
+  pub vatsim_data_degraded: Metric<u64>,
+  pub vatsim_data_stale: Metric<u64>,
+  pub vatsim_connected_clients: Metric<u64>,
+  pub vatsim_unique_users: Metric<u64>,
+  pub airport_movements: Metric<u64>,
+  pub weather_provider_error_count: Metric<u64>,
+  pub weather_blacklist_size: Metric<usize>,
+  pub track_store_bytes: Metric<u64>,
+  pub track_store_files: Metric<u64>,
+  pub track_append_count: Metric<u64>,
+  pub track_appends_per_sec: Metric<f64>,
+  pub track_append_latency_sec: Metric<f64>,
+  pub track_write_amplification: Metric<f64>,
+  pub track_cleanup_count: Metric<u64>,
+  pub metric_label_sets_dropped: Metric<usize>,
   pub process_started_at: DateTime<Utc>,
 }
 
@@ -245,6 +620,86 @@ impl Metrics {
         "Time spent cleaning up database stored objects",
         MetricType::Gauge,
       ),
+      fixed_data_stale: Metric::new(
+        "fixed_data_stale",
+        "Set to 1 if fixed data has been loaded from a stale cache",
+        MetricType::Gauge,
+      ),
+      vatsim_data_degraded: Metric::new(
+        "vatsim_data_degraded",
+        "Set to 1 if no fresh Vatsim data has been loaded for too long",
+        MetricType::Gauge,
+      ),
+      vatsim_data_stale: Metric::new(
+        "vatsim_data_stale",
+        "Set to 1 if the Vatsim data timestamp is too old",
+        MetricType::Gauge,
+      ),
+      vatsim_connected_clients: Metric::new(
+        "vatsim_connected_clients",
+        "Clients connected to Vatsim according to the data feed",
+        MetricType::Gauge,
+      ),
+      vatsim_unique_users: Metric::new(
+        "vatsim_unique_users",
+        "Unique users connected to Vatsim according to the data feed",
+        MetricType::Gauge,
+      ),
+      airport_movements: Metric::new(
+        "airport_movements",
+        "Arrivals and departures observed at airports during the current hour",
+        MetricType::Gauge,
+      ),
+      weather_provider_error_count: Metric::new(
+        "weather_provider_error_count",
+        "Weather provider request error count",
+        MetricType::Counter,
+      ),
+      weather_blacklist_size: Metric::new(
+        "weather_blacklist_size",
+        "Locations with no weather available, not requested until their blacklisting expires",
+        MetricType::Gauge,
+      ),
+      track_store_bytes: Metric::new(
+        "track_store_bytes",
+        "Total size of the track files",
+        MetricType::Gauge,
+      ),
+      track_store_files: Metric::new(
+        "track_store_files",
+        "Number of the track files",
+        MetricType::Gauge,
+      ),
+      track_append_count: Metric::new(
+        "track_append_count",
+        "Track points appended",
+        MetricType::Counter,
+      ),
+      track_appends_per_sec: Metric::new(
+        "track_appends_per_sec",
+        "Track points appended per second between the latest two batches",
+        MetricType::Gauge,
+      ),
+      track_append_latency_sec: Metric::new(
+        "track_append_latency_sec",
+        "Mean time of a track file append in the latest batch",
+        MetricType::Gauge,
+      ),
+      track_write_amplification: Metric::new(
+        "track_write_amplification",
+        "Bytes written per byte of new track points in the latest batch",
+        MetricType::Gauge,
+      ),
+      track_cleanup_count: Metric::new(
+        "track_cleanup_count",
+        "Track files deleted and track points compacted by the cleanup",
+        MetricType::Counter,
+      ),
+      metric_label_sets_dropped: Metric::new(
+        "metric_label_sets_dropped",
+        "Label sets folded into \"other\" by the cardinality limits on the latest update",
+        MetricType::Gauge,
+      ),
       process_started_at: Utc::now(),
     }
   }
@@ -270,6 +725,36 @@ impl Metrics {
     metrics.push(self.vatsim_data_request_count.render());
     metrics.push(self.vatsim_data_request_error_count.render());
     metrics.push(self.db_cleanup_time_sec.render());
+    metrics.push(query_evaluation_count().render());
+    metrics.push(query_field_usage().render());
+    metrics.push(query_failure_count().render());
+    metrics.push(trackfile_corruption_count().render());
+    metrics.push(self.fixed_data_stale.render());
+    metrics.push(self.vatsim_data_degraded.render());
+    metrics.push(self.vatsim_data_stale.render());
+    metrics.push(self.vatsim_connected_clients.render());
+    metrics.push(self.vatsim_unique_users.render());
+    metrics.push(self.airport_movements.render());
+    metrics.push(self.weather_provider_error_count.render());
+    metrics.push(self.weather_blacklist_size.render());
+    metrics.push(self.track_store_bytes.render());
+    metrics.push(self.track_store_files.render());
+    metrics.push(self.track_append_count.render());
+    metrics.push(self.track_appends_per_sec.render());
+    metrics.push(self.track_append_latency_sec.render());
+    metrics.push(self.track_write_amplification.render());
+    metrics.push(self.track_cleanup_count.render());
+    metrics.push(self.metric_label_sets_dropped.render());
+    metrics.push(unresolved_aircraft_count().render());
+    metrics.push(rpc_request_count().render());
+    metrics.push(rpc_error_count().render());
+    metrics.push(rpc_in_flight().render());
+    metrics.push(rpc_latency_sec().render());
+    metrics.push(streams_open().render());
+    metrics.push(stream_messages_sent().render());
+    metrics.push(stream_bytes_sent().render());
+    metrics.push(api_key_bytes_sent().render());
+    metrics.push(api_key_quota_rejected().render());
 
     let mut metric = Metric::new("uptime", "Process uptime in sec", MetricType::Counter);
     let sec = seconds_since(self.process_started_at).ceil() as u64;
@@ -301,6 +786,78 @@ impl From<Metrics> for camden::MetricSet {
       process_started_at: value.process_started_at.timestamp_millis() as u64,
       vatsim_data_request_count: Some(value.vatsim_data_request_count.into()),
       vatsim_data_request_error_count: Some(value.vatsim_data_request_error_count.into()),
+      query_evaluation_count: Some(query_evaluation_count().into()),
+      query_field_usage: Some(query_field_usage().into()),
+      query_failure_count: Some(query_failure_count().into()),
+      track_store_bytes: Some(value.track_store_bytes.into()),
+      track_store_files: Some(value.track_store_files.into()),
+      track_append_count: Some(value.track_append_count.into()),
+      track_appends_per_sec: Some(value.track_appends_per_sec.into()),
+      track_append_latency_sec: Some(value.track_append_latency_sec.into()),
+      track_write_amplification: Some(value.track_write_amplification.into()),
+      track_cleanup_count: Some(value.track_cleanup_count.into()),
+      metric_label_sets_dropped: Some(value.metric_label_sets_dropped.into()),
+      fixed_data_stale: Some(value.fixed_data_stale.into()),
+      vatsim_data_degraded: Some(value.vatsim_data_degraded.into()),
+      vatsim_data_stale: Some(value.vatsim_data_stale.into()),
+      vatsim_connected_clients: Some(value.vatsim_connected_clients.into()),
+      vatsim_unique_users: Some(value.vatsim_unique_users.into()),
+      airport_movements: Some(value.airport_movements.into()),
+      trackfile_corruption_count: Some(trackfile_corruption_count().into()),
+      weather_provider_error_count: Some(value.weather_provider_error_count.into()),
+      weather_blacklist_size: Some(value.weather_blacklist_size.into()),
+      unresolved_aircraft_count: Some(unresolved_aircraft_count().into()),
+      rpc_request_count: Some(rpc_request_count().into()),
+      rpc_error_count: Some(rpc_error_count().into()),
+      rpc_in_flight: Some(rpc_in_flight().into()),
+      rpc_latency_sec: Some(rpc_latency_sec().into()),
+      streams_open: Some(streams_open().into()),
+      stream_messages_sent: Some(stream_messages_sent().into()),
+      stream_bytes_sent: Some(stream_bytes_sent().into()),
+      api_key_bytes_sent: Some(api_key_bytes_sent().into()),
+      api_key_quota_rejected: Some(api_key_quota_rejected().into()),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_histogram_render() {
+    let mut metric = Histogram::new("latency_sec", "Latency", &[0.1, 1.0]);
+    metric.set(labels!("method" = "GetPilot"), &[2, 1, 1], 5.5);
+    assert_eq!(
+      metric.render(),
+      r#"# HELP latency_sec Latency
+# TYPE latency_sec histogram
+latency_sec_bucket{method="GetPilot",le="0.1"} 2
+latency_sec_bucket{method="GetPilot",le="1"} 3
+latency_sec_bucket{method="GetPilot",le="+Inf"} 4
+latency_sec_sum{method="GetPilot"} 5.5
+latency_sec_count{method="GetPilot"} 4
+"#
+    );
+
+    let metric: camden::Metric = metric.into();
+    assert_eq!(metric.int_values[r#"method="GetPilot",le="1""#], 3);
+    assert_eq!(metric.float_values[r#"method="GetPilot""#], 5.5);
+  }
+
+  #[test]
+  fn test_label_limiter() {
+    let mut limiter = LabelLimiter::new("country_code", 1);
+    limiter.add(labels!("country_code" = "GB", "type" = "pilot"), 10);
+    limiter.add(labels!("country_code" = "GB", "type" = "tower"), 1);
+    limiter.add(labels!("country_code" = "FR", "type" = "pilot"), 5);
+    limiter.add(labels!("country_code" = "DE", "type" = "pilot"), 3);
+    let mut metric = Metric::new("objects", "Objects", MetricType::Gauge);
+    assert_eq!(limiter.apply(&mut metric), 2);
+
+    let metric: camden::Metric = metric.into();
+    assert_eq!(metric.int_values.len(), 3);
+    assert_eq!(metric.int_values[r#"country_code="GB",type="pilot""#], 10);
+    assert_eq!(metric.int_values[r#"country_code="other",type="pilot""#], 8);
+  }
+}
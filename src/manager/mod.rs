@@ -1,62 +1,138 @@
+mod altitude;
+pub mod events;
+pub mod geofence;
+pub mod ingest;
 pub mod metrics;
+pub mod movements;
+mod offline;
 pub mod spatial;
 
 use self::{
-  metrics::Metrics,
-  spatial::{PointObject, RectObject},
+  altitude::{correct_altitude, local_qnh},
+  events::{
+    AirportDiff, CycleDiff, DiffHistory, EventBatch, FirDiff, HazardDiff, DIFF_HISTORY_SIZE,
+    EVENT_BUS_CAPACITY,
+  },
+  geofence::{GeofenceEvents, GeofenceRegistry, Shape, GEOFENCE_BUS_CAPACITY},
+  ingest::{build_pilot_index, build_protos, IndexOptions, PilotProtos, PilotSnapshot},
+  metrics::{LabelLimiter, Metrics},
+  movements::{locate_threshold, MovementCount, Movements},
+  offline::OfflinePilots,
+  spatial::{fir_polygons, locate_fir, PointObject, PolygonObject, RectObject},
 };
 
 use crate::{
-  config::Config,
+  atis::datis::fetch_datis,
+  config::{Config, ConfigError, ConfigSource},
   fixed::{
-    data::FixedData,
+    data::{CodePreference, FixedData, FixedDataCounts},
+    navaids::Navaid,
     parser::load_fixed,
-    types::{Airport, FIR},
+    types::{Airport, GeonamesCountry, FIR},
   },
   labels,
+  lee::parser::expression::Expression,
   moving::{
     controller::{Controller, Facility},
-    load_vatsim_data,
     pilot::Pilot,
+    route::{expand_route, Waypoint},
+    source::{build_source, fetch_all, DataSource},
+    transceiver::{fetch_transceivers, tuned_to, Transceiver, Transceivers},
+  },
+  service::camden,
+  session::{Session, SessionStore},
+  track::{archive::Archive, stats::TrackStats, trackpoint::TrackPoint, Store, TrackBatch},
+  types::{Point, Rect},
+  util::{backoff_delay, http_client, seconds_since, Counter},
+  weather::{
+    sigmet::{fetch_hazards, Hazard},
+    winds::{WindVector, WindsAloft},
+    BlacklistEntry, WeatherChange, WeatherManager,
   },
-  track::{trackpoint::TrackPoint, Store},
-  types::Rect,
-  util::{seconds_since, Counter},
-  weather::WeatherManager,
 };
 
-use chrono::{Duration, Utc};
-use log::{debug, error, info};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
 use rstar::RTree;
 use std::{
   collections::{HashMap, HashSet},
   sync::Arc,
+  time::Instant,
+};
+use tokio::{
+  sync::{broadcast, mpsc, watch, Mutex, RwLock},
+  task::block_in_place,
+  time::sleep,
 };
-use tokio::{sync::RwLock, time::sleep};
+use tracing::{info_span, Instrument};
 
 const CLEANUP_EVERY_X_ITER: u8 = 5;
+const FIXED_DATA_ATTEMPTS: u32 = 6;
+// ingestion cycles the track writer may fall behind by
+const TRACK_QUEUE_SIZE: usize = 16;
+
+// work for the track writer task
+#[derive(Debug)]
+enum TrackJob {
+  Write(TrackBatch),
+  // tracks of the pilots gone offline, to be uploaded to the archive
+  Archive(Vec<Arc<Pilot>>),
+}
 
 #[derive(Debug)]
 pub struct Manager {
-  cfg: Config,
+  // swapped as a whole on reload, loops pick the new values up on their
+  // next iteration
+  cfg: ArcSwap<Config>,
+  config_source: ConfigSource,
+  http: reqwest::Client,
   fixed: RwLock<FixedData>,
 
-  pilots: RwLock<HashMap<String, Pilot>>,
-  pilots2d: RwLock<RTree<PointObject>>,
-  pilots_po: RwLock<HashMap<String, PointObject>>,
+  // read on every stream tick, swapped as a whole once per ingestion
+  // cycle so readers neither wait for nor block the ingestion
+  pilots: ArcSwap<PilotSnapshot>,
+  controllers: RwLock<HashMap<String, Controller>>,
+  controllers2d: RwLock<RTree<RectObject>>,
+  controllers_by_facility: RwLock<HashMap<Facility, HashSet<String>>>,
+  transceivers: RwLock<Transceivers>,
 
   airports2d: RwLock<RTree<PointObject>>,
   firs2d: RwLock<RTree<RectObject>>,
+  fir_polygons: RwLock<RTree<PolygonObject>>,
   tracks: RwLock<Store>,
+  track_queue: mpsc::Sender<TrackJob>,
+  track_rx: Mutex<mpsc::Receiver<TrackJob>>,
+  archive: Option<Arc<Archive>>,
+  sessions: RwLock<SessionStore>,
+  wx: Arc<WeatherManager>,
+  hazards: RwLock<HashMap<String, Hazard>>,
+  hazards2d: RwLock<RTree<RectObject>>,
+  winds: WindsAloft,
+  events: broadcast::Sender<EventBatch>,
+  history: RwLock<DiffHistory>,
+  degraded: watch::Sender<bool>,
+  data_stale: watch::Sender<bool>,
+  last_fresh_at: RwLock<DateTime<Utc>>,
+  movements: RwLock<Movements>,
+  geofences: RwLock<GeofenceRegistry>,
+  offline: RwLock<OfflinePilots>,
+  geofence_events: broadcast::Sender<GeofenceEvents>,
 
   metrics: RwLock<Metrics>,
 }
 
 impl Manager {
-  pub async fn new(cfg: Config) -> Self {
+  pub async fn new(cfg: Config, config_source: ConfigSource) -> Self {
     info!("setting vatsim data manager up");
 
-    let tracks = Store::new(&cfg.track.folder);
+    let tracks = Store::new(
+      &cfg.track.folder,
+      cfg.track.open_files,
+      cfg.track.open_files_idle,
+      cfg.track.durability,
+    );
 
     info!("cleaning up tracks");
     let t = Utc::now();
@@ -68,43 +144,195 @@ impl Manager {
       info!("boot-time track store cleanup took {process_time}s");
     }
 
+    let sessions = SessionStore::new(&cfg.sessions.folder, cfg.sessions.retention);
+    let res = sessions.cleanup();
+    if let Err(err) = res {
+      error!("error cleaning up sessions: {}", err);
+    }
+
+    let http = http_client(&cfg.http).expect("invalid http client configuration");
+    let (track_queue, track_rx) = mpsc::channel(TRACK_QUEUE_SIZE);
+    let archive = cfg
+      .track
+      .archive
+      .clone()
+      .map(|cfg| Arc::new(Archive::new(cfg, http.clone())));
+    let (events, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+    let (degraded, _) = watch::channel(false);
+    let (data_stale, _) = watch::channel(false);
+    let (geofence_events, _) = broadcast::channel(GEOFENCE_BUS_CAPACITY);
+    let winds = WindsAloft::new(cfg.winds.clone(), http.clone());
+    let wx = Arc::new(WeatherManager::new(
+      cfg.weather.clone(),
+      &cfg.cache.weather,
+      http.clone(),
+    ));
+    let offline = OfflinePilots::new(
+      cfg.offline_cache.capacity,
+      Duration::from_std(cfg.offline_cache.retention).unwrap_or(Duration::minutes(10)),
+    );
+
     Self {
-      cfg,
+      cfg: ArcSwap::from_pointee(cfg),
+      config_source,
+      http,
       fixed: RwLock::new(FixedData::empty()),
-      pilots: RwLock::new(HashMap::new()),
-      pilots2d: RwLock::new(RTree::new()),
-      pilots_po: RwLock::new(HashMap::new()),
+      pilots: ArcSwap::from_pointee(PilotSnapshot::default()),
+      controllers: RwLock::new(HashMap::new()),
+      controllers2d: RwLock::new(RTree::new()),
+      controllers_by_facility: RwLock::new(HashMap::new()),
+      transceivers: RwLock::new(HashMap::new()),
       airports2d: RwLock::new(RTree::new()),
       firs2d: RwLock::new(RTree::new()),
+      fir_polygons: RwLock::new(RTree::new()),
       tracks: RwLock::new(tracks),
+      track_queue,
+      track_rx: Mutex::new(track_rx),
+      archive,
+      sessions: RwLock::new(sessions),
+      wx,
+      hazards: RwLock::new(HashMap::new()),
+      hazards2d: RwLock::new(RTree::new()),
+      winds,
+      events,
+      history: RwLock::new(DiffHistory::new(DIFF_HISTORY_SIZE)),
+      degraded,
+      data_stale,
+      last_fresh_at: RwLock::new(Utc::now()),
+      movements: RwLock::new(Movements::new()),
+      geofences: RwLock::new(GeofenceRegistry::new()),
+      offline: RwLock::new(offline),
+      geofence_events,
       metrics: RwLock::new(Metrics::new()),
     }
   }
 
-  pub fn config(&self) -> &Config {
-    &self.cfg
+  pub fn config(&self) -> Arc<Config> {
+    self.cfg.load_full()
+  }
+
+  pub fn config_path(&self) -> &str {
+    &self.config_source.path
+  }
+
+  // Rereads the config file and applies it unless settings which need a
+  // restart have changed, in which case the current config is kept
+  pub async fn reload_config(&self) -> Result<(), ConfigError> {
+    let cfg = self.config_source.load()?;
+    cfg.validate()?;
+    let blockers = self.config().reload_blockers(&cfg);
+    if !blockers.is_empty() {
+      return Err(ConfigError::NotReloadable(blockers));
+    }
+
+    log::set_max_level(cfg.log.level);
+    self.wx.reload(cfg.weather.clone());
+    self
+      .sessions
+      .write()
+      .await
+      .set_retention(cfg.sessions.retention);
+    let retention =
+      Duration::from_std(cfg.offline_cache.retention).unwrap_or(Duration::minutes(10));
+    self.offline.write().await.set_retention(retention);
+    self.cfg.store(Arc::new(cfg));
+    info!("config reloaded from {}", self.config_source.path);
+    Ok(())
+  }
+
+  pub fn subscribe_events(&self) -> broadcast::Receiver<EventBatch> {
+    self.events.subscribe()
+  }
+
+  pub fn watch_degraded(&self) -> watch::Receiver<bool> {
+    self.degraded.subscribe()
+  }
+
+  pub fn is_degraded(&self) -> bool {
+    *self.degraded.borrow()
+  }
+
+  pub fn watch_data_stale(&self) -> watch::Receiver<bool> {
+    self.data_stale.subscribe()
+  }
+
+  pub async fn vatsim_data_timestamp(&self) -> i64 {
+    self.metrics.read().await.vatsim_data_timestamp
+  }
+
+  pub async fn last_fresh_data_at(&self) -> DateTime<Utc> {
+    *self.last_fresh_at.read().await
+  }
+
+  // hourly arrivals and departures observed at the airport, oldest first
+  pub async fn get_airport_movements(&self, icao: &str) -> Vec<(DateTime<Utc>, MovementCount)> {
+    self.movements.read().await.airport_movements(icao)
   }
 
   pub async fn render_metrics(&self) -> String {
     self.metrics.read().await.render()
   }
 
-  pub async fn get_all_pilots(&self) -> Vec<Pilot> {
-    let pilots_idx = self.pilots.read().await;
-    pilots_idx.values().cloned().collect()
+  // the current pilots along with their conversions for the clients
+  pub fn pilot_protos(&self) -> PilotProtos {
+    self.pilots.load_full().into()
+  }
+
+  pub async fn get_all_pilots(&self) -> Vec<Arc<Pilot>> {
+    self.pilots.load().pilots.values().cloned().collect()
+  }
+
+  pub async fn get_all_controllers(&self) -> Vec<Controller> {
+    let controllers = self.controllers.read().await;
+    controllers.values().cloned().collect()
+  }
+
+  pub async fn get_controller_by_callsign(&self, callsign: &str) -> Option<Controller> {
+    self.controllers.read().await.get(callsign).cloned()
+  }
+
+  // controllers located within the rect, radar ones are located by
+  // their FIR boundaries, the rest by their airport position
+  pub async fn get_controllers(&self, rect: &Rect) -> Vec<Controller> {
+    let controllers2d = self.controllers2d.read().await;
+    let controllers = self.controllers.read().await;
+    let mut found = HashMap::new();
+
+    for env in rect.envelopes() {
+      for ro in controllers2d.locate_in_envelope_intersecting(&env) {
+        if let Some(ctrl) = controllers.get(&ro.id) {
+          found.insert(ro.id.clone(), ctrl.clone());
+        }
+      }
+    }
+    found.into_values().collect()
+  }
+
+  pub async fn get_controllers_by_facility(&self, facility: &Facility) -> Vec<Controller> {
+    let index = self.controllers_by_facility.read().await;
+    let controllers = self.controllers.read().await;
+    index
+      .get(facility)
+      .map(|callsigns| {
+        callsigns
+          .iter()
+          .filter_map(|cs| controllers.get(cs).cloned())
+          .collect()
+      })
+      .unwrap_or_default()
   }
 
-  pub async fn get_all_airports(&self, show_uncontrolled_wx: bool) -> Vec<Airport> {
+  pub async fn get_all_airports(&self, show_uncontrolled_wx: bool) -> Vec<Arc<Airport>> {
     let fixed = self.fixed.read().await;
     fixed
       .airports()
       .iter()
-      .filter(|arpt| !arpt.controllers.is_empty() || (show_uncontrolled_wx && arpt.wx.is_some()))
+      .filter(|arpt| arpt.is_staffed() || (show_uncontrolled_wx && arpt.wx.is_some()))
       .cloned()
       .collect()
   }
 
-  pub async fn get_all_firs(&self) -> Vec<FIR> {
+  pub async fn get_all_firs(&self) -> Vec<Arc<FIR>> {
     let fixed = self.fixed.read().await;
     fixed
       .firs()
@@ -114,14 +342,14 @@ impl Manager {
       .collect()
   }
 
-  pub async fn get_pilots(&self, rect: &Rect, subscribed_ids: &HashSet<String>) -> Vec<Pilot> {
-    let pilots2d = self.pilots2d.read().await;
-    let pilots_idx = self.pilots.read().await;
+  pub async fn get_pilots(&self, rect: &Rect, subscribed_ids: &HashSet<String>) -> Vec<Arc<Pilot>> {
+    let snapshot = self.pilots.load();
+    let pilots_idx = &snapshot.pilots;
     let mut pilots = vec![];
     let mut subs = subscribed_ids.clone();
 
     for env in rect.envelopes() {
-      for po in pilots2d.locate_in_envelope(&env) {
+      for po in snapshot.pilots2d.locate_in_envelope(&env) {
         let pilot = pilots_idx.get(&po.id);
         if let Some(pilot) = pilot {
           subs.remove(&pilot.callsign);
@@ -140,7 +368,7 @@ impl Manager {
     pilots
   }
 
-  pub async fn get_airports(&self, rect: &Rect, show_uncontrolled_wx: bool) -> Vec<Airport> {
+  pub async fn get_airports(&self, rect: &Rect, show_uncontrolled_wx: bool) -> Vec<Arc<Airport>> {
     let airports2d = self.airports2d.read().await;
     let fixed = self.fixed.read().await;
     let mut airports = vec![];
@@ -149,7 +377,7 @@ impl Manager {
       for po in airports2d.locate_in_envelope(&env) {
         let airport = fixed.find_airport_compound(&po.id);
         if let Some(airport) = airport {
-          if !airport.controllers.is_empty() || (show_uncontrolled_wx && airport.wx.is_some()) {
+          if airport.is_staffed() || (show_uncontrolled_wx && airport.wx.is_some()) {
             airports.push(airport)
           }
         }
@@ -158,7 +386,7 @@ impl Manager {
     airports
   }
 
-  pub async fn get_firs(&self, rect: &Rect) -> Vec<FIR> {
+  pub async fn get_firs(&self, rect: &Rect) -> Vec<Arc<FIR>> {
     let firs2d = self.firs2d.read().await;
     let fixed = self.fixed.read().await;
     let mut firs = HashMap::new();
@@ -174,55 +402,888 @@ impl Manager {
     firs.into_values().collect()
   }
 
-  pub async fn find_airport(&self, code: &str) -> Option<Airport> {
+  pub async fn get_pilot_countries(&self, pilots: &[Arc<Pilot>]) -> Vec<Option<GeonamesCountry>> {
+    let fixed = self.fixed.read().await;
+    pilots
+      .iter()
+      .map(|pilot| fixed.get_geonames_country_by_position(pilot.position))
+      .collect()
+  }
+
+  pub async fn find_airport(&self, code: &str) -> Option<Arc<Airport>> {
     self.fixed.read().await.find_airport(code)
   }
 
-  async fn setup_fixed_data(&self) -> Result<(), Box<dyn std::error::Error>> {
-    info!("loading fixed data");
-    let fixed = load_fixed(&self.cfg).await?; // TODO retries
-    for arpt in fixed.airports() {
-      self.airports2d.write().await.insert(arpt.into());
+  pub async fn find_airport_by(&self, code: &str, pref: CodePreference) -> Option<Arc<Airport>> {
+    self.fixed.read().await.find_airport_by(code, pref)
+  }
+
+  pub async fn find_airports(&self, code: &str, pref: CodePreference) -> Vec<Arc<Airport>> {
+    self.fixed.read().await.find_airports(code, pref)
+  }
+
+  pub async fn find_navaids(&self, ident: &str) -> Vec<Navaid> {
+    self.fixed.read().await.find_navaids(ident)
+  }
+
+  pub async fn get_navaids(&self, rect: &Rect) -> Vec<Navaid> {
+    self.fixed.read().await.get_navaids(rect)
+  }
+
+  pub async fn expand_route(&self, route: &str, from: Point, to: Point) -> Vec<Waypoint> {
+    let fixed = self.fixed.read().await;
+    expand_route(route, from, to, |ident, near| {
+      fixed.closest_navaid(ident, near).map(|n| n.position)
+    })
+  }
+
+  async fn load_fixed_with_retries(&self) -> Result<FixedData, Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    loop {
+      // the last attempt is allowed to fall back to the stale cached files
+      let allow_stale = attempt + 1 >= FIXED_DATA_ATTEMPTS;
+      // errors aren't Send, so make sure none is held across the awaits below
+      let res = {
+        let res = load_fixed(&self.config(), &self.http, allow_stale).await;
+        match res {
+          Ok(loaded) => Ok(loaded),
+          Err(err) if allow_stale => return Err(err),
+          Err(err) => Err(err.to_string()),
+        }
+      };
+
+      match res {
+        Ok((fixed, stale)) => {
+          if stale {
+            error!("running on stale fixed data");
+          }
+          self
+            .metrics
+            .write()
+            .await
+            .fixed_data_stale
+            .set_single(stale as u64);
+          return Ok(fixed);
+        }
+        Err(err) => {
+          let delay = backoff_delay(
+            attempt,
+            std::time::Duration::from_secs(1),
+            std::time::Duration::from_secs(60),
+          );
+          error!(
+            "error loading fixed data: {err}, retrying in {}s",
+            delay.as_secs_f32()
+          );
+          sleep(delay).await;
+          attempt += 1;
+        }
+      }
+    }
+  }
+
+  // Builds the spatial indexes for the new fixed data and swaps them all in
+  // at once, keeping the weather and controllers assigned to the current data
+  async fn install_fixed_data(&self, mut fixed: FixedData) {
+    let airports: Vec<PointObject> = fixed
+      .airports()
+      .iter()
+      .map(|arpt| arpt.as_ref().into())
+      .collect();
+    let firs: Vec<RectObject> = fixed.firs().iter().map(|fir| fir.as_ref().into()).collect();
+    let airports = RTree::bulk_load(airports);
+    let firs = RTree::bulk_load(firs);
+    let polygons = RTree::bulk_load(fir_polygons(fixed.firs()));
+
+    // lock order matches the readers: rtrees first, then fixed data
+    let mut airports2d = self.airports2d.write().await;
+    let mut firs2d = self.firs2d.write().await;
+    let mut polygons2d = self.fir_polygons.write().await;
+    let mut current = self.fixed.write().await;
+    {
+      let controllers = self.controllers.read().await;
+      fixed.inherit_state(&current, &controllers);
     }
-    for fir in fixed.firs() {
-      self.firs2d.write().await.insert(fir.into())
+    // the weather failed to load for the old codes says nothing about the
+    // new ones
+    let remapped = fixed.remapped_icao(&current);
+    if !remapped.is_empty() {
+      let count = self
+        .wx
+        .unblacklist(remapped.iter().map(|s| s.as_str()))
+        .await;
+      debug!("{count} remapped locations removed from the weather blacklist");
     }
-    self.fixed.write().await.fill(fixed);
+    *airports2d = airports;
+    *firs2d = firs;
+    *polygons2d = polygons;
+    current.fill(fixed);
+  }
+
+  async fn setup_fixed_data(&self) -> Result<(), Box<dyn std::error::Error>> {
+    info!("loading fixed data");
+    let fixed = self.load_fixed_with_retries().await?;
+    self.install_fixed_data(fixed).await;
     info!("fixed data configured");
     Ok(())
   }
 
-  async fn remove_pilot(&self, callsign: &str) -> bool {
-    let po = { self.pilots_po.write().await.remove(callsign) };
-    if let Some(po) = po {
-      self.pilots2d.write().await.remove(&po);
-      self.pilots.write().await.remove(callsign);
-      true
-    } else {
-      false
+  pub async fn refresh_fixed_data(&self) -> Result<FixedDataCounts, Box<dyn std::error::Error>> {
+    info!("refreshing fixed data");
+    let t = Utc::now();
+    let (fixed, stale) = load_fixed(&self.config(), &self.http, false).await?;
+    let counts = fixed.counts();
+    self.install_fixed_data(fixed).await;
+    self
+      .metrics
+      .write()
+      .await
+      .fixed_data_stale
+      .set_single(stale as u64);
+    info!("fixed data refreshed in {}s", seconds_since(t));
+    Ok(counts)
+  }
+
+  pub async fn flush_weather_cache(&self) -> usize {
+    self.wx.flush().await
+  }
+
+  pub async fn weather_blacklist(&self) -> Vec<BlacklistEntry> {
+    self.wx.blacklist().await
+  }
+
+  pub async fn unblacklist_weather(&self, icao: &str) -> bool {
+    self.wx.unblacklist([icao]).await > 0
+  }
+
+  pub async fn get_winds_aloft(
+    &self,
+    rect: &Rect,
+    fl: u32,
+  ) -> Result<(u32, Vec<WindVector>), Box<dyn std::error::Error + Send + Sync>> {
+    self.winds.get(rect, fl).await
+  }
+
+  pub async fn get_all_hazards(&self) -> Vec<Hazard> {
+    self.hazards.read().await.values().cloned().collect()
+  }
+
+  pub async fn get_hazards(&self, rect: &Rect) -> Vec<Hazard> {
+    let hazards2d = self.hazards2d.read().await;
+    let hazards = self.hazards.read().await;
+    let mut found = HashMap::new();
+    for env in rect.envelopes() {
+      for ro in hazards2d.locate_in_envelope_intersecting(&env) {
+        if let Some(hazard) = hazards.get(&ro.id) {
+          found.insert(ro.id.clone(), hazard.clone());
+        }
+      }
+    }
+    found.into_values().collect()
+  }
+
+  // Reloads the SIGMETs periodically, publishing the hazards appeared,
+  // changed and gone, expired ones included
+  pub async fn run_hazards(&self) {
+    loop {
+      let res = fetch_hazards(&self.http, &self.config().weather.sigmet_url).await;
+      match res {
+        Ok(fresh) => {
+          let now = Utc::now();
+          let fresh: HashMap<String, Hazard> = fresh
+            .into_iter()
+            .filter(|h| !h.expired(now))
+            .map(|h| (h.id.clone(), h))
+            .collect();
+          debug!("{} hazards loaded", fresh.len());
+          self.install_hazards(fresh).await;
+        }
+        Err(err) => {
+          error!("error loading sigmets: {err}");
+          // keep the current ones until they expire
+          let now = Utc::now();
+          let current = self.hazards.read().await.clone();
+          let valid = current
+            .into_iter()
+            .filter(|(_, h)| !h.expired(now))
+            .collect();
+          self.install_hazards(valid).await;
+        }
+      }
+      sleep(self.config().weather.sigmet_refresh_interval).await;
+    }
+  }
+
+  // Polls the digital ATIS feed, the airports visibility and active runways
+  // depend on it so the changes are published as airport updates
+  pub async fn run_datis(&self) {
+    if !self.config().datis.enabled {
+      return;
+    }
+    loop {
+      let res = fetch_datis(&self.http, &self.config().datis.url).await;
+      match res {
+        Ok(datis) => {
+          debug!("{} digital atis loaded", datis.len());
+          let (prev_airports, prev_firs) = self.visible_fixed_state().await;
+          self.fixed.write().await.set_datis(datis);
+          let (airports, firs, weather) = self.fixed_state_diff(prev_airports, prev_firs).await;
+          let diff = CycleDiff {
+            airports,
+            firs,
+            weather,
+            ..Default::default()
+          };
+          if !diff.is_empty() {
+            self.publish_diff(diff).await;
+          }
+        }
+        // keep the current ones until the next successful refresh
+        Err(err) => error!("error loading digital atis: {err}"),
+      }
+      sleep(self.config().datis.refresh_interval).await;
+    }
+  }
+
+  async fn install_hazards(&self, fresh: HashMap<String, Hazard>) {
+    let mut diff = HazardDiff::default();
+    {
+      let current = self.hazards.read().await;
+      for (id, hazard) in fresh.iter() {
+        if current.get(id) != Some(hazard) {
+          diff.set.push(hazard.clone());
+        }
+      }
+      for (id, hazard) in current.iter() {
+        if !fresh.contains_key(id) {
+          diff.delete.push(hazard.clone());
+        }
+      }
+    }
+    if diff.is_empty() {
+      return;
+    }
+
+    let tree = RTree::bulk_load(fresh.values().map(RectObject::from).collect());
+    {
+      let mut hazards2d = self.hazards2d.write().await;
+      let mut hazards = self.hazards.write().await;
+      *hazards2d = tree;
+      *hazards = fresh;
     }
+    self
+      .publish_diff(CycleDiff {
+        hazards: diff,
+        ..Default::default()
+      })
+      .await;
+  }
+
+  // Stores the state worth keeping across restarts
+  pub async fn shutdown(&self) {
+    match self.wx.save_cache().await {
+      Ok(count) => info!("{count} weather entries stored"),
+      Err(err) => error!("error storing weather cache: {err}"),
+    }
+  }
+
+  // Requests weather for the uncontrolled airports within the rect that
+  // have none or an outdated one, loaded by run_viewport_weather
+  pub async fn request_viewport_weather(&self, rect: &Rect) {
+    let now = Utc::now();
+    let ttl = self.wx.ttl();
+    let mut locations = vec![];
+    {
+      let airports2d = self.airports2d.read().await;
+      let fixed = self.fixed.read().await;
+      'outer: for env in rect.envelopes() {
+        for po in airports2d.locate_in_envelope(&env) {
+          if locations.len() >= self.config().weather.viewport_max_airports {
+            break 'outer;
+          }
+          let airport = fixed.find_airport_compound(&po.id);
+          if let Some(airport) = airport {
+            let outdated = airport.wx.as_ref().is_none_or(|wx| now - wx.ts >= ttl);
+            if !airport.is_pseudo && airport.controllers.is_empty() && outdated {
+              locations.push(airport.icao.clone());
+            }
+          }
+        }
+      }
+    }
+    if !locations.is_empty() {
+      debug!("{} airports requested for weather", locations.len());
+      self.wx.request(locations).await;
+    }
+  }
+
+  // Loads the weather requested by map_updates clients in batches, the
+  // airports updated are published like any other change
+  pub async fn run_viewport_weather(&self) {
+    loop {
+      sleep(self.config().weather.viewport_interval).await;
+      let locations = self.wx.take_requested().await;
+      if locations.is_empty() {
+        continue;
+      }
+
+      let (prev_airports, prev_firs) = self.visible_fixed_state().await;
+      self
+        .wx
+        .preload(locations.iter().map(|s| s.as_str()).collect())
+        .await;
+      for icao in locations.iter() {
+        let wx = self.wx.get(icao).await;
+        if let Some(wx) = wx {
+          self.fixed.write().await.set_airport_weather(icao, wx);
+        }
+      }
+
+      let (airports, firs, weather) = self.fixed_state_diff(prev_airports, prev_firs).await;
+      let diff = CycleDiff {
+        airports,
+        firs,
+        weather,
+        ..Default::default()
+      };
+      if !diff.is_empty() {
+        self.publish_diff(diff).await;
+      }
+    }
+  }
+
+  pub async fn run_fixed_refresh(&self) {
+    loop {
+      sleep(self.config().fixed.refresh_period).await;
+      let res = self.refresh_fixed_data().await;
+      if let Err(err) = res {
+        error!("error refreshing fixed data, keeping the current one: {err}");
+      }
+    }
+  }
+
+  // Hands the track points over to the writer task, the ingestion loop
+  // never waits for the disk
+  fn queue_track_job(&self, job: TrackJob) {
+    if let Err(err) = self.track_queue.try_send(job) {
+      let job = match err {
+        mpsc::error::TrySendError::Full(job) => job,
+        mpsc::error::TrySendError::Closed(job) => job,
+      };
+      match job {
+        TrackJob::Write(batch) => error!(
+          "track writer is lagging behind, {} track points dropped",
+          batch.len()
+        ),
+        TrackJob::Archive(pilots) => error!(
+          "track writer is lagging behind, {} tracks not archived",
+          pilots.len()
+        ),
+      }
+    }
+  }
+
+  // Writes the pilot tracks queued by the ingestion loop. Batches queued up
+  // while the previous one was being written are merged so that every file
+  // is appended once. Tracks are archived after their last points are written
+  pub async fn run_track_writer(&self) {
+    let mut rx = self.track_rx.lock().await;
+    let mut appended: u64 = 0;
+    let mut last_batch_at: Option<Instant> = None;
+    while let Some(job) = rx.recv().await {
+      let mut batch = TrackBatch::new();
+      let mut archived = vec![];
+      let mut jobs = vec![job];
+      while let Ok(job) = rx.try_recv() {
+        jobs.push(job);
+      }
+      for job in jobs {
+        match job {
+          TrackJob::Write(more) => {
+            for (filename, points) in more {
+              batch.entry(filename).or_default().extend(points);
+            }
+          }
+          TrackJob::Archive(pilots) => archived.extend(pilots),
+        }
+      }
+
+      let t = Utc::now();
+      let tracks = self.tracks.read().await;
+      let stats = block_in_place(|| tracks.write_batch(batch));
+      debug!(
+        "{} track files written in {}s, {} failed",
+        stats.files - stats.failed,
+        seconds_since(t),
+        stats.failed
+      );
+
+      appended += stats.points as u64;
+      let now = Instant::now();
+      {
+        let mut metrics = self.metrics.write().await;
+        metrics.track_append_count.set_single(appended);
+        if let Some(at) = last_batch_at {
+          let rate = stats.points as f64 / (now - at).as_secs_f64();
+          metrics.track_appends_per_sec.set_single(rate);
+        }
+        metrics
+          .track_append_latency_sec
+          .set_single(stats.append_latency.as_secs_f64());
+        metrics
+          .track_write_amplification
+          .set_single(stats.write_amplification());
+      }
+      last_batch_at = Some(now);
+
+      if let Some(archive) = &self.archive {
+        for pilot in archived {
+          let data = match block_in_place(|| tracks.read_track_file(&pilot, archive.compress())) {
+            Ok(data) => data,
+            Err(err) => {
+              error!(
+                "error reading track of {} to archive: {err}",
+                pilot.callsign
+              );
+              continue;
+            }
+          };
+          let archive = archive.clone();
+          tokio::spawn(async move {
+            let key = archive.key(&pilot);
+            match archive.upload(&key, data).await {
+              Ok(_) => debug!("track of {} archived as {key}", pilot.callsign),
+              Err(err) => error!("error archiving track of {}: {err}", pilot.callsign),
+            }
+          });
+        }
+      }
+    }
+  }
+
+  // Fetches the pilot's track back from the archive if the local file
+  // is gone. An online pilot's file only lacks points until the first
+  // batch is written, so the archive isn't asked for it then
+  async fn restore_track(&self, pilot: &Pilot) {
+    let Some(archive) = &self.archive else {
+      return;
+    };
+    let online = self
+      .pilots
+      .load()
+      .pilots
+      .get(&pilot.callsign)
+      .is_some_and(|p| p.cid == pilot.cid && p.logon_time == pilot.logon_time);
+    {
+      let tracks = self.tracks.read().await;
+      let missing = if online {
+        !tracks.track_file_exists(pilot)
+      } else {
+        tracks.track_missing(pilot)
+      };
+      if !missing {
+        return;
+      }
+    }
+    let key = archive.key(pilot);
+    match archive.download(&key).await {
+      Ok(Some(data)) => {
+        let res = self.tracks.read().await.restore_track_file(pilot, &data);
+        match res {
+          Ok(_) => debug!("track of {} restored from {key}", pilot.callsign),
+          Err(err) => error!("error restoring track of {}: {err}", pilot.callsign),
+        }
+      }
+      Ok(None) => {}
+      Err(err) => error!("error fetching archived track {key}: {err}"),
+    }
+  }
+
+  pub async fn run_transceivers(&self) {
+    loop {
+      let res = fetch_transceivers(&self.http, &self.config().transceivers.url).await;
+      if let Some(transceivers) = res {
+        debug!("{} stations with transceivers loaded", transceivers.len());
+        *self.transceivers.write().await = transceivers;
+      }
+      sleep(self.config().transceivers.poll_period).await;
+    }
+  }
+
+  pub async fn get_transceivers(&self, callsign: &str) -> Vec<Transceiver> {
+    let transceivers = self.transceivers.read().await;
+    transceivers.get(callsign).cloned().unwrap_or_default()
+  }
+
+  // pilots and controllers tuned to the frequency, in kHz. Controllers
+  // without transceivers data yet are matched by their primary frequency
+  pub async fn get_on_frequency(&self, freq: u32) -> (Vec<Arc<Pilot>>, Vec<Controller>) {
+    let transceivers = self.transceivers.read().await;
+    let tuned = |callsign: &str| {
+      transceivers
+        .get(callsign)
+        .map(|trs| tuned_to(trs, freq))
+        .unwrap_or(false)
+    };
+
+    let pilots = self
+      .pilots
+      .load()
+      .pilots
+      .values()
+      .filter(|pilot| tuned(&pilot.callsign))
+      .cloned()
+      .collect();
+
+    let controllers = self
+      .controllers
+      .read()
+      .await
+      .values()
+      .filter(|ctrl| ctrl.freq == freq || tuned(&ctrl.callsign))
+      .cloned()
+      .collect();
+
+    (pilots, controllers)
+  }
+
+  fn install_pilots(&self, snapshot: PilotSnapshot) {
+    self.pilots.store(Arc::new(snapshot));
+  }
+
+  // Flips the degraded flag depending on how long ago fresh data has been
+  // loaded, dropping the pilots too old to be trusted while degraded
+  async fn check_watchdog(&self) {
+    let last_fresh_at = self.last_fresh_data_at().await;
+    let degraded_after =
+      Duration::from_std(self.config().api.degraded_after).unwrap_or(Duration::minutes(5));
+    let degraded = Utc::now() - last_fresh_at > degraded_after;
+
+    let changed = self.degraded.send_if_modified(|current| {
+      let changed = *current != degraded;
+      *current = degraded;
+      changed
+    });
+    if changed {
+      if degraded {
+        warn!("no fresh vatsim data since {last_fresh_at}, switching to degraded mode");
+      } else {
+        info!("fresh vatsim data loaded, leaving degraded mode");
+      }
+      self
+        .metrics
+        .write()
+        .await
+        .vatsim_data_degraded
+        .set_single(degraded as u64);
+    }
+
+    if degraded {
+      if let Some(max_age) = self.config().api.max_pilot_age {
+        self.drop_old_pilots(max_age).await;
+      }
+    }
+
+    self.check_data_age().await;
+  }
+
+  // Flips the data stale flag depending on the age of the feed's own
+  // timestamp. Nothing is stale until the first data has been loaded
+  async fn check_data_age(&self) {
+    let ts = self.vatsim_data_timestamp().await;
+    let age = Utc::now().timestamp() - ts;
+    let stale = ts > 0 && age > self.config().api.data_stale_after.as_secs() as i64;
+
+    let changed = self.data_stale.send_if_modified(|current| {
+      let changed = *current != stale;
+      *current = stale;
+      changed
+    });
+    if changed {
+      if stale {
+        warn!("vatsim data is {age}s old");
+      } else {
+        info!("vatsim data is up to date again");
+      }
+      self
+        .metrics
+        .write()
+        .await
+        .vatsim_data_stale
+        .set_single(stale as u64);
+    }
+  }
+
+  async fn drop_old_pilots(&self, max_age: std::time::Duration) {
+    let oldest = Utc::now() - Duration::from_std(max_age).unwrap_or(Duration::minutes(15));
+    let (index, protos) = {
+      let prev = self.pilots.load_full();
+      let fresh: Vec<Pilot> = prev
+        .pilots
+        .values()
+        .filter(|pilot| pilot.last_updated >= oldest)
+        .map(|pilot| Pilot::clone(pilot))
+        .collect();
+      if fresh.len() == prev.pilots.len() {
+        return;
+      }
+      let opts = IndexOptions {
+        keep_since: None,
+        reconnect_grace: Duration::zero(),
+      };
+      block_in_place(|| {
+        let index = build_pilot_index(fresh, &prev.pilots, opts);
+        let protos = build_protos(&index.pilots, &prev);
+        (index, protos)
+      })
+    };
+
+    info!("dropping {} outdated pilots", index.diff.delete.len());
+    self.install_pilots(PilotSnapshot {
+      pilots: index.pilots,
+      pilots2d: index.pilots2d,
+      protos,
+    });
+    for pilot in index.finished.iter() {
+      self.store_session(pilot).await;
+    }
+    self
+      .publish_diff(CycleDiff {
+        pilots: index.diff,
+        ..Default::default()
+      })
+      .await;
+  }
+
+  async fn install_controllers(
+    &self,
+    controllers: HashMap<String, Controller>,
+    objects: Vec<RectObject>,
+  ) {
+    let mut by_facility: HashMap<Facility, HashSet<String>> = HashMap::new();
+    for ctrl in controllers.values() {
+      by_facility
+        .entry(ctrl.facility.clone())
+        .or_default()
+        .insert(ctrl.callsign.clone());
+    }
+    let tree = RTree::bulk_load(objects);
+
+    // lock order matches the readers: index first, then controllers
+    let mut controllers2d = self.controllers2d.write().await;
+    let mut index = self.controllers_by_facility.write().await;
+    let mut current = self.controllers.write().await;
+    *controllers2d = tree;
+    *index = by_facility;
+    *current = controllers;
+  }
+
+  async fn store_session(&self, pilot: &Pilot) {
+    let res = self.sessions.read().await.store_session(pilot);
+    if let Err(err) = res {
+      error!("error storing pilot session: {}", err);
+    }
+  }
+
+  // stored sessions of the pilot followed by the current one if online
+  pub async fn get_pilot_sessions(
+    &self,
+    cid: u32,
+    since: DateTime<Utc>,
+  ) -> Result<Vec<Session>, Box<dyn std::error::Error>> {
+    let mut sessions = self.sessions.read().await.get_sessions(cid, since)?;
+    sessions.extend(
+      self
+        .pilots
+        .load()
+        .pilots
+        .values()
+        .filter(|pilot| pilot.cid == cid)
+        .map(|pilot| pilot.as_ref().into()),
+    );
+    Ok(sessions)
+  }
+
+  // airports and firs which are worth showing to anyone, used to find out
+  // which of them have changed during an ingestion cycle
+  async fn visible_fixed_state(
+    &self,
+  ) -> (HashMap<String, Arc<Airport>>, HashMap<String, Arc<FIR>>) {
+    let airports = self
+      .get_all_airports(true)
+      .await
+      .into_iter()
+      .map(|arpt| (arpt.compound_id(), arpt))
+      .collect();
+    let firs = self
+      .get_all_firs()
+      .await
+      .into_iter()
+      .map(|fir| (fir.icao.clone(), fir))
+      .collect();
+    (airports, firs)
+  }
+
+  async fn fixed_state_diff(
+    &self,
+    prev_airports: HashMap<String, Arc<Airport>>,
+    prev_firs: HashMap<String, Arc<FIR>>,
+  ) -> (AirportDiff, FirDiff, Vec<WeatherChange>) {
+    let (airports, firs) = self.visible_fixed_state().await;
+    let mut arpt_diff = AirportDiff::default();
+    let mut fir_diff = FirDiff::default();
+    let mut weather = vec![];
+
+    for (id, arpt) in airports.iter() {
+      let prev = prev_airports.get(id);
+      // untouched airports are still the same instances
+      if !prev.is_some_and(|prev| Arc::ptr_eq(prev, arpt) || prev == arpt) {
+        arpt_diff.set.push(arpt.clone());
+      }
+      let prev_wx = prev.and_then(|p| p.wx.as_ref());
+      if let (Some(prev_wx), Some(wx)) = (prev_wx, arpt.wx.as_ref()) {
+        if prev_wx != wx {
+          weather.push(WeatherChange {
+            airport_id: id.clone(),
+            icao: arpt.icao.clone(),
+            wx: wx.clone(),
+            significant: wx.significant_change(prev_wx),
+          });
+        }
+      }
+    }
+
+    for (id, arpt) in prev_airports.into_iter() {
+      if !airports.contains_key(&id) {
+        arpt_diff.delete.push(arpt);
+      }
+    }
+
+    for (icao, fir) in firs.iter() {
+      let prev = prev_firs.get(icao);
+      if !prev.is_some_and(|prev| Arc::ptr_eq(prev, fir) || prev == fir) {
+        fir_diff.set.push(fir.clone());
+      }
+    }
+
+    for (icao, fir) in prev_firs.into_iter() {
+      if !firs.contains_key(&icao) {
+        fir_diff.delete.push(fir);
+      }
+    }
+
+    (arpt_diff, fir_diff, weather)
+  }
+
+  async fn publish_diff(&self, diff: CycleDiff) {
+    {
+      let now = Utc::now();
+      let mut offline = self.offline.write().await;
+      for pilot in diff.pilots.set.iter() {
+        offline.remove(&pilot.callsign);
+      }
+      for pilot in diff.pilots.delete.iter() {
+        offline.insert(pilot.clone(), now);
+      }
+      offline.cleanup(now);
+      debug!("{} pilots gone offline recently", offline.len());
+    }
+
+    let fence_events = self.geofences.write().await.update(&diff.pilots);
+    if !fence_events.is_empty() {
+      debug!("publishing {} geofence events", fence_events.len());
+      let _ = self.geofence_events.send(Arc::new(fence_events));
+    }
+
+    if self.archive.is_some() && !diff.pilots.delete.is_empty() {
+      self.queue_track_job(TrackJob::Archive(diff.pilots.delete.clone()));
+    }
+
+    let diff = self.history.write().await.push(diff);
+    debug!("publishing diff #{} of {} objects", diff.seq, diff.len());
+    // an error here only means nobody is listening at the moment
+    let _ = self.events.send(diff);
+  }
+
+  pub fn subscribe_geofences(&self) -> broadcast::Receiver<GeofenceEvents> {
+    self.geofence_events.subscribe()
+  }
+
+  pub async fn create_geofence(
+    &self,
+    name: &str,
+    shape: Shape,
+    query: &str,
+    filter: Option<Expression<Pilot>>,
+  ) -> camden::Geofence {
+    let pilots = self.pilots.load_full();
+    self.geofences.write().await.create(
+      name,
+      shape,
+      query,
+      filter,
+      pilots.pilots.values().map(Arc::as_ref),
+    )
+  }
+
+  pub async fn delete_geofence(&self, id: &str) -> bool {
+    self.geofences.write().await.delete(id)
+  }
+
+  pub async fn list_geofences(&self) -> Vec<camden::Geofence> {
+    self.geofences.read().await.list()
+  }
+
+  pub async fn last_diff_seq(&self) -> u64 {
+    self.history.read().await.last_seq()
+  }
+
+  // diffs published after the given one, None if it's too old to catch up
+  pub async fn diffs_since(&self, seq: u64) -> Option<Vec<EventBatch>> {
+    self.history.read().await.since(seq)
   }
 
   pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let sources: Vec<_> = self
+      .config()
+      .sources()
+      .iter()
+      .map(|src| build_source(src, &self.http))
+      .collect();
+    self.run_with_sources(sources).await
+  }
+
+  // The ingestion loop, polling the sources given instead of the configured
+  // ones, e.g. a replayed capture
+  pub async fn run_with_sources(
+    &self,
+    sources: Vec<Arc<dyn DataSource>>,
+  ) -> Result<(), Box<dyn std::error::Error>> {
     self.setup_fixed_data().await?;
 
-    let mut pilots_callsigns = HashSet::new();
     let mut controllers: HashMap<String, Controller> = HashMap::new();
     let mut data_updated_at = 0;
     let mut cleanup = CLEANUP_EVERY_X_ITER;
     let mut request_count = 0;
     let mut error_count = 0;
+    let mut deleted_files = 0;
+    let mut compacted_points = 0;
 
-    // TODO: configurable weather ttl
-    let wx_manager = WeatherManager::new(Duration::seconds(1800));
-    let wx_manager = Arc::new(wx_manager);
+    let wx_manager = self.wx.clone();
+    match wx_manager.load_cache().await {
+      Ok(count) => info!("{count} weather entries restored from cache"),
+      Err(err) => error!("error loading weather cache: {err}"),
+    }
     let wx_move = wx_manager.clone();
     tokio::spawn(async move { wx_move.run().await });
 
     loop {
+      let cycle = info_span!("ingestion_cycle");
       info!("loading vatsim data");
       let t = Utc::now();
-      let data = load_vatsim_data(&self.cfg).await;
+      let data = fetch_all(&sources)
+        .instrument(info_span!(parent: &cycle, "fetch"))
+        .await;
       let process_time = seconds_since(t);
       request_count += 1;
 
@@ -244,73 +1305,113 @@ impl Manager {
         let ts = data.general.updated_at.timestamp();
         if ts > data_updated_at {
           data_updated_at = ts;
-          self.metrics.write().await.vatsim_data_timestamp = ts;
-          // region:pilots_processing
-          let mut fresh_pilots_callsigns = HashSet::new();
+          *self.last_fresh_at.write().await = Utc::now();
+          {
+            let mut metrics = self.metrics.write().await;
+            metrics.vatsim_data_timestamp = ts;
+            metrics
+              .vatsim_connected_clients
+              .set_single(data.general.connected_clients as u64);
+            metrics
+              .vatsim_unique_users
+              .set_single(data.general.unique_users as u64);
+          }
+          let mut diff = CycleDiff::default();
 
+          // region:pilots_processing
           info!("processing pilots");
           let t = Utc::now();
           let pcount = data.pilots.len();
 
-          let mut pilots_grouped = Counter::new();
-          {
-            for pilot in data.pilots.into_iter() {
-              // avoid duplication in rtree
-              self.remove_pilot(&pilot.callsign).await;
-
-              // collecting pilots callsigns to find those disappeared since
-              // the previous iteration
-              fresh_pilots_callsigns.insert(pilot.callsign.clone());
-
-              let po: PointObject = (&pilot).into();
-
-              let mut pilots2d = self.pilots2d.write().await;
-              let mut pilots_po = self.pilots_po.write().await;
-              let mut pilots = self.pilots.write().await;
-
-              // tracking first, to avoid additional cloning while inserting into hashmap later
-              let tracks = self.tracks.write().await;
-              let res = tracks.store_track(&pilot);
-              if let Err(err) = res {
-                error!("error storing pilot track: {}", err);
-              }
-
-              let country = self
-                .fixed
-                .read()
-                .await
-                .get_geonames_country_by_position(pilot.position);
-              if let Some(country) = country {
-                pilots_grouped.inc(country.geoname_id);
-              }
+          let stale_before = data.general.updated_at
+            - Duration::from_std(self.config().api.stale_threshold).unwrap_or(Duration::minutes(2));
+          let fresh: Vec<Pilot> = data
+            .pilots
+            .into_iter()
+            .map(|mut pilot| {
+              pilot.stale = pilot.last_updated < stale_before;
+              pilot
+            })
+            .collect();
+          let reconnect_grace =
+            Duration::from_std(self.config().api.reconnect_grace).unwrap_or(Duration::minutes(3));
+          let opts = IndexOptions {
+            keep_since: Some(data.general.updated_at - reconnect_grace),
+            reconnect_grace,
+          };
 
-              // We have to keep point objects in both hashmap and rtree
-              // because rtree doesn't support searching by id:
-              //
-              // We need to search point objects by id for removing a pilot
-              // from RTree "by id". We search for a point object in the HashMap
-              // then we pass it to .remove() method of the tree where it's
-              // being searched by coords and then checked with PartialEq,
-              // so it's OK that the HashMap and RTree contain copies of the object.
-              // See remove_pilot() method for details
-              pilots2d.insert(po.clone());
-              pilots_po.insert(pilot.callsign.clone(), po);
-              pilots.insert(pilot.callsign.clone(), pilot);
+          // the heavy lifting happens off the async runtime and without
+          // blocking the readers, the indexes are swapped in afterwards
+          let (index, protos, countries, movements, batch) = {
+            let (prev, tracks, airports2d, polygons2d, fixed, mut movements) = async {
+              (
+                self.pilots.load_full(),
+                self.tracks.read().await,
+                self.airports2d.read().await,
+                self.fir_polygons.read().await,
+                self.fixed.read().await,
+                self.movements.write().await,
+              )
             }
-          }
+            .instrument(info_span!(parent: &cycle, "pilots_lock_wait"))
+            .await;
+            let _span = info_span!(parent: &cycle, "pilots_index").entered();
+            block_in_place(|| {
+              let transition_altitude = self.config().weather.transition_altitude;
+              let qnh_max_distance = self.config().weather.qnh_max_distance;
+              let now = Utc::now();
+              let mut fresh = fresh;
+              fresh.par_iter_mut().for_each(|pilot| {
+                correct_altitude(pilot, transition_altitude, |pos| {
+                  local_qnh(&fixed, &airports2d, pos, qnh_max_distance)
+                });
+                pilot.set_utc_offsets(|icao| fixed.airport_utc_offset(icao, now));
+                pilot.current_fir = locate_fir(&polygons2d, pilot.position);
+              });
+              let index = build_pilot_index(fresh, &prev.pilots, opts);
+              let protos = build_protos(&index.pilots, &prev);
+              let observed = movements.update(
+                index.pilots.values().map(Arc::as_ref),
+                |pos| locate_threshold(&fixed, &airports2d, pos),
+                Utc::now(),
+              );
+              let batch = tracks.track_batch(index.pilots.values().map(Arc::as_ref));
+              let countries: Vec<String> = index
+                .pilots
+                .par_iter()
+                .filter_map(|(_, pilot)| {
+                  fixed
+                    .get_geonames_country_by_position(pilot.position)
+                    .map(|country| country.geoname_id)
+                })
+                .collect();
+              (index, protos, countries, observed.len(), batch)
+            })
+          };
+          debug!("{movements} airport movements observed");
+          self.queue_track_job(TrackJob::Write(batch));
 
-          // for each callsign not met this iteration let's remove it from the indexes
-          for cs in pilots_callsigns.difference(&fresh_pilots_callsigns) {
-            self.remove_pilot(cs).await;
+          let mut pilots_grouped = Counter::new();
+          for geoname_id in countries.into_iter() {
+            pilots_grouped.inc(geoname_id);
           }
 
-          // setup this iteration as "previous"
-          pilots_callsigns = fresh_pilots_callsigns;
+          async {
+            self.install_pilots(PilotSnapshot {
+              pilots: index.pilots,
+              pilots2d: index.pilots2d,
+              protos,
+            });
+            for pilot in index.finished.iter() {
+              self.store_session(pilot).await;
+            }
+          }
+          .instrument(info_span!(parent: &cycle, "install_pilots"))
+          .await;
+          diff.pilots = index.diff;
 
-          let mut vatsim_objects_online = {
-            let metrics = self.metrics.read().await;
-            metrics.vatsim_objects_online.duplicate()
-          };
+          let mut vatsim_objects_online =
+            LabelLimiter::new("country_code", self.config().metrics.top_countries);
 
           let process_time = seconds_since(t);
           {
@@ -321,8 +1422,12 @@ impl Manager {
 
             let fixed = self.fixed.read().await;
             for (geo_id, count) in pilots_grouped.iter() {
-              let country = fixed.get_geonames_country_by_id(geo_id).unwrap();
-              vatsim_objects_online.set(
+              // fixed data may have been swapped since the pilots were located
+              let Some(country) = fixed.get_geonames_country_by_id(geo_id) else {
+                warn!("country {geo_id} not found, {count} pilots left out of metrics");
+                continue;
+              };
+              vatsim_objects_online.add(
                 labels!(
                   "object_type" = "pilot",
                   "country_code" = &country.iso,
@@ -332,18 +1437,39 @@ impl Manager {
               );
             }
           }
+          {
+            let counts = self.movements.read().await.current_hour();
+            let mut metrics = self.metrics.write().await;
+            metrics.airport_movements.reset();
+            for (icao, count) in counts.into_iter() {
+              metrics.airport_movements.set(
+                labels!("icao" = &icao, "movement_type" = "arrival"),
+                count.arrivals as u64,
+              );
+              metrics.airport_movements.set(
+                labels!("icao" = &icao, "movement_type" = "departure"),
+                count.departures as u64,
+              );
+            }
+          }
           info!("{} pilots processed in {}s", pcount, process_time);
           // endregion:pilots_processing
 
           // region:controllers_processing
           info!("processing controllers");
           let t = Utc::now();
+          let (prev_airports, prev_firs) = self.visible_fixed_state().await;
           let mut fresh_controllers = HashMap::new();
           let mut ccount = 0;
           let mut ctrl_grouped = Counter::new();
           let mut controlled_arpt = HashSet::new();
+          let mut ctrl_objects = vec![];
           {
-            let mut fixed = self.fixed.write().await;
+            let mut fixed = self
+              .fixed
+              .write()
+              .instrument(info_span!(parent: &cycle, "controllers_lock_wait"))
+              .await;
 
             for ctrl in data.controllers.into_iter() {
               match &ctrl.facility {
@@ -352,8 +1478,14 @@ impl Manager {
                 }
                 Facility::Radar => {
                   fresh_controllers.insert(ctrl.callsign.clone(), ctrl.clone());
+                  let callsign = ctrl.callsign.clone();
                   let fir = fixed.set_fir_controller(ctrl);
                   if let Some(fir) = fir {
+                    let rect = Rect {
+                      south_west: fir.boundaries.min,
+                      north_east: fir.boundaries.max,
+                    };
+                    ctrl_objects.push(RectObject::new(&callsign, rect));
                     let country = fir.country.as_ref();
                     if let Some(country) = country {
                       let key = format!("{}:radar", country.geoname_id);
@@ -364,8 +1496,14 @@ impl Manager {
                 _ => {
                   fresh_controllers.insert(ctrl.callsign.clone(), ctrl.clone());
                   let facility = ctrl.facility.clone();
+                  let callsign = ctrl.callsign.clone();
                   let arpt = fixed.set_airport_controller(ctrl);
                   if let Some(arpt) = arpt {
+                    let rect = Rect {
+                      south_west: arpt.position,
+                      north_east: arpt.position,
+                    };
+                    ctrl_objects.push(RectObject::new(&callsign, rect));
                     controlled_arpt.insert(arpt.icao.clone());
                     let country = arpt.country.as_ref();
                     if let Some(country) = country {
@@ -379,7 +1517,10 @@ impl Manager {
             }
 
             let locations: Vec<&str> = controlled_arpt.iter().map(|s| s.as_str()).collect();
-            wx_manager.preload(locations).await;
+            wx_manager
+              .preload(locations)
+              .instrument(info_span!(parent: &cycle, "weather_preload"))
+              .await;
 
             for icao in controlled_arpt.iter() {
               let wx = wx_manager.get(icao).await;
@@ -389,8 +1530,15 @@ impl Manager {
             }
           }
 
+          for (cs, ctrl) in fresh_controllers.iter() {
+            if controllers.get(cs) != Some(ctrl) {
+              diff.controllers.set.push(ctrl.clone());
+            }
+          }
+
           for (cs, ctrl) in controllers.iter() {
             if !fresh_controllers.contains_key(cs) {
+              diff.controllers.delete.push(ctrl.clone());
               match ctrl.facility {
                 Facility::Radar => self.fixed.write().await.reset_fir_controller(ctrl),
                 _ => {
@@ -399,21 +1547,38 @@ impl Manager {
               }
             }
           }
+          self.fixed.write().await.update_coverage();
           controllers = fresh_controllers;
+          self
+            .install_controllers(controllers.clone(), ctrl_objects)
+            .await;
 
           let process_time = seconds_since(t);
+          let blacklist_size = wx_manager.blacklist_size().await;
           {
             let mut metrics = self.metrics.write().await;
             metrics
               .processing_time_sec
               .set(labels!("object_type" = "controller"), process_time);
+            for (provider, count) in wx_manager.provider_errors() {
+              metrics
+                .weather_provider_error_count
+                .set(labels!("provider" = provider), count);
+            }
+            metrics.weather_blacklist_size.set_single(blacklist_size);
 
             let fixed = self.fixed.read().await;
             for (key, count) in ctrl_grouped.iter() {
               let tokens: Vec<&str> = key.split(':').collect();
-              let country = fixed.get_geonames_country_by_id(tokens[0]).unwrap();
+              let Some(country) = fixed.get_geonames_country_by_id(tokens[0]) else {
+                warn!(
+                  "country {} not found, {count} controllers left out of metrics",
+                  tokens[0]
+                );
+                continue;
+              };
               let facility = tokens[1];
-              vatsim_objects_online.set(
+              vatsim_objects_online.add(
                 labels!(
                   "object_type" = "controller",
                   "controller_type" = facility,
@@ -427,66 +1592,133 @@ impl Manager {
           info!("{} controllers processed in {}s", ccount, process_time);
           // endregion:controllers_processing
 
+          async {
+            (diff.airports, diff.firs, diff.weather) =
+              self.fixed_state_diff(prev_airports, prev_firs).await;
+            self.publish_diff(diff).await;
+          }
+          .instrument(info_span!(parent: &cycle, "publish_diff"))
+          .await;
+
           {
             let mut metrics = self.metrics.write().await;
+            let mut objects_online = metrics.vatsim_objects_online.duplicate();
+            let dropped = vatsim_objects_online.apply(&mut objects_online);
+            metrics.vatsim_objects_online.replace_values(objects_online);
             metrics
-              .vatsim_objects_online
-              .replace_values(vatsim_objects_online);
+              .metric_label_sets_dropped
+              .set(labels!("metric" = "vatsim_objects_online"), dropped);
           }
         }
 
         let t = Utc::now();
-        let res = self.tracks.read().await.counters();
+        let counters = self.tracks.read().await.counters();
         let process_time = seconds_since(t);
-        match res {
-          Ok((tc, tpc)) => {
-            let mut metrics = self.metrics.write().await;
-            metrics
-              .database_objects_count
-              .set(labels!("object_type" = "track"), tc);
-            metrics
-              .database_objects_count
-              .set(labels!("object_type" = "trackpoint"), tpc);
-            metrics
-              .database_objects_count_fetch_time_sec
-              .set_single(process_time);
-          }
-          Err(err) => {
-            error!("error getting track store counters: {err}");
-          }
+        {
+          let mut metrics = self.metrics.write().await;
+          metrics
+            .database_objects_count
+            .set(labels!("object_type" = "track"), counters.tracks);
+          metrics
+            .database_objects_count
+            .set(labels!("object_type" = "trackpoint"), counters.points);
+          metrics.track_store_files.set_single(counters.tracks);
+          metrics.track_store_bytes.set_single(counters.bytes);
+          metrics
+            .database_objects_count_fetch_time_sec
+            .set_single(process_time);
         }
 
         cleanup -= 1;
         if cleanup == 0 {
           let t = Utc::now();
-          let res = self.tracks.write().await.cleanup();
+          let res = self.sessions.write().await.cleanup();
+          if let Err(err) = res {
+            error!("error cleaning up session store: {err}");
+          }
+          // the files are locked one at a time, the track writer goes on
+          let tracks = self.tracks.read().await;
+          let res = block_in_place(|| tracks.cleanup());
+          drop(tracks);
           match res {
             Err(err) => error!("error cleaning up track store: {err}"),
-            Ok(_) => {
+            Ok(stats) => {
               let process_time = seconds_since(t);
               info!("track store cleanup took {process_time}s");
               cleanup = CLEANUP_EVERY_X_ITER;
+              deleted_files += stats.deleted_files;
+              compacted_points += stats.compacted_points;
+              let mut metrics = self.metrics.write().await;
+              metrics
+                .track_cleanup_count
+                .set(labels!("kind" = "deleted_files"), deleted_files);
+              metrics
+                .track_cleanup_count
+                .set(labels!("kind" = "compacted_points"), compacted_points);
             }
           }
         } else {
           debug!("{cleanup} iterations to track store cleanup");
         }
       }
-      sleep(self.cfg.api.poll_period).await;
+      self.check_watchdog().await;
+      sleep(self.config().api.poll_period).await;
     }
   }
 
-  pub async fn get_pilot_by_callsign(&self, callsign: &str) -> Option<Pilot> {
-    self.pilots.read().await.get(callsign).cloned()
+  pub async fn get_pilot_by_callsign(&self, callsign: &str) -> Option<Arc<Pilot>> {
+    self.pilots.load().pilots.get(callsign).cloned()
+  }
+
+  // a pilot gone offline recently along with the time it's happened
+  pub async fn get_offline_pilot(&self, callsign: &str) -> Option<(Arc<Pilot>, DateTime<Utc>)> {
+    self.offline.read().await.get(callsign)
+  }
+
+  pub async fn get_offline_pilots(&self) -> Vec<(Arc<Pilot>, DateTime<Utc>)> {
+    self.offline.read().await.all()
   }
 
   pub async fn get_pilot_track(
     &self,
     pilot: &Pilot,
   ) -> Result<Vec<TrackPoint>, Box<dyn std::error::Error>> {
+    self.restore_track(pilot).await;
     Ok(self.tracks.read().await.get_track_points(pilot)?)
   }
 
+  pub async fn get_pilot_track_stats(
+    &self,
+    pilot: &Pilot,
+  ) -> Result<TrackStats, Box<dyn std::error::Error>> {
+    self.restore_track(pilot).await;
+    Ok(self.tracks.read().await.get_track_stats(pilot)?)
+  }
+
+  pub async fn get_pilot_track_chunk(
+    &self,
+    pilot: &Pilot,
+    pos: usize,
+    len: usize,
+  ) -> Result<Vec<TrackPoint>, Box<dyn std::error::Error>> {
+    if pos == 0 {
+      self.restore_track(pilot).await;
+    }
+    Ok(self.tracks.read().await.get_track_chunk(pilot, pos, len)?)
+  }
+
+  pub async fn get_pilot_track_range(
+    &self,
+    pilot: &Pilot,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    max_points: Option<usize>,
+  ) -> Result<Vec<TrackPoint>, Box<dyn std::error::Error>> {
+    self.restore_track(pilot).await;
+    let tracks = self.tracks.read().await;
+    Ok(tracks.get_track_points_range(pilot, from, to, max_points)?)
+  }
+
   pub async fn get_metrics_clone(&self) -> Metrics {
     self.metrics.read().await.clone()
   }
@@ -0,0 +1,243 @@
+use super::spatial::PointObject;
+use crate::{fixed::data::FixedData, moving::pilot::Pilot, types::Point};
+use chrono::{DateTime, Utc};
+use geo::HaversineDistance;
+use geo_types::Point as GeoPoint;
+use rstar::{RTree, AABB};
+use std::collections::{BTreeMap, HashMap};
+
+// slower than this means the aircraft is on the ground
+const GROUND_SPEED: i32 = 40;
+// faster than this means it might be airborne
+const AIRBORNE_SPEED: i32 = 60;
+// height above the runway threshold to consider a departure complete
+const LIFTOFF_HEIGHT_FT: i32 = 100;
+// how far from a runway threshold a movement may be observed, meters
+const THRESHOLD_RADIUS: f64 = 5000.0;
+// rough envelope around a pilot to look for airports in, degrees
+const SEARCH_DEG: f64 = 0.15;
+// how many hourly buckets to keep
+pub const MOVEMENT_HOURS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+  Ground,
+  Airborne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Movement {
+  Arrival,
+  Departure,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MovementCount {
+  pub arrivals: u32,
+  pub departures: u32,
+}
+
+// A runway threshold close to a position, with the airport it belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+  pub icao: String,
+  pub elevation_ft: i32,
+}
+
+// Looks for the closest runway threshold within THRESHOLD_RADIUS
+pub fn locate_threshold(
+  fixed: &FixedData,
+  airports2d: &RTree<PointObject>,
+  position: Point,
+) -> Option<Threshold> {
+  let env = AABB::from_corners(
+    Point {
+      lat: position.lat - SEARCH_DEG,
+      lng: position.lng - SEARCH_DEG,
+    },
+    Point {
+      lat: position.lat + SEARCH_DEG,
+      lng: position.lng + SEARCH_DEG,
+    },
+  );
+  let pos: GeoPoint = position.into();
+
+  let mut closest: Option<(f64, Threshold)> = None;
+  for po in airports2d.locate_in_envelope(&env) {
+    let Some(arpt) = fixed.find_airport_compound(&po.id) else {
+      continue;
+    };
+    for rwy in arpt.runways.values() {
+      let threshold = GeoPoint::new(rwy.longitude, rwy.latitude);
+      let dist = pos.haversine_distance(&threshold);
+      if dist > THRESHOLD_RADIUS {
+        continue;
+      }
+      if closest.as_ref().is_none_or(|(d, _)| dist < *d) {
+        closest = Some((
+          dist,
+          Threshold {
+            icao: arpt.icao.clone(),
+            elevation_ft: rwy.elevation_ft,
+          },
+        ));
+      }
+    }
+  }
+  closest.map(|(_, threshold)| threshold)
+}
+
+// Observes pilots going from the ground to the air and back near runway
+// thresholds, counting arrivals and departures per airport per hour
+#[derive(Debug, Default)]
+pub struct Movements {
+  phases: HashMap<String, Phase>,
+  hourly: BTreeMap<i64, HashMap<String, MovementCount>>,
+}
+
+fn hour_of(t: DateTime<Utc>) -> i64 {
+  t.timestamp() / 3600 * 3600
+}
+
+impl Movements {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn update<'a>(
+    &mut self,
+    pilots: impl Iterator<Item = &'a Pilot>,
+    locate: impl Fn(Point) -> Option<Threshold>,
+    now: DateTime<Utc>,
+  ) -> Vec<(String, Movement)> {
+    let mut movements = vec![];
+    let mut phases = HashMap::new();
+
+    for pilot in pilots {
+      let prev = self.phases.get(&pilot.callsign).copied();
+      let phase = if pilot.groundspeed < GROUND_SPEED {
+        if prev == Some(Phase::Airborne) {
+          if let Some(threshold) = locate(pilot.position) {
+            movements.push((threshold.icao, Movement::Arrival));
+          }
+        }
+        Some(Phase::Ground)
+      } else if pilot.groundspeed >= AIRBORNE_SPEED {
+        if prev == Some(Phase::Ground) {
+          match locate(pilot.position) {
+            // still rolling down the runway
+            Some(threshold) if pilot.altitude <= threshold.elevation_ft + LIFTOFF_HEIGHT_FT => {
+              Some(Phase::Ground)
+            }
+            Some(threshold) => {
+              movements.push((threshold.icao, Movement::Departure));
+              Some(Phase::Airborne)
+            }
+            None => Some(Phase::Airborne),
+          }
+        } else {
+          Some(Phase::Airborne)
+        }
+      } else {
+        prev
+      };
+
+      if let Some(phase) = phase {
+        phases.insert(pilot.callsign.clone(), phase);
+      }
+    }
+    self.phases = phases;
+
+    let hour = hour_of(now);
+    let counts = self.hourly.entry(hour).or_default();
+    for (icao, movement) in movements.iter() {
+      let count = counts.entry(icao.clone()).or_default();
+      match movement {
+        Movement::Arrival => count.arrivals += 1,
+        Movement::Departure => count.departures += 1,
+      }
+    }
+    while self.hourly.len() > MOVEMENT_HOURS {
+      self.hourly.pop_first();
+    }
+    movements
+  }
+
+  // hourly counters of the airport, oldest first
+  pub fn airport_movements(&self, icao: &str) -> Vec<(DateTime<Utc>, MovementCount)> {
+    self
+      .hourly
+      .iter()
+      .filter_map(|(hour, counts)| {
+        let count = counts.get(icao).copied().unwrap_or_default();
+        DateTime::from_timestamp(*hour, 0).map(|hour| (hour, count))
+      })
+      .collect()
+  }
+
+  // counters of every airport for the current hour
+  pub fn current_hour(&self) -> HashMap<String, MovementCount> {
+    self
+      .hourly
+      .last_key_value()
+      .map(|(_, counts)| counts.clone())
+      .unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  fn pilot(callsign: &str, groundspeed: i32, altitude: i32) -> Pilot {
+    Pilot {
+      altitude,
+      groundspeed,
+      ..Pilot::test_default(callsign)
+    }
+  }
+
+  fn locate(_: Point) -> Option<Threshold> {
+    Some(Threshold {
+      icao: "UUEE".into(),
+      elevation_ft: 600,
+    })
+  }
+
+  #[test]
+  fn test_movements() {
+    let mut mv = Movements::new();
+    let now = Utc::now();
+
+    mv.update([pilot("AAA", 10, 600)].iter(), locate, now);
+    // takeoff roll
+    let res = mv.update([pilot("AAA", 120, 620)].iter(), locate, now);
+    assert!(res.is_empty());
+    let res = mv.update([pilot("AAA", 160, 1200)].iter(), locate, now);
+    assert_eq!(res, vec![("UUEE".to_owned(), Movement::Departure)]);
+
+    // first seen in the air, then landing
+    mv.update([pilot("BBB", 140, 2000)].iter(), locate, now);
+    let res = mv.update([pilot("BBB", 20, 600)].iter(), locate, now);
+    assert_eq!(res, vec![("UUEE".to_owned(), Movement::Arrival)]);
+
+    let res = mv.update(
+      [pilot("CCC", 20, 600)].iter(),
+      locate,
+      now + Duration::hours(1),
+    );
+    assert!(res.is_empty());
+
+    let hours = mv.airport_movements("UUEE");
+    assert_eq!(hours.len(), 2);
+    assert_eq!(
+      hours[0].1,
+      MovementCount {
+        arrivals: 1,
+        departures: 1
+      }
+    );
+    assert_eq!(hours[1].1, MovementCount::default());
+  }
+}
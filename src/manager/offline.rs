@@ -0,0 +1,94 @@
+use crate::moving::pilot::Pilot;
+use chrono::{DateTime, Duration, Utc};
+use std::{collections::HashMap, sync::Arc};
+
+// Pilots gone offline recently, kept around for the queries coming
+// shortly after, e.g. someone clicking an aircraft that has just landed
+#[derive(Debug)]
+pub struct OfflinePilots {
+  capacity: usize,
+  retention: Duration,
+  pilots: HashMap<String, (Arc<Pilot>, DateTime<Utc>)>,
+}
+
+impl OfflinePilots {
+  pub fn new(capacity: usize, retention: Duration) -> Self {
+    Self {
+      capacity,
+      retention,
+      pilots: HashMap::new(),
+    }
+  }
+
+  pub fn set_retention(&mut self, retention: Duration) {
+    self.retention = retention;
+  }
+
+  pub fn insert(&mut self, pilot: Arc<Pilot>, offline_since: DateTime<Utc>) {
+    if self.capacity == 0 {
+      return;
+    }
+    if self.pilots.len() >= self.capacity && !self.pilots.contains_key(&pilot.callsign) {
+      let oldest = self
+        .pilots
+        .iter()
+        .min_by_key(|(_, (_, since))| *since)
+        .map(|(cs, _)| cs.clone());
+      if let Some(oldest) = oldest {
+        self.pilots.remove(&oldest);
+      }
+    }
+    self
+      .pilots
+      .insert(pilot.callsign.clone(), (pilot, offline_since));
+  }
+
+  pub fn remove(&mut self, callsign: &str) {
+    self.pilots.remove(callsign);
+  }
+
+  pub fn get(&self, callsign: &str) -> Option<(Arc<Pilot>, DateTime<Utc>)> {
+    self.pilots.get(callsign).cloned()
+  }
+
+  pub fn all(&self) -> Vec<(Arc<Pilot>, DateTime<Utc>)> {
+    self.pilots.values().cloned().collect()
+  }
+
+  pub fn cleanup(&mut self, now: DateTime<Utc>) {
+    let oldest = now - self.retention;
+    self.pilots.retain(|_, (_, since)| *since >= oldest);
+  }
+
+  pub fn len(&self) -> usize {
+    self.pilots.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pilot(callsign: &str) -> Arc<Pilot> {
+    Arc::new(Pilot::test_default(callsign))
+  }
+
+  #[test]
+  fn test_offline_pilots() {
+    let now = Utc::now();
+    let mut offline = OfflinePilots::new(2, Duration::minutes(10));
+    offline.insert(pilot("AAA"), now - Duration::minutes(5));
+    offline.insert(pilot("BBB"), now - Duration::minutes(1));
+    offline.insert(pilot("CCC"), now);
+    // the oldest one is evicted
+    assert_eq!(offline.len(), 2);
+    assert!(offline.get("AAA").is_none());
+
+    offline.cleanup(now + Duration::seconds(9 * 60 + 30));
+    assert_eq!(offline.len(), 1);
+    assert!(offline.get("CCC").is_some());
+
+    offline.remove("CCC");
+    assert_eq!(offline.len(), 0);
+  }
+}
@@ -2,8 +2,12 @@ use crate::{
   fixed::types::{Airport, FIR},
   moving::pilot::Pilot,
   types::{Point, Rect},
+  weather::sigmet::Hazard,
 };
-use rstar::{RTreeObject, AABB};
+use geo::Contains;
+use geo_types::{Coord, LineString, Polygon};
+use rstar::{RTree, RTreeObject, AABB};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct PointObject {
@@ -11,6 +15,12 @@ pub struct PointObject {
   point: Point,
 }
 
+impl PointObject {
+  pub fn point(&self) -> Point {
+    self.point
+  }
+}
+
 impl RTreeObject for PointObject {
   type Envelope = AABB<Point>;
 
@@ -49,6 +59,15 @@ pub struct RectObject {
   rect: Rect,
 }
 
+impl RectObject {
+  pub fn new(id: &str, rect: Rect) -> Self {
+    Self {
+      id: id.to_owned(),
+      rect,
+    }
+  }
+}
+
 impl RTreeObject for RectObject {
   type Envelope = AABB<Point>;
 
@@ -69,12 +88,103 @@ impl From<&FIR> for RectObject {
   }
 }
 
+impl From<&Hazard> for RectObject {
+  fn from(hazard: &Hazard) -> Self {
+    Self {
+      id: hazard.id.clone(),
+      rect: hazard.bounds(),
+    }
+  }
+}
+
 impl PartialEq for RectObject {
   fn eq(&self, other: &Self) -> bool {
     self.id == other.id
   }
 }
 
+// A polygon of FIR boundaries, unlike RectObject this can tell whether a
+// point is actually within the FIR
+#[derive(Debug, Clone)]
+pub struct PolygonObject {
+  pub id: String,
+  poly: Polygon,
+  rect: Rect,
+}
+
+impl PolygonObject {
+  pub fn new(id: &str, points: &[Point]) -> Self {
+    let mut rect = Rect {
+      south_west: Point {
+        lat: f64::MAX,
+        lng: f64::MAX,
+      },
+      north_east: Point {
+        lat: f64::MIN,
+        lng: f64::MIN,
+      },
+    };
+    for p in points.iter() {
+      rect.south_west.lat = rect.south_west.lat.min(p.lat);
+      rect.south_west.lng = rect.south_west.lng.min(p.lng);
+      rect.north_east.lat = rect.north_east.lat.max(p.lat);
+      rect.north_east.lng = rect.north_east.lng.max(p.lng);
+    }
+    let exterior: LineString = points
+      .iter()
+      .map(|p| Coord { x: p.lng, y: p.lat })
+      .collect();
+    Self {
+      id: id.to_owned(),
+      poly: Polygon::new(exterior, vec![]),
+      rect,
+    }
+  }
+
+  pub fn contains(&self, point: Point) -> bool {
+    let point: geo_types::Point = point.into();
+    self.poly.contains(&point)
+  }
+
+  fn bbox_area(&self) -> f64 {
+    (self.rect.north_east.lat - self.rect.south_west.lat)
+      * (self.rect.north_east.lng - self.rect.south_west.lng)
+  }
+}
+
+impl RTreeObject for PolygonObject {
+  type Envelope = AABB<Point>;
+
+  fn envelope(&self) -> Self::Envelope {
+    AABB::from_corners(self.rect.south_west, self.rect.north_east)
+  }
+}
+
+// every part of the FIR boundaries becomes a polygon of its own
+pub fn fir_polygons(firs: &[Arc<FIR>]) -> Vec<PolygonObject> {
+  firs
+    .iter()
+    .flat_map(|fir| {
+      fir
+        .boundaries
+        .points
+        .iter()
+        .filter(|ring| ring.len() >= 3)
+        .map(|ring| PolygonObject::new(&fir.icao, ring))
+    })
+    .collect()
+}
+
+// ICAO code of the FIR the point is within. Where the boundaries overlap the
+// FIR with the smallest bounding box is taken as the most specific one
+pub fn locate_fir(polygons: &RTree<PolygonObject>, point: Point) -> Option<String> {
+  polygons
+    .locate_in_envelope_intersecting(&AABB::from_point(point))
+    .filter(|po| po.contains(point))
+    .min_by(|a, b| a.bbox_area().total_cmp(&b.bbox_area()))
+    .map(|po| po.id.clone())
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -101,4 +211,31 @@ mod tests {
     let objs = tree.locate_in_envelope(&env).collect::<Vec<_>>();
     assert_eq!(objs.len(), 0);
   }
+
+  #[test]
+  fn test_locate_fir() {
+    let p = |lat, lng| Point { lat, lng };
+    // an L-shaped FIR with a smaller one overlapping its corner
+    let outer = PolygonObject::new(
+      "OUTR",
+      &[
+        p(0.0, 0.0),
+        p(0.0, 10.0),
+        p(5.0, 10.0),
+        p(5.0, 5.0),
+        p(10.0, 5.0),
+        p(10.0, 0.0),
+      ],
+    );
+    let inner = PolygonObject::new(
+      "INNR",
+      &[p(0.0, 0.0), p(0.0, 2.0), p(2.0, 2.0), p(2.0, 0.0)],
+    );
+    let tree = RTree::bulk_load(vec![outer, inner]);
+
+    assert_eq!(locate_fir(&tree, p(3.0, 3.0)), Some("OUTR".into()));
+    assert_eq!(locate_fir(&tree, p(1.0, 1.0)), Some("INNR".into()));
+    // within the bounding box but outside the polygon
+    assert_eq!(locate_fir(&tree, p(8.0, 8.0)), None);
+  }
 }
@@ -2,7 +2,7 @@ mod data;
 
 use lazy_static::lazy_static;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Mutex};
 
 use crate::service::camden;
 
@@ -81,7 +81,13 @@ impl From<&Aircraft> for camden::Aircraft {
   }
 }
 
+// distinct unresolved designators tracked at most, the equipment field is
+// free text
+const MAX_UNRESOLVED: usize = 500;
+const MAX_LOOKUP_RESULTS: usize = 20;
+
 lazy_static! {
+  static ref UNRESOLVED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
   static ref DB: HashMap<&'static str, Vec<&'static Aircraft>> = {
     let mut db: HashMap<&'static str, Vec<&'static Aircraft>> = HashMap::new();
     for atype in data::MODELS {
@@ -96,7 +102,45 @@ lazy_static! {
   };
 }
 
+// Designator part of a flight plan aircraft field, e.g. B738 out of ICAO
+// "B738/M-SDE3FGHIM2M3RWXY/LB1" or FAA "H/B744/L"
+pub fn designator_from_equipment(code: &str) -> String {
+  let code = code.trim().to_uppercase();
+  let mut tokens = code.split('/').map(|t| t.trim());
+  let mut designator = tokens.next().unwrap_or_default();
+  // FAA style wake category prefix
+  if designator.len() == 1 {
+    designator = tokens.next().unwrap_or_default();
+  }
+  designator
+    .split(['-', ' '])
+    .next()
+    .unwrap_or_default()
+    .to_owned()
+}
+
+fn record_unresolved(designator: &str) {
+  if designator.is_empty() {
+    return;
+  }
+  let mut unresolved = UNRESOLVED.lock().unwrap();
+  if let Some(count) = unresolved.get_mut(designator) {
+    *count += 1;
+  } else if unresolved.len() < MAX_UNRESOLVED {
+    unresolved.insert(designator.to_owned(), 1);
+  }
+}
+
+// Designators no aircraft has been found for with the number of lookups,
+// exposed via metrics
+pub fn unresolved_counters() -> Vec<(String, u64)> {
+  let unresolved = UNRESOLVED.lock().unwrap();
+  unresolved.iter().map(|(k, v)| (k.clone(), *v)).collect()
+}
+
 pub fn guess_aircraft_types(code: &str) -> Option<&'static Aircraft> {
+  let designator = designator_from_equipment(code);
+  let code = designator.as_str();
   // pff unicode is tough
   let mut indices: Vec<usize> = code.char_indices().map(|(i, _)| i).collect();
   indices.push(code.len());
@@ -108,10 +152,95 @@ pub fn guess_aircraft_types(code: &str) -> Option<&'static Aircraft> {
     let atypes = DB.get(partial_code);
     if let Some(atypes) = atypes {
       if !atypes.is_empty() {
-        return atypes.get(0).copied();
+        return atypes.first().copied();
       }
     }
     l -= 1;
   }
+  record_unresolved(code);
   None
 }
+
+// uppercase alphanumeric words, dashes and dots dropped so that 737-800
+// and 737800 match
+fn title_words(title: &str) -> Vec<String> {
+  title
+    .to_uppercase()
+    .replace(['-', '.'], "")
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|w| !w.is_empty())
+    .map(|w| w.to_owned())
+    .collect()
+}
+
+// Aircraft by a designator or an equipment string, falling back to the
+// models having every word of the query in their manufacturer and name,
+// exact names first
+pub fn lookup_aircraft(query: &str) -> Vec<&'static Aircraft> {
+  let designator = designator_from_equipment(query);
+  if let Some(atypes) = DB.get(designator.as_str()) {
+    return atypes.clone();
+  }
+
+  let words = title_words(query);
+  if words.is_empty() {
+    return vec![];
+  }
+  let mut found: Vec<(bool, &'static Aircraft)> = data::MODELS
+    .iter()
+    .filter_map(|atype| {
+      let name = title_words(atype.name);
+      let mut haystack = title_words(atype.manufacturer_code);
+      haystack.extend(name.iter().cloned());
+      let matches = words
+        .iter()
+        .all(|w| haystack.iter().any(|h| h.starts_with(w.as_str())));
+      matches.then(|| (words.ends_with(&name), atype))
+    })
+    .collect();
+  found.sort_by_key(|(exact, atype)| (!exact, atype.name.len()));
+  found
+    .into_iter()
+    .take(MAX_LOOKUP_RESULTS)
+    .map(|(_, atype)| atype)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_guess_aircraft_types() {
+    assert_eq!(
+      designator_from_equipment("B738/M-SDE3FGHIM2M3RWXY/LB1"),
+      "B738"
+    );
+    assert_eq!(designator_from_equipment("H/B744/L"), "B744");
+    assert_eq!(designator_from_equipment(" a320 "), "A320");
+    assert_eq!(designator_from_equipment(""), "");
+
+    let at = guess_aircraft_types("B738/M-SDE3FGHIM2M3RWXY/LB1").unwrap();
+    assert_eq!(at.designator, "B738");
+    let at = guess_aircraft_types("H/B744/L").unwrap();
+    assert_eq!(at.designator, "B744");
+    assert!(guess_aircraft_types("ZZZZ/L").is_none());
+    assert!(unresolved_counters()
+      .iter()
+      .any(|(designator, count)| designator == "ZZZZ" && *count > 0));
+  }
+
+  #[test]
+  fn test_lookup_aircraft() {
+    let found = lookup_aircraft("b738/m-sde3fghim2m3rwxy/lb1");
+    assert!(!found.is_empty());
+    assert!(found.iter().all(|at| at.designator == "B738"));
+
+    let found = lookup_aircraft("Boeing 737-800");
+    assert_eq!(found[0].designator, "B738");
+    assert_eq!(found[0].name, "737-800");
+
+    assert!(lookup_aircraft("").is_empty());
+    assert!(lookup_aircraft("Nonexistent Flying Machine").is_empty());
+  }
+}
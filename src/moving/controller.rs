@@ -3,9 +3,13 @@ use std::fmt::Display;
 use chrono::{DateTime, Utc};
 use serde::Serialize;
 
-use crate::service::camden;
+use super::ratings::{controller_rating_name, controller_rating_valid};
+use crate::{
+  atis::info::{parse_atis, AtisInfo},
+  service::camden,
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub enum Facility {
   Reject = 0,
   ATIS = 1,
@@ -73,6 +77,9 @@ pub struct Controller {
   pub human_readable: Option<String>,
   pub last_updated: DateTime<Utc>,
   pub logon_time: DateTime<Utc>,
+  pub source: String,
+  // parsed from text_atis for ATIS controllers
+  pub atis: Option<AtisInfo>,
 }
 
 impl PartialEq for Controller {
@@ -91,11 +98,14 @@ impl PartialEq for Controller {
       && self.text_atis == other.text_atis
       && self.human_readable == other.human_readable
       && self.logon_time == other.logon_time
+      && self.source == other.source
   }
 }
 
 impl From<Controller> for camden::Controller {
   fn from(value: Controller) -> Self {
+    let rating_name = controller_rating_name(value.rating).unwrap_or_default();
+    let rating_valid = controller_rating_valid(&value.facility, value.rating);
     let facility: camden::Facility = value.facility.into();
     Self {
       cid: value.cid,
@@ -111,6 +121,11 @@ impl From<Controller> for camden::Controller {
       human_readable: value.human_readable,
       last_updated: value.last_updated.timestamp_millis() as u64,
       logon_time: value.logon_time.timestamp_millis() as u64,
+      source: value.source,
+      transceivers: vec![],
+      atis: value.atis.map(|v| v.into()),
+      rating_name: rating_name.to_owned(),
+      rating_valid,
     }
   }
 }
@@ -142,6 +157,18 @@ impl ControllerSet {
       && self.tower.is_none()
       && self.approach.is_none()
   }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Controller> {
+    [
+      &self.atis,
+      &self.delivery,
+      &self.ground,
+      &self.tower,
+      &self.approach,
+    ]
+    .into_iter()
+    .flatten()
+  }
 }
 
 impl From<ControllerSet> for camden::ControllerSet {
@@ -168,6 +195,10 @@ impl From<super::exttypes::Controller> for Controller {
     } else {
       "".to_owned()
     };
+    let atis = match facility {
+      Facility::ATIS => Some(parse_atis(&text_atis)),
+      _ => None,
+    };
     let now = Utc::now();
 
     let logon_time = DateTime::parse_from_rfc3339(&ctrl.logon_time)
@@ -191,6 +222,8 @@ impl From<super::exttypes::Controller> for Controller {
       last_updated,
       logon_time,
       human_readable: None,
+      source: String::new(),
+      atis,
     }
   }
 }
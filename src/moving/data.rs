@@ -52,3 +52,39 @@ impl From<super::exttypes::Data> for Data {
     }
   }
 }
+
+impl Data {
+  pub fn with_source(mut self, tag: &str) -> Self {
+    for pilot in self.pilots.iter_mut() {
+      pilot.source = tag.to_owned();
+    }
+    for ctrl in self.controllers.iter_mut() {
+      ctrl.source = tag.to_owned();
+    }
+    self
+  }
+
+  // shifts every timestamp so that the payload looks updated at the given time
+  pub fn rebased(mut self, updated_at: DateTime<Utc>) -> Self {
+    let shift = updated_at - self.general.updated_at;
+    self.general.updated_at = updated_at;
+    for pilot in self.pilots.iter_mut() {
+      pilot.logon_time += shift;
+      pilot.last_updated += shift;
+    }
+    for ctrl in self.controllers.iter_mut() {
+      ctrl.logon_time += shift;
+      ctrl.last_updated += shift;
+    }
+    self
+  }
+
+  pub fn merge(mut self, other: Self) -> Self {
+    self.general.connected_clients += other.general.connected_clients;
+    self.general.unique_users += other.general.unique_users;
+    self.general.updated_at = self.general.updated_at.max(other.general.updated_at);
+    self.pilots.extend(other.pilots);
+    self.controllers.extend(other.controllers);
+    self
+  }
+}
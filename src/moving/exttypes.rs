@@ -69,3 +69,20 @@ pub struct Data {
   pub controllers: Vec<Controller>,
   pub atis: Vec<Controller>,
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transceiver {
+  pub id: u32,
+  pub frequency: u64,
+  pub lat_deg: f64,
+  pub lon_deg: f64,
+  pub height_msl_m: f64,
+  pub height_agl_m: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransceiverEntry {
+  pub callsign: String,
+  pub transceivers: Vec<Transceiver>,
+}
@@ -1,9 +1,13 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::Serialize;
+use std::sync::Arc;
 
 use crate::{service::camden, types::Point};
 
-use super::aircraft::{guess_aircraft_types, Aircraft};
+use super::{
+  aircraft::{guess_aircraft_types, Aircraft},
+  ratings::pilot_rating_name,
+};
 
 #[derive(Debug, Clone, Serialize, PartialEq)]
 pub struct Pilot {
@@ -23,16 +27,48 @@ pub struct Pilot {
   pub logon_time: DateTime<Utc>,
   pub last_updated: DateTime<Utc>,
   pub aircraft_type: Option<&'static Aircraft>,
+  pub source: String,
+  pub emergency: bool,
+  pub stale: bool,
+  // altitude corrected with the local QNH for pilots below the transition
+  // altitude, feet
+  pub corrected_altitude: Option<i32>,
+  // UTC offsets of the departure and arrival airports, minutes
+  pub departure_utc_offset: Option<i32>,
+  pub arrival_utc_offset: Option<i32>,
+  // ICAO code of the FIR the pilot is within
+  pub current_fir: Option<String>,
 }
 
+const EMERGENCY_SQUAWKS: [&str; 3] = ["7500", "7600", "7700"];
+
 impl Pilot {
+  pub fn is_emergency_squawk(transponder: &str) -> bool {
+    EMERGENCY_SQUAWKS.contains(&transponder)
+  }
+
+  pub fn set_utc_offsets(&mut self, offset: impl Fn(&str) -> Option<i32>) {
+    let (dep, arr) = match &self.flight_plan {
+      Some(fp) => (offset(&fp.departure), offset(&fp.arrival)),
+      None => (None, None),
+    };
+    self.departure_utc_offset = dep;
+    self.arrival_utc_offset = arr;
+  }
+
+  // same pilot flying the same flight plan, logged on again shortly
+  // after the previous session's last update
+  pub fn is_reconnection_of(&self, prev: &Self, grace: Duration) -> bool {
+    self.cid == prev.cid
+      && self.callsign == prev.callsign
+      && !self.flightplan_changed(prev)
+      && self.logon_time - prev.last_updated <= grace
+  }
+
   pub fn flightplan_changed(&self, other: &Self) -> bool {
-    if self.flight_plan.is_some() && other.flight_plan.is_some() {
-      let fp = self.flight_plan.as_ref().unwrap().clone();
-      let ofp = other.flight_plan.as_ref().unwrap().clone();
-      fp != ofp
-    } else {
-      self.flight_plan.is_some() != other.flight_plan.is_some()
+    match (&self.flight_plan, &other.flight_plan) {
+      (Some(fp), Some(ofp)) => fp != ofp,
+      _ => self.flight_plan.is_some() != other.flight_plan.is_some(),
     }
   }
 }
@@ -53,6 +89,39 @@ pub struct FlightPlan {
   pub route: String,
 }
 
+// A pilot at 0,0 with nothing but the callsign set, tests override the
+// fields they care about
+#[cfg(test)]
+impl Pilot {
+  pub fn test_default(callsign: &str) -> Self {
+    Self {
+      cid: 1,
+      name: "".into(),
+      callsign: callsign.into(),
+      server: "".into(),
+      pilot_rating: 0,
+      position: Point { lat: 0.0, lng: 0.0 },
+      altitude: 0,
+      groundspeed: 0,
+      transponder: "".into(),
+      heading: 0,
+      qnh_i_hg: 0,
+      qnh_mb: 0,
+      flight_plan: None,
+      logon_time: Utc::now(),
+      last_updated: Utc::now(),
+      aircraft_type: None,
+      source: "".into(),
+      emergency: false,
+      stale: false,
+      corrected_altitude: None,
+      departure_utc_offset: None,
+      arrival_utc_offset: None,
+      current_fir: None,
+    }
+  }
+}
+
 impl From<crate::moving::exttypes::FlightPlan> for FlightPlan {
   fn from(src: crate::moving::exttypes::FlightPlan) -> Self {
     // Use this type converter to normalise FlightPlan data and
@@ -115,6 +184,8 @@ impl From<crate::moving::exttypes::Pilot> for Pilot {
       None
     };
 
+    let emergency = Pilot::is_emergency_squawk(&src.transponder);
+
     Self {
       cid: src.cid,
       name: src.name,
@@ -135,10 +206,24 @@ impl From<crate::moving::exttypes::Pilot> for Pilot {
       logon_time,
       last_updated,
       aircraft_type,
+      source: String::new(),
+      emergency,
+      // set by the manager as staleness depends on the data timestamp
+      stale: false,
+      corrected_altitude: None,
+      departure_utc_offset: None,
+      arrival_utc_offset: None,
+      current_fir: None,
     }
   }
 }
 
+impl From<Arc<Pilot>> for camden::Pilot {
+  fn from(value: Arc<Pilot>) -> Self {
+    Arc::unwrap_or_clone(value).into()
+  }
+}
+
 impl From<Pilot> for camden::Pilot {
   fn from(value: Pilot) -> Self {
     Self {
@@ -147,6 +232,9 @@ impl From<Pilot> for camden::Pilot {
       callsign: value.callsign,
       server: value.server,
       pilot_rating: value.pilot_rating,
+      pilot_rating_name: pilot_rating_name(value.pilot_rating)
+        .unwrap_or_default()
+        .to_owned(),
       position: Some(value.position.into()),
       altitude: value.altitude,
       groundspeed: value.groundspeed,
@@ -159,6 +247,15 @@ impl From<Pilot> for camden::Pilot {
       logon_time: value.logon_time.timestamp_millis() as u64,
       track: vec![],
       aircraft_type: value.aircraft_type.map(|at| at.into()),
+      source: value.source,
+      emergency: value.emergency,
+      stale: value.stale,
+      corrected_altitude: value.corrected_altitude,
+      departure_utc_offset: value.departure_utc_offset,
+      arrival_utc_offset: value.arrival_utc_offset,
+      current_fir: value.current_fir,
+      transceivers: vec![],
+      offline_since: None,
     }
   }
 }
@@ -0,0 +1,104 @@
+use super::controller::Facility;
+
+// VATSIM controller ratings: id, short and long name
+const CONTROLLER_RATINGS: &[(i32, &str, &str)] = &[
+  (-1, "INA", "Inactive"),
+  (0, "SUS", "Suspended"),
+  (1, "OBS", "Observer"),
+  (2, "S1", "Tower Trainee"),
+  (3, "S2", "Tower Controller"),
+  (4, "S3", "Senior Student"),
+  (5, "C1", "Enroute Controller"),
+  (6, "C2", "Controller 2 (not in use)"),
+  (7, "C3", "Senior Controller"),
+  (8, "I1", "Instructor"),
+  (9, "I2", "Instructor 2 (not in use)"),
+  (10, "I3", "Senior Instructor"),
+  (11, "SUP", "Supervisor"),
+  (12, "ADM", "Administrator"),
+];
+
+// VATSIM pilot ratings, ids grow along with the ratings
+const PILOT_RATINGS: &[(i32, &str, &str)] = &[
+  (0, "NEW", "Basic Member"),
+  (1, "PPL", "Private Pilot License"),
+  (3, "IR", "Instrument Rating"),
+  (7, "CMEL", "Commercial Multi-Engine License"),
+  (15, "ATPL", "Airline Transport Pilot License"),
+  (31, "FI", "Flight Instructor"),
+  (63, "FE", "Flight Examiner"),
+];
+
+fn short_name(table: &[(i32, &'static str, &str)], id: i32) -> Option<&'static str> {
+  table
+    .iter()
+    .find(|(rid, _, _)| *rid == id)
+    .map(|(_, short, _)| *short)
+}
+
+fn by_short_name(table: &[(i32, &str, &str)], name: &str) -> Option<i32> {
+  table
+    .iter()
+    .find(|(_, short, _)| short.eq_ignore_ascii_case(name))
+    .map(|(id, _, _)| *id)
+}
+
+pub fn controller_rating_name(id: i32) -> Option<&'static str> {
+  short_name(CONTROLLER_RATINGS, id)
+}
+
+pub fn controller_rating_by_name(name: &str) -> Option<i32> {
+  by_short_name(CONTROLLER_RATINGS, name)
+}
+
+pub fn controller_rating_names() -> Vec<&'static str> {
+  CONTROLLER_RATINGS
+    .iter()
+    .map(|(_, short, _)| *short)
+    .collect()
+}
+
+pub fn pilot_rating_name(id: i32) -> Option<&'static str> {
+  short_name(PILOT_RATINGS, id)
+}
+
+pub fn pilot_rating_by_name(name: &str) -> Option<i32> {
+  by_short_name(PILOT_RATINGS, name)
+}
+
+pub fn pilot_rating_names() -> Vec<&'static str> {
+  PILOT_RATINGS.iter().map(|(_, short, _)| *short).collect()
+}
+
+// Whether the rating allows working the facility, e.g. S2 for a tower.
+// Supervisors and administrators may open any position
+pub fn controller_rating_valid(facility: &Facility, rating: i32) -> bool {
+  let min_rating = match facility {
+    Facility::Reject => return true,
+    Facility::ATIS | Facility::Delivery | Facility::Ground => 2,
+    Facility::Tower => 3,
+    Facility::Approach => 4,
+    Facility::Radar => 5,
+  };
+  rating >= min_rating
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ratings() {
+    assert_eq!(controller_rating_name(5), Some("C1"));
+    assert_eq!(controller_rating_name(42), None);
+    assert_eq!(controller_rating_by_name("c1"), Some(5));
+    assert_eq!(pilot_rating_name(15), Some("ATPL"));
+    assert_eq!(pilot_rating_by_name("PPL"), Some(1));
+    assert_eq!(pilot_rating_by_name("C1"), None);
+
+    assert!(controller_rating_valid(&Facility::Ground, 2));
+    assert!(!controller_rating_valid(&Facility::Tower, 2));
+    assert!(!controller_rating_valid(&Facility::Radar, 4));
+    assert!(controller_rating_valid(&Facility::Radar, 11));
+  }
+}
@@ -0,0 +1,198 @@
+use crate::{
+  service::camden,
+  types::{split_antimeridian, Point},
+};
+use geo::{HaversineDistance, HaversineIntermediate};
+use geo_types::Point as GeoPoint;
+
+// distance between interpolated points in meters
+const ROUTE_STEP: f64 = 100_000.0;
+// how much farther than the destination a waypoint may be from the
+// previous one, meters. Protects from picking a navaid sharing the ident
+// on the other side of the world
+const MAX_DETOUR: f64 = 500_000.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+  pub ident: String,
+  pub position: Point,
+}
+
+impl From<Waypoint> for camden::Waypoint {
+  fn from(value: Waypoint) -> Self {
+    Self {
+      ident: value.ident,
+      position: Some(value.position.into()),
+    }
+  }
+}
+
+// Great circle between two points split into drawable parts
+pub fn great_circle(from: Point, to: Point) -> Vec<Vec<Point>> {
+  route_polyline(&[from, to])
+}
+
+// Great circles between the consecutive points split into drawable parts
+pub fn route_polyline(points: &[Point]) -> Vec<Vec<Point>> {
+  let mut line: Vec<Point> = vec![];
+  for leg in points.windows(2) {
+    let from: GeoPoint = leg[0].into();
+    let to: GeoPoint = leg[1].into();
+    let fill = from.haversine_intermediate_fill(&to, ROUTE_STEP, true);
+    // every leg starts where the previous one ends
+    let skip = if line.is_empty() { 0 } else { 1 };
+    line.extend(fill.into_iter().skip(skip).map(|p| Point {
+      lat: p.y(),
+      lng: p.x(),
+    }));
+  }
+  split_antimeridian(&line)
+}
+
+// Route tokens which might be navaid idents. Airways, procedures, speed and
+// level changes and coordinates all have digits in them
+fn waypoint_idents(route: &str) -> impl Iterator<Item = &str> {
+  route
+    .split_whitespace()
+    .map(|token| token.split('/').next().unwrap_or_default())
+    .filter(|token| {
+      !token.is_empty()
+        && token.len() <= 5
+        && *token != "DCT"
+        && token.chars().all(|c| c.is_ascii_uppercase())
+    })
+}
+
+// Resolves the flight plan route between the departure and the arrival
+// to waypoints, every ident looked up closest to the previous waypoint
+pub fn expand_route(
+  route: &str,
+  from: Point,
+  to: Point,
+  lookup: impl Fn(&str, Point) -> Option<Point>,
+) -> Vec<Waypoint> {
+  let dest: GeoPoint = to.into();
+  let mut prev = from;
+  let mut waypoints = vec![];
+  for ident in waypoint_idents(route) {
+    let Some(position) = lookup(ident, prev) else {
+      continue;
+    };
+    let prev_geo: GeoPoint = prev.into();
+    let dist = prev_geo.haversine_distance(&position.into());
+    if dist > prev_geo.haversine_distance(&dest) + MAX_DETOUR {
+      continue;
+    }
+    waypoints.push(Waypoint {
+      ident: ident.to_owned(),
+      position,
+    });
+    prev = position;
+  }
+  waypoints
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_great_circle() {
+    // EGLL - KJFK
+    let from = Point {
+      lat: 51.47,
+      lng: -0.45,
+    };
+    let to = Point {
+      lat: 40.64,
+      lng: -73.78,
+    };
+    let parts = great_circle(from, to);
+    assert_eq!(parts.len(), 1);
+    let points = &parts[0];
+    assert!(points.len() > 50);
+    assert_eq!(points[0], from);
+    // the great circle goes well north of both ends
+    assert!(points.iter().any(|p| p.lat > 52.0));
+
+    // RJTT - KSFO crosses the antimeridian
+    let from = Point {
+      lat: 35.55,
+      lng: 139.78,
+    };
+    let to = Point {
+      lat: 37.62,
+      lng: -122.38,
+    };
+    assert_eq!(great_circle(from, to).len(), 2);
+  }
+
+  #[test]
+  fn test_expand_route() {
+    let navaids = [
+      (
+        "BPK",
+        Point {
+          lat: 51.75,
+          lng: -0.11,
+        },
+      ),
+      (
+        "CLN",
+        Point {
+          lat: 51.85,
+          lng: 1.15,
+        },
+      ),
+      // same ident far away
+      (
+        "CLN",
+        Point {
+          lat: -33.0,
+          lng: 151.0,
+        },
+      ),
+      (
+        "SPY",
+        Point {
+          lat: 52.54,
+          lng: 4.85,
+        },
+      ),
+    ];
+    let lookup = |ident: &str, near: Point| {
+      let near: GeoPoint = near.into();
+      navaids
+        .iter()
+        .filter(|(id, _)| *id == ident)
+        .map(|(_, p)| *p)
+        .min_by(|a, b| {
+          let da = near.haversine_distance(&(*a).into());
+          let db = near.haversine_distance(&(*b).into());
+          da.total_cmp(&db)
+        })
+    };
+    // EGLL - EHAM
+    let from = Point {
+      lat: 51.47,
+      lng: -0.45,
+    };
+    let to = Point {
+      lat: 52.31,
+      lng: 4.76,
+    };
+    let route = "N0450F250 BPK7G BPK Q295 CLN/N0440F240 DCT SPY UL9 XXX";
+    let waypoints = expand_route(route, from, to, lookup);
+    let idents: Vec<&str> = waypoints.iter().map(|w| w.ident.as_str()).collect();
+    assert_eq!(idents, vec!["BPK", "CLN", "SPY"]);
+    assert_eq!(waypoints[1].position.lat, 51.85);
+
+    let mut points = vec![from];
+    points.extend(waypoints.iter().map(|w| w.position));
+    points.push(to);
+    let parts = route_polyline(&points);
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0][0], from);
+    assert!(parts[0].contains(&waypoints[1].position));
+  }
+}
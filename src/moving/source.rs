@@ -0,0 +1,207 @@
+use super::{data::Data, exttypes};
+use crate::config::{Source, SourceKind};
+use chrono::Utc;
+use log::error;
+use reqwest::Client;
+use std::{
+  io,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+use tracing::{info_span, Instrument};
+
+#[tonic::async_trait]
+pub trait DataSource: Send + Sync {
+  // source tag propagated onto every pilot and controller fetched
+  fn tag(&self) -> &str;
+  async fn fetch(&self) -> Option<Data>;
+}
+
+// Vatsim v3 JSON API
+pub struct VatsimSource {
+  tag: String,
+  url: String,
+  client: Client,
+}
+
+impl VatsimSource {
+  pub fn new(tag: &str, url: &str, client: Client) -> Self {
+    Self {
+      tag: tag.to_owned(),
+      url: url.to_owned(),
+      client,
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl DataSource for VatsimSource {
+  fn tag(&self) -> &str {
+    &self.tag
+  }
+
+  async fn fetch(&self) -> Option<Data> {
+    let res = async { self.client.get(&self.url).send().await?.bytes().await }
+      .instrument(info_span!("download", source = self.tag))
+      .await;
+    let raw = match res {
+      Ok(raw) => raw,
+      Err(err) => {
+        error!("[{}] error loading vatsim data: {err:?}", self.tag);
+        return None;
+      }
+    };
+    let res = info_span!("parse", source = self.tag)
+      .in_scope(|| serde_json::from_slice::<exttypes::Data>(&raw));
+    let data = match res {
+      Ok(data) => data,
+      Err(err) => {
+        error!("[{}] error parsing vatsim data: {err:?}", self.tag);
+        return None;
+      }
+    };
+    Some(data.into())
+  }
+}
+
+// Local file in Vatsim v3 JSON format, handy for offline development
+pub struct FileSource {
+  tag: String,
+  path: PathBuf,
+}
+
+impl FileSource {
+  pub fn new(tag: &str, path: &str) -> Self {
+    Self {
+      tag: tag.to_owned(),
+      path: PathBuf::from(path.trim_start_matches("file://")),
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl DataSource for FileSource {
+  fn tag(&self) -> &str {
+    &self.tag
+  }
+
+  async fn fetch(&self) -> Option<Data> {
+    let res = std::fs::read_to_string(&self.path);
+    let raw = match res {
+      Ok(raw) => raw,
+      Err(err) => {
+        error!("[{}] error reading {:?}: {err}", self.tag, self.path);
+        return None;
+      }
+    };
+    let res = info_span!("parse", source = self.tag)
+      .in_scope(|| serde_json::from_str::<exttypes::Data>(&raw));
+    match res {
+      Ok(data) => Some(data.into()),
+      Err(err) => {
+        error!("[{}] error parsing {:?}: {err}", self.tag, self.path);
+        None
+      }
+    }
+  }
+}
+
+// Captured payloads in Vatsim v3 JSON format replayed one per fetch, in
+// name order if the path is a directory, starting over once done. Payloads
+// are rebased to the time they're replayed at
+pub struct ReplaySource {
+  tag: String,
+  captures: Vec<FileSource>,
+  next: AtomicUsize,
+}
+
+impl ReplaySource {
+  pub fn new(tag: &str, path: &str) -> io::Result<Self> {
+    let path = PathBuf::from(path.trim_start_matches("file://"));
+    let mut files = vec![];
+    if path.is_dir() {
+      for entry in std::fs::read_dir(&path)? {
+        let file = entry?.path();
+        if file.is_file() {
+          files.push(file);
+        }
+      }
+      files.sort();
+    } else if path.is_file() {
+      files.push(path.clone());
+    }
+    if files.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no captures found at {path:?}"),
+      ));
+    }
+    Ok(Self {
+      tag: tag.to_owned(),
+      captures: files
+        .iter()
+        .map(|file| FileSource::new(tag, &file.to_string_lossy()))
+        .collect(),
+      next: AtomicUsize::new(0),
+    })
+  }
+}
+
+#[tonic::async_trait]
+impl DataSource for ReplaySource {
+  fn tag(&self) -> &str {
+    &self.tag
+  }
+
+  async fn fetch(&self) -> Option<Data> {
+    let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.captures.len();
+    let data = self.captures[idx].fetch().await?;
+    Some(data.rebased(Utc::now()))
+  }
+}
+
+pub fn build_source(src: &Source, client: &Client) -> Arc<dyn DataSource> {
+  match src.kind {
+    SourceKind::Vatsim => Arc::new(VatsimSource::new(&src.tag, &src.url, client.clone())),
+    SourceKind::File => Arc::new(FileSource::new(&src.tag, &src.url)),
+  }
+}
+
+// Fetches all the sources concurrently and merges the results. Returns
+// None only if every source failed
+pub async fn fetch_all(sources: &[Arc<dyn DataSource>]) -> Option<Data> {
+  let handles: Vec<_> = sources
+    .iter()
+    .cloned()
+    .map(|src| {
+      tokio::spawn(
+        async move {
+          let data = src.fetch().await;
+          data.map(|data| data.with_source(src.tag()))
+        }
+        .in_current_span(),
+      )
+    })
+    .collect();
+
+  let mut merged: Option<Data> = None;
+  for handle in handles {
+    let res = handle.await;
+    let data = match res {
+      Ok(Some(data)) => data,
+      Ok(None) => continue,
+      Err(err) => {
+        error!("data source task failed: {err}");
+        continue;
+      }
+    };
+    merged = match merged {
+      None => Some(data),
+      Some(merged) => Some(merged.merge(data)),
+    };
+  }
+  merged
+}
@@ -0,0 +1,132 @@
+use super::exttypes;
+use crate::{service::camden, types::Point};
+use log::error;
+use reqwest::Client;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transceiver {
+  pub id: u32,
+  // kHz, same as Controller.freq
+  pub freq: u32,
+  pub position: Point,
+  pub height_msl: f64,
+  pub height_agl: f64,
+}
+
+impl From<exttypes::Transceiver> for Transceiver {
+  fn from(value: exttypes::Transceiver) -> Self {
+    Self {
+      id: value.id,
+      freq: (value.frequency / 1000) as u32,
+      position: Point {
+        lat: value.lat_deg,
+        lng: value.lon_deg,
+      },
+      height_msl: value.height_msl_m,
+      height_agl: value.height_agl_m,
+    }
+  }
+}
+
+impl From<Transceiver> for camden::Transceiver {
+  fn from(value: Transceiver) -> Self {
+    Self {
+      id: value.id,
+      freq: value.freq,
+      position: Some(value.position.into()),
+      height_msl: value.height_msl,
+      height_agl: value.height_agl,
+    }
+  }
+}
+
+// transceivers of every station connected, by callsign
+pub type Transceivers = HashMap<String, Vec<Transceiver>>;
+
+pub fn tuned_to(transceivers: &[Transceiver], freq: u32) -> bool {
+  transceivers.iter().any(|t| t.freq == freq)
+}
+
+fn parse_transceivers(entries: Vec<exttypes::TransceiverEntry>) -> Transceivers {
+  entries
+    .into_iter()
+    .map(|entry| {
+      let transceivers = entry.transceivers.into_iter().map(|t| t.into()).collect();
+      (entry.callsign, transceivers)
+    })
+    .collect()
+}
+
+pub async fn fetch_transceivers(client: &Client, url: &str) -> Option<Transceivers> {
+  let res = client.get(url).send().await;
+  let response = match res {
+    Ok(response) => response,
+    Err(err) => {
+      error!("error loading transceivers data: {err:?}");
+      return None;
+    }
+  };
+  let res = response.json::<Vec<exttypes::TransceiverEntry>>().await;
+  match res {
+    Ok(entries) => Some(parse_transceivers(entries)),
+    Err(err) => {
+      error!("error parsing transceivers data: {err:?}");
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_transceivers() {
+    let raw = r#"[
+      {
+        "callsign": "EGLL_TWR",
+        "transceivers": [
+          {
+            "id": 0,
+            "frequency": 118505000,
+            "latDeg": 51.4775,
+            "lonDeg": -0.4614,
+            "heightMslM": 25.0,
+            "heightAglM": 0.0
+          }
+        ]
+      },
+      {
+        "callsign": "BAW123",
+        "transceivers": [
+          {
+            "id": 0,
+            "frequency": 118505000,
+            "latDeg": 51.5,
+            "lonDeg": -0.5,
+            "heightMslM": 1200.0,
+            "heightAglM": 1180.0
+          },
+          {
+            "id": 1,
+            "frequency": 121500000,
+            "latDeg": 51.5,
+            "lonDeg": -0.5,
+            "heightMslM": 1200.0,
+            "heightAglM": 1180.0
+          }
+        ]
+      }
+    ]"#;
+    let entries: Vec<exttypes::TransceiverEntry> = serde_json::from_str(raw).unwrap();
+    let trs = parse_transceivers(entries);
+    assert_eq!(trs.len(), 2);
+
+    let pilot = trs.get("BAW123").unwrap();
+    assert_eq!(pilot.len(), 2);
+    assert_eq!(pilot[0].freq, 118505);
+    assert!(tuned_to(pilot, 121500));
+    assert!(!tuned_to(trs.get("EGLL_TWR").unwrap(), 121500));
+  }
+}
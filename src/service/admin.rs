@@ -0,0 +1,107 @@
+use super::{
+  camden::{
+    camden_admin_server::CamdenAdmin, ActiveStreamsResponse, FlushWeatherCacheResponse, NoParams,
+    ReloadConfigResponse, ReloadFixedDataResponse, UnblacklistWeatherRequest,
+    UnblacklistWeatherResponse, WeatherBlacklistResponse,
+  },
+  streams::active_streams,
+};
+use crate::{config::ConfigError, manager::Manager};
+use log::{error, info};
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+#[derive(Debug)]
+pub struct CamdenAdminService {
+  manager: Arc<Manager>,
+}
+
+impl CamdenAdminService {
+  pub fn new(manager: Arc<Manager>) -> Self {
+    Self { manager }
+  }
+}
+
+#[tonic::async_trait]
+impl CamdenAdmin for CamdenAdminService {
+  async fn reload_fixed_data(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<ReloadFixedDataResponse>, Status> {
+    info!("fixed data reload requested");
+    let counts = self
+      .manager
+      .refresh_fixed_data()
+      .await
+      .map_err(|err| Status::internal(format!("error reloading fixed data: {err}")))?;
+    Ok(Response::new(ReloadFixedDataResponse {
+      airports: counts.airports as u64,
+      firs: counts.firs as u64,
+      runways: counts.runways as u64,
+      navaids: counts.navaids as u64,
+    }))
+  }
+
+  async fn reload_config(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<ReloadConfigResponse>, Status> {
+    info!("config reload requested");
+    self.manager.reload_config().await.map_err(|err| {
+      error!("config not reloaded: {err}");
+      match err {
+        ConfigError::NotReloadable(_) => Status::failed_precondition(err.to_string()),
+        ConfigError::Parse(_) | ConfigError::InvalidOverride(_) | ConfigError::Invalid(_) => {
+          Status::invalid_argument(err.to_string())
+        }
+        ConfigError::Io(_) => Status::internal(err.to_string()),
+      }
+    })?;
+    Ok(Response::new(ReloadConfigResponse {
+      path: self.manager.config_path().to_owned(),
+    }))
+  }
+
+  async fn flush_weather_cache(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<FlushWeatherCacheResponse>, Status> {
+    info!("weather cache flush requested");
+    let flushed = self.manager.flush_weather_cache().await;
+    Ok(Response::new(FlushWeatherCacheResponse {
+      flushed: flushed as u64,
+    }))
+  }
+
+  async fn list_weather_blacklist(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<WeatherBlacklistResponse>, Status> {
+    let entries = self.manager.weather_blacklist().await;
+    Ok(Response::new(WeatherBlacklistResponse {
+      entries: entries.into_iter().map(|e| e.into()).collect(),
+    }))
+  }
+
+  async fn unblacklist_weather(
+    &self,
+    request: Request<UnblacklistWeatherRequest>,
+  ) -> Result<Response<UnblacklistWeatherResponse>, Status> {
+    let icao = request.into_inner().icao.to_uppercase();
+    if icao.is_empty() {
+      return Err(Status::invalid_argument("icao is required"));
+    }
+    info!("weather unblacklisting of {icao} requested");
+    let removed = self.manager.unblacklist_weather(&icao).await;
+    Ok(Response::new(UnblacklistWeatherResponse { removed }))
+  }
+
+  async fn list_active_streams(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<ActiveStreamsResponse>, Status> {
+    Ok(Response::new(ActiveStreamsResponse {
+      streams: active_streams().into_iter().map(|s| s.into()).collect(),
+    }))
+  }
+}
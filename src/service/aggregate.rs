@@ -0,0 +1,142 @@
+use super::camden::AggregateGroup;
+use crate::{fixed::types::GeonamesCountry, moving::pilot::Pilot, util::Counter};
+use std::{str::FromStr, sync::Arc};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupBy {
+  Arrival,
+  Departure,
+  Aircraft,
+  Country,
+  Server,
+}
+
+const GROUP_BY_FIELDS: &[&str] = &["arrival", "departure", "aircraft", "country", "server"];
+
+impl FromStr for GroupBy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "arrival" => Ok(Self::Arrival),
+      "departure" => Ok(Self::Departure),
+      "aircraft" => Ok(Self::Aircraft),
+      "country" => Ok(Self::Country),
+      "server" => Ok(Self::Server),
+      _ => Err(format!(
+        "{} is not a valid field to group by, valid fields are: [{}]",
+        s,
+        GROUP_BY_FIELDS.join(", ")
+      )),
+    }
+  }
+}
+
+fn group_key(pilot: &Pilot, country: Option<&GeonamesCountry>, group_by: &GroupBy) -> String {
+  match group_by {
+    GroupBy::Arrival => pilot
+      .flight_plan
+      .as_ref()
+      .map(|fp| fp.arrival.clone())
+      .unwrap_or_default(),
+    GroupBy::Departure => pilot
+      .flight_plan
+      .as_ref()
+      .map(|fp| fp.departure.clone())
+      .unwrap_or_default(),
+    GroupBy::Aircraft => match pilot.aircraft_type {
+      Some(at) => at.designator.to_owned(),
+      None => pilot
+        .flight_plan
+        .as_ref()
+        .map(|fp| fp.aircraft.clone())
+        .unwrap_or_default(),
+    },
+    GroupBy::Server => pilot.server.clone(),
+    GroupBy::Country => country.map(|c| c.iso.clone()).unwrap_or_default(),
+  }
+}
+
+// Groups pilots by the given field. Countries are resolved by the manager
+// and passed in the same order as pilots, they're only used when grouping
+// by country
+pub fn aggregate_pilots(
+  pilots: &[Arc<Pilot>],
+  countries: &[Option<GeonamesCountry>],
+  group_by: &GroupBy,
+) -> Vec<AggregateGroup> {
+  let mut counter = Counter::new();
+  for (i, pilot) in pilots.iter().enumerate() {
+    let country = countries.get(i).and_then(Option::as_ref);
+    counter.inc(group_key(pilot, country, group_by));
+  }
+
+  let mut groups: Vec<AggregateGroup> = counter
+    .iter()
+    .map(|(key, count)| AggregateGroup {
+      key: key.clone(),
+      count: *count as u64,
+    })
+    .collect();
+  groups.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+  groups
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_group_by_parse() {
+    assert_eq!(GroupBy::from_str("Arrival"), Ok(GroupBy::Arrival));
+    assert_eq!(GroupBy::from_str("server"), Ok(GroupBy::Server));
+    assert!(GroupBy::from_str("altitude").is_err());
+  }
+
+  #[test]
+  fn test_aggregate_pilots() {
+    let pilot = |callsign: &str, server: &str| {
+      Arc::new(Pilot {
+        server: server.into(),
+        ..Pilot::test_default(callsign)
+      })
+    };
+    let pilots = vec![
+      pilot("AAA", "UK"),
+      pilot("BBB", "USA-EAST"),
+      pilot("CCC", "GERMANY"),
+      pilot("DDD", "USA-EAST"),
+    ];
+    let country = |iso: &str| {
+      Some(GeonamesCountry {
+        iso: iso.into(),
+        ..Default::default()
+      })
+    };
+    let countries = vec![country("GB"), country("US"), None, country("US")];
+
+    let keys = |groups: Vec<AggregateGroup>| {
+      groups
+        .into_iter()
+        .map(|g| (g.key, g.count))
+        .collect::<Vec<_>>()
+    };
+    // the largest groups come first, ties are ordered by key
+    assert_eq!(
+      keys(aggregate_pilots(&pilots, &countries, &GroupBy::Server)),
+      vec![
+        ("USA-EAST".into(), 2),
+        ("GERMANY".into(), 1),
+        ("UK".into(), 1)
+      ]
+    );
+    assert_eq!(
+      keys(aggregate_pilots(&pilots, &countries, &GroupBy::Country)),
+      vec![("US".into(), 2), ("".into(), 1), ("GB".into(), 1)]
+    );
+    assert_eq!(
+      keys(aggregate_pilots(&pilots, &[], &GroupBy::Country)),
+      vec![("".into(), 4)]
+    );
+  }
+}
@@ -1,13 +1,20 @@
 use crate::{
   fixed::types::{Airport, FIR},
-  moving::pilot::Pilot,
+  manager::events::{ControllerDiff, Diff, PilotDiff},
+  moving::{controller::Controller, pilot::Pilot},
+  weather::sigmet::Hazard,
 };
-use std::collections::{HashMap, HashSet};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+type PilotList = Vec<Arc<Pilot>>;
 
 pub fn calc_pilots_online(
-  pilots: &[Pilot],
-  prev: &mut HashMap<String, Pilot>,
-) -> (Vec<Pilot>, Vec<Pilot>, Vec<Pilot>) {
+  pilots: &[Arc<Pilot>],
+  prev: &mut HashMap<String, Arc<Pilot>>,
+) -> (PilotList, PilotList, PilotList) {
   let mut pilots_add = vec![];
   let mut pilots_delete = vec![];
   let mut pilots_fp = vec![];
@@ -16,12 +23,16 @@ pub fn calc_pilots_online(
   for pilot in pilots.iter() {
     keys.insert(pilot.callsign.clone());
     let existing = prev.get(&pilot.callsign);
-    if existing.is_none() {
-      pilots_add.push(pilot.clone());
-      prev.insert(pilot.callsign.clone(), pilot.clone());
-    } else if existing.unwrap().flightplan_changed(pilot) {
-      pilots_fp.push(pilot.clone());
-      prev.insert(pilot.callsign.clone(), pilot.clone());
+    match existing {
+      None => {
+        pilots_add.push(pilot.clone());
+        prev.insert(pilot.callsign.clone(), pilot.clone());
+      }
+      Some(existing) if existing.flightplan_changed(pilot) => {
+        pilots_fp.push(pilot.clone());
+        prev.insert(pilot.callsign.clone(), pilot.clone());
+      }
+      _ => {}
     }
   }
 
@@ -35,10 +46,63 @@ pub fn calc_pilots_online(
   (pilots_add, pilots_delete, pilots_fp)
 }
 
+pub fn calc_controllers_online(
+  controllers: &[Controller],
+  prev: &mut HashMap<String, Controller>,
+) -> (Vec<Controller>, Vec<Controller>) {
+  let mut ctrls_add = vec![];
+  let mut ctrls_delete = vec![];
+  let mut keys = HashSet::new();
+
+  for ctrl in controllers.iter() {
+    keys.insert(ctrl.callsign.clone());
+    if !prev.contains_key(&ctrl.callsign) {
+      ctrls_add.push(ctrl.clone());
+      prev.insert(ctrl.callsign.clone(), ctrl.clone());
+    }
+  }
+
+  let prev_keys = HashSet::from_iter(prev.keys().cloned());
+  let keys_to_remove = prev_keys.difference(&keys);
+
+  for cs in keys_to_remove {
+    let ctrl = prev.remove(cs).unwrap();
+    ctrls_delete.push(ctrl);
+  }
+  (ctrls_add, ctrls_delete)
+}
+
+pub fn calc_controllers(
+  controllers: &[Controller],
+  prev: &mut HashMap<String, Controller>,
+) -> (Vec<Controller>, Vec<Controller>) {
+  let mut ctrls_set = vec![];
+  let mut ctrls_delete = vec![];
+  let mut keys = HashSet::new();
+
+  for ctrl in controllers.iter() {
+    keys.insert(ctrl.callsign.clone());
+    if prev.get(&ctrl.callsign) == Some(ctrl) {
+      continue;
+    }
+    ctrls_set.push(ctrl.clone());
+    prev.insert(ctrl.callsign.clone(), ctrl.clone());
+  }
+
+  let prev_keys = HashSet::from_iter(prev.keys().cloned());
+  let keys_to_remove = prev_keys.difference(&keys);
+
+  for cs in keys_to_remove {
+    let ctrl = prev.remove(cs).unwrap();
+    ctrls_delete.push(ctrl);
+  }
+  (ctrls_set, ctrls_delete)
+}
+
 pub fn calc_pilots(
-  pilots: &[Pilot],
-  prev: &mut HashMap<String, Pilot>,
-) -> (Vec<Pilot>, Vec<Pilot>) {
+  pilots: &[Arc<Pilot>],
+  prev: &mut HashMap<String, Arc<Pilot>>,
+) -> (PilotList, PilotList) {
   let mut pilots_set = vec![];
   let mut pilots_delete = vec![];
   let mut keys = HashSet::new();
@@ -47,8 +111,9 @@ pub fn calc_pilots(
     keys.insert(pilot.callsign.clone());
     let existing = prev.get(&pilot.callsign);
 
+    // the manager keeps the instance of a pilot until it changes
     if let Some(existing) = existing {
-      if existing == pilot {
+      if Arc::ptr_eq(existing, pilot) || existing == pilot {
         continue;
       }
     }
@@ -68,9 +133,9 @@ pub fn calc_pilots(
 }
 
 pub fn calc_airports(
-  airports: &[Airport],
-  prev: &mut HashMap<String, Airport>,
-) -> (Vec<Airport>, Vec<Airport>) {
+  airports: &[Arc<Airport>],
+  prev: &mut HashMap<String, Arc<Airport>>,
+) -> (Vec<Arc<Airport>>, Vec<Arc<Airport>>) {
   let mut arpts_set = vec![];
   let mut arpts_delete = vec![];
   let mut keys = HashSet::new();
@@ -81,7 +146,7 @@ pub fn calc_airports(
     keys.insert(cmp_id);
 
     if let Some(existing) = existing {
-      if existing == arpt {
+      if Arc::ptr_eq(existing, arpt) || existing == arpt {
         continue;
       }
     }
@@ -100,7 +165,10 @@ pub fn calc_airports(
   (arpts_set, arpts_delete)
 }
 
-pub fn calc_firs(firs: &[FIR], prev: &mut HashMap<String, FIR>) -> (Vec<FIR>, Vec<FIR>) {
+pub fn calc_firs(
+  firs: &[Arc<FIR>],
+  prev: &mut HashMap<String, Arc<FIR>>,
+) -> (Vec<Arc<FIR>>, Vec<Arc<FIR>>) {
   let mut firs_set = vec![];
   let mut firs_delete = vec![];
   let mut keys = HashSet::new();
@@ -109,7 +177,7 @@ pub fn calc_firs(firs: &[FIR], prev: &mut HashMap<String, FIR>) -> (Vec<FIR>, Ve
     let existing = prev.get(&fir.icao);
     keys.insert(fir.icao.clone());
     if let Some(existing) = existing {
-      if existing == fir {
+      if Arc::ptr_eq(existing, fir) || existing == fir {
         continue;
       }
     }
@@ -126,3 +194,166 @@ pub fn calc_firs(firs: &[FIR], prev: &mut HashMap<String, FIR>) -> (Vec<FIR>, Ve
 
   (firs_set, firs_delete)
 }
+
+pub fn calc_hazards(
+  hazards: &[Hazard],
+  prev: &mut HashMap<String, Hazard>,
+) -> (Vec<Hazard>, Vec<Hazard>) {
+  let mut hazards_set = vec![];
+  let mut hazards_delete = vec![];
+  let mut keys = HashSet::new();
+
+  for hazard in hazards.iter() {
+    keys.insert(hazard.id.clone());
+    if prev.get(&hazard.id) == Some(hazard) {
+      continue;
+    }
+    hazards_set.push(hazard.clone());
+    prev.insert(hazard.id.clone(), hazard.clone());
+  }
+
+  let prev_keys = HashSet::from_iter(prev.keys().cloned());
+  let keys_to_remove = prev_keys.difference(&keys);
+  for key in keys_to_remove {
+    let hazard = prev.remove(key).unwrap();
+    hazards_delete.push(hazard);
+  }
+
+  (hazards_set, hazards_delete)
+}
+
+// Intersects a global diff with what a connection is able to see,
+// returning the objects to set and to delete on the client side
+pub fn calc_diff<T: Clone + PartialEq>(
+  diff: &Diff<T>,
+  prev: &mut HashMap<String, T>,
+  key: impl Fn(&T) -> String,
+  visible: impl Fn(&T) -> bool,
+) -> (Vec<T>, Vec<T>) {
+  let mut objs_set = vec![];
+  let mut objs_delete = vec![];
+
+  for obj in diff.set.iter() {
+    let k = key(obj);
+    if visible(obj) {
+      if prev.get(&k) != Some(obj) {
+        objs_set.push(obj.clone());
+        prev.insert(k, obj.clone());
+      }
+    } else if let Some(obj) = prev.remove(&k) {
+      objs_delete.push(obj);
+    }
+  }
+
+  for obj in diff.delete.iter() {
+    if let Some(obj) = prev.remove(&key(obj)) {
+      objs_delete.push(obj);
+    }
+  }
+  (objs_set, objs_delete)
+}
+
+pub fn calc_pilots_online_diff(
+  diff: &PilotDiff,
+  prev: &mut HashMap<String, Arc<Pilot>>,
+) -> (PilotList, PilotList, PilotList) {
+  let mut pilots_add = vec![];
+  let mut pilots_delete = vec![];
+  let mut pilots_fp = vec![];
+
+  for pilot in diff.set.iter() {
+    let existing = prev.insert(pilot.callsign.clone(), pilot.clone());
+    match existing {
+      None => pilots_add.push(pilot.clone()),
+      Some(existing) if existing.flightplan_changed(pilot) => pilots_fp.push(pilot.clone()),
+      _ => {}
+    }
+  }
+
+  for pilot in diff.delete.iter() {
+    if let Some(pilot) = prev.remove(&pilot.callsign) {
+      pilots_delete.push(pilot);
+    }
+  }
+  (pilots_add, pilots_delete, pilots_fp)
+}
+
+pub fn calc_controllers_online_diff(
+  diff: &ControllerDiff,
+  prev: &mut HashMap<String, Controller>,
+) -> (Vec<Controller>, Vec<Controller>) {
+  let mut ctrls_add = vec![];
+  let mut ctrls_delete = vec![];
+
+  for ctrl in diff.set.iter() {
+    let existing = prev.insert(ctrl.callsign.clone(), ctrl.clone());
+    if existing.is_none() {
+      ctrls_add.push(ctrl.clone());
+    }
+  }
+
+  for ctrl in diff.delete.iter() {
+    if let Some(ctrl) = prev.remove(&ctrl.callsign) {
+      ctrls_delete.push(ctrl);
+    }
+  }
+  (ctrls_add, ctrls_delete)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::Point;
+
+  fn pilot(callsign: &str, lat: f64, lng: f64) -> Arc<Pilot> {
+    Arc::new(Pilot {
+      position: Point { lat, lng },
+      ..Pilot::test_default(callsign)
+    })
+  }
+
+  fn diff(set: Vec<Arc<Pilot>>, delete: Vec<Arc<Pilot>>) -> PilotDiff {
+    PilotDiff { set, delete }
+  }
+
+  #[test]
+  fn test_calc_diff() {
+    let mut prev = HashMap::new();
+    let key = |p: &Arc<Pilot>| p.callsign.clone();
+    let visible = |p: &Arc<Pilot>| p.position.lat > 0.0;
+
+    let d = diff(
+      vec![pilot("AAA", 1.0, 0.0), pilot("BBB", -1.0, 0.0)],
+      vec![],
+    );
+    let (set, delete) = calc_diff(&d, &mut prev, key, visible);
+    assert_eq!(set.len(), 1);
+    assert_eq!(set[0].callsign, "AAA");
+    assert!(delete.is_empty());
+
+    // moving out of view deletes, going offline out of view is ignored
+    let d = diff(vec![pilot("AAA", -1.0, 0.0)], vec![pilot("BBB", -1.0, 0.0)]);
+    let (set, delete) = calc_diff(&d, &mut prev, key, visible);
+    assert!(set.is_empty());
+    assert_eq!(delete.len(), 1);
+    assert_eq!(delete[0].callsign, "AAA");
+    assert!(prev.is_empty());
+  }
+
+  #[test]
+  fn test_pilots_online_diff() {
+    let mut prev = HashMap::new();
+    let d = diff(vec![pilot("AAA", 1.0, 0.0)], vec![]);
+    let (add, delete, fp) = calc_pilots_online_diff(&d, &mut prev);
+    assert_eq!((add.len(), delete.len(), fp.len()), (1, 0, 0));
+
+    // position change only is not reported
+    let d = diff(vec![pilot("AAA", 2.0, 0.0)], vec![]);
+    let (add, delete, fp) = calc_pilots_online_diff(&d, &mut prev);
+    assert_eq!((add.len(), delete.len(), fp.len()), (0, 0, 0));
+
+    let d = diff(vec![], vec![pilot("AAA", 2.0, 0.0)]);
+    let (add, delete, fp) = calc_pilots_online_diff(&d, &mut prev);
+    assert_eq!((add.len(), delete.len(), fp.len()), (0, 1, 0));
+  }
+}
@@ -0,0 +1,136 @@
+use super::camden::CountryStats;
+use crate::fixed::types::{Airport, GeonamesCountry, FIR};
+use std::{collections::BTreeMap, sync::Arc};
+
+fn entry<'a>(
+  stats: &'a mut BTreeMap<String, CountryStats>,
+  country: &GeonamesCountry,
+) -> &'a mut CountryStats {
+  stats
+    .entry(country.iso.clone())
+    .or_insert_with(|| CountryStats {
+      iso: country.iso.clone(),
+      name: country.name.clone(),
+      continent: country.continent.clone(),
+      ..Default::default()
+    })
+}
+
+// Online pilots, controllers by facility and controlled airports per
+// geonames country, ordered by the country code. Pilot countries are
+// resolved by the manager, airports and FIRs carry theirs. Objects outside
+// of any country are not counted
+pub fn country_stats(
+  pilot_countries: &[Option<GeonamesCountry>],
+  airports: &[Arc<Airport>],
+  firs: &[Arc<FIR>],
+) -> Vec<CountryStats> {
+  let mut stats = BTreeMap::new();
+  for country in pilot_countries.iter().flatten() {
+    entry(&mut stats, country).pilots += 1;
+  }
+  for arpt in airports.iter().filter(|arpt| !arpt.controllers.is_empty()) {
+    if let Some(country) = arpt.country.as_ref() {
+      let entry = entry(&mut stats, country);
+      entry.controlled_airports += 1;
+      for ctrl in arpt.controllers.iter() {
+        *entry
+          .controllers
+          .entry(ctrl.facility.to_string())
+          .or_default() += 1;
+      }
+    }
+  }
+  for fir in firs.iter() {
+    if let Some(country) = fir.country.as_ref() {
+      let entry = entry(&mut stats, country);
+      *entry.controllers.entry("radar".to_owned()).or_default() += fir.controllers.len() as u32;
+    }
+  }
+  stats.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    moving::controller::{Controller, ControllerSet, Facility},
+    types::Point,
+  };
+  use chrono::Utc;
+  use std::collections::HashMap;
+
+  fn country(iso: &str) -> GeonamesCountry {
+    GeonamesCountry {
+      iso: iso.into(),
+      name: iso.into(),
+      continent: "EU".into(),
+      ..Default::default()
+    }
+  }
+
+  fn controller(callsign: &str, facility: Facility) -> Controller {
+    Controller {
+      cid: 1,
+      name: "".into(),
+      callsign: callsign.into(),
+      freq: 0,
+      facility,
+      rating: 0,
+      server: "".into(),
+      visual_range: 0,
+      atis_code: "".into(),
+      text_atis: "".into(),
+      human_readable: None,
+      last_updated: Utc::now(),
+      logon_time: Utc::now(),
+      source: "".into(),
+      atis: None,
+    }
+  }
+
+  #[test]
+  fn test_country_stats() {
+    let mut controllers = ControllerSet::empty();
+    controllers.tower = Some(controller("EGLL_TWR", Facility::Tower));
+    controllers.ground = Some(controller("EGLL_GND", Facility::Ground));
+    let egll = Airport {
+      icao: "EGLL".into(),
+      iata: "LHR".into(),
+      name: "Heathrow".into(),
+      position: Point { lat: 0.0, lng: 0.0 },
+      fir_id: "EGTT".into(),
+      is_pseudo: false,
+      controllers,
+      runways: HashMap::new(),
+      frequencies: vec![],
+      country: Some(country("GB")),
+      timezone: None,
+      wx: None,
+      covered_by: None,
+      datis: None,
+    };
+    // no controllers of its own
+    let egkk = Airport {
+      icao: "EGKK".into(),
+      controllers: ControllerSet::empty(),
+      ..egll.clone()
+    };
+
+    let pilots = [
+      Some(country("GB")),
+      Some(country("FR")),
+      None,
+      Some(country("GB")),
+    ];
+    let stats = country_stats(&pilots, &[Arc::new(egll), Arc::new(egkk)], &[]);
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].iso, "FR");
+    assert_eq!(stats[0].pilots, 1);
+    assert_eq!(stats[0].controlled_airports, 0);
+    assert_eq!(stats[1].pilots, 2);
+    assert_eq!(stats[1].controlled_airports, 1);
+    assert_eq!(stats[1].controllers["tower"], 1);
+    assert_eq!(stats[1].controllers["ground"], 1);
+  }
+}
@@ -0,0 +1,145 @@
+use super::{
+  camden::{explain_expression::Left, ExplainCondition, ExplainExpression},
+  filter::{compile_filter, normalize_condition},
+};
+use crate::{
+  lee::parser::{
+    condition::Condition,
+    error::CompileError,
+    expression::{CombineOperator, Expression, LeftExpression},
+  },
+  moving::pilot::Pilot,
+};
+
+fn explain_condition(cond: &Condition) -> Result<ExplainCondition, CompileError> {
+  // compile the condition to make sure the explanation
+  // is only returned for queries which are actually valid
+  let cond = cond.clone().precompile()?;
+  let _ = compile_filter(cond.clone())?;
+  let (cond, rewrites) = normalize_condition(cond)?;
+  Ok(ExplainCondition {
+    field: cond.field(),
+    operator: cond.operator.literal().to_owned(),
+    value: cond.value.raw(),
+    value_type: cond.value.value_type().to_owned(),
+    rewrites,
+  })
+}
+
+// Converts a parsed (not yet compiled) expression into its proto representation.
+// Equality chains are folded the way compilation does it, so the tree explained
+// is the one evaluated
+pub fn explain_expression(expr: &mut Expression<Pilot>) -> Result<ExplainExpression, CompileError> {
+  let folded = expr.fold_or_equals();
+  let left = match expr.left.as_mut() {
+    LeftExpression::Expression(expr) => Left::Expression(Box::new(explain_expression(expr)?)),
+    LeftExpression::Condition(cond) => {
+      let mut explained = explain_condition(cond)?;
+      if folded > 0 {
+        explained.rewrites.insert(
+          0,
+          format!(
+            "{folded} {} equality conditions folded into a set",
+            explained.field
+          ),
+        );
+      }
+      Left::Condition(explained)
+    }
+    LeftExpression::CompiledFilter(_) => {
+      return Err(CompileError {
+        msg: "can't explain an already compiled expression".into(),
+      })
+    }
+  };
+
+  let operator = expr.operator.as_ref().map(|op| match op {
+    CombineOperator::And => "and".to_owned(),
+    CombineOperator::Or => "or".to_owned(),
+  });
+
+  let right = match expr.right.as_mut() {
+    Some(right) => Some(Box::new(explain_expression(right)?)),
+    None => None,
+  };
+
+  Ok(ExplainExpression {
+    left: Some(left),
+    operator,
+    right,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lee::make_expr;
+
+  #[test]
+  fn test_explain() {
+    let expr = make_expr::<Pilot>("(rules = \"vfr\" or alt > 3000) and callsign =~ \"^BAW\"");
+    let mut expr = expr.ok().unwrap();
+    let res = explain_expression(&mut expr);
+    assert!(res.is_ok());
+    let res = res.ok().unwrap();
+    assert_eq!(res.operator, Some("and".to_owned()));
+
+    let inner = match res.left.unwrap() {
+      Left::Expression(inner) => inner,
+      _ => panic!("nested expression expected"),
+    };
+    let cond = match inner.left.unwrap() {
+      Left::Condition(cond) => cond,
+      _ => panic!("condition expected"),
+    };
+    assert_eq!(cond.field, "rules");
+    assert_eq!(cond.operator, "==");
+    assert_eq!(cond.value, "V");
+    assert_eq!(cond.rewrites.len(), 1);
+
+    let right = res.right.unwrap();
+    let cond = match right.left.unwrap() {
+      Left::Condition(cond) => cond,
+      _ => panic!("condition expected"),
+    };
+    assert_eq!(cond.field, "callsign");
+    assert_eq!(cond.value_type, "regex");
+    assert!(cond.rewrites.is_empty());
+  }
+
+  #[test]
+  fn test_explain_folded() {
+    let query = "rules == \"ifr\" or rules == \"v\" or alt > 3000";
+    let mut expr = make_expr::<Pilot>(query).ok().unwrap();
+    let res = explain_expression(&mut expr).ok().unwrap();
+    assert_eq!(res.operator, Some("or".to_owned()));
+
+    let cond = match res.left.unwrap() {
+      Left::Condition(cond) => cond,
+      _ => panic!("condition expected"),
+    };
+    assert_eq!(cond.field, "rules");
+    assert_eq!(cond.value, "I, V");
+    assert_eq!(cond.value_type, "set");
+    assert_eq!(
+      cond.rewrites[0],
+      "2 rules equality conditions folded into a set"
+    );
+
+    let right = res.right.unwrap();
+    assert!(right.right.is_none());
+    let cond = match right.left.unwrap() {
+      Left::Condition(cond) => cond,
+      _ => panic!("condition expected"),
+    };
+    assert_eq!(cond.field, "alt");
+  }
+
+  #[test]
+  fn test_explain_invalid_field() {
+    let expr = make_expr::<Pilot>("alt > 3000 and hello == 1");
+    let mut expr = expr.ok().unwrap();
+    let res = explain_expression(&mut expr);
+    assert!(res.is_err());
+  }
+}
@@ -1,12 +1,21 @@
 use crate::{
   lee::parser::{
-    condition::{Condition, Value},
+    condition::{Condition, Operator, Value},
     error::CompileError,
     expression::EvaluateFunc,
   },
-  moving::pilot::Pilot,
+  moving::{
+    controller::Controller,
+    pilot::Pilot,
+    ratings::{
+      controller_rating_by_name, controller_rating_name, controller_rating_names,
+      pilot_rating_by_name, pilot_rating_name, pilot_rating_names,
+    },
+  },
 };
+use chrono::Utc;
 use lazy_static::lazy_static;
+use regex::Regex;
 
 lazy_static! {
   static ref ALLOWED_FIELDS: &'static [&'static str] = &[
@@ -20,72 +29,213 @@ lazy_static! {
     "arrival",
     "departure",
     "rules",
+    "route",
+    "remarks",
+    "logon_time",
+    "last_updated",
+    "online_minutes",
+    "source",
+    "fir",
+    "rating",
   ];
+  static ref ALLOWED_CONTROLLER_FIELDS: &'static [&'static str] =
+    &["callsign", "name", "cid", "facility", "freq", "rating", "server", "source",];
+  static ref STRING_FIELDS: &'static [&'static str] = &[
+    "callsign",
+    "name",
+    "aircraft",
+    "arrival",
+    "departure",
+    "rules",
+    "route",
+    "remarks",
+    "source",
+    "fir",
+  ];
+  static ref CONTROLLER_STRING_FIELDS: &'static [&'static str] =
+    &["callsign", "name", "server", "source",];
+  static ref TIME_FIELDS: &'static [&'static str] = &["logon_time", "last_updated",];
+  static ref FACILITIES: &'static [&'static str] =
+    &["atis", "delivery", "ground", "tower", "approach", "radar",];
+}
+
+fn normalize_rule(v: &str) -> Result<String, CompileError> {
+  let v = v.to_lowercase();
+  let norm_value = match v.as_str() {
+    "i" | "ifr" => "I",
+    "v" | "vfr" => "V",
+    _ => {
+      return Err(CompileError {
+        msg: "invalid rules value, valid ones are ['v', 'i', 'vfr', 'ifr']".into(),
+      })
+    }
+  };
+  Ok(norm_value.to_owned())
+}
+
+fn normalize_rules(value: &Value) -> Result<Value, CompileError> {
+  match value {
+    Value::String(v) => Ok(Value::String(normalize_rule(v)?)),
+    // rules =~ "ifr" matches the normalized rule, as it always has
+    Value::Regex(re) => Ok(Value::Regex(
+      Regex::new(&normalize_rule(re.as_str())?).unwrap(),
+    )),
+    Value::Set(v) => Ok(Value::Set(
+      v.iter()
+        .map(|v| normalize_rule(v))
+        .collect::<Result<_, _>>()?,
+    )),
+    _ => Err(CompileError {
+      msg: format!("invalid rules value type {}", value.value_type()),
+    }),
+  }
+}
+
+// Rating names are turned into ids so that ratings can be compared, sets
+// of names folded from equality chains are kept as names
+fn normalize_rating(
+  value: &Value,
+  by_name: fn(&str) -> Option<i32>,
+  names: fn() -> Vec<&'static str>,
+) -> Result<Value, CompileError> {
+  let invalid = || CompileError {
+    msg: format!(
+      "invalid rating value, valid ones are [{}]",
+      names().join(", ")
+    ),
+  };
+  match value {
+    Value::String(v) => by_name(v)
+      .map(|id| Value::Integer(id as i64))
+      .ok_or_else(invalid),
+    Value::Set(v) => Ok(Value::Set(
+      v.iter()
+        .map(|v| by_name(v).map(|_| v.to_uppercase()).ok_or_else(invalid))
+        .collect::<Result<_, _>>()?,
+    )),
+    Value::Integer(_) | Value::Float(_) => Ok(value.clone()),
+    _ => Err(CompileError {
+      msg: format!("invalid rating value type {}", value.value_type()),
+    }),
+  }
+}
+
+fn eval_rating(
+  value: &Value,
+  rating: i32,
+  name: fn(i32) -> Option<&'static str>,
+  operator: Operator,
+) -> bool {
+  match value {
+    Value::Set(_) => value.eval_str(name(rating).unwrap_or_default(), operator),
+    _ => value.eval_i64(rating as i64, operator),
+  }
+}
+
+// Normalisation pass run before compilation, returns the condition
+// as it is going to be compiled along with the list of rewrites applied
+pub fn normalize_condition(cond: Condition) -> Result<(Condition, Vec<String>), CompileError> {
+  let mut rewrites = vec![];
+  let value = match (cond.ident.as_str(), cond.func) {
+    ("rules", None) => normalize_rules(&cond.value)?,
+    ("rating", None) => normalize_rating(&cond.value, pilot_rating_by_name, pilot_rating_names)?,
+    _ => cond.value.clone(),
+  };
+
+  if value != cond.value {
+    rewrites.push(format!(
+      "{} value {} normalized to {}",
+      cond.ident,
+      cond.value.as_string(),
+      value.as_string()
+    ));
+  }
+
+  Ok((Condition { value, ..cond }, rewrites))
 }
 
 // Compilation callback
-// TODO: add checks for supported condition identifiers
 pub fn compile_filter(cond: Condition) -> Result<Box<EvaluateFunc<Pilot>>, CompileError> {
+  let (cond, _) = normalize_condition(cond)?;
+  check_func(&cond, &STRING_FIELDS)?;
+  check_time_value(&cond, &TIME_FIELDS)?;
+  check_ordered_value(&cond, &["rating"])?;
   let ident = cond.ident.clone();
+  let func = cond.func;
   let value = cond.value.clone();
   let operator = cond.operator.clone();
 
   let evalfunc: Box<EvaluateFunc<Pilot>> = match ident.as_str() {
-    "rules" => {
-      let norm_value = match value {
-        Value::String(v) => {
-          let v = v.to_lowercase();
-          match v.as_str() {
-            "i" | "ifr" => "I",
-            "v" | "vfr" => "V",
-            _ => {
-              return Err(CompileError {
-                msg: "invalid rules value, valid ones are ['v', 'i', 'vfr', 'ifr']".into(),
-              })
-            }
-          }
-        }
-        _ => {
-          return Err(CompileError {
-            msg: format!("invalid rules value type {}", value.value_type()),
-          });
-        }
-      };
-      let norm_value = Value::String(norm_value.to_owned());
-      Box::new(move |pilot| {
-        pilot
-          .flight_plan
-          .as_ref()
-          .map(|fp| norm_value.eval_str(&fp.flight_rules, operator.clone()))
-          .unwrap_or(false)
-      })
+    "rules" => Box::new(move |pilot| {
+      pilot
+        .flight_plan
+        .as_ref()
+        .map(|fp| value.eval_str_func(&fp.flight_rules, func, operator.clone()))
+        .unwrap_or(false)
+    }),
+    "callsign" => {
+      Box::new(move |pilot| value.eval_str_func(&pilot.callsign, func, operator.clone()))
     }
-    "callsign" => Box::new(move |pilot| value.eval_str(&pilot.callsign, operator.clone())),
-    "name" => Box::new(move |pilot| value.eval_str(&pilot.name, operator.clone())),
+    "name" => Box::new(move |pilot| value.eval_str_func(&pilot.name, func, operator.clone())),
+    "source" => Box::new(move |pilot| value.eval_str_func(&pilot.source, func, operator.clone())),
+    "fir" => Box::new(move |pilot| {
+      pilot
+        .current_fir
+        .as_ref()
+        .is_some_and(|fir| value.eval_str_func(fir, func, operator.clone()))
+    }),
     "alt" => Box::new(move |pilot| value.eval_i64(pilot.altitude as i64, operator.clone())),
     "gs" => Box::new(move |pilot| value.eval_i64(pilot.groundspeed as i64, operator.clone())),
     "lat" => Box::new(move |pilot| value.eval_f64(pilot.position.lat, operator.clone())),
     "lng" => Box::new(move |pilot| value.eval_f64(pilot.position.lng, operator.clone())),
     "cid" => Box::new(move |pilot| value.eval_i64(pilot.cid as i64, operator.clone())),
+    "rating" => Box::new(move |pilot| {
+      eval_rating(
+        &value,
+        pilot.pilot_rating,
+        pilot_rating_name,
+        operator.clone(),
+      )
+    }),
     "aircraft" => Box::new(move |pilot| {
       pilot
         .flight_plan
         .as_ref()
-        .map(|fp| value.eval_str(&fp.aircraft, operator.clone()))
+        .map(|fp| value.eval_str_func(&fp.aircraft, func, operator.clone()))
         .unwrap_or(false)
     }),
     "arrival" => Box::new(move |pilot| {
       pilot
         .flight_plan
         .as_ref()
-        .map(|fp| value.eval_str(&fp.arrival, operator.clone()))
+        .map(|fp| value.eval_str_func(&fp.arrival, func, operator.clone()))
         .unwrap_or(false)
     }),
     "departure" => Box::new(move |pilot| {
       pilot
         .flight_plan
         .as_ref()
-        .map(|fp| value.eval_str(&fp.departure, operator.clone()))
+        .map(|fp| value.eval_str_func(&fp.departure, func, operator.clone()))
+        .unwrap_or(false)
+    }),
+    "logon_time" => Box::new(move |pilot| value.eval_time(pilot.logon_time, operator.clone())),
+    "last_updated" => Box::new(move |pilot| value.eval_time(pilot.last_updated, operator.clone())),
+    "online_minutes" => Box::new(move |pilot| {
+      let online = Utc::now() - pilot.logon_time;
+      value.eval_i64(online.num_minutes(), operator.clone())
+    }),
+    "route" => Box::new(move |pilot| {
+      pilot
+        .flight_plan
+        .as_ref()
+        .map(|fp| value.eval_str_func(&fp.route, func, operator.clone()))
+        .unwrap_or(false)
+    }),
+    "remarks" => Box::new(move |pilot| {
+      pilot
+        .flight_plan
+        .as_ref()
+        .map(|fp| value.eval_str_func(&fp.remarks, func, operator.clone()))
         .unwrap_or(false)
     }),
     _ => {
@@ -101,13 +251,213 @@ pub fn compile_filter(cond: Condition) -> Result<Box<EvaluateFunc<Pilot>>, Compi
   Ok(evalfunc)
 }
 
+fn check_func(cond: &Condition, string_fields: &[&str]) -> Result<(), CompileError> {
+  match cond.func {
+    Some(func) if !string_fields.contains(&cond.ident.as_str()) => Err(CompileError {
+      msg: format!(
+        "{}() can only be applied to string fields: [{}]",
+        func.literal(),
+        string_fields.join(", ")
+      ),
+    }),
+    _ => Ok(()),
+  }
+}
+
+fn check_ordered_value(cond: &Condition, ordered_fields: &[&str]) -> Result<(), CompileError> {
+  let ordering = matches!(
+    cond.operator,
+    Operator::Less | Operator::LessOrEqual | Operator::Greater | Operator::GreaterOrEqual
+  );
+  match cond.value {
+    Value::String(_) if ordering && !ordered_fields.contains(&cond.ident.as_str()) => {
+      Err(CompileError {
+        msg: format!(
+          "strings can only be compared to ordered fields: [{}]",
+          ordered_fields.join(", ")
+        ),
+      })
+    }
+    _ => Ok(()),
+  }
+}
+
+fn check_time_value(cond: &Condition, time_fields: &[&str]) -> Result<(), CompileError> {
+  match cond.value {
+    Value::Time(_) if !time_fields.contains(&cond.ident.as_str()) => Err(CompileError {
+      msg: format!(
+        "relative time values can only be compared to time fields: [{}]",
+        time_fields.join(", ")
+      ),
+    }),
+    _ => Ok(()),
+  }
+}
+
+fn normalize_facility_name(v: &str) -> Result<String, CompileError> {
+  let v = v.to_lowercase();
+  if FACILITIES.contains(&v.as_str()) {
+    Ok(v)
+  } else {
+    Err(CompileError {
+      msg: format!(
+        "invalid facility value, valid ones are [{}]",
+        FACILITIES.join(", ")
+      ),
+    })
+  }
+}
+
+fn normalize_facility(value: &Value) -> Result<Value, CompileError> {
+  match value {
+    Value::String(v) => Ok(Value::String(normalize_facility_name(v)?)),
+    Value::Set(v) => Ok(Value::Set(
+      v.iter()
+        .map(|v| normalize_facility_name(v))
+        .collect::<Result<_, _>>()?,
+    )),
+    Value::Regex(_) => Ok(value.clone()),
+    _ => Err(CompileError {
+      msg: format!("invalid facility value type {}", value.value_type()),
+    }),
+  }
+}
+
+// Compilation callback for controller subscriptions
+pub fn compile_controller_filter(
+  cond: Condition,
+) -> Result<Box<EvaluateFunc<Controller>>, CompileError> {
+  check_func(&cond, &CONTROLLER_STRING_FIELDS)?;
+  check_time_value(&cond, &[])?;
+  check_ordered_value(&cond, &["rating"])?;
+  let ident = cond.ident.clone();
+  let func = cond.func;
+  let value = cond.value.clone();
+  let operator = cond.operator.clone();
+
+  let evalfunc: Box<EvaluateFunc<Controller>> = match ident.as_str() {
+    "callsign" => Box::new(move |ctrl| value.eval_str_func(&ctrl.callsign, func, operator.clone())),
+    "name" => Box::new(move |ctrl| value.eval_str_func(&ctrl.name, func, operator.clone())),
+    "cid" => Box::new(move |ctrl| value.eval_i64(ctrl.cid as i64, operator.clone())),
+    "facility" => {
+      let value = normalize_facility(&value)?;
+      Box::new(move |ctrl| value.eval_str(&ctrl.facility.to_string(), operator.clone()))
+    }
+    "freq" => Box::new(move |ctrl| value.eval_i64(ctrl.freq as i64, operator.clone())),
+    "rating" => {
+      let value = normalize_rating(&value, controller_rating_by_name, controller_rating_names)?;
+      Box::new(move |ctrl| {
+        eval_rating(
+          &value,
+          ctrl.rating,
+          controller_rating_name,
+          operator.clone(),
+        )
+      })
+    }
+    "server" => Box::new(move |ctrl| value.eval_str_func(&ctrl.server, func, operator.clone())),
+    "source" => Box::new(move |ctrl| value.eval_str_func(&ctrl.source, func, operator.clone())),
+    _ => {
+      return Err(CompileError {
+        msg: format!(
+          "{} is not a valid controller field to query, valid fields are: [{}]",
+          cond.ident,
+          ALLOWED_CONTROLLER_FIELDS.join(", ")
+        ),
+      })
+    }
+  };
+  Ok(evalfunc)
+}
+
 #[cfg(test)]
 pub mod tests {
-  use super::compile_filter;
+  use super::{compile_controller_filter, compile_filter, normalize_condition};
   use crate::{
-    lee::{make_expr, parser::expression::CompileFunc},
-    moving::pilot::Pilot,
+    lee::{
+      make_expr,
+      parser::{
+        condition::Value,
+        expression::{CompileFunc, LeftExpression},
+      },
+    },
+    moving::{
+      controller::{Controller, Facility},
+      pilot::{FlightPlan, Pilot},
+    },
   };
+  use chrono::Utc;
+
+  fn controller(callsign: &str) -> Controller {
+    Controller {
+      cid: 1,
+      name: "".into(),
+      callsign: callsign.into(),
+      freq: 118500,
+      facility: Facility::Tower,
+      rating: 0,
+      server: "".into(),
+      visual_range: 0,
+      atis_code: "".into(),
+      text_atis: "".into(),
+      human_readable: None,
+      last_updated: Utc::now(),
+      logon_time: Utc::now(),
+      source: "".into(),
+      atis: None,
+    }
+  }
+
+  #[test]
+  fn test_normalize_rules() {
+    let res = make_expr::<Pilot>("rules == \"ifr\"");
+    let expr = res.ok().unwrap();
+    let cond = match expr.left.as_ref() {
+      LeftExpression::Condition(cond) => cond.clone(),
+      _ => unreachable!(),
+    };
+    let res = normalize_condition(cond);
+    assert!(res.is_ok());
+    let (cond, rewrites) = res.ok().unwrap();
+    assert!(cond.value == Value::String("I".into()));
+    assert_eq!(rewrites.len(), 1);
+
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+    let mut expr = make_expr::<Pilot>("rules =~ \"ifr\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_ok());
+    let pilot = |rules: &str| Pilot {
+      flight_plan: Some(FlightPlan {
+        flight_rules: rules.into(),
+        aircraft: "".into(),
+        departure: "".into(),
+        arrival: "".into(),
+        alternate: "".into(),
+        cruise_tas: 0,
+        altitude: 0,
+        deptime: "".into(),
+        enroute_time: "".into(),
+        fuel_time: "".into(),
+        remarks: "".into(),
+        route: "".into(),
+      }),
+      ..Pilot::test_default("AAA")
+    };
+    assert!(expr.evaluate(&pilot("I")));
+    assert!(!expr.evaluate(&pilot("V")));
+
+    let mut expr = make_expr::<Pilot>("rules =~ \"^I\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
+
+  #[test]
+  fn test_invalid_regex() {
+    let res = make_expr::<Pilot>("callsign =~ \"^(AFL\"");
+    assert!(res.is_ok());
+    let mut expr = res.ok().unwrap();
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+    let res = expr.compile(&cb);
+    assert!(res.is_err());
+  }
 
   #[test]
   fn test_invalid_field() {
@@ -119,4 +469,94 @@ pub mod tests {
     let res = expr.compile(&cb);
     assert!(res.is_err());
   }
+
+  #[test]
+  fn test_controller_filter() {
+    let cb: Box<CompileFunc<Controller>> = Box::new(compile_controller_filter);
+
+    let mut expr = make_expr::<Controller>("facility == \"Tower\" and freq == 118500")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_ok());
+
+    let mut expr = make_expr::<Controller>("facility == \"center\"")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_err());
+
+    let mut expr = make_expr::<Controller>("alt > 3000").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
+
+  #[test]
+  fn test_rating_filter() {
+    let cb: Box<CompileFunc<Controller>> = Box::new(compile_controller_filter);
+    let mut ctrl = controller("EGLL_TWR");
+
+    let mut expr = make_expr::<Controller>("rating >= \"C1\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_ok());
+    ctrl.rating = 3;
+    assert!(!expr.evaluate(&ctrl));
+    ctrl.rating = 7;
+    assert!(expr.evaluate(&ctrl));
+
+    let mut expr = make_expr::<Controller>("rating == \"s2\" or rating == \"S3\"")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_ok());
+    assert!(!expr.evaluate(&ctrl));
+    ctrl.rating = 3;
+    assert!(expr.evaluate(&ctrl));
+
+    let mut expr = make_expr::<Controller>("rating >= \"ATPL\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+    let mut expr = make_expr::<Pilot>("rating >= \"IR\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_ok());
+    let mut expr = make_expr::<Pilot>("callsign > \"AFL\"").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
+
+  #[test]
+  fn test_len_only_on_string_fields() {
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+
+    let mut expr = make_expr::<Pilot>("len(remarks) > 0 and route contains \"UL620\"")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_ok());
+
+    let mut expr = make_expr::<Pilot>("len(alt) > 3").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
+
+  #[test]
+  fn test_time_fields() {
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+
+    let mut expr = make_expr::<Pilot>("logon_time < now-6h or online_minutes > 180")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_ok());
+
+    let mut expr = make_expr::<Pilot>("alt > now-1h").ok().unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
+
+  #[test]
+  fn test_or_equals_set() {
+    let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+    let mut expr = make_expr::<Pilot>("rules == \"ifr\" or rules == \"v\"")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_ok());
+    assert!(expr.operator.is_none());
+
+    let cb: Box<CompileFunc<Controller>> = Box::new(compile_controller_filter);
+    let mut expr = make_expr::<Controller>("facility == \"tower\" or facility == \"center\"")
+      .ok()
+      .unwrap();
+    assert!(expr.compile(&cb).is_err());
+  }
 }
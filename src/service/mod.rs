@@ -2,25 +2,63 @@ pub mod camden {
   tonic::include_proto!("camden");
 }
 
+pub mod admin;
+mod aggregate;
 mod calc;
+mod countries;
+mod explain;
 mod filter;
+pub mod quota;
+pub mod rpc_metrics;
+pub mod streams;
+mod view;
+pub mod web;
 
-use crate::lee::parser::expression::CompileFunc;
-use crate::manager::Manager;
-use crate::moving::pilot::Pilot;
-use crate::service::filter::compile_filter;
-use crate::types::Rect;
-use crate::util::seconds_since;
-use crate::{lee::make_expr, util::proxy_requests};
+use crate::fixed::types::{Airport, FIR};
+use crate::lee::parser::expression::{flush_evaluations, CompileFunc, Expression, Limits};
+use crate::manager::{
+  events::{CycleDiff, EventBatch},
+  geofence::Shape,
+  ingest::PilotProtos,
+  Manager,
+};
+use crate::moving::{
+  aircraft::lookup_aircraft,
+  controller::{Controller, Facility},
+  pilot::Pilot,
+  route::route_polyline,
+};
+use crate::service::{
+  aggregate::{aggregate_pilots, GroupBy},
+  countries::country_stats,
+  explain::explain_expression,
+  filter::{compile_controller_filter, compile_filter},
+  streams::StreamHandle,
+  view::{BoundariesDetail, MapState, MapView},
+};
+use crate::track::export::{export_track, ExportFormat};
+use crate::types::{Point, Rect};
+use crate::util::{from_millis, seconds_since};
+use crate::weather::sigmet::Hazard;
+use crate::{lee::make_expr_limited, util::proxy_requests};
 use camden::{
-  camden_server::Camden, map_updates_request::Request as ServiceRequest, update::ObjectUpdate,
-  AirportRequest, AirportResponse, AirportUpdate, BuildInfoResponse, FirUpdate, MapUpdatesRequest,
-  MetricSet, MetricSetTextResponse, NoParams, PilotListResponse, PilotRequest, PilotResponse,
-  PilotUpdate, QueryRequest, QueryResponse, QuerySubscriptionRequest, QuerySubscriptionRequestType,
-  QuerySubscriptionUpdate, QuerySubscriptionUpdateType, Update, UpdateType,
+  camden_server::Camden, update::ObjectUpdate, AggregateRequest, AggregateResponse,
+  AircraftListResponse, AircraftRequest, AirportCandidate, AirportCandidatesResponse,
+  AirportMovementsRequest, AirportMovementsResponse, AirportRequest, AirportResponse,
+  AirportUpdate, BuildInfoResponse, ControllerListRequest, ControllerListResponse,
+  ControllerRequest, ControllerResponse, ControllerUpdate, CountryStatsResponse, DataNotice,
+  DeleteGeofenceResponse, ExplainQueryResponse, ExportTrackRequest, ExportTrackResponse, FirUpdate,
+  FrequencyRequest, FrequencyResponse, Geofence, GeofenceEvent, GeofenceListResponse,
+  GeofenceRequest, GeofenceSubscriptionRequest, HazardUpdate, HealthResponse, HourlyMovements,
+  MapUpdatesRequest, MetricSet, MetricSetTextResponse, MetricsStreamRequest, NavaidListRequest,
+  NavaidListResponse, NavaidRequest, NoParams, PilotListResponse, PilotRequest, PilotResponse,
+  PilotSessionsRequest, PilotSessionsResponse, PilotUpdate, QueryRequest, QueryResponse,
+  QuerySubscriptionRequest, QuerySubscriptionRequestType, QuerySubscriptionTarget,
+  QuerySubscriptionUpdate, QuerySubscriptionUpdateType, RoutePolylineResponse, TrackChunk,
+  TrackExportFormat, TrackStatsResponse, Update, UpdateType, WindsAloftRequest, WindsAloftResponse,
 };
-use chrono::Utc;
-use log::{debug, info};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
 use std::{
   collections::hash_map::Entry,
   collections::{HashMap, HashSet},
@@ -28,38 +66,341 @@ use std::{
   sync::Arc,
   time::Duration,
 };
-use tokio::sync::mpsc::{self, error::TryRecvError};
-use tokio::time::sleep;
+use tokio::{
+  sync::{broadcast::error::RecvError, mpsc, watch},
+  time::{interval, MissedTickBehavior},
+};
 use tokio_stream::Stream;
 use tonic::{Request, Response, Status, Streaming};
 
+// number of track points sent in one StreamPilotTrack message
+const TRACK_CHUNK_SIZE: usize = 1000;
+
 #[derive(Debug)]
 pub struct CamdenService {
   manager: Arc<Manager>,
 }
 
+impl From<TrackExportFormat> for ExportFormat {
+  fn from(value: TrackExportFormat) -> Self {
+    match value {
+      TrackExportFormat::Geojson => Self::GeoJson,
+      TrackExportFormat::Gpx => Self::Gpx,
+      TrackExportFormat::Kml => Self::Kml,
+    }
+  }
+}
+
 impl CamdenService {
   pub fn new(manager: Arc<Manager>) -> Self {
     Self { manager }
   }
+
+  // online pilot by callsign, falling back to the ones gone offline
+  // recently if requested, along with the time they were last seen
+  async fn find_pilot(
+    &self,
+    request: &PilotRequest,
+  ) -> Option<(Arc<Pilot>, Option<DateTime<Utc>>)> {
+    let pilot = self.manager.get_pilot_by_callsign(&request.callsign).await;
+    if let Some(pilot) = pilot {
+      return Some((pilot, None));
+    }
+    if !request.include_offline {
+      return None;
+    }
+    self
+      .manager
+      .get_offline_pilot(&request.callsign)
+      .await
+      .map(|(pilot, since)| (pilot, Some(since)))
+  }
 }
 
-// if zoom is less than this, the map might be wrapped on screen, thus we
-// need to show all the objects without checking current user map boundaries
-const MIN_ZOOM: f64 = 3.0;
+fn apply_query(query: &str, limits: &Limits, pilots: &mut Vec<Arc<Pilot>>) -> Result<(), String> {
+  if query.is_empty() {
+    return Ok(());
+  }
+
+  let mut expr =
+    make_expr_limited::<Pilot>(query, limits).map_err(|err| format!("query parse error: {err}"))?;
+  let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+  expr
+    .compile(&cb)
+    .map_err(|err| format!("query compile error: {err}"))?;
+  pilots.retain(|pilot| expr.evaluate(pilot));
+  flush_evaluations();
+  Ok(())
+}
+
+enum SubscriptionFilter {
+  Pilot(Expression<Pilot>),
+  Controller(Expression<Controller>),
+}
+
+impl SubscriptionFilter {
+  fn new(query: &str, target: i32, limits: &Limits) -> Option<Self> {
+    const CONTROLLER: i32 = QuerySubscriptionTarget::QstController as i32;
+    match target {
+      CONTROLLER => {
+        let mut expr = make_expr_limited::<Controller>(query, limits).ok()?;
+        let cb: Box<CompileFunc<Controller>> = Box::new(compile_controller_filter);
+        expr.compile(&cb).ok()?;
+        Some(Self::Controller(expr))
+      }
+      _ => {
+        let mut expr = make_expr_limited::<Pilot>(query, limits).ok()?;
+        let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+        expr.compile(&cb).ok()?;
+        Some(Self::Pilot(expr))
+      }
+    }
+  }
+
+  fn matches_pilot(&self, pilot: &Pilot) -> bool {
+    match self {
+      Self::Pilot(expr) => expr.evaluate(pilot),
+      Self::Controller(_) => false,
+    }
+  }
+
+  fn matches_controller(&self, ctrl: &Controller) -> bool {
+    match self {
+      Self::Pilot(_) => false,
+      Self::Controller(expr) => expr.evaluate(ctrl),
+    }
+  }
+}
+
+impl Update {
+  // every map update carries the current state of the service
+  fn stamp(mut self, degraded: &watch::Receiver<bool>) -> Self {
+    self.degraded = *degraded.borrow();
+    self
+  }
+
+  fn data_notice(stale: bool, vatsim_data_timestamp: i64) -> Self {
+    let age = Utc::now().timestamp() - vatsim_data_timestamp;
+    Self {
+      object_update: Some(ObjectUpdate::DataNotice(DataNotice {
+        stale,
+        vatsim_data_timestamp: vatsim_data_timestamp as u64,
+        data_age_sec: age.max(0) as u64,
+      })),
+      degraded: false,
+    }
+  }
+}
+
+// whatever wakes a streaming handler up
+enum Input<T> {
+  Request(Option<T>),
+  Events(Result<EventBatch, RecvError>),
+  Degraded(Result<(), watch::error::RecvError>),
+  DataStale(Result<(), watch::error::RecvError>),
+}
+
+#[derive(Default)]
+struct OnlineChanges {
+  pilots_add: Vec<Arc<Pilot>>,
+  pilots_delete: Vec<Arc<Pilot>>,
+  pilots_fp: Vec<Arc<Pilot>>,
+  ctrls_add: Vec<Controller>,
+  ctrls_delete: Vec<Controller>,
+}
+
+impl OnlineChanges {
+  fn apply_diff(
+    &mut self,
+    diff: &CycleDiff,
+    pilots_state: &mut HashMap<String, Arc<Pilot>>,
+    controllers_state: &mut HashMap<String, Controller>,
+  ) {
+    let (pilots_add, pilots_delete, pilots_fp) =
+      calc::calc_pilots_online_diff(&diff.pilots, pilots_state);
+    let (ctrls_add, ctrls_delete) =
+      calc::calc_controllers_online_diff(&diff.controllers, controllers_state);
+    self.pilots_add.extend(pilots_add);
+    self.pilots_delete.extend(pilots_delete);
+    self.pilots_fp.extend(pilots_fp);
+    self.ctrls_add.extend(ctrls_add);
+    self.ctrls_delete.extend(ctrls_delete);
+  }
+
+  fn subscription_updates(
+    &self,
+    subscriptions: &HashMap<String, SubscriptionFilter>,
+    protos: &PilotProtos,
+  ) -> Vec<QuerySubscriptionUpdate> {
+    let mut updates = vec![];
+    let pilots = [
+      (&self.pilots_add, QuerySubscriptionUpdateType::Online),
+      (&self.pilots_fp, QuerySubscriptionUpdateType::Flightplan),
+      (&self.pilots_delete, QuerySubscriptionUpdateType::Offline),
+    ];
+    for (list, update_type) in pilots {
+      for pilot in list.iter() {
+        for (id, filter) in subscriptions.iter() {
+          if filter.matches_pilot(pilot) {
+            updates.push(QuerySubscriptionUpdate {
+              subscription_id: id.to_owned(),
+              update_type: update_type as i32,
+              pilot: Some(protos.get(pilot.clone())),
+              controller: None,
+            });
+          }
+        }
+      }
+    }
+
+    let controllers = [
+      (&self.ctrls_add, QuerySubscriptionUpdateType::Online),
+      (&self.ctrls_delete, QuerySubscriptionUpdateType::Offline),
+    ];
+    for (list, update_type) in controllers {
+      for ctrl in list.iter() {
+        for (id, filter) in subscriptions.iter() {
+          if filter.matches_controller(ctrl) {
+            updates.push(QuerySubscriptionUpdate {
+              subscription_id: id.to_owned(),
+              update_type: update_type as i32,
+              pilot: None,
+              controller: Some(ctrl.clone().into()),
+            });
+          }
+        }
+      }
+    }
+    flush_evaluations();
+    updates
+  }
+}
+
+fn pilot_updates(
+  set: Vec<Arc<Pilot>>,
+  delete: Vec<Arc<Pilot>>,
+  protos: &PilotProtos,
+) -> Vec<Update> {
+  [(set, UpdateType::Set), (delete, UpdateType::Delete)]
+    .into_iter()
+    .filter(|(pilots, _)| !pilots.is_empty())
+    .map(|(pilots, update_type)| Update {
+      object_update: Some(ObjectUpdate::PilotUpdate(PilotUpdate {
+        update_type: update_type as i32,
+        pilots: pilots.into_iter().map(|p| protos.get(p)).collect(),
+      })),
+      degraded: false,
+    })
+    .collect()
+}
+
+fn airport_updates(set: Vec<Arc<Airport>>, delete: Vec<Arc<Airport>>) -> Vec<Update> {
+  [(set, UpdateType::Set), (delete, UpdateType::Delete)]
+    .into_iter()
+    .filter(|(airports, _)| !airports.is_empty())
+    .map(|(airports, update_type)| Update {
+      object_update: Some(ObjectUpdate::AirportUpdate(AirportUpdate {
+        update_type: update_type as i32,
+        airports: airports.into_iter().map(|a| a.into()).collect(),
+      })),
+      degraded: false,
+    })
+    .collect()
+}
+
+fn controller_updates(set: Vec<Controller>, delete: Vec<Controller>) -> Vec<Update> {
+  [(set, UpdateType::Set), (delete, UpdateType::Delete)]
+    .into_iter()
+    .filter(|(controllers, _)| !controllers.is_empty())
+    .map(|(controllers, update_type)| Update {
+      object_update: Some(ObjectUpdate::ControllerUpdate(ControllerUpdate {
+        update_type: update_type as i32,
+        controllers: controllers.into_iter().map(|c| c.into()).collect(),
+      })),
+      degraded: false,
+    })
+    .collect()
+}
+
+// Controllers are few, so the overlay is simply recalculated whenever
+// any of them changes
+async fn controller_changes(
+  manager: &Manager,
+  view: &MapView,
+  state: &mut MapState,
+) -> Vec<Update> {
+  let controllers = match view.bounds.as_ref() {
+    Some(_) if !view.show_controllers => vec![],
+    Some(_) if view.no_bounds => manager.get_all_controllers().await,
+    Some(rect) => manager.get_controllers(rect).await,
+    None => return vec![],
+  };
+  let (ctrls_set, ctrls_delete) = calc::calc_controllers(&controllers, &mut state.controllers);
+  controller_updates(ctrls_set, ctrls_delete)
+}
+
+async fn cluster_changes(manager: &Manager, view: &MapView, state: &mut MapState) -> Vec<Update> {
+  let pilots = match view.bounds.as_ref() {
+    Some(_) if view.budget.is_none() || view.filter.is_some() => vec![],
+    Some(_) if view.no_bounds => manager.get_all_pilots().await,
+    Some(rect) => manager.get_pilots(rect, &HashSet::new()).await,
+    None => return vec![],
+  };
+  state.cluster_updates(&pilots, view)
+}
+
+fn fir_updates(
+  set: Vec<Arc<FIR>>,
+  delete: Vec<Arc<FIR>>,
+  detail: &BoundariesDetail,
+) -> Vec<Update> {
+  [(set, UpdateType::Set), (delete, UpdateType::Delete)]
+    .into_iter()
+    .filter(|(firs, _)| !firs.is_empty())
+    .map(|(firs, update_type)| Update {
+      object_update: Some(ObjectUpdate::FirUpdate(FirUpdate {
+        update_type: update_type as i32,
+        firs: firs.into_iter().map(|f| detail.apply(f).into()).collect(),
+      })),
+      degraded: false,
+    })
+    .collect()
+}
+
+fn hazard_updates(set: Vec<Hazard>, delete: Vec<Hazard>) -> Vec<Update> {
+  [(set, UpdateType::Set), (delete, UpdateType::Delete)]
+    .into_iter()
+    .filter(|(hazards, _)| !hazards.is_empty())
+    .map(|(hazards, update_type)| Update {
+      object_update: Some(ObjectUpdate::HazardUpdate(HazardUpdate {
+        update_type: update_type as i32,
+        hazards: hazards.into_iter().map(|h| h.into()).collect(),
+      })),
+      degraded: false,
+    })
+    .collect()
+}
 
 #[tonic::async_trait]
 impl Camden for CamdenService {
   type MapUpdatesStream = Pin<Box<dyn Stream<Item = Result<Update, Status>> + Send + 'static>>;
   type SubscribeQueryStream =
     Pin<Box<dyn Stream<Item = Result<QuerySubscriptionUpdate, Status>> + Send + 'static>>;
+  type StreamPilotTrackStream =
+    Pin<Box<dyn Stream<Item = Result<TrackChunk, Status>> + Send + 'static>>;
+  type SubscribeGeofenceStream =
+    Pin<Box<dyn Stream<Item = Result<GeofenceEvent, Status>> + Send + 'static>>;
+  type StreamMetricsStream =
+    Pin<Box<dyn Stream<Item = Result<MetricSet, Status>> + Send + 'static>>;
 
   async fn subscribe_query(
     &self,
     request: Request<Streaming<QuerySubscriptionRequest>>,
   ) -> Result<Response<Self::SubscribeQueryStream>, Status> {
     let manager = self.manager.clone();
+    let limits = manager.config().query.limits();
     let remote = request.remote_addr().unwrap();
+    let stream_handle = StreamHandle::register("subscribe_query", remote.to_string());
     let remote = format!("subscribe_query:{:?}", remote);
     info!("[{remote}] client connected");
     let stream = request.into_inner();
@@ -67,21 +408,35 @@ impl Camden for CamdenService {
     let (tx, rx) = mpsc::channel(100);
     tokio::spawn(async move { proxy_requests(stream, tx).await });
     let mut pilots_state = HashMap::new();
+    let mut controllers_state = HashMap::new();
     let mut subscriptions = HashMap::new();
+    let mut queries = HashMap::new();
+    // subscribe before taking the initial snapshot so that nothing is missed
+    let mut events = manager.subscribe_events();
 
+    let handle = stream_handle.clone();
     let output = async_stream::try_stream! {
       let mut rx = rx;
-      let mut next_update = Utc::now();
+
+      // only the changes happening after the client has connected are reported
+      let mut last_seq = manager.last_diff_seq().await;
+      let pilots = manager.get_all_pilots().await;
+      calc::calc_pilots_online(&pilots, &mut pilots_state);
+      let controllers = manager.get_all_controllers().await;
+      calc::calc_controllers_online(&controllers, &mut controllers_state);
 
       loop {
-        let res = rx.try_recv();
-        match res {
-          Err(TryRecvError::Disconnected) => {
+        let input = tokio::select! {
+          msg = rx.recv() => Input::Request(msg),
+          batch = events.recv() => Input::Events(batch),
+        };
+
+        let changes = match input {
+          Input::Request(None) => {
             info!("received disconnected error");
             break
-          },
-          Err(TryRecvError::Empty) => {},
-          Ok(msg) => {
+          }
+          Input::Request(Some(msg)) => {
             if let Some(subscription) = msg.subscription {
               const ADD: i32 = QuerySubscriptionRequestType::SubscriptionAdd as i32;
               const DEL: i32 = QuerySubscriptionRequestType::SubscriptionDelete as i32;
@@ -90,83 +445,68 @@ impl Camden for CamdenService {
                   debug!("sub add {subscription:?}");
                   if let Entry::Vacant(e) = subscriptions.entry(subscription.id) {
                     if !subscription.query.is_empty() {
-                      let res = make_expr::<Pilot>(&subscription.query);
-                      if let Ok(mut expr) = res {
-                        let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
-                        let filter = expr.compile(&cb).map(|_| expr);
-                        if let Ok(filter) = filter {
-                          e.insert(filter);
-                          next_update = Utc::now();
-                        }
+                      let filter = SubscriptionFilter::new(&subscription.query, subscription.target, &limits);
+                      if let Some(filter) = filter {
+                        queries.insert(e.key().clone(), subscription.query);
+                        e.insert(filter);
                       }
                     }
                   }
                 },
                 DEL => {
                   debug!("sub del {subscription:?}");
-                  if subscriptions.contains_key(&subscription.id) {
-                    subscriptions.remove(&subscription.id);
-                    next_update = Utc::now();
-                  }
+                  subscriptions.remove(&subscription.id);
+                  queries.remove(&subscription.id);
                 },
                 _ => unreachable!()
               }
+              handle.set_view(None, queries.values().cloned().collect());
             }
+            continue
           }
-        }
-
-        let now = Utc::now();
-        if now >= next_update {
-          let pilots = manager.get_all_pilots().await;
-          let (pilots_add, pilots_delete, pilots_fp) = calc::calc_pilots_online(&pilots, &mut pilots_state);
-
-          for pilot in pilots_add.iter() {
-            for (id, filter) in subscriptions.iter() {
-              if filter.evaluate(pilot) {
-                let update = QuerySubscriptionUpdate {
-                  subscription_id: id.to_owned(),
-                  update_type: QuerySubscriptionUpdateType::Online as i32,
-                  pilot: Some(pilot.clone().into())
-                };
-                yield update;
-              }
-            }
-          }
-
-          for pilot in pilots_fp.iter() {
-            for (id, filter) in subscriptions.iter() {
-              if filter.evaluate(pilot) {
-                let update = QuerySubscriptionUpdate {
-                  subscription_id: id.to_owned(),
-                  update_type: QuerySubscriptionUpdateType::Flightplan as i32,
-                  pilot: Some(pilot.clone().into())
-                };
-                yield update;
-              }
+          Input::Events(Ok(batch)) => {
+            // already applied while catching up
+            if batch.seq <= last_seq {
+              continue
             }
+            last_seq = batch.seq;
+            let mut changes = OnlineChanges::default();
+            changes.apply_diff(&batch, &mut pilots_state, &mut controllers_state);
+            changes
           }
-
-          for pilot in pilots_delete.iter() {
-            for (id, filter) in subscriptions.iter() {
-              if filter.evaluate(pilot) {
-                let update = QuerySubscriptionUpdate {
-                  subscription_id: id.to_owned(),
-                  update_type: QuerySubscriptionUpdateType::Offline as i32,
-                  pilot: Some(pilot.clone().into())
-                };
-                yield update;
+          Input::Events(Err(RecvError::Lagged(n))) => {
+            let mut changes = OnlineChanges::default();
+            let diffs = manager.diffs_since(last_seq).await;
+            if let Some(diffs) = diffs {
+              debug!("[{remote}] lagged behind by {n} update cycles, catching up");
+              for diff in diffs.iter() {
+                changes.apply_diff(diff, &mut pilots_state, &mut controllers_state);
+                last_seq = diff.seq;
               }
+            } else {
+              warn!("[{remote}] lagged behind by {n} update cycles, resyncing");
+              last_seq = manager.last_diff_seq().await;
+              let pilots = manager.get_all_pilots().await;
+              let (pilots_add, pilots_delete, pilots_fp) = calc::calc_pilots_online(&pilots, &mut pilots_state);
+              let controllers = manager.get_all_controllers().await;
+              let (ctrls_add, ctrls_delete) = calc::calc_controllers_online(&controllers, &mut controllers_state);
+              changes = OnlineChanges { pilots_add, pilots_delete, pilots_fp, ctrls_add, ctrls_delete };
             }
+            changes
           }
+          Input::Events(Err(RecvError::Closed)) => break,
+          Input::Degraded(_) | Input::DataStale(_) => continue,
+        };
 
-          next_update = Utc::now() + Duration::from_secs(5);
+        for update in changes.subscription_updates(&subscriptions, &manager.pilot_protos()) {
+          yield update;
         }
-        sleep(Duration::from_millis(50)).await;
       }
 
       info!("[{remote}] client disconnected");
 
     };
+    let output = stream_handle.meter(output);
     Ok(Response::new(Box::pin(output) as Self::SubscribeQueryStream))
   }
 
@@ -175,7 +515,10 @@ impl Camden for CamdenService {
     request: Request<Streaming<MapUpdatesRequest>>,
   ) -> Result<Response<Self::MapUpdatesStream>, Status> {
     let manager = self.manager.clone();
+    let limits = manager.config().query.limits();
+    let weather_min_zoom = manager.config().weather.viewport_min_zoom;
     let remote = request.remote_addr().unwrap();
+    let stream_handle = StreamHandle::register("map_updates", remote.to_string());
     let remote = format!("map_updates:{:?}", remote);
     info!("[{remote}] client connected");
     let stream = request.into_inner();
@@ -183,192 +526,201 @@ impl Camden for CamdenService {
 
     tokio::spawn(async move { proxy_requests(stream, tx).await });
 
-    let mut bounds = None;
-    let mut filter = None;
-    let mut show_wx = false;
-
-    let mut pilots_state = HashMap::new();
-    let mut airports_state = HashMap::new();
-    let mut firs_state = HashMap::new();
-    let mut subscriptions = HashSet::new();
+    let mut view = MapView::default();
+    let mut state = MapState::default();
+    let mut events = manager.subscribe_events();
+    let mut degraded_rx = manager.watch_degraded();
+    let mut data_stale_rx = manager.watch_data_stale();
 
+    let handle = stream_handle.clone();
     let output = async_stream::try_stream! {
       let mut rx = rx;
-      let mut next_update = Utc::now();
+      let mut last_seq = manager.last_diff_seq().await;
+
+      if *data_stale_rx.borrow_and_update() {
+        let ts = manager.vatsim_data_timestamp().await;
+        yield Update::data_notice(true, ts).stamp(&degraded_rx);
+      }
 
       loop {
-        let res = rx.try_recv();
+        let input = tokio::select! {
+          msg = rx.recv() => Input::Request(msg),
+          batch = events.recv() => Input::Events(batch),
+          res = degraded_rx.changed() => Input::Degraded(res),
+          res = data_stale_rx.changed() => Input::DataStale(res),
+        };
 
-        match res {
-          Err(TryRecvError::Disconnected) => {
+        let mut resync = false;
+        match input {
+          Input::Request(None) => {
             info!("received disconnected error");
             break
-          },
-          Err(TryRecvError::Empty) => {},
-          Ok(msg) => {
-            next_update = Utc::now();
-            if msg.request.is_some() {
-              let req = msg.request.unwrap();
-              match req {
-                ServiceRequest::Filter(flt) => {
-                  debug!("client {:?} filter request {}", remote, flt);
-                  filter = {
-                    if !flt.is_empty() {
-                      let res = make_expr::<Pilot>(&flt);
-                      if let Ok(mut expr) = res {
-                        let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
-                        expr.compile(&cb).map(|_| expr).ok()
-                      } else {
-                        None
-                      }
-                    } else {
-                      None
-                    }
-                  };
-                }
-                ServiceRequest::Bounds(bds) => {
-                  debug!("client {:?} bounds request {:?}", remote, bds);
-                  bounds = Some(bds);
-                }
-                ServiceRequest::ShowWx(value) => {
-                  debug!("client {:?} show_wx request {}", remote, value);
-                  show_wx = value;
-                }
-                ServiceRequest::SubscribeId(value) => {
-                  debug!("client {:?} subscribe request {}", remote, value);
-                  subscriptions.insert(value);
+          }
+          Input::Request(Some(msg)) => {
+            let detail = view.boundaries_detail;
+            if let Some(req) = msg.request {
+              view.apply(req, &limits, &remote);
+            }
+            // apply everything the client has sent so far before resyncing
+            while let Ok(msg) = rx.try_recv() {
+              if let Some(req) = msg.request {
+                view.apply(req, &limits, &remote);
+              }
+            }
+            view.budget = manager.config().map_budget.level(view.zoom);
+            handle.set_view(view.bounds, view.filter_query.iter().cloned().collect());
+            // the firs already sent have to be resent with the new geometry
+            if view.boundaries_detail != detail {
+              state.firs.clear();
+            }
+            resync = true;
+          }
+          Input::Events(Ok(batch)) => {
+            if batch.seq > last_seq {
+              last_seq = batch.seq;
+              let t = Utc::now();
+              let updates = state.apply_diff(&batch, &view, &manager.pilot_protos());
+              debug!("[{remote}] diff #{} of {} objects applied in {}s", batch.seq, batch.len(), seconds_since(t));
+              for update in updates {
+                yield update.stamp(&degraded_rx);
+              }
+              for update in cluster_changes(&manager, &view, &mut state).await {
+                yield update.stamp(&degraded_rx);
+              }
+              if !batch.controllers.is_empty() {
+                for update in controller_changes(&manager, &view, &mut state).await {
+                  yield update.stamp(&degraded_rx);
                 }
-                ServiceRequest::UnsubscribeId(value) => {
-                  debug!("client {:?} unsubscribe request {}", remote, value);
-                  subscriptions.remove(&value);
+              }
+            }
+          }
+          Input::Events(Err(RecvError::Lagged(n))) => {
+            let diffs = manager.diffs_since(last_seq).await;
+            if let Some(diffs) = diffs {
+              debug!("[{remote}] lagged behind by {n} update cycles, catching up");
+              let protos = manager.pilot_protos();
+              for diff in diffs.iter() {
+                last_seq = diff.seq;
+                for update in state.apply_diff(diff, &view, &protos) {
+                  yield update.stamp(&degraded_rx);
                 }
               }
+              for update in cluster_changes(&manager, &view, &mut state).await {
+                yield update.stamp(&degraded_rx);
+              }
+              for update in controller_changes(&manager, &view, &mut state).await {
+                yield update.stamp(&degraded_rx);
+              }
+            } else {
+              warn!("[{remote}] lagged behind by {n} update cycles, resyncing");
+              resync = true;
             }
           }
-        };
+          Input::Events(Err(RecvError::Closed)) => break,
+          Input::Degraded(Ok(())) => {
+            yield Update { object_update: None, degraded: false }.stamp(&degraded_rx);
+          }
+          Input::Degraded(Err(_)) => break,
+          Input::DataStale(Ok(())) => {
+            let stale = *data_stale_rx.borrow_and_update();
+            let ts = manager.vatsim_data_timestamp().await;
+            yield Update::data_notice(stale, ts).stamp(&degraded_rx);
+          }
+          Input::DataStale(Err(_)) => break,
+        }
 
-        if let Some(b) = bounds.as_ref() {
-          let dt = Utc::now();
-          if dt >= next_update {
-            let rect: Rect = b.clone().into();
-            let no_bounds = b.zoom < MIN_ZOOM;
+        if !resync {
+          continue;
+        }
 
-            let t = Utc::now();
-            let mut pilots = if no_bounds {
-              manager.get_all_pilots().await
-            } else {
-              manager.get_pilots(&rect, &subscriptions).await
-            };
+        if let Some(rect) = view.bounds {
+          last_seq = manager.last_diff_seq().await;
+          let t = Utc::now();
+          let mut pilots = if view.no_bounds {
+            manager.get_all_pilots().await
+          } else {
+            manager.get_pilots(&rect, &view.subscriptions).await
+          };
 
-            debug!("[{remote}] {} pilots loaded in {}s", pilots.len(), seconds_since(t));
+          debug!("[{remote}] {} pilots loaded in {}s", pilots.len(), seconds_since(t));
 
-            if let Some(f) = filter.as_ref() {
-              pilots.retain(|pilot| subscriptions.contains(&pilot.callsign) || f.evaluate(pilot));
-            }
+          if view.filter.is_some() || view.budget.is_some() {
+            pilots.retain(|pilot| view.subscriptions.contains(&pilot.callsign) || view.pilot_selected(pilot));
+            flush_evaluations();
+          }
 
-            let t = Utc::now();
-            let (pilots_set, pilots_delete) = calc::calc_pilots(&pilots, &mut pilots_state);
-            debug!("[{remote}] {} pilots diff calculated in {}s, set={}/del={}", pilots.len(), seconds_since(t), pilots_set.len(), pilots_delete.len());
-
-            let objects: Vec<camden::Pilot> = pilots_set.into_iter().map(|p| p.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::PilotUpdate(PilotUpdate {
-                  update_type: UpdateType::Set as i32,
-                  pilots: objects,
-                })),
-              };
-              yield update;
-            }
+          let t = Utc::now();
+          let (pilots_set, pilots_delete) = calc::calc_pilots(&pilots, &mut state.pilots);
+          debug!("[{remote}] {} pilots diff calculated in {}s, set={}/del={}", pilots.len(), seconds_since(t), pilots_set.len(), pilots_delete.len());
 
-            let objects: Vec<camden::Pilot> = pilots_delete.into_iter().map(|p| p.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::PilotUpdate(PilotUpdate {
-                  update_type: UpdateType::Delete as i32,
-                  pilots: objects,
-                })),
-              };
-              yield update;
-            }
+          for update in state.alerts(&pilots_set, &pilots_delete) {
+            yield update.stamp(&degraded_rx);
+          }
+          for update in pilot_updates(pilots_set, pilots_delete, &manager.pilot_protos()) {
+            yield update.stamp(&degraded_rx);
+          }
+          for update in cluster_changes(&manager, &view, &mut state).await {
+            yield update.stamp(&degraded_rx);
+          }
 
-            let t = Utc::now();
-            let airports = if no_bounds {
-              manager.get_all_airports(show_wx).await
-            } else {
-              manager.get_airports(&rect, show_wx).await
-            };
-
-            debug!("[{remote}] {} airports loaded in {}s", airports.len(), seconds_since(t));
-            let t = Utc::now();
-            let (arpts_set, arpts_delete) = calc::calc_airports(&airports, &mut airports_state);
-            debug!("[{remote}] {} airports diff calculated in {}s, set={}/del={}", airports.len(), seconds_since(t), arpts_set.len(), arpts_delete.len());
-
-            let objects: Vec<camden::Airport> = arpts_set.into_iter().map(|a| a.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::AirportUpdate(AirportUpdate {
-                  update_type: UpdateType::Set as i32,
-                  airports: objects,
-                })),
-              };
-              yield update;
-            }
+          if let Some(rect) = view.weather_bounds(weather_min_zoom) {
+            manager.request_viewport_weather(rect).await;
+          }
 
-            let objects: Vec<camden::Airport> = arpts_delete.into_iter().map(|a| a.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::AirportUpdate(AirportUpdate {
-                  update_type: UpdateType::Delete as i32,
-                  airports: objects,
-                })),
-              };
-              yield update;
-            }
+          let t = Utc::now();
+          let airports = if view.no_bounds {
+            manager.get_all_airports(view.show_wx).await
+          } else {
+            manager.get_airports(&rect, view.show_wx).await
+          };
 
-            let t = Utc::now();
-            let firs = if no_bounds {
-              manager.get_all_firs().await
-            } else {
-              manager.get_firs(&rect).await
-            };
-
-            debug!("[{remote}] {} firs loaded in {}s", firs.len(), seconds_since(t));
-            let t = Utc::now();
-            let (firs_set, firs_delete) = calc::calc_firs(&firs, &mut firs_state);
-            debug!("[{remote}] {} firs diff calculated in {}s, set={}/del={}", firs.len(), seconds_since(t), firs_set.len(), firs_delete.len());
-
-            let objects: Vec<camden::Fir> = firs_set.into_iter().map(|f| f.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::FirUpdate(FirUpdate {
-                  update_type: UpdateType::Set as i32,
-                  firs: objects,
-                })),
-              };
-              yield update;
-            }
+          debug!("[{remote}] {} airports loaded in {}s", airports.len(), seconds_since(t));
+          let t = Utc::now();
+          let (arpts_set, arpts_delete) = calc::calc_airports(&airports, &mut state.airports);
+          debug!("[{remote}] {} airports diff calculated in {}s, set={}/del={}", airports.len(), seconds_since(t), arpts_set.len(), arpts_delete.len());
 
-            let objects: Vec<camden::Fir> = firs_delete.into_iter().map(|f| f.into()).collect();
-            if !objects.is_empty() {
-              let update = Update {
-                object_update: Some(ObjectUpdate::FirUpdate(FirUpdate {
-                  update_type: UpdateType::Delete as i32,
-                  firs: objects,
-                })),
-              };
-              yield update;
-            }
+          for update in airport_updates(arpts_set, arpts_delete) {
+            yield update.stamp(&degraded_rx);
+          }
+
+          let t = Utc::now();
+          let firs = if view.no_bounds {
+            manager.get_all_firs().await
+          } else {
+            manager.get_firs(&rect).await
+          };
+
+          debug!("[{remote}] {} firs loaded in {}s", firs.len(), seconds_since(t));
+          let t = Utc::now();
+          let (firs_set, firs_delete) = calc::calc_firs(&firs, &mut state.firs);
+          debug!("[{remote}] {} firs diff calculated in {}s, set={}/del={}", firs.len(), seconds_since(t), firs_set.len(), firs_delete.len());
+
+          for update in fir_updates(firs_set, firs_delete, &view.boundaries_detail) {
+            yield update.stamp(&degraded_rx);
+          }
 
-            next_update = dt + Duration::from_secs(5);
+          let hazards = if !view.show_hazards {
+            vec![]
+          } else if view.no_bounds {
+            manager.get_all_hazards().await
+          } else {
+            manager.get_hazards(&rect).await
+          };
+          let (hazards_set, hazards_delete) = calc::calc_hazards(&hazards, &mut state.hazards);
+          for update in hazard_updates(hazards_set, hazards_delete) {
+            yield update.stamp(&degraded_rx);
+          }
+
+          for update in controller_changes(&manager, &view, &mut state).await {
+            yield update.stamp(&degraded_rx);
           }
         }
-        sleep(Duration::from_millis(50)).await;
       }
 
       info!("[{remote}] client disconnected");
     };
 
+    let output = stream_handle.meter(output);
     Ok(Response::new(Box::pin(output) as Self::MapUpdatesStream))
   }
 
@@ -377,62 +729,368 @@ impl Camden for CamdenService {
     request: Request<PilotRequest>,
   ) -> Result<Response<PilotResponse>, Status> {
     let request = request.into_inner();
-    let pilot = self.manager.get_pilot_by_callsign(&request.callsign).await;
-    match pilot {
-      Some(pilot) => {
+    match self.find_pilot(&request).await {
+      Some((pilot, offline_since)) => {
+        let from = request.track_from.map(|ts| from_millis(ts as i64));
+        let to = request.track_to.map(|ts| from_millis(ts as i64));
+        let max_points = request.track_max_points.map(|n| n as usize);
         let tps = self
           .manager
-          .get_pilot_track(&pilot)
+          .get_pilot_track_range(&pilot, from, to, max_points)
+          .await
+          .map_err(|err| Status::unavailable(format!("{err}")))?;
+        let stats = self
+          .manager
+          .get_pilot_track_stats(&pilot)
           .await
           .map_err(|err| Status::unavailable(format!("{err}")))?;
         let mut pilot: camden::Pilot = pilot.into();
 
         pilot.track = tps.into_iter().map(|tp| tp.into()).collect();
+        pilot.offline_since = offline_since.map(|since| since.timestamp_millis() as u64);
+        pilot.transceivers = self
+          .manager
+          .get_transceivers(&pilot.callsign)
+          .await
+          .into_iter()
+          .map(|t| t.into())
+          .collect();
 
-        Ok(Response::new(PilotResponse { pilot: Some(pilot) }))
+        Ok(Response::new(PilotResponse {
+          pilot: Some(pilot),
+          track_stats: Some(stats.into()),
+        }))
       }
       None => Err(Status::not_found("pilot not found")),
     }
   }
+  async fn get_track_stats(
+    &self,
+    request: Request<PilotRequest>,
+  ) -> Result<Response<TrackStatsResponse>, Status> {
+    let request = request.into_inner();
+    let Some((pilot, _)) = self.find_pilot(&request).await else {
+      return Err(Status::not_found("pilot not found"));
+    };
+    let stats = self
+      .manager
+      .get_pilot_track_stats(&pilot)
+      .await
+      .map_err(|err| Status::unavailable(format!("{err}")))?;
+    Ok(Response::new(TrackStatsResponse {
+      stats: Some(stats.into()),
+    }))
+  }
+
+  async fn export_track(
+    &self,
+    request: Request<ExportTrackRequest>,
+  ) -> Result<Response<ExportTrackResponse>, Status> {
+    let request = request.into_inner();
+    let format: ExportFormat = TrackExportFormat::from_i32(request.format)
+      .ok_or_else(|| Status::invalid_argument("unknown export format"))?
+      .into();
+    let pilot_request = PilotRequest {
+      callsign: request.callsign,
+      include_offline: request.include_offline,
+      ..Default::default()
+    };
+    let Some((pilot, _)) = self.find_pilot(&pilot_request).await else {
+      return Err(Status::not_found("pilot not found"));
+    };
+    let tps = self
+      .manager
+      .get_pilot_track(&pilot)
+      .await
+      .map_err(|err| Status::unavailable(format!("{err}")))?;
+    let (doc, content_type) = export_track(&pilot.callsign, &tps, format);
+    Ok(Response::new(ExportTrackResponse {
+      data: doc.into_bytes(),
+      content_type: content_type.into(),
+    }))
+  }
+
+  async fn stream_pilot_track(
+    &self,
+    request: Request<PilotRequest>,
+  ) -> Result<Response<Self::StreamPilotTrackStream>, Status> {
+    let request = request.into_inner();
+    let Some((pilot, _)) = self.find_pilot(&request).await else {
+      return Err(Status::not_found("pilot not found"));
+    };
+    let manager = self.manager.clone();
+
+    let output = async_stream::try_stream! {
+      let mut offset = 0;
+      loop {
+        let points = manager
+          .get_pilot_track_chunk(&pilot, offset, TRACK_CHUNK_SIZE)
+          .await
+          .map_err(|err| Status::unavailable(format!("{err}")))?;
+        if points.is_empty() {
+          break;
+        }
+        let len = points.len();
+        yield TrackChunk {
+          points: points.into_iter().map(|tp| tp.into()).collect(),
+          offset: offset as u64,
+        };
+        if len < TRACK_CHUNK_SIZE {
+          break;
+        }
+        offset += len;
+      }
+    };
+    Ok(Response::new(
+      Box::pin(output) as Self::StreamPilotTrackStream
+    ))
+  }
+
+  async fn get_route_polyline(
+    &self,
+    request: Request<PilotRequest>,
+  ) -> Result<Response<RoutePolylineResponse>, Status> {
+    let request = request.into_inner();
+    let pilot = self.manager.get_pilot_by_callsign(&request.callsign).await;
+    let Some(pilot) = pilot else {
+      return Err(Status::not_found("pilot not found"));
+    };
+    let Some(fp) = pilot.flight_plan.as_ref() else {
+      return Err(Status::failed_precondition("pilot has no flight plan"));
+    };
+
+    let dep = self.manager.find_airport(&fp.departure).await;
+    let Some(dep) = dep else {
+      return Err(Status::not_found(format!(
+        "departure airport {} not found",
+        fp.departure
+      )));
+    };
+    let arr = self.manager.find_airport(&fp.arrival).await;
+    let Some(arr) = arr else {
+      return Err(Status::not_found(format!(
+        "arrival airport {} not found",
+        fp.arrival
+      )));
+    };
+
+    let waypoints = self
+      .manager
+      .expand_route(&fp.route, dep.position, arr.position)
+      .await;
+    let mut points = vec![dep.position];
+    points.extend(waypoints.iter().map(|w| w.position));
+    points.push(arr.position);
+
+    let segments = route_polyline(&points)
+      .into_iter()
+      .map(|points| camden::PointList {
+        points: points.into_iter().map(|p| p.into()).collect(),
+      })
+      .collect();
+
+    Ok(Response::new(RoutePolylineResponse {
+      segments,
+      departure: Some(dep.position.into()),
+      arrival: Some(arr.position.into()),
+      waypoints: waypoints.into_iter().map(|w| w.into()).collect(),
+    }))
+  }
+
+  async fn find_navaid(
+    &self,
+    request: Request<NavaidRequest>,
+  ) -> Result<Response<NavaidListResponse>, Status> {
+    let ident = request.into_inner().ident.to_uppercase();
+    let navaids = self.manager.find_navaids(&ident).await;
+    if navaids.is_empty() {
+      return Err(Status::not_found(format!("navaid {ident} not found")));
+    }
+    Ok(Response::new(NavaidListResponse {
+      navaids: navaids.into_iter().map(|n| n.into()).collect(),
+    }))
+  }
+
+  async fn lookup_aircraft(
+    &self,
+    request: Request<AircraftRequest>,
+  ) -> Result<Response<AircraftListResponse>, Status> {
+    let query = request.into_inner().query;
+    let aircraft = lookup_aircraft(&query);
+    if aircraft.is_empty() {
+      return Err(Status::not_found(format!("aircraft {query} not found")));
+    }
+    Ok(Response::new(AircraftListResponse {
+      aircraft: aircraft.into_iter().map(|at| at.into()).collect(),
+    }))
+  }
+
+  async fn list_navaids(
+    &self,
+    request: Request<NavaidListRequest>,
+  ) -> Result<Response<NavaidListResponse>, Status> {
+    let Some(bounds) = request.into_inner().bounds else {
+      return Err(Status::invalid_argument("bounds are required"));
+    };
+    let rect: Rect = bounds.into();
+    let navaids = self.manager.get_navaids(&rect).await;
+    Ok(Response::new(NavaidListResponse {
+      navaids: navaids.into_iter().map(|n| n.into()).collect(),
+    }))
+  }
 
   async fn list_pilots(
     &self,
     request: Request<QueryRequest>,
   ) -> Result<Response<PilotListResponse>, Status> {
     let request = request.into_inner();
+    let limits = self.manager.config().query.limits();
     let mut pilots = self.manager.get_all_pilots().await;
-
-    if !request.query.is_empty() {
-      let expr = make_expr::<Pilot>(&request.query);
-      match expr {
-        Ok(mut expr) => {
-          let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
-          let res = expr.compile(&cb);
-          match res {
-            Ok(_) => {
-              pilots.retain(|pilot| expr.evaluate(pilot));
-              // pilots = pilots
-              //   .into_iter()
-              //   .filter(|pilot| expr.evaluate(pilot))
-              //   .collect()
-            }
-            Err(err) => {
-              return Err(Status::failed_precondition(format!(
-                "query compile error: {err}"
-              )));
-            }
-          }
-        }
-        Err(err) => {
-          return Err(Status::failed_precondition(format!(
-            "query parse error: {err}"
-          )));
-        }
+    let mut offline_since = HashMap::new();
+    if request.include_offline {
+      for (pilot, since) in self.manager.get_offline_pilots().await.into_iter() {
+        offline_since.insert(pilot.callsign.clone(), since);
+        pilots.push(pilot);
       }
     }
+    apply_query(&request.query, &limits, &mut pilots).map_err(Status::failed_precondition)?;
 
     Ok(Response::new(PilotListResponse {
-      pilots: pilots.into_iter().map(|pilot| pilot.into()).collect(),
+      pilots: pilots
+        .into_iter()
+        .map(|pilot| {
+          let since = offline_since.get(&pilot.callsign);
+          let mut pilot: camden::Pilot = pilot.into();
+          pilot.offline_since = since.map(|since| since.timestamp_millis() as u64);
+          pilot
+        })
+        .collect(),
+    }))
+  }
+
+  async fn get_pilot_sessions(
+    &self,
+    request: Request<PilotSessionsRequest>,
+  ) -> Result<Response<PilotSessionsResponse>, Status> {
+    let request = request.into_inner();
+    let since = from_millis(request.since as i64);
+    let sessions = self
+      .manager
+      .get_pilot_sessions(request.cid, since)
+      .await
+      .map_err(|err| Status::unavailable(format!("{err}")))?;
+    Ok(Response::new(PilotSessionsResponse {
+      sessions: sessions.into_iter().map(|s| s.into()).collect(),
+    }))
+  }
+
+  async fn get_controller(
+    &self,
+    request: Request<ControllerRequest>,
+  ) -> Result<Response<ControllerResponse>, Status> {
+    let request = request.into_inner();
+    let ctrl = self
+      .manager
+      .get_controller_by_callsign(&request.callsign)
+      .await;
+    match ctrl {
+      Some(ctrl) => {
+        let mut ctrl: camden::Controller = ctrl.into();
+        ctrl.transceivers = self
+          .manager
+          .get_transceivers(&ctrl.callsign)
+          .await
+          .into_iter()
+          .map(|t| t.into())
+          .collect();
+        Ok(Response::new(ControllerResponse {
+          controller: Some(ctrl),
+        }))
+      }
+      None => Err(Status::not_found("controller not found")),
+    }
+  }
+
+  async fn list_on_frequency(
+    &self,
+    request: Request<FrequencyRequest>,
+  ) -> Result<Response<FrequencyResponse>, Status> {
+    let request = request.into_inner();
+    if request.freq == 0 {
+      return Err(Status::invalid_argument("frequency is required"));
+    }
+    let (pilots, controllers) = self.manager.get_on_frequency(request.freq).await;
+    Ok(Response::new(FrequencyResponse {
+      pilots: pilots.into_iter().map(|p| p.into()).collect(),
+      controllers: controllers.into_iter().map(|c| c.into()).collect(),
+    }))
+  }
+
+  async fn list_controllers(
+    &self,
+    request: Request<ControllerListRequest>,
+  ) -> Result<Response<ControllerListResponse>, Status> {
+    let request = request.into_inner();
+    let mut controllers = match request.bounds {
+      Some(bounds) => {
+        let rect: Rect = bounds.into();
+        self.manager.get_controllers(&rect).await
+      }
+      None if request.facilities.len() == 1 => {
+        let facility = Facility::from(request.facilities[0] as i8);
+        self.manager.get_controllers_by_facility(&facility).await
+      }
+      None => self.manager.get_all_controllers().await,
+    };
+
+    if !request.facilities.is_empty() {
+      let facilities: Vec<Facility> = request
+        .facilities
+        .iter()
+        .map(|f| Facility::from(*f as i8))
+        .collect();
+      controllers.retain(|ctrl| facilities.contains(&ctrl.facility));
+    }
+
+    Ok(Response::new(ControllerListResponse {
+      controllers: controllers.into_iter().map(|ctrl| ctrl.into()).collect(),
+    }))
+  }
+
+  async fn aggregate_pilots(
+    &self,
+    request: Request<AggregateRequest>,
+  ) -> Result<Response<AggregateResponse>, Status> {
+    let request = request.into_inner();
+    let group_by: GroupBy = request.group_by.parse().map_err(Status::invalid_argument)?;
+
+    let limits = self.manager.config().query.limits();
+    let mut pilots = self.manager.get_all_pilots().await;
+    apply_query(&request.query, &limits, &mut pilots).map_err(Status::failed_precondition)?;
+
+    let countries = if group_by == GroupBy::Country {
+      self.manager.get_pilot_countries(&pilots).await
+    } else {
+      vec![]
+    };
+
+    let groups = aggregate_pilots(&pilots, &countries, &group_by);
+    Ok(Response::new(AggregateResponse {
+      groups,
+      total: pilots.len() as u64,
+    }))
+  }
+
+  async fn get_country_stats(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<CountryStatsResponse>, Status> {
+    let pilots = self.manager.get_all_pilots().await;
+    let pilot_countries = self.manager.get_pilot_countries(&pilots).await;
+    let airports = self.manager.get_all_airports(false).await;
+    let firs = self.manager.get_all_firs().await;
+    Ok(Response::new(CountryStatsResponse {
+      countries: country_stats(&pilot_countries, &airports, &firs),
     }))
   }
 
@@ -441,7 +1099,8 @@ impl Camden for CamdenService {
     request: Request<AirportRequest>,
   ) -> Result<Response<AirportResponse>, Status> {
     let request = request.into_inner();
-    let airport = self.manager.find_airport(&request.code).await;
+    let pref = request.prefer().into();
+    let airport = self.manager.find_airport_by(&request.code, pref).await;
     match airport {
       Some(airport) => Ok(Response::new(AirportResponse {
         airport: Some(airport.into()),
@@ -450,12 +1109,175 @@ impl Camden for CamdenService {
     }
   }
 
+  async fn list_airports_by_code(
+    &self,
+    request: Request<AirportRequest>,
+  ) -> Result<Response<AirportCandidatesResponse>, Status> {
+    let request = request.into_inner();
+    let pref = request.prefer().into();
+    let candidates = self
+      .manager
+      .find_airports(&request.code, pref)
+      .await
+      .into_iter()
+      .map(|arpt| AirportCandidate {
+        compound_id: arpt.compound_id(),
+        airport: Some(arpt.into()),
+      })
+      .collect();
+    Ok(Response::new(AirportCandidatesResponse { candidates }))
+  }
+
+  async fn get_airport_movements(
+    &self,
+    request: Request<AirportMovementsRequest>,
+  ) -> Result<Response<AirportMovementsResponse>, Status> {
+    let request = request.into_inner();
+    let airport = self.manager.find_airport(&request.code).await;
+    let Some(airport) = airport else {
+      return Err(Status::not_found("airport not found"));
+    };
+    let hours = self
+      .manager
+      .get_airport_movements(&airport.icao)
+      .await
+      .into_iter()
+      .map(|(hour, count)| HourlyMovements {
+        hour: hour.timestamp_millis() as u64,
+        arrivals: count.arrivals,
+        departures: count.departures,
+      })
+      .collect();
+    Ok(Response::new(AirportMovementsResponse {
+      icao: airport.icao.clone(),
+      hours,
+    }))
+  }
+
+  async fn get_winds_aloft(
+    &self,
+    request: Request<WindsAloftRequest>,
+  ) -> Result<Response<WindsAloftResponse>, Status> {
+    let request = request.into_inner();
+    if request.sw.is_none() || request.ne.is_none() {
+      return Err(Status::invalid_argument("sw and ne are required"));
+    }
+    let rect: Rect = camden::MapBounds {
+      sw: request.sw,
+      ne: request.ne,
+      zoom: 0.0,
+    }
+    .into();
+    let (pressure_level, vectors) = self
+      .manager
+      .get_winds_aloft(&rect, request.fl)
+      .await
+      .map_err(|err| Status::unavailable(format!("{err}")))?;
+    Ok(Response::new(WindsAloftResponse {
+      pressure_level,
+      vectors: vectors.into_iter().map(|v| v.into()).collect(),
+    }))
+  }
+
+  async fn create_geofence(
+    &self,
+    request: Request<Geofence>,
+  ) -> Result<Response<Geofence>, Status> {
+    let request = request.into_inner();
+    let shape = match request.shape {
+      Some(camden::geofence::Shape::Polygon(pl)) => {
+        let points: Vec<Point> = pl.points.into_iter().map(|p| p.into()).collect();
+        Shape::polygon(&points)
+      }
+      Some(camden::geofence::Shape::Circle(circle)) => circle
+        .center
+        .and_then(|center| Shape::circle(center.into(), circle.radius)),
+      None => None,
+    };
+    let Some(shape) = shape else {
+      return Err(Status::invalid_argument(
+        "a polygon of at least 3 points or a circle with a positive radius is required",
+      ));
+    };
+
+    let filter = if request.query.is_empty() {
+      None
+    } else {
+      let limits = self.manager.config().query.limits();
+      let mut expr = make_expr_limited::<Pilot>(&request.query, &limits)
+        .map_err(|err| Status::invalid_argument(format!("{err}")))?;
+      let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+      expr
+        .compile(&cb)
+        .map_err(|err| Status::invalid_argument(format!("{err}")))?;
+      Some(expr)
+    };
+
+    let fence = self
+      .manager
+      .create_geofence(&request.name, shape, &request.query, filter)
+      .await;
+    info!("geofence {} \"{}\" created", fence.id, fence.name);
+    Ok(Response::new(fence))
+  }
+
+  async fn delete_geofence(
+    &self,
+    request: Request<GeofenceRequest>,
+  ) -> Result<Response<DeleteGeofenceResponse>, Status> {
+    let request = request.into_inner();
+    let deleted = self.manager.delete_geofence(&request.id).await;
+    Ok(Response::new(DeleteGeofenceResponse { deleted }))
+  }
+
+  async fn list_geofences(
+    &self,
+    _: Request<NoParams>,
+  ) -> Result<Response<GeofenceListResponse>, Status> {
+    let geofences = self.manager.list_geofences().await;
+    Ok(Response::new(GeofenceListResponse { geofences }))
+  }
+
+  async fn subscribe_geofence(
+    &self,
+    request: Request<GeofenceSubscriptionRequest>,
+  ) -> Result<Response<Self::SubscribeGeofenceStream>, Status> {
+    let remote = request.remote_addr().unwrap();
+    let remote = format!("subscribe_geofence:{:?}", remote);
+    info!("[{remote}] client connected");
+    let ids: HashSet<String> = request.into_inner().geofence_ids.into_iter().collect();
+    let mut events = self.manager.subscribe_geofences();
+
+    let output = async_stream::try_stream! {
+      loop {
+        let batch = match events.recv().await {
+          Ok(batch) => batch,
+          Err(RecvError::Lagged(n)) => {
+            warn!("[{remote}] lagged behind by {n} geofence updates, some events are lost");
+            continue
+          }
+          Err(RecvError::Closed) => break,
+        };
+        for event in batch.iter() {
+          if ids.is_empty() || ids.contains(&event.geofence_id) {
+            yield event.clone().into();
+          }
+        }
+      }
+      info!("[{remote}] client disconnected");
+    };
+    Ok(Response::new(
+      Box::pin(output) as Self::SubscribeGeofenceStream
+    ))
+  }
+
   async fn check_query(
     &self,
     request: Request<QueryRequest>,
   ) -> Result<Response<QueryResponse>, Status> {
     let request = request.into_inner();
-    let res = make_expr::<Pilot>(&request.query);
+    let limits = self.manager.config().query.limits();
+    let res = make_expr_limited::<Pilot>(&request.query, &limits);
     match res {
       Ok(expr) => {
         let mut expr = expr;
@@ -479,6 +1301,34 @@ impl Camden for CamdenService {
     }
   }
 
+  async fn explain_query(
+    &self,
+    request: Request<QueryRequest>,
+  ) -> Result<Response<ExplainQueryResponse>, Status> {
+    let request = request.into_inner();
+    let limits = self.manager.config().query.limits();
+    let res = make_expr_limited::<Pilot>(&request.query, &limits);
+    match res {
+      Ok(mut expr) => match explain_expression(&mut expr) {
+        Ok(expression) => Ok(Response::new(ExplainQueryResponse {
+          valid: true,
+          error_message: None,
+          expression: Some(expression),
+        })),
+        Err(err) => Ok(Response::new(ExplainQueryResponse {
+          valid: false,
+          error_message: Some(format!("{err}")),
+          expression: None,
+        })),
+      },
+      Err(err) => Ok(Response::new(ExplainQueryResponse {
+        valid: false,
+        error_message: Some(format!("{err}")),
+        expression: None,
+      })),
+    }
+  }
+
   async fn build_info(&self, _: Request<NoParams>) -> Result<Response<BuildInfoResponse>, Status> {
     let pkgname = env!("CARGO_PKG_NAME").to_owned();
     let pkgversion = env!("CARGO_PKG_VERSION").to_owned();
@@ -492,6 +1342,16 @@ impl Camden for CamdenService {
     }))
   }
 
+  async fn get_health(&self, _: Request<NoParams>) -> Result<Response<HealthResponse>, Status> {
+    let metrics = self.manager.get_metrics_clone().await;
+    let last_fresh_data_at = self.manager.last_fresh_data_at().await;
+    Ok(Response::new(HealthResponse {
+      degraded: self.manager.is_degraded(),
+      vatsim_data_timestamp: metrics.vatsim_data_timestamp as u64,
+      last_fresh_data_at: last_fresh_data_at.timestamp_millis() as u64,
+    }))
+  }
+
   async fn get_metrics(&self, _: Request<NoParams>) -> Result<Response<MetricSet>, Status> {
     let metrics = self.manager.get_metrics_clone().await;
     Ok(Response::new(metrics.into()))
@@ -504,4 +1364,29 @@ impl Camden for CamdenService {
     let text = self.manager.render_metrics().await;
     Ok(Response::new(MetricSetTextResponse { text }))
   }
+
+  async fn stream_metrics(
+    &self,
+    request: Request<MetricsStreamRequest>,
+  ) -> Result<Response<Self::StreamMetricsStream>, Status> {
+    let manager = self.manager.clone();
+    let remote = request.remote_addr().unwrap();
+    let stream_handle = StreamHandle::register("stream_metrics", remote.to_string());
+    let remote = format!("stream_metrics:{:?}", remote);
+    let requested = Duration::from_millis(request.into_inner().interval_ms as u64);
+    let period = manager.config().metrics.stream_interval(requested);
+    info!("[{remote}] client connected, sending metrics every {period:?}");
+
+    let output = async_stream::try_stream! {
+      let mut ticker = interval(period);
+      ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      loop {
+        ticker.tick().await;
+        let metrics: MetricSet = manager.get_metrics_clone().await.into();
+        yield metrics;
+      }
+    };
+    let output = stream_handle.meter(output);
+    Ok(Response::new(Box::pin(output) as Self::StreamMetricsStream))
+  }
 }
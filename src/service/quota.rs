@@ -0,0 +1,286 @@
+use crate::manager::Manager;
+use chrono::{NaiveDate, Utc};
+use http::{Request, Response};
+use http_body::{Body, SizeHint};
+use lazy_static::lazy_static;
+use prost::bytes::Bytes;
+use std::{
+  collections::HashMap,
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+};
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyUsage {
+  pub bytes_sent: u64,
+  // UTC day bytes_today is counted for
+  pub day: Option<NaiveDate>,
+  pub bytes_today: u64,
+  // calls refused once the quota has been used up
+  pub rejected: u64,
+}
+
+impl KeyUsage {
+  fn roll(&mut self, today: NaiveDate) {
+    if self.day != Some(today) {
+      self.day = Some(today);
+      self.bytes_today = 0;
+    }
+  }
+}
+
+lazy_static! {
+  // by client name, i.e. the configured key names and the anonymous one
+  static ref KEY_USAGE: Mutex<HashMap<String, KeyUsage>> = Mutex::new(HashMap::new());
+}
+
+pub fn key_usage() -> HashMap<String, KeyUsage> {
+  KEY_USAGE.lock().unwrap().clone()
+}
+
+fn record_sent(client: &str, bytes: u64, today: NaiveDate) {
+  let mut usage = KEY_USAGE.lock().unwrap();
+  let usage = usage.entry(client.to_owned()).or_default();
+  usage.roll(today);
+  usage.bytes_sent += bytes;
+  usage.bytes_today += bytes;
+}
+
+// whether the client may make another call, the refused calls are counted
+fn within_quota(client: &str, daily_bytes: Option<u64>, today: NaiveDate) -> bool {
+  let Some(daily_bytes) = daily_bytes else {
+    return true;
+  };
+  let mut usage = KEY_USAGE.lock().unwrap();
+  let usage = usage.entry(client.to_owned()).or_default();
+  usage.roll(today);
+  if usage.bytes_today < daily_bytes {
+    return true;
+  }
+  usage.rejected += 1;
+  false
+}
+
+fn quota_exceeded(client: &str, daily_bytes: Option<u64>) -> Status {
+  Status::resource_exhausted(format!(
+    "QUOTA_EXCEEDED: daily quota of {} bytes used up by {client}",
+    daily_bytes.unwrap_or_default()
+  ))
+}
+
+// Response body counting the bytes sent to the client. Streams going on
+// past the quota are ended with the quota error in the trailers
+pub struct CountedBody {
+  inner: BoxBody,
+  client: String,
+  daily_bytes: Option<u64>,
+  exceeded: Option<Status>,
+}
+
+impl CountedBody {
+  fn new(inner: BoxBody, client: String, daily_bytes: Option<u64>) -> Self {
+    Self {
+      inner,
+      client,
+      daily_bytes,
+      exceeded: None,
+    }
+  }
+}
+
+impl Body for CountedBody {
+  type Data = Bytes;
+  type Error = Status;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    if self.exceeded.is_some() {
+      return Poll::Ready(None);
+    }
+    if !within_quota(&self.client, self.daily_bytes, Utc::now().date_naive()) {
+      self.exceeded = Some(quota_exceeded(&self.client, self.daily_bytes));
+      return Poll::Ready(None);
+    }
+    let res = Pin::new(&mut self.inner).poll_data(cx);
+    if let Poll::Ready(Some(Ok(data))) = &res {
+      record_sent(&self.client, data.len() as u64, Utc::now().date_naive());
+    }
+    res
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+    if let Some(status) = self.exceeded.take() {
+      let mut trailers = status.to_http().into_parts().0.headers;
+      trailers.remove(http::header::CONTENT_TYPE);
+      return Poll::Ready(Ok(Some(trailers)));
+    }
+    Pin::new(&mut self.inner).poll_trailers(cx)
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.exceeded.is_none() && self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+// Accounts the bytes sent per client and refuses the calls of the ones
+// over their daily quota, their open streams are ended too. Quotas are read from the current config thus
+// follow the reloads
+#[derive(Clone)]
+pub struct QuotaLayer {
+  manager: Arc<Manager>,
+}
+
+impl QuotaLayer {
+  pub fn new(manager: Arc<Manager>) -> Self {
+    Self { manager }
+  }
+}
+
+impl<S> Layer<S> for QuotaLayer {
+  type Service = QuotaService<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    QuotaService {
+      inner,
+      manager: self.manager.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct QuotaService<S> {
+  inner: S,
+  manager: Arc<Manager>,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for QuotaService<S>
+where
+  S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = Response<BoxBody>;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    let cfg = self.manager.config();
+    let api_key = req
+      .headers()
+      .get(API_KEY_HEADER)
+      .and_then(|v| v.to_str().ok());
+    let (client, daily_bytes) = cfg.quota.client(api_key);
+    let client = client.to_owned();
+    if !within_quota(&client, daily_bytes, Utc::now().date_naive()) {
+      let status = quota_exceeded(&client, daily_bytes);
+      return Box::pin(async move { Ok(status.to_http()) });
+    }
+    let fut = self.inner.call(req);
+    Box::pin(async move {
+      let res = fut.await?;
+      Ok(res.map(|inner| CountedBody::new(inner, client, daily_bytes).boxed_unsync()))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tonic::Code;
+
+  #[test]
+  fn test_quota() {
+    let day = NaiveDate::from_ymd_opt(2023, 10, 1).unwrap();
+    assert!(within_quota("test_quota", None, day));
+    assert!(within_quota("test_quota", Some(100), day));
+
+    record_sent("test_quota", 60, day);
+    record_sent("test_quota", 60, day);
+    assert!(!within_quota("test_quota", Some(100), day));
+    // no quota means no limit
+    assert!(within_quota("test_quota", None, day));
+
+    // the daily count starts over, the total doesn't
+    let next_day = day.succ_opt().unwrap();
+    assert!(within_quota("test_quota", Some(100), next_day));
+    let usage = &key_usage()["test_quota"];
+    assert_eq!(usage.bytes_sent, 120);
+    assert_eq!(usage.bytes_today, 0);
+    assert_eq!(usage.rejected, 1);
+
+    let status = quota_exceeded("test_quota", Some(100));
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(status.message().starts_with("QUOTA_EXCEEDED"));
+  }
+
+  // a stream of fixed size messages
+  struct Messages(usize);
+
+  impl Body for Messages {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+      mut self: Pin<&mut Self>,
+      _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+      if self.0 == 0 {
+        return Poll::Ready(None);
+      }
+      self.0 -= 1;
+      Poll::Ready(Some(Ok(Bytes::from_static(&[0; 40]))))
+    }
+
+    fn poll_trailers(
+      self: Pin<&mut Self>,
+      _: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+      Poll::Ready(Ok(None))
+    }
+  }
+
+  #[tokio::test]
+  async fn test_quota_stream() {
+    let mut body = CountedBody::new(Messages(10).boxed_unsync(), "test_stream".into(), Some(100));
+    let mut received = 0;
+    while let Some(data) = body.data().await {
+      received += data.unwrap().len();
+    }
+    // the stream is cut once the quota is used up
+    assert_eq!(received, 120);
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(
+      trailers["grpc-status"],
+      (Code::ResourceExhausted as i32).to_string()
+    );
+    assert!(key_usage()["test_stream"].rejected > 0);
+
+    // not without a quota
+    let mut body = CountedBody::new(Messages(10).boxed_unsync(), "test_stream".into(), None);
+    let mut received = 0;
+    while let Some(data) = body.data().await {
+      received += data.unwrap().len();
+    }
+    assert_eq!(received, 400);
+    assert!(body.trailers().await.unwrap().is_none());
+  }
+}
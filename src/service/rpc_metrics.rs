@@ -0,0 +1,237 @@
+use http::{HeaderMap, Request, Response};
+use http_body::{Body, SizeHint};
+use lazy_static::lazy_static;
+use std::{
+  collections::HashMap,
+  future::Future,
+  pin::Pin,
+  sync::Mutex,
+  task::{Context, Poll},
+  time::Instant,
+};
+use tonic::Code;
+use tower_layer::Layer;
+use tower_service::Service;
+
+// Upper bounds of the RPC latency histogram buckets, seconds. Streams are
+// observed when they end thus mostly land in the last ones
+pub const LATENCY_BUCKETS: &[f64] = &[
+  0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 300.0, 3600.0,
+];
+
+// unknown paths are counted too, this keeps them from growing the stats
+// indefinitely
+const MAX_METHODS: usize = 200;
+
+#[derive(Debug, Clone, Default)]
+pub struct RpcStats {
+  pub requests: u64,
+  // calls in progress, open streams included
+  pub in_flight: u64,
+  // by gRPC code name, e.g. NotFound
+  pub errors: HashMap<String, u64>,
+  // observations per LATENCY_BUCKETS bucket, non-cumulative, the extra
+  // last one is for the slower calls
+  pub latency_buckets: Vec<u64>,
+  pub latency_sum: f64,
+}
+
+impl RpcStats {
+  fn observe(&mut self, seconds: f64) {
+    if self.latency_buckets.is_empty() {
+      self.latency_buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+    }
+    let idx = LATENCY_BUCKETS
+      .iter()
+      .position(|bound| seconds <= *bound)
+      .unwrap_or(LATENCY_BUCKETS.len());
+    self.latency_buckets[idx] += 1;
+    self.latency_sum += seconds;
+  }
+}
+
+lazy_static! {
+  static ref RPC_STATS: Mutex<HashMap<String, RpcStats>> = Mutex::new(HashMap::new());
+}
+
+// per method snapshot
+pub fn rpc_stats() -> HashMap<String, RpcStats> {
+  RPC_STATS.lock().unwrap().clone()
+}
+
+fn with_stats(method: &str, f: impl FnOnce(&mut RpcStats)) {
+  let mut stats = RPC_STATS.lock().unwrap();
+  if let Some(entry) = stats.get_mut(method) {
+    f(entry);
+  } else if stats.len() < MAX_METHODS {
+    f(stats.entry(method.to_owned()).or_default());
+  }
+}
+
+// "/camden.Camden/MapUpdates" -> "MapUpdates"
+fn method_name(path: &str) -> &str {
+  path.rsplit('/').next().unwrap_or(path)
+}
+
+// An RPC in progress. It travels along with the response body and is
+// recorded as finished once the body is dropped, i.e. when a stream ends
+#[derive(Debug)]
+struct RpcCall {
+  method: String,
+  started: Instant,
+  status_seen: bool,
+}
+
+impl RpcCall {
+  fn start(method: &str) -> Self {
+    with_stats(method, |stats| {
+      stats.requests += 1;
+      stats.in_flight += 1;
+    });
+    Self {
+      method: method.to_owned(),
+      started: Instant::now(),
+      status_seen: false,
+    }
+  }
+
+  // grpc-status comes in the headers of trailers-only responses, e.g.
+  // unary errors, and in the trailers otherwise
+  fn check_status(&mut self, headers: &HeaderMap) {
+    if self.status_seen {
+      return;
+    }
+    let code = headers
+      .get("grpc-status")
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.parse::<i32>().ok())
+      .map(Code::from_i32);
+    if let Some(code) = code {
+      self.status_seen = true;
+      if code != Code::Ok {
+        with_stats(&self.method, |stats| {
+          *stats.errors.entry(format!("{code:?}")).or_default() += 1;
+        });
+      }
+    }
+  }
+}
+
+impl Drop for RpcCall {
+  fn drop(&mut self) {
+    let seconds = self.started.elapsed().as_secs_f64();
+    with_stats(&self.method, |stats| {
+      stats.in_flight = stats.in_flight.saturating_sub(1);
+      stats.observe(seconds);
+    });
+  }
+}
+
+pub struct MeteredBody<B> {
+  inner: B,
+  call: RpcCall,
+}
+
+impl<B: Body + Unpin> Body for MeteredBody<B> {
+  type Data = B::Data;
+  type Error = B::Error;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    Pin::new(&mut self.inner).poll_data(cx)
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+    let res = Pin::new(&mut self.inner).poll_trailers(cx);
+    if let Poll::Ready(Ok(Some(trailers))) = &res {
+      self.call.check_status(trailers);
+    }
+    res
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+// Records request, error and latency stats per gRPC method
+#[derive(Debug, Clone, Default)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+  type Service = RpcMetrics<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RpcMetrics { inner }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcMetrics<S> {
+  inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RpcMetrics<S>
+where
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = Response<MeteredBody<ResBody>>;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    let mut call = RpcCall::start(method_name(req.uri().path()));
+    let fut = self.inner.call(req);
+    Box::pin(async move {
+      let (parts, inner) = fut.await?.into_parts();
+      call.check_status(&parts.headers);
+      Ok(Response::from_parts(parts, MeteredBody { inner, call }))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_rpc_call() {
+    assert_eq!(method_name("/camden.Camden/MapUpdates"), "MapUpdates");
+
+    let mut call = RpcCall::start("TestRpcCall");
+    assert_eq!(rpc_stats()["TestRpcCall"].in_flight, 1);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("grpc-status", "5".parse().unwrap());
+    call.check_status(&headers);
+    // status is recorded once
+    call.check_status(&headers);
+    drop(call);
+
+    let mut call = RpcCall::start("TestRpcCall");
+    headers.insert("grpc-status", "0".parse().unwrap());
+    call.check_status(&headers);
+    drop(call);
+
+    let stats = &rpc_stats()["TestRpcCall"];
+    assert_eq!(stats.requests, 2);
+    assert_eq!(stats.in_flight, 0);
+    assert_eq!(stats.errors.len(), 1);
+    assert_eq!(stats.errors["NotFound"], 1);
+    assert_eq!(stats.latency_buckets.iter().sum::<u64>(), 2);
+  }
+}
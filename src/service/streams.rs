@@ -0,0 +1,166 @@
+use super::camden;
+use crate::types::Rect;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use prost::Message;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, Weak,
+  },
+};
+use tonic::Status;
+
+// What a long-lived stream is looking at and how much it has been sent
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+  pub id: u64,
+  pub kind: &'static str,
+  pub remote: String,
+  pub connected_at: DateTime<Utc>,
+  pub bounds: Option<Rect>,
+  // map_updates filter or subscribe_query subscription queries
+  pub filters: Vec<String>,
+  pub messages_sent: u64,
+  pub bytes_sent: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SentTotals {
+  pub messages: u64,
+  pub bytes: u64,
+}
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+  // streams are unregistered as soon as their handles are dropped
+  static ref STREAMS: Mutex<HashMap<u64, Weak<Mutex<StreamInfo>>>> = Mutex::new(HashMap::new());
+  // by stream kind, closed streams included
+  static ref SENT_TOTALS: Mutex<HashMap<&'static str, SentTotals>> = Mutex::new(HashMap::new());
+}
+
+// Open streams, the ones sent the most first
+pub fn active_streams() -> Vec<StreamInfo> {
+  let mut streams = STREAMS.lock().unwrap();
+  streams.retain(|_, info| info.strong_count() > 0);
+  let mut active: Vec<StreamInfo> = streams
+    .values()
+    .filter_map(|info| info.upgrade())
+    .map(|info| info.lock().unwrap().clone())
+    .collect();
+  active.sort_by(|a, b| b.bytes_sent.cmp(&a.bytes_sent).then(a.id.cmp(&b.id)));
+  active
+}
+
+pub fn sent_totals() -> HashMap<&'static str, SentTotals> {
+  SENT_TOTALS.lock().unwrap().clone()
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamHandle {
+  info: Arc<Mutex<StreamInfo>>,
+}
+
+impl StreamHandle {
+  pub fn register(kind: &'static str, remote: String) -> Self {
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+    let info = Arc::new(Mutex::new(StreamInfo {
+      id,
+      kind,
+      remote,
+      connected_at: Utc::now(),
+      bounds: None,
+      filters: vec![],
+      messages_sent: 0,
+      bytes_sent: 0,
+    }));
+    let mut streams = STREAMS.lock().unwrap();
+    streams.retain(|_, info| info.strong_count() > 0);
+    streams.insert(id, Arc::downgrade(&info));
+    Self { info }
+  }
+
+  pub fn set_view(&self, bounds: Option<Rect>, filters: Vec<String>) {
+    let mut info = self.info.lock().unwrap();
+    info.bounds = bounds;
+    info.filters = filters;
+  }
+
+  fn sent(&self, msg: &impl Message) {
+    let bytes = msg.encoded_len() as u64;
+    let kind = {
+      let mut info = self.info.lock().unwrap();
+      info.messages_sent += 1;
+      info.bytes_sent += bytes;
+      info.kind
+    };
+    let mut totals = SENT_TOTALS.lock().unwrap();
+    let totals = totals.entry(kind).or_default();
+    totals.messages += 1;
+    totals.bytes += bytes;
+  }
+
+  // Counts the messages going out of the stream, the handle lives as long
+  // as the stream does
+  pub fn meter<T: Message>(
+    self,
+    stream: impl Stream<Item = Result<T, Status>>,
+  ) -> impl Stream<Item = Result<T, Status>> {
+    stream.inspect(move |item| {
+      if let Ok(msg) = item {
+        self.sent(msg);
+      }
+    })
+  }
+}
+
+impl From<StreamInfo> for camden::ActiveStream {
+  fn from(value: StreamInfo) -> Self {
+    Self {
+      id: value.id,
+      kind: value.kind.to_owned(),
+      remote: value.remote,
+      connected_at: value.connected_at.timestamp_millis() as u64,
+      bounds: value.bounds.map(|rect| camden::MapBounds {
+        sw: Some(rect.south_west.into()),
+        ne: Some(rect.north_east.into()),
+        zoom: 0.0,
+      }),
+      filters: value.filters,
+      messages_sent: value.messages_sent,
+      bytes_sent: value.bytes_sent,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio_stream::iter;
+
+  #[tokio::test]
+  async fn test_stream_handle() {
+    let handle = StreamHandle::register("test_stream", "127.0.0.1:50000".into());
+    handle.set_view(None, vec!["alt > 10000".into()]);
+    let id = handle.info.lock().unwrap().id;
+
+    let msgs = vec![
+      Ok(camden::NoParams {}),
+      Ok(camden::NoParams {}),
+      Err(Status::internal("")),
+    ];
+    let stream = handle.clone().meter(iter(msgs));
+    assert_eq!(stream.collect::<Vec<_>>().await.len(), 3);
+
+    let info = active_streams().into_iter().find(|s| s.id == id).unwrap();
+    assert_eq!(info.messages_sent, 2);
+    assert_eq!(info.filters, vec!["alt > 10000"]);
+    assert_eq!(sent_totals()["test_stream"].messages, 2);
+
+    drop(handle);
+    assert!(active_streams().iter().all(|s| s.id != id));
+  }
+}
@@ -0,0 +1,491 @@
+use super::{
+  airport_updates,
+  calc::calc_diff,
+  camden::{
+    self, map_updates_request::Request as ServiceRequest, update::ObjectUpdate, PilotAlert,
+    PilotAlertType, PilotAlertUpdate, PilotCluster, PilotClusters, Update, WeatherUpdate,
+  },
+  filter::compile_filter,
+  fir_updates, hazard_updates, pilot_updates,
+};
+use crate::{
+  config::ZoomBudget,
+  fixed::types::{Airport, FIR},
+  lee::{
+    make_expr_limited,
+    parser::expression::{flush_evaluations, CompileFunc, Expression, Limits},
+  },
+  manager::{events::CycleDiff, ingest::PilotProtos},
+  moving::{controller::Controller, pilot::Pilot},
+  types::{Point, Rect},
+  weather::{sigmet::Hazard, WeatherChange},
+};
+use log::debug;
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  sync::Arc,
+};
+
+// if zoom is less than this, the map might be wrapped on screen, thus we
+// need to show all the objects without checking current user map boundaries
+const MIN_ZOOM: f64 = 3.0;
+
+// FIR boundaries simplification requested by a map_updates client
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BoundariesDetail {
+  pub tolerance: f64,
+  pub max_points: usize,
+}
+
+impl BoundariesDetail {
+  pub fn apply(&self, fir: Arc<FIR>) -> Arc<FIR> {
+    if *self == Self::default() {
+      return fir;
+    }
+    let mut fir = Arc::unwrap_or_clone(fir);
+    fir.boundaries = fir.boundaries.simplified(self.tolerance, self.max_points);
+    Arc::new(fir)
+  }
+}
+
+impl From<camden::BoundariesDetail> for BoundariesDetail {
+  fn from(value: camden::BoundariesDetail) -> Self {
+    Self {
+      tolerance: value.tolerance.max(0.0),
+      max_points: value.max_points as usize,
+    }
+  }
+}
+
+// What a map_updates client is currently looking at
+#[derive(Default)]
+pub struct MapView {
+  pub bounds: Option<Rect>,
+  pub no_bounds: bool,
+  pub zoom: f64,
+  pub filter: Option<Expression<Pilot>>,
+  // source of the filter in use
+  pub filter_query: Option<String>,
+  pub show_wx: bool,
+  pub show_controllers: bool,
+  pub show_hazards: bool,
+  pub boundaries_detail: BoundariesDetail,
+  pub subscriptions: HashSet<String>,
+  // zoomed out past a map_budget level
+  pub budget: Option<ZoomBudget>,
+}
+
+impl MapView {
+  pub fn apply(&mut self, req: ServiceRequest, limits: &Limits, remote: &str) {
+    match req {
+      ServiceRequest::Filter(flt) => {
+        debug!("client {:?} filter request {}", remote, flt);
+        self.filter = {
+          if !flt.is_empty() {
+            let res = make_expr_limited::<Pilot>(&flt, limits);
+            if let Ok(mut expr) = res {
+              let cb: Box<CompileFunc<Pilot>> = Box::new(compile_filter);
+              expr.compile(&cb).map(|_| expr).ok()
+            } else {
+              None
+            }
+          } else {
+            None
+          }
+        };
+        self.filter_query = self.filter.as_ref().map(|_| flt);
+      }
+      ServiceRequest::Bounds(bds) => {
+        debug!("client {:?} bounds request {:?}", remote, bds);
+        self.no_bounds = bds.zoom < MIN_ZOOM;
+        self.zoom = bds.zoom;
+        self.bounds = Some(bds.into());
+      }
+      ServiceRequest::ShowWx(value) => {
+        debug!("client {:?} show_wx request {}", remote, value);
+        self.show_wx = value;
+      }
+      ServiceRequest::ShowControllers(value) => {
+        debug!("client {:?} show_controllers request {}", remote, value);
+        self.show_controllers = value;
+      }
+      ServiceRequest::ShowHazards(value) => {
+        debug!("client {:?} show_hazards request {}", remote, value);
+        self.show_hazards = value;
+      }
+      ServiceRequest::BoundariesDetail(value) => {
+        debug!("client {:?} boundaries_detail request {:?}", remote, value);
+        self.boundaries_detail = value.into();
+      }
+      ServiceRequest::SubscribeId(value) => {
+        debug!("client {:?} subscribe request {}", remote, value);
+        self.subscriptions.insert(value);
+      }
+      ServiceRequest::UnsubscribeId(value) => {
+        debug!("client {:?} unsubscribe request {}", remote, value);
+        self.subscriptions.remove(&value);
+      }
+    }
+  }
+
+  // Bounds to load the uncontrolled airports weather within, if the client
+  // shows weather and is zoomed in far enough
+  pub fn weather_bounds(&self, min_zoom: f64) -> Option<&Rect> {
+    if !self.show_wx || self.no_bounds || self.zoom < min_zoom {
+      return None;
+    }
+    self.bounds.as_ref()
+  }
+
+  pub fn pilot_visible(&self, pilot: &Pilot) -> bool {
+    if self.subscriptions.contains(&pilot.callsign) {
+      return true;
+    }
+    let in_bounds = match self.bounds.as_ref() {
+      None => false,
+      Some(rect) => self.no_bounds || rect.contains(pilot.position),
+    };
+    in_bounds && self.pilot_selected(pilot)
+  }
+
+  // Whether the pilot is to be sent one by one once in view. Within a
+  // budget it takes an emergency or a filter matching it
+  pub fn pilot_selected(&self, pilot: &Pilot) -> bool {
+    if self.budget.is_some() && pilot.emergency {
+      return true;
+    }
+    match self.filter.as_ref() {
+      Some(f) => f.evaluate(pilot),
+      None => self.budget.is_none(),
+    }
+  }
+
+  pub fn airport_visible(&self, arpt: &Airport) -> bool {
+    let in_bounds = match self.bounds.as_ref() {
+      None => false,
+      Some(rect) => self.no_bounds || rect.contains(arpt.position),
+    };
+    in_bounds && (arpt.is_staffed() || (self.show_wx && arpt.wx.is_some()))
+  }
+
+  pub fn fir_visible(&self, fir: &FIR) -> bool {
+    let in_bounds = match self.bounds.as_ref() {
+      None => false,
+      Some(rect) => self.no_bounds || rect.intersects(fir.boundaries.min, fir.boundaries.max),
+    };
+    in_bounds && !fir.is_empty()
+  }
+
+  pub fn hazard_visible(&self, hazard: &Hazard) -> bool {
+    let in_bounds = match self.bounds.as_ref() {
+      None => false,
+      Some(rect) => {
+        let bounds = hazard.bounds();
+        self.no_bounds || rect.intersects(bounds.south_west, bounds.north_east)
+      }
+    };
+    in_bounds && self.show_hazards
+  }
+}
+
+// What a map_updates client has already been sent
+#[derive(Default)]
+pub struct MapState {
+  pub pilots: HashMap<String, Arc<Pilot>>,
+  pub airports: HashMap<String, Arc<Airport>>,
+  pub firs: HashMap<String, Arc<FIR>>,
+  pub controllers: HashMap<String, Controller>,
+  pub hazards: HashMap<String, Hazard>,
+  // emergency and stale flags of the pilots sent
+  pub flags: HashMap<String, (bool, bool)>,
+  pub clusters: Vec<PilotCluster>,
+}
+
+// Counts the pilots per grid cell, cells are sorted to keep the clusters
+// comparable between updates
+fn cluster_pilots<'a>(pilots: impl Iterator<Item = &'a Pilot>, cell: f64) -> Vec<PilotCluster> {
+  let mut cells: BTreeMap<(i64, i64), (f64, f64, u32)> = BTreeMap::new();
+  for pilot in pilots {
+    let Point { lat, lng } = pilot.position;
+    let key = ((lat / cell).floor() as i64, (lng / cell).floor() as i64);
+    let sums = cells.entry(key).or_default();
+    sums.0 += lat;
+    sums.1 += lng;
+    sums.2 += 1;
+  }
+  cells
+    .into_values()
+    .map(|(lat, lng, count)| PilotCluster {
+      position: Some(
+        Point {
+          lat: lat / count as f64,
+          lng: lng / count as f64,
+        }
+        .into(),
+      ),
+      count,
+    })
+    .collect()
+}
+
+impl MapState {
+  // tracks the flags of pilots sent to the client, producing an alert
+  // for every flag raised or cleared
+  pub fn alerts(&mut self, set: &[Arc<Pilot>], delete: &[Arc<Pilot>]) -> Vec<Update> {
+    let mut alerts = vec![];
+    for pilot in set.iter() {
+      let flags = (pilot.emergency, pilot.stale);
+      let prev = self.flags.get(&pilot.callsign).copied().unwrap_or_default();
+      let changes = [
+        (PilotAlertType::Emergency, prev.0, flags.0),
+        (PilotAlertType::Stale, prev.1, flags.1),
+      ];
+      for (alert_type, was, is) in changes {
+        if was != is {
+          alerts.push(PilotAlert {
+            callsign: pilot.callsign.clone(),
+            alert_type: alert_type as i32,
+            active: is,
+            transponder: pilot.transponder.clone(),
+          });
+        }
+      }
+      if flags == (false, false) {
+        self.flags.remove(&pilot.callsign);
+      } else {
+        self.flags.insert(pilot.callsign.clone(), flags);
+      }
+    }
+    for pilot in delete.iter() {
+      self.flags.remove(&pilot.callsign);
+    }
+
+    if alerts.is_empty() {
+      return vec![];
+    }
+    vec![Update {
+      object_update: Some(ObjectUpdate::PilotAlertUpdate(PilotAlertUpdate { alerts })),
+      degraded: false,
+    }]
+  }
+
+  // Clusters of the pilots in view the client hasn't been sent one by one,
+  // there are none unless within a budget and with no filter. Sent only
+  // when they change
+  pub fn cluster_updates(&mut self, pilots: &[Arc<Pilot>], view: &MapView) -> Vec<Update> {
+    let clusters = match view.budget {
+      Some(budget) if view.filter.is_none() => cluster_pilots(
+        pilots
+          .iter()
+          .filter(|pilot| !view.pilot_visible(pilot))
+          .map(Arc::as_ref),
+        budget.cluster_cell,
+      ),
+      _ => vec![],
+    };
+    flush_evaluations();
+    if clusters == self.clusters {
+      return vec![];
+    }
+    self.clusters = clusters.clone();
+    vec![Update {
+      object_update: Some(ObjectUpdate::PilotClusters(PilotClusters { clusters })),
+      degraded: false,
+    }]
+  }
+
+  // only the airports the client has been sent get their weather updates
+  pub fn weather_updates(&self, changes: &[WeatherChange]) -> Vec<Update> {
+    let changes: Vec<_> = changes
+      .iter()
+      .filter(|change| self.airports.contains_key(&change.airport_id))
+      .map(|change| change.clone().into())
+      .collect();
+    if changes.is_empty() {
+      return vec![];
+    }
+    vec![Update {
+      object_update: Some(ObjectUpdate::WeatherUpdate(WeatherUpdate { changes })),
+      degraded: false,
+    }]
+  }
+
+  pub fn apply_diff(
+    &mut self,
+    diff: &CycleDiff,
+    view: &MapView,
+    protos: &PilotProtos,
+  ) -> Vec<Update> {
+    if view.bounds.is_none() {
+      return vec![];
+    }
+
+    let (pilots_set, pilots_delete) = calc_diff(
+      &diff.pilots,
+      &mut self.pilots,
+      |p| p.callsign.clone(),
+      |p| view.pilot_visible(p),
+    );
+    flush_evaluations();
+    let (arpts_set, arpts_delete) = calc_diff(
+      &diff.airports,
+      &mut self.airports,
+      |a| a.compound_id(),
+      |a| view.airport_visible(a),
+    );
+    let (firs_set, firs_delete) = calc_diff(
+      &diff.firs,
+      &mut self.firs,
+      |f| f.icao.clone(),
+      |f| view.fir_visible(f),
+    );
+    let (hazards_set, hazards_delete) = calc_diff(
+      &diff.hazards,
+      &mut self.hazards,
+      |h| h.id.clone(),
+      |h| view.hazard_visible(h),
+    );
+
+    let mut updates = self.alerts(&pilots_set, &pilots_delete);
+    updates.extend(pilot_updates(pilots_set, pilots_delete, protos));
+    updates.extend(airport_updates(arpts_set, arpts_delete));
+    updates.extend(self.weather_updates(&diff.weather));
+    updates.extend(fir_updates(firs_set, firs_delete, &view.boundaries_detail));
+    updates.extend(hazard_updates(hazards_set, hazards_delete));
+    updates
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pilot(callsign: &str, transponder: &str, stale: bool) -> Arc<Pilot> {
+    pilot_at(callsign, transponder, stale, Point { lat: 0.0, lng: 0.0 })
+  }
+
+  fn pilot_at(callsign: &str, transponder: &str, stale: bool, position: Point) -> Arc<Pilot> {
+    Arc::new(Pilot {
+      position,
+      transponder: transponder.into(),
+      emergency: Pilot::is_emergency_squawk(transponder),
+      stale,
+      ..Pilot::test_default(callsign)
+    })
+  }
+
+  fn alerts(updates: Vec<Update>) -> Vec<PilotAlert> {
+    updates
+      .into_iter()
+      .flat_map(|u| match u.object_update {
+        Some(ObjectUpdate::PilotAlertUpdate(upd)) => upd.alerts,
+        _ => vec![],
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_alerts() {
+    let mut state = MapState::default();
+
+    let res = alerts(state.alerts(&[pilot("AAA", "2000", false)], &[]));
+    assert!(res.is_empty());
+
+    let res = alerts(state.alerts(&[pilot("AAA", "7700", false)], &[]));
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].alert_type, PilotAlertType::Emergency as i32);
+    assert!(res[0].active);
+
+    // still squawking, nothing new to report
+    let res = alerts(state.alerts(&[pilot("AAA", "7700", true)], &[]));
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].alert_type, PilotAlertType::Stale as i32);
+
+    let res = alerts(state.alerts(&[pilot("AAA", "2000", false)], &[]));
+    assert_eq!(res.len(), 2);
+    assert!(res.iter().all(|a| !a.active));
+    assert!(state.flags.is_empty());
+  }
+
+  #[test]
+  fn test_weather_bounds() {
+    let mut view = MapView {
+      bounds: Some(Rect {
+        south_west: Point {
+          lat: 50.0,
+          lng: 0.0,
+        },
+        north_east: Point {
+          lat: 51.0,
+          lng: 1.0,
+        },
+      }),
+      zoom: 8.0,
+      ..Default::default()
+    };
+    assert!(view.weather_bounds(7.0).is_none());
+
+    view.show_wx = true;
+    assert!(view.weather_bounds(7.0).is_some());
+    assert!(view.weather_bounds(9.0).is_none());
+
+    view.no_bounds = true;
+    assert!(view.weather_bounds(7.0).is_none());
+  }
+
+  fn clusters(updates: Vec<Update>) -> Option<Vec<(f64, u32)>> {
+    updates.into_iter().find_map(|u| match u.object_update {
+      Some(ObjectUpdate::PilotClusters(upd)) => Some(
+        upd
+          .clusters
+          .into_iter()
+          .map(|c| (c.position.unwrap().lat, c.count))
+          .collect(),
+      ),
+      _ => None,
+    })
+  }
+
+  #[test]
+  fn test_budget() {
+    let at = |lat| Point { lat, lng: 1.0 };
+    let pilots = vec![
+      pilot_at("AAA", "2000", false, at(1.0)),
+      pilot_at("BBB", "2000", false, at(3.0)),
+      pilot_at("CCC", "7700", false, at(5.0)),
+      pilot_at("DDD", "2000", false, at(15.0)),
+    ];
+    let mut view = MapView {
+      bounds: Some(Rect {
+        south_west: at(-80.0),
+        north_east: at(80.0),
+      }),
+      no_bounds: true,
+      ..Default::default()
+    };
+    let mut state = MapState::default();
+    assert!(view.pilot_visible(&pilots[0]));
+    assert!(state.cluster_updates(&pilots, &view).is_empty());
+
+    view.budget = Some(ZoomBudget {
+      below_zoom: 3.0,
+      cluster_cell: 10.0,
+    });
+    view.subscriptions.insert("DDD".into());
+    let visible: Vec<&str> = pilots
+      .iter()
+      .filter(|p| view.pilot_visible(p))
+      .map(|p| p.callsign.as_str())
+      .collect();
+    assert_eq!(visible, vec!["CCC", "DDD"]);
+    let res = clusters(state.cluster_updates(&pilots, &view));
+    assert_eq!(res, Some(vec![(2.0, 2)]));
+    // nothing changed, nothing to send
+    assert!(state.cluster_updates(&pilots, &view).is_empty());
+
+    // zoomed in again, the clusters are cleared
+    view.budget = None;
+    let res = clusters(state.cluster_updates(&pilots, &view));
+    assert_eq!(res, Some(vec![]));
+  }
+}
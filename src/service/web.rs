@@ -0,0 +1,98 @@
+use crate::manager::Manager;
+use hyper::{
+  header::CONTENT_TYPE,
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::json;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+// Plain HTTP endpoints for scrapers and probes which don't speak gRPC:
+// prometheus metrics, health and build version
+pub struct WebService {
+  manager: Arc<Manager>,
+}
+
+fn respond(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, content_type)
+    .body(Body::from(body))
+    .unwrap()
+}
+
+fn version() -> String {
+  json!({
+    "name": env!("CARGO_PKG_NAME"),
+    "version": env!("CARGO_PKG_VERSION"),
+    "repository": env!("CARGO_PKG_REPOSITORY"),
+  })
+  .to_string()
+}
+
+impl WebService {
+  pub fn new(manager: Arc<Manager>) -> Self {
+    Self { manager }
+  }
+
+  async fn handle(&self, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+      return respond(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "text/plain",
+        "method not allowed\n".into(),
+      );
+    }
+    match req.uri().path() {
+      "/metrics" => respond(
+        StatusCode::OK,
+        "text/plain; version=0.0.4",
+        self.manager.render_metrics().await,
+      ),
+      "/healthz" => {
+        // degraded service is still up but serves stale data, probes
+        // should see it as unhealthy
+        let degraded = self.manager.is_degraded();
+        let last_fresh_data_at = self.manager.last_fresh_data_at().await;
+        let status = if degraded {
+          StatusCode::SERVICE_UNAVAILABLE
+        } else {
+          StatusCode::OK
+        };
+        let body = json!({
+          "degraded": degraded,
+          "last_fresh_data_at": last_fresh_data_at.to_rfc3339(),
+        });
+        respond(status, "application/json", body.to_string())
+      }
+      "/version" => respond(StatusCode::OK, "application/json", version()),
+      _ => respond(StatusCode::NOT_FOUND, "text/plain", "not found\n".into()),
+    }
+  }
+
+  pub async fn serve(self, addr: SocketAddr) -> Result<(), hyper::Error> {
+    let svc = Arc::new(self);
+    let make_svc = make_service_fn(move |_| {
+      let svc = svc.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let svc = svc.clone();
+          async move { Ok::<_, Infallible>(svc.handle(req).await) }
+        }))
+      }
+    });
+    Server::bind(&addr).serve(make_svc).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_version() {
+    let v: serde_json::Value = serde_json::from_str(&version()).unwrap();
+    assert_eq!(v["name"], "simwatch-grpc");
+    assert_eq!(v["version"], env!("CARGO_PKG_VERSION"));
+  }
+}
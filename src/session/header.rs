@@ -0,0 +1,69 @@
+use chrono::Utc;
+
+use crate::trackfile::{EntryLayout, TrackFileHeader};
+
+const SESSION_VERSION: u64 = 3;
+const SESSION_MAGIC_NUMBER: u64 = 0x5E55104E2C41B7D3;
+
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct Header {
+  magic: u64,
+  version: u64,
+  ts: u64,
+  count: u64,
+}
+
+impl Default for Header {
+  fn default() -> Self {
+    Self {
+      magic: SESSION_MAGIC_NUMBER,
+      version: SESSION_VERSION,
+      ts: Utc::now().timestamp_millis() as u64,
+      count: 0,
+    }
+  }
+}
+
+impl TrackFileHeader for Header {
+  fn check_magic(&self) -> bool {
+    self.magic == SESSION_MAGIC_NUMBER
+  }
+
+  fn version(&self) -> u64 {
+    self.version
+  }
+
+  fn timestamp(&self) -> u64 {
+    self.ts
+  }
+
+  fn count(&self) -> u64 {
+    self.count
+  }
+
+  fn inc(&mut self) {
+    self.ts = Utc::now().timestamp_millis() as u64;
+    self.count += 1;
+  }
+
+  fn set_count(&mut self, count: u64) {
+    self.count = count;
+  }
+
+  fn set_version(&mut self, version: u64) {
+    self.version = version;
+  }
+
+  fn set_timestamp(&mut self, ts: u64) {
+    self.ts = ts;
+  }
+
+  fn entry_layout(version: u64) -> EntryLayout {
+    match version {
+      1 => EntryLayout::Raw,
+      2 => EntryLayout::Checksummed,
+      _ => EntryLayout::Encoded,
+    }
+  }
+}
@@ -0,0 +1,214 @@
+pub mod header;
+pub mod record;
+
+use self::{header::Header, record::SessionRecord};
+use crate::moving::pilot::Pilot;
+use crate::service::camden;
+use crate::trackfile::{Result, TrackFile};
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Session {
+  pub cid: u32,
+  pub callsign: String,
+  pub departure: String,
+  pub arrival: String,
+  pub logon_time: DateTime<Utc>,
+  pub last_seen: DateTime<Utc>,
+  pub online: bool,
+}
+
+impl From<SessionRecord> for Session {
+  fn from(value: SessionRecord) -> Self {
+    Self {
+      cid: value.cid,
+      callsign: value.callsign(),
+      departure: value.departure(),
+      arrival: value.arrival(),
+      logon_time: value.logon_time(),
+      last_seen: value.last_seen(),
+      online: false,
+    }
+  }
+}
+
+impl From<&Pilot> for Session {
+  fn from(value: &Pilot) -> Self {
+    let (departure, arrival) = match value.flight_plan.as_ref() {
+      Some(fp) => (fp.departure.clone(), fp.arrival.clone()),
+      None => ("".into(), "".into()),
+    };
+    Self {
+      cid: value.cid,
+      callsign: value.callsign.clone(),
+      departure,
+      arrival,
+      logon_time: value.logon_time,
+      last_seen: value.last_updated,
+      online: true,
+    }
+  }
+}
+
+impl From<Session> for camden::PilotSession {
+  fn from(value: Session) -> Self {
+    Self {
+      cid: value.cid,
+      callsign: value.callsign,
+      departure: value.departure,
+      arrival: value.arrival,
+      logon_time: value.logon_time.timestamp_millis() as u64,
+      last_seen: value.last_seen.timestamp_millis() as u64,
+      online: value.online,
+    }
+  }
+}
+
+// Completed pilot sessions, one file per cid
+#[derive(Debug)]
+pub struct SessionStore {
+  folder: String,
+  retention: Duration,
+}
+
+impl SessionStore {
+  pub fn new(folder: &str, retention: std::time::Duration) -> Self {
+    Self {
+      folder: folder.to_owned(),
+      retention: Duration::from_std(retention).unwrap_or(Duration::days(30)),
+    }
+  }
+
+  pub fn set_retention(&mut self, retention: std::time::Duration) {
+    self.retention = Duration::from_std(retention).unwrap_or(Duration::days(30));
+  }
+
+  fn session_filename(&self, cid: u32) -> PathBuf {
+    let first = format!("{}", cid / 10000);
+    Path::new(&self.folder)
+      .join(first)
+      .join(format!("{cid}.bin"))
+  }
+
+  fn get_session_file(&self, cid: u32) -> Result<TrackFile<SessionRecord, Header>> {
+    let filename = self.session_filename(cid);
+    if let Some(folder) = filename.parent() {
+      if !Path::is_dir(folder) {
+        std::fs::create_dir_all(folder)?;
+      }
+    }
+    TrackFile::new(&filename.display().to_string())
+  }
+
+  pub fn store_session(&self, pilot: &Pilot) -> Result<()> {
+    let mut file = self.get_session_file(pilot.cid)?;
+    file.append(&pilot.into())?;
+    Ok(())
+  }
+
+  pub fn get_sessions(&self, cid: u32, since: DateTime<Utc>) -> Result<Vec<Session>> {
+    if !self.session_filename(cid).is_file() {
+      return Ok(vec![]);
+    }
+    let file = self.get_session_file(cid)?;
+    let since = since.timestamp_millis();
+    let sessions = file
+      .read_all()?
+      .into_iter()
+      .filter(|rec| rec.last_seen >= since)
+      .map(|rec| rec.into())
+      .collect();
+    Ok(sessions)
+  }
+
+  // removes the files of pilots not seen for longer than the retention
+  // period, upgrading and recovering the rest, nothing may be writing
+  // meanwhile
+  pub fn cleanup(&self) -> Result<()> {
+    let root = Path::new(&self.folder);
+    if !root.is_dir() {
+      return Ok(());
+    }
+    let min_date = Utc::now() - self.retention;
+    for dir in std::fs::read_dir(root)?.flatten() {
+      if !dir.path().is_dir() {
+        continue;
+      }
+      for entry in std::fs::read_dir(dir.path())?.flatten() {
+        let filename = entry.path().display().to_string();
+        let file = TrackFile::<SessionRecord, Header>::repair(&filename);
+        if let Ok(file) = file {
+          if let Ok(mtime) = file.mtime() {
+            if mtime < min_date {
+              debug!("destroying session file {filename} older than {min_date:?}");
+              let _ = file.destroy();
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::moving::pilot::FlightPlan;
+  use std::env::temp_dir;
+
+  fn pilot(callsign: &str, logon_time: DateTime<Utc>) -> Pilot {
+    Pilot {
+      cid: 1234567,
+      flight_plan: Some(FlightPlan {
+        flight_rules: "I".into(),
+        aircraft: "".into(),
+        departure: "EGLL".into(),
+        arrival: "LFPG".into(),
+        alternate: "".into(),
+        cruise_tas: 0,
+        altitude: 0,
+        deptime: "".into(),
+        enroute_time: "".into(),
+        fuel_time: "".into(),
+        remarks: "".into(),
+        route: "".into(),
+      }),
+      logon_time,
+      last_updated: logon_time + Duration::hours(1),
+      ..Pilot::test_default(callsign)
+    }
+  }
+
+  #[test]
+  fn test_store_sessions() {
+    let folder = temp_dir().join(format!("sessions-test-{}", std::process::id()));
+    let store = SessionStore::new(
+      folder.to_str().unwrap(),
+      std::time::Duration::from_secs(86400 * 30),
+    );
+
+    let now = Utc::now();
+    store
+      .store_session(&pilot("BAW1", now - Duration::days(2)))
+      .unwrap();
+    store.store_session(&pilot("BAW2", now)).unwrap();
+
+    let sessions = store.get_sessions(1234567, now).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].callsign, "BAW2");
+    assert_eq!(sessions[0].departure, "EGLL");
+    assert_eq!(sessions[0].arrival, "LFPG");
+    assert!(!sessions[0].online);
+
+    let sessions = store
+      .get_sessions(1234567, now - Duration::days(7))
+      .unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(store.get_sessions(7654321, now).unwrap().is_empty());
+
+    std::fs::remove_dir_all(folder).unwrap();
+  }
+}
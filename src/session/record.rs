@@ -0,0 +1,83 @@
+use crate::{moving::pilot::Pilot, trackfile::TrackFileEntry, util::from_millis};
+use chrono::{DateTime, Utc};
+
+// Session file entries are stored as they are in memory, so the
+// strings are stored as zero-padded byte arrays
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct SessionRecord {
+  pub cid: u32,
+  callsign: [u8; 16],
+  departure: [u8; 8],
+  arrival: [u8; 8],
+  pub logon_time: i64,
+  pub last_seen: i64,
+}
+
+fn to_fixed<const N: usize>(value: &str) -> [u8; N] {
+  let mut buf = [0; N];
+  let len = value.len().min(N);
+  buf[..len].copy_from_slice(&value.as_bytes()[..len]);
+  buf
+}
+
+fn from_fixed(value: &[u8]) -> String {
+  let len = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+  String::from_utf8_lossy(&value[..len]).into_owned()
+}
+
+impl TrackFileEntry for SessionRecord {}
+
+impl SessionRecord {
+  pub fn callsign(&self) -> String {
+    from_fixed(&self.callsign)
+  }
+
+  pub fn departure(&self) -> String {
+    from_fixed(&self.departure)
+  }
+
+  pub fn arrival(&self) -> String {
+    from_fixed(&self.arrival)
+  }
+
+  pub fn logon_time(&self) -> DateTime<Utc> {
+    from_millis(self.logon_time)
+  }
+
+  pub fn last_seen(&self) -> DateTime<Utc> {
+    from_millis(self.last_seen)
+  }
+}
+
+impl From<&Pilot> for SessionRecord {
+  fn from(value: &Pilot) -> Self {
+    let (departure, arrival) = match value.flight_plan.as_ref() {
+      Some(fp) => (fp.departure.as_str(), fp.arrival.as_str()),
+      None => ("", ""),
+    };
+    Self {
+      cid: value.cid,
+      callsign: to_fixed(&value.callsign),
+      departure: to_fixed(departure),
+      arrival: to_fixed(arrival),
+      logon_time: value.logon_time.timestamp_millis(),
+      last_seen: value.last_updated.timestamp_millis(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fixed_strings() {
+    let buf: [u8; 8] = to_fixed("EGLL");
+    assert_eq!(from_fixed(&buf), "EGLL");
+    let buf: [u8; 4] = to_fixed("TOOLONG");
+    assert_eq!(from_fixed(&buf), "TOOL");
+    let buf: [u8; 4] = to_fixed("");
+    assert_eq!(from_fixed(&buf), "");
+  }
+}
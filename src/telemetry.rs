@@ -0,0 +1,45 @@
+use crate::config::Tracing;
+use opentelemetry::{
+  sdk::{
+    trace::{self, Sampler},
+    Resource,
+  },
+  trace::TraceError,
+  KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+// Installs the OTLP span exporter if an endpoint is configured. Logging
+// stays with the log crate, only spans are exported
+pub fn init_tracing(cfg: &Tracing) -> Result<bool, TraceError> {
+  let Some(endpoint) = cfg.otlp_endpoint.as_ref() else {
+    return Ok(false);
+  };
+  let tracer = opentelemetry_otlp::new_pipeline()
+    .tracing()
+    .with_exporter(
+      opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint),
+    )
+    .with_trace_config(
+      trace::config()
+        .with_sampler(Sampler::TraceIdRatioBased(cfg.sample_ratio))
+        .with_resource(Resource::new(vec![KeyValue::new(
+          "service.name",
+          cfg.service_name.clone(),
+        )])),
+    )
+    .install_batch(opentelemetry::runtime::Tokio)?;
+  tracing_subscriber::registry()
+    .with(tracing_opentelemetry::layer().with_tracer(tracer))
+    .try_init()
+    .map_err(|err| TraceError::Other(err.into()))?;
+  Ok(true)
+}
+
+// Flushes the spans not exported yet
+pub fn shutdown_tracing() {
+  opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,232 @@
+use crate::{config, moving::pilot::Pilot};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+// Completed track files uploaded to S3 compatible storage, addressed
+// path-style as endpoint/bucket/key
+#[derive(Debug)]
+pub struct Archive {
+  cfg: config::Archive,
+  client: reqwest::Client,
+}
+
+impl Archive {
+  pub fn new(cfg: config::Archive, client: reqwest::Client) -> Self {
+    Self { cfg, client }
+  }
+
+  pub fn compress(&self) -> bool {
+    self.cfg.compress
+  }
+
+  // prefix/yyyy/mm/dd/cid/cid.callsign.logon.bin, dated by the logon time
+  pub fn key(&self, pilot: &Pilot) -> String {
+    let key = format!(
+      "{}/{}/{}.{}.{}.bin",
+      pilot.logon_time.format("%Y/%m/%d"),
+      pilot.cid,
+      pilot.cid,
+      pilot.callsign,
+      pilot.logon_time.timestamp()
+    );
+    let prefix = self.cfg.prefix.trim_matches('/');
+    if prefix.is_empty() {
+      key
+    } else {
+      format!("{prefix}/{key}")
+    }
+  }
+
+  pub async fn upload(&self, key: &str, data: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let request = self.request(reqwest::Method::PUT, key, &data, Utc::now())?;
+    let response = request.body(data).send().await?;
+    response.error_for_status()?;
+    Ok(())
+  }
+
+  // None if there's no such object in the archive
+  pub async fn download(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let request = self.request(reqwest::Method::GET, key, &[], Utc::now())?;
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+    let data = response.error_for_status()?.bytes().await?;
+    Ok(Some(data.to_vec()))
+  }
+
+  fn request(
+    &self,
+    method: reqwest::Method,
+    key: &str,
+    payload: &[u8],
+    now: DateTime<Utc>,
+  ) -> Result<reqwest::RequestBuilder, Box<dyn Error + Send + Sync>> {
+    let (url, path) = object_url(&self.cfg.endpoint, &self.cfg.bucket, key)?;
+    let host = match (url.host_str(), url.port()) {
+      (Some(host), Some(port)) => format!("{host}:{port}"),
+      (Some(host), None) => host.to_owned(),
+      _ => return Err(format!("invalid archive endpoint {}", self.cfg.endpoint).into()),
+    };
+
+    let payload_hash = hex::encode(Sha256::digest(payload));
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let headers = [
+      ("host", host.as_str()),
+      ("x-amz-content-sha256", payload_hash.as_str()),
+      ("x-amz-date", amz_date.as_str()),
+    ];
+    let signature = sign(
+      &self.cfg.secret_key,
+      &self.cfg.region,
+      method.as_str(),
+      &path,
+      &headers,
+      &payload_hash,
+      now,
+    );
+    let authorization = format!(
+      "AWS4-HMAC-SHA256 Credential={}/{}/{}/s3/aws4_request, SignedHeaders={SIGNED_HEADERS}, Signature={signature}",
+      self.cfg.access_key,
+      now.format("%Y%m%d"),
+      self.cfg.region,
+    );
+
+    Ok(
+      self
+        .client
+        .request(method, url)
+        .header("x-amz-content-sha256", payload_hash)
+        .header("x-amz-date", amz_date)
+        .header("authorization", authorization),
+    )
+  }
+}
+
+// The object url and its path as signed, the bucket and key are appended
+// to the endpoint path so that endpoints behind a path prefix work
+fn object_url(
+  endpoint: &str,
+  bucket: &str,
+  key: &str,
+) -> Result<(Url, String), Box<dyn Error + Send + Sync>> {
+  let mut url = Url::parse(endpoint)?;
+  let path = format!(
+    "{}/{bucket}/{}",
+    url.path().trim_end_matches('/'),
+    uri_encode(key)
+  );
+  url.set_path(&path);
+  Ok((url, path))
+}
+
+fn uri_encode(key: &str) -> String {
+  let mut encoded = String::new();
+  for b in key.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+        encoded.push(b as char)
+      }
+      _ => encoded.push_str(&format!("%{b:02X}")),
+    }
+  }
+  encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+  // hmac accepts keys of any length
+  let mut mac = HmacSha256::new_from_slice(key).unwrap();
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+// AWS signature version 4, headers must be lowercase and sorted by name
+fn sign(
+  secret_key: &str,
+  region: &str,
+  method: &str,
+  path: &str,
+  headers: &[(&str, &str)],
+  payload_hash: &str,
+  now: DateTime<Utc>,
+) -> String {
+  let date = now.format("%Y%m%d").to_string();
+  let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+  let scope = format!("{date}/{region}/s3/aws4_request");
+
+  let canonical_headers: String = headers
+    .iter()
+    .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+    .collect();
+  let signed_headers: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+  let canonical_request = format!(
+    "{method}\n{path}\n\n{canonical_headers}\n{}\n{payload_hash}",
+    signed_headers.join(";")
+  );
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+    hex::encode(Sha256::digest(canonical_request.as_bytes()))
+  );
+
+  let key = hmac(format!("AWS4{secret_key}").as_bytes(), &date);
+  let key = hmac(&key, region);
+  let key = hmac(&key, "s3");
+  let key = hmac(&key, "aws4_request");
+  hex::encode(hmac(&key, &string_to_sign))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sign() {
+    // the GET object example from the AWS S3 signature v4 documentation
+    let now = DateTime::from_timestamp(1369353600, 0).unwrap();
+    let payload_hash = hex::encode(Sha256::digest(b""));
+    let signature = sign(
+      "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+      "us-east-1",
+      "GET",
+      "/test.txt",
+      &[
+        ("host", "examplebucket.s3.amazonaws.com"),
+        ("range", "bytes=0-9"),
+        ("x-amz-content-sha256", &payload_hash),
+        ("x-amz-date", "20130524T000000Z"),
+      ],
+      &payload_hash,
+      now,
+    );
+    assert_eq!(
+      signature,
+      "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+    );
+  }
+
+  #[test]
+  fn test_object_url() {
+    let (url, path) = object_url("http://localhost:9000", "tracks", "a/b c.bin").unwrap();
+    assert_eq!(url.as_str(), "http://localhost:9000/tracks/a/b%20c.bin");
+    assert_eq!(path, "/tracks/a/b%20c.bin");
+
+    let (url, path) = object_url("https://example.com/s3/", "tracks", "a.bin").unwrap();
+    assert_eq!(url.as_str(), "https://example.com/s3/tracks/a.bin");
+    assert_eq!(path, "/s3/tracks/a.bin");
+  }
+
+  #[test]
+  fn test_uri_encode() {
+    assert_eq!(
+      uri_encode("2024/01/02/1/1.AB C.1.bin"),
+      "2024/01/02/1/1.AB%20C.1.bin"
+    );
+  }
+}
@@ -0,0 +1,94 @@
+use super::trackpoint::TrackPoint;
+use geo::SimplifyIdx;
+use geo_types::LineString;
+
+// Douglas-Peucker tolerance in degrees, roughly 100m
+const TOLERANCE: f64 = 0.001;
+// climbs and descents are kept with this resolution, feet
+const ALT_STEP: i32 = 1000;
+
+// Downsamples a track segment keeping the first and the last points, the
+// turns and the altitude changes. Returns the indices of the points to keep
+pub fn downsample(points: &[TrackPoint]) -> Vec<usize> {
+  if points.len() < 3 {
+    return (0..points.len()).collect();
+  }
+  let line: LineString = points.iter().map(|tp| (tp.lng, tp.lat)).collect();
+  let mut keep = vec![false; points.len()];
+  for idx in line.simplify_idx(&TOLERANCE) {
+    keep[idx] = true;
+  }
+
+  let mut last_alt = points[0].alt;
+  for (idx, tp) in points.iter().enumerate() {
+    if keep[idx] || (tp.alt - last_alt).abs() >= ALT_STEP {
+      keep[idx] = true;
+      last_alt = tp.alt;
+    }
+  }
+
+  keep
+    .into_iter()
+    .enumerate()
+    .filter_map(|(idx, keep)| if keep { Some(idx) } else { None })
+    .collect()
+}
+
+// Evenly spread indices of at most max_points out of len, the last
+// point is kept unless there's no room at all
+pub fn decimate(len: usize, max_points: usize) -> Vec<usize> {
+  if len <= max_points {
+    return (0..len).collect();
+  }
+  if max_points == 0 {
+    return vec![];
+  }
+  if max_points == 1 {
+    return vec![len - 1];
+  }
+  let step = (len - 1) as f64 / (max_points - 1) as f64;
+  (0..max_points)
+    .map(|i| (i as f64 * step).round() as usize)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tp(lat: f64, lng: f64, alt: i32) -> TrackPoint {
+    TrackPoint {
+      lat,
+      lng,
+      alt,
+      hdg: 90,
+      gs: 450,
+      ts: 0,
+    }
+  }
+
+  #[test]
+  fn test_downsample() {
+    // straight leg east at cruise, then a turn north
+    let mut points: Vec<TrackPoint> = (0..100).map(|i| tp(50.0, i as f64 * 0.1, 35000)).collect();
+    points.extend((1..50).map(|i| tp(50.0 + i as f64 * 0.1, 9.9, 35000)));
+    let keep = downsample(&points);
+    assert_eq!(keep, vec![0, 99, 148]);
+
+    // a descent along a straight line keeps a point every ALT_STEP
+    let points: Vec<TrackPoint> = (0..10)
+      .map(|i| tp(50.0, i as f64 * 0.1, 10000 - i * 500))
+      .collect();
+    let keep = downsample(&points);
+    assert_eq!(keep, vec![0, 2, 4, 6, 8, 9]);
+  }
+
+  #[test]
+  fn test_decimate() {
+    assert_eq!(decimate(3, 10), vec![0, 1, 2]);
+    assert_eq!(decimate(11, 6), vec![0, 2, 4, 6, 8, 10]);
+    assert_eq!(decimate(10, 1), vec![9]);
+    assert!(decimate(0, 0).is_empty());
+    assert!(decimate(10, 0).is_empty());
+  }
+}
@@ -0,0 +1,161 @@
+use super::trackpoint::TrackPoint;
+use crate::util::from_millis;
+use geojson::{Feature, Geometry, JsonObject, Value};
+use std::fmt::Write;
+
+const FT_TO_M: f64 = 0.3048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+  GeoJson,
+  Gpx,
+  Kml,
+}
+
+impl ExportFormat {
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      Self::GeoJson => "application/geo+json",
+      Self::Gpx => "application/gpx+xml",
+      Self::Kml => "application/vnd.google-earth.kml+xml",
+    }
+  }
+}
+
+// Converts a track into the given format, returns the document along with
+// its content type
+pub fn export_track(
+  name: &str,
+  points: &[TrackPoint],
+  format: ExportFormat,
+) -> (String, &'static str) {
+  let doc = match format {
+    ExportFormat::GeoJson => to_geojson(name, points),
+    ExportFormat::Gpx => to_gpx(name, points),
+    ExportFormat::Kml => to_kml(name, points),
+  };
+  (doc, format.content_type())
+}
+
+fn altitude_m(tp: &TrackPoint) -> f64 {
+  (tp.alt as f64 * FT_TO_M).round()
+}
+
+fn escape_xml(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn to_geojson(name: &str, points: &[TrackPoint]) -> String {
+  let coords = points
+    .iter()
+    .map(|tp| vec![tp.lng, tp.lat, altitude_m(tp)])
+    .collect();
+  let times: Vec<String> = points
+    .iter()
+    .map(|tp| from_millis(tp.ts).to_rfc3339())
+    .collect();
+
+  let mut properties = JsonObject::new();
+  properties.insert("name".into(), name.into());
+  properties.insert("times".into(), times.into());
+
+  let feature = Feature {
+    bbox: None,
+    geometry: Some(Geometry::new(Value::LineString(coords))),
+    id: None,
+    properties: Some(properties),
+    foreign_members: None,
+  };
+  feature.to_string()
+}
+
+fn to_gpx(name: &str, points: &[TrackPoint]) -> String {
+  let name = escape_xml(name);
+  let mut doc = String::from(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+     <gpx version=\"1.1\" creator=\"simwatch-grpc\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+  );
+  let _ = writeln!(doc, "<trk><name>{name}</name><trkseg>");
+  for tp in points {
+    let _ = writeln!(
+      doc,
+      "<trkpt lat=\"{}\" lon=\"{}\"><ele>{}</ele><time>{}</time></trkpt>",
+      tp.lat,
+      tp.lng,
+      altitude_m(tp),
+      from_millis(tp.ts).to_rfc3339()
+    );
+  }
+  doc.push_str("</trkseg></trk>\n</gpx>\n");
+  doc
+}
+
+fn to_kml(name: &str, points: &[TrackPoint]) -> String {
+  let name = escape_xml(name);
+  let mut doc = String::from(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+     <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n",
+  );
+  let _ = writeln!(doc, "<Document><Placemark><name>{name}</name>");
+  doc.push_str("<LineString><altitudeMode>absolute</altitudeMode><coordinates>\n");
+  for tp in points {
+    let _ = writeln!(doc, "{},{},{}", tp.lng, tp.lat, altitude_m(tp));
+  }
+  doc.push_str("</coordinates></LineString></Placemark></Document>\n</kml>\n");
+  doc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use geojson::GeoJson;
+
+  fn track() -> Vec<TrackPoint> {
+    vec![
+      TrackPoint {
+        lat: 51.5,
+        lng: -0.1,
+        alt: 1000,
+        hdg: 90,
+        gs: 200,
+        ts: 1_700_000_000_000,
+      },
+      TrackPoint {
+        lat: 51.6,
+        lng: 0.2,
+        alt: 5000,
+        hdg: 90,
+        gs: 250,
+        ts: 1_700_000_060_000,
+      },
+    ]
+  }
+
+  #[test]
+  fn test_export_track() {
+    let points = track();
+
+    let (doc, content_type) = export_track("A&B", &points, ExportFormat::GeoJson);
+    assert_eq!(content_type, "application/geo+json");
+    let gj: GeoJson = doc.parse().unwrap();
+    let GeoJson::Feature(feature) = gj else {
+      panic!("feature expected");
+    };
+    let Some(Value::LineString(coords)) = feature.geometry.map(|g| g.value) else {
+      panic!("linestring expected");
+    };
+    assert_eq!(coords.len(), 2);
+    assert_eq!(coords[1], vec![0.2, 51.6, 1524.0]);
+
+    let (doc, _) = export_track("A&B", &points, ExportFormat::Gpx);
+    assert!(doc.contains("<name>A&amp;B</name>"));
+    assert_eq!(doc.matches("<trkpt ").count(), 2);
+    assert!(doc.contains("<time>2023-11-14T22:13:20+00:00</time>"));
+
+    let (doc, _) = export_track("A&B", &points, ExportFormat::Kml);
+    assert!(doc.contains("-0.1,51.5,305\n"));
+  }
+}
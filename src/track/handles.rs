@@ -0,0 +1,201 @@
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap},
+  fmt::Debug,
+  hash::{Hash, Hasher},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+// files of different paths are opened in parallel unless their paths
+// share a stripe
+const OPEN_STRIPES: usize = 64;
+
+struct Entry<T> {
+  handle: Arc<Mutex<T>>,
+  used: Instant,
+}
+
+// Open file handles keyed by path. Handles idle for longer than the idle
+// period are closed when new ones are opened, the least recently used one
+// is closed if the cache is full nevertheless. Handles still in use are
+// never closed, so there's one handle per file at a time
+pub struct HandleCache<T> {
+  capacity: usize,
+  idle: Duration,
+  entries: Mutex<HashMap<String, Entry<T>>>,
+  // held while a file is opened, so that it's opened once
+  opening: Vec<Mutex<()>>,
+}
+
+impl<T> HandleCache<T> {
+  pub fn new(capacity: usize, idle: Duration) -> Self {
+    Self {
+      capacity,
+      idle,
+      entries: Mutex::new(HashMap::new()),
+      opening: (0..OPEN_STRIPES).map(|_| Mutex::new(())).collect(),
+    }
+  }
+
+  fn opening(&self, path: &str) -> &Mutex<()> {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    &self.opening[hasher.finish() as usize % OPEN_STRIPES]
+  }
+
+  fn cached(&self, path: &str) -> Option<Arc<Mutex<T>>> {
+    self.entries.lock().unwrap().get_mut(path).map(|entry| {
+      entry.used = Instant::now();
+      entry.handle.clone()
+    })
+  }
+
+  pub fn get_or_open<E>(
+    &self,
+    path: &str,
+    open: impl FnOnce() -> Result<T, E>,
+  ) -> Result<Arc<Mutex<T>>, E> {
+    if let Some(handle) = self.cached(path) {
+      return Ok(handle);
+    }
+
+    // files are opened with the cache unlocked, one thread per file
+    let _opening = self.opening(path).lock().unwrap();
+    if let Some(handle) = self.cached(path) {
+      return Ok(handle);
+    }
+    let handle = Arc::new(Mutex::new(open()?));
+    if self.capacity == 0 {
+      return Ok(handle);
+    }
+    let now = Instant::now();
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() >= self.capacity {
+      entries
+        .retain(|_, entry| now - entry.used < self.idle || Arc::strong_count(&entry.handle) > 1);
+    }
+    if entries.len() >= self.capacity {
+      let lru = entries
+        .iter()
+        .filter(|(_, entry)| Arc::strong_count(&entry.handle) == 1)
+        .min_by_key(|(_, entry)| entry.used)
+        .map(|(path, _)| path.clone());
+      if let Some(lru) = lru {
+        entries.remove(&lru);
+      }
+    }
+    entries.insert(
+      path.to_owned(),
+      Entry {
+        handle: handle.clone(),
+        used: now,
+      },
+    );
+    Ok(handle)
+  }
+
+  // Runs f with the open handle of the file if there's one, with the one
+  // opened just for it otherwise. The file isn't opened by anyone else
+  // meanwhile, the cache is left as is
+  pub fn with_handle<R, E>(
+    &self,
+    path: &str,
+    open: impl FnOnce() -> Result<T, E>,
+    f: impl FnOnce(&mut T) -> R,
+  ) -> Result<R, E> {
+    let opening = self.opening(path).lock().unwrap();
+    let cached = self
+      .entries
+      .lock()
+      .unwrap()
+      .get(path)
+      .map(|entry| entry.handle.clone());
+    if let Some(handle) = cached {
+      drop(opening);
+      let mut handle = handle.lock().unwrap();
+      return Ok(f(&mut handle));
+    }
+    let mut handle = open()?;
+    Ok(f(&mut handle))
+  }
+
+  pub fn remove(&self, path: &str) {
+    self.entries.lock().unwrap().remove(path);
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+}
+
+impl<T> Debug for HandleCache<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("HandleCache")
+      .field("capacity", &self.capacity)
+      .field("idle", &self.idle)
+      .field("len", &self.len())
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_handle_cache() {
+    let cache: HandleCache<u32> = HandleCache::new(2, Duration::from_secs(60));
+    let open = |value| move || Ok::<u32, ()>(value);
+
+    cache.get_or_open("a", open(1)).unwrap();
+    cache.get_or_open("b", open(2)).unwrap();
+    // already open, the opener is not called
+    let a = cache.get_or_open("a", open(10)).unwrap();
+    assert_eq!(*a.lock().unwrap(), 1);
+
+    // b is the least recently used one
+    cache.get_or_open("c", open(3)).unwrap();
+    assert_eq!(cache.len(), 2);
+    let b = cache.get_or_open("b", open(20)).unwrap();
+    assert_eq!(*b.lock().unwrap(), 20);
+
+    let cache: HandleCache<u32> = HandleCache::new(2, Duration::ZERO);
+    cache.get_or_open("a", open(1)).unwrap();
+    cache.get_or_open("b", open(2)).unwrap();
+    // all the idle handles are closed at once
+    cache.get_or_open("c", open(3)).unwrap();
+    assert_eq!(cache.len(), 1);
+
+    // handles in use are kept open even over the capacity
+    let cache: HandleCache<u32> = HandleCache::new(1, Duration::ZERO);
+    let a = cache.get_or_open("a", open(1)).unwrap();
+    cache.get_or_open("b", open(2)).unwrap();
+    assert_eq!(cache.len(), 2);
+    let again = cache.get_or_open("a", open(10)).unwrap();
+    assert!(Arc::ptr_eq(&a, &again));
+    drop((a, again));
+    cache.get_or_open("c", open(3)).unwrap();
+    assert_eq!(cache.len(), 1);
+  }
+
+  #[test]
+  fn test_with_handle() {
+    let cache: HandleCache<u32> = HandleCache::new(2, Duration::from_secs(60));
+    let open = |value| move || Ok::<u32, ()>(value);
+
+    // the open handle is the one used
+    let a = cache.get_or_open("a", open(1)).unwrap();
+    let res = cache.with_handle("a", open(10), |value| {
+      *value += 1;
+      *value
+    });
+    assert_eq!(res, Ok(2));
+    assert_eq!(*a.lock().unwrap(), 2);
+
+    // files which aren't open are opened for the call only
+    let res = cache.with_handle("b", open(20), |value| *value);
+    assert_eq!(res, Ok(20));
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.with_handle("c", || Err(()), |value| *value), Err(()));
+  }
+}
@@ -1,8 +1,9 @@
 use chrono::Utc;
+use std::mem::size_of;
 
-use crate::trackfile::TrackFileHeader;
+use crate::trackfile::{EntryLayout, HeaderPrefix, TrackFileHeader};
 
-const TRACK_VERSION: u64 = 1;
+const TRACK_VERSION: u64 = 4;
 const TRACK_MAGIC_NUMBER: u64 = 0x119F3E5F006A42C8;
 
 #[derive(Debug, Clone)]
@@ -12,6 +13,18 @@ pub struct Header {
   version: u64,
   ts: u64,
   count: u64,
+  // points older than this have been compacted already
+  compacted: u64,
+}
+
+impl Header {
+  pub fn compacted(&self) -> i64 {
+    self.compacted as i64
+  }
+
+  pub fn set_compacted(&mut self, ts: i64) {
+    self.compacted = ts as u64;
+  }
 }
 
 impl Default for Header {
@@ -21,6 +34,7 @@ impl Default for Header {
       version: TRACK_VERSION,
       ts: Utc::now().timestamp_millis() as u64,
       count: 0,
+      compacted: 0,
     }
   }
 }
@@ -46,4 +60,33 @@ impl TrackFileHeader for Header {
     self.ts = Utc::now().timestamp_millis() as u64;
     self.count += 1;
   }
+
+  fn set_count(&mut self, count: u64) {
+    self.count = count;
+  }
+
+  fn set_version(&mut self, version: u64) {
+    self.version = version;
+  }
+
+  fn set_timestamp(&mut self, ts: u64) {
+    self.ts = ts;
+  }
+
+  fn entry_layout(version: u64) -> EntryLayout {
+    match version {
+      1 => EntryLayout::Raw,
+      2 | 3 => EntryLayout::Checksummed,
+      _ => EntryLayout::Encoded,
+    }
+  }
+
+  // the compaction watermark has been added in version 3
+  fn size_of_version(version: u64) -> usize {
+    if version < 3 {
+      size_of::<HeaderPrefix>()
+    } else {
+      size_of::<Self>()
+    }
+  }
 }
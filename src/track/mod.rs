@@ -1,28 +1,126 @@
+pub mod archive;
+mod compact;
+pub mod export;
+mod handles;
 pub mod header;
+pub mod stats;
 pub mod trackpoint;
-use self::{header::Header, trackpoint::TrackPoint};
+use self::{
+  compact::{decimate, downsample},
+  handles::HandleCache,
+  header::Header,
+  stats::{StatsSidecar, TrackStats},
+  trackpoint::TrackPoint,
+};
+use crate::config::Durability;
 use crate::moving::pilot::Pilot;
-use crate::trackfile::{Result, TrackFile};
-use chrono::{Duration, Utc};
-use log::debug;
-use std::path::{Path, PathBuf};
+use crate::trackfile::{read_header_prefix, Result, TrackFile, TrackFileHeader};
+use chrono::{DateTime, Duration, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, error};
+use rayon::prelude::*;
+use std::{
+  collections::HashMap,
+  io::{Read, Write},
+  mem::size_of,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+// track points older than this are downsampled during the cleanup
+const COMPACT_AFTER_MINUTES: i64 = 60;
+// fewer points to downsample are left for the next cleanups, that's
+// about an hour of flight
+const COMPACT_MIN_POINTS: usize = 240;
+
+// Track points waiting to be written, keyed by the track file name
+pub type TrackBatch = HashMap<String, Vec<TrackPoint>>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StoreCounters {
+  pub tracks: u64,
+  pub points: u64,
+  // total size of the track files
+  pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CleanupStats {
+  pub deleted_files: u64,
+  pub compacted_points: u64,
+}
+
+// What a write_batch call has done
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchStats {
+  pub files: usize,
+  pub failed: usize,
+  pub points: usize,
+  pub bytes_written: u64,
+  // mean time a file append has taken
+  pub append_latency: std::time::Duration,
+}
+
+impl BatchStats {
+  // Bytes written per byte of the new track points in memory. The points
+  // are stored encoded, the headers and the rewritten tails of the files
+  // add up
+  pub fn write_amplification(&self) -> f64 {
+    let payload = self.points * size_of::<TrackPoint>();
+    if payload == 0 {
+      0.0
+    } else {
+      self.bytes_written as f64 / payload as f64
+    }
+  }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+type TrackFileHandle = Arc<Mutex<TrackFile<TrackPoint, Header>>>;
+type TrackPointFile = TrackFile<TrackPoint, Header>;
+
+// Index of the first of count points with the timestamp not less than ts
+fn lower_bound(file: &TrackPointFile, count: usize, ts: i64) -> Result<usize> {
+  let (mut lo, mut hi) = (0, count);
+  while lo < hi {
+    let mid = (lo + hi) / 2;
+    if file.read_at(mid)?.ts < ts {
+      lo = mid + 1;
+    } else {
+      hi = mid;
+    }
+  }
+  Ok(lo)
+}
 
 #[derive(Debug)]
 pub struct Store {
   folder: String,
+  durability: Durability,
+  handles: HandleCache<TrackPointFile>,
+  // counted on cleanup and kept up to date by the writes in between
+  counters: Mutex<StoreCounters>,
 }
 
 impl Store {
-  pub fn new(folder: &str) -> Self {
+  pub fn new(
+    folder: &str,
+    open_files: usize,
+    open_files_idle: std::time::Duration,
+    durability: Durability,
+  ) -> Self {
     Self {
       folder: folder.to_owned(),
+      durability,
+      handles: HandleCache::new(open_files, open_files_idle),
+      counters: Mutex::new(StoreCounters::default()),
     }
   }
 
-  fn collect_track_files<T: AsRef<Path>>(
-    &self,
-    path: Option<T>,
-  ) -> Result<Vec<TrackFile<TrackPoint, Header>>> {
+  // Names of all the track files in the folder
+  fn collect_track_files<T: AsRef<Path>>(&self, path: Option<T>) -> Result<Vec<String>> {
     let real_path = match path {
       Some(ref path) => path.as_ref(),
       None => Path::new(&self.folder),
@@ -39,42 +137,114 @@ impl Store {
           files.extend(self.collect_track_files(Some(dir_path))?);
         } else if ft.is_file() {
           let filename = real_path.join(dir_entry.file_name());
-          let filename = filename.to_str().unwrap();
-          let tf = TrackFile::new(filename);
-          if let Ok(tf) = tf {
-            files.push(tf)
+          if filename.extension().is_none_or(|ext| ext != "bin") {
+            continue;
           }
+          files.push(filename.to_str().unwrap().to_owned());
         }
       }
     }
     Ok(files)
   }
 
-  pub fn counters(&self) -> Result<(u64, u64)> {
-    let mut track_count = 0;
-    let mut track_point_count = 0;
-    for file in self.collect_track_files::<&str>(None)? {
-      let count = file.count();
-      if let Ok(count) = count {
-        track_count += 1;
-        track_point_count += count;
-      }
-    }
-    Ok((track_count, track_point_count))
+  pub fn counters(&self) -> StoreCounters {
+    *self.counters.lock().unwrap()
   }
 
-  pub fn cleanup(&self) -> Result<()> {
-    for file in self.collect_track_files::<&str>(None)? {
-      let mtime = file.mtime();
-      if let Ok(mtime) = mtime {
-        let min_date = Utc::now() - Duration::days(2);
-        if mtime < min_date {
-          debug!("destroying file {} older than {:?}", file.name(), min_date);
-          let _ = file.destroy();
+  // Accounts the points and bytes a write has added to the file, given
+  // its count and size before
+  fn count_growth(&self, file: &TrackPointFile, count: u64, size: u64) -> Result<()> {
+    let mut counters = self.counters.lock().unwrap();
+    counters.points += file.count()?.saturating_sub(count);
+    counters.bytes = (counters.bytes + file.size()).saturating_sub(size);
+    Ok(())
+  }
+
+  // Upgrades and recovers the track files, removing the outdated ones and
+  // compacting the rest. Files are locked one at a time, the others are
+  // written meanwhile
+  pub fn cleanup(&self) -> Result<CleanupStats> {
+    let mut stats = CleanupStats::default();
+    let mut counters = StoreCounters::default();
+    let min_date = Utc::now() - Duration::days(2);
+    for filename in self.collect_track_files::<&str>(None)? {
+      // the open files are in use thus intact, the rest are repaired
+      let res = self.handles.with_handle(
+        &filename,
+        || TrackFile::repair(&filename),
+        |file| self.cleanup_file(file, min_date),
+      );
+      match res.and_then(|res| res) {
+        Ok(Some((removed, points, bytes))) => {
+          stats.compacted_points += removed as u64;
+          counters.tracks += 1;
+          counters.points += points;
+          counters.bytes += bytes;
+        }
+        Ok(None) => {
+          self.handles.remove(&filename);
+          stats.deleted_files += 1;
         }
+        Err(err) => debug!("skipping track file {filename}: {err}"),
       }
     }
-    Ok(())
+    *self.counters.lock().unwrap() = counters;
+    debug!(
+      "{} track points removed by compaction",
+      stats.compacted_points
+    );
+    Ok(stats)
+  }
+
+  // Removes the file if outdated, compacts it otherwise. Returns the
+  // points removed by compaction along with the points and bytes left,
+  // None if the file is removed
+  fn cleanup_file(
+    &self,
+    file: &mut TrackPointFile,
+    min_date: DateTime<Utc>,
+  ) -> Result<Option<(usize, u64, u64)>> {
+    if file.mtime().is_ok_and(|mtime| mtime < min_date) {
+      debug!("destroying file {} older than {:?}", file.name(), min_date);
+      let _ = std::fs::remove_file(Self::stats_filename(file.name()));
+      std::fs::remove_file(file.name())?;
+      return Ok(None);
+    }
+    let removed = self.compact(file).unwrap_or_else(|err| {
+      error!("error compacting track file {}: {err}", file.name());
+      0
+    });
+    let bytes = std::fs::metadata(file.name()).map_or(0, |meta| meta.len());
+    Ok(Some((removed, file.count()?, bytes)))
+  }
+
+  // Downsamples the older part of a track, the recent points are kept as
+  // is. The points compacted already are neither read nor downsampled
+  // again, the file is left alone until there's enough new ones
+  fn compact(&self, file: &mut TrackPointFile) -> Result<usize> {
+    let mut header = file.header()?;
+    let count = header.count() as usize;
+    let compact_before = (Utc::now() - Duration::minutes(COMPACT_AFTER_MINUTES)).timestamp_millis();
+    let from = lower_bound(file, count, header.compacted())?;
+    let split = lower_bound(file, count, compact_before)?;
+    if split.saturating_sub(from) < COMPACT_MIN_POINTS {
+      return Ok(0);
+    }
+
+    let points = file.read_all()?;
+    // the last point kept by the previous compaction joins the new ones
+    let anchor = from.saturating_sub(1);
+    let keep = downsample(&points[anchor..split]);
+    let mut compacted = points[..anchor].to_vec();
+    compacted.extend(keep.into_iter().map(|idx| points[anchor + idx].clone()));
+    compacted.extend_from_slice(&points[split..]);
+    if compacted.len() < points.len() {
+      file.rewrite(&compacted)?;
+      header = file.header()?;
+    }
+    header.set_compacted(compact_before);
+    file.set_header(&header)?;
+    Ok(points.len() - compacted.len())
   }
 
   fn pilot_track_filename(&self, pilot: &Pilot) -> String {
@@ -91,27 +261,527 @@ impl Store {
     format!("{}", pilot_track_filename.display())
   }
 
-  fn get_pilot_track_file(&self, pilot: &Pilot) -> Result<TrackFile<TrackPoint, Header>> {
+  fn get_pilot_track_file(&self, pilot: &Pilot) -> Result<TrackFileHandle> {
     let filename = self.pilot_track_filename(pilot);
-    let mut buf = PathBuf::from(&filename);
-    buf.pop();
-    if !Path::is_dir(&buf) {
-      std::fs::create_dir_all(&buf)?;
-    }
-    let pilot_track = TrackFile::new(&filename)?;
-    Ok(pilot_track)
+    self.open_track_file(&filename)
+  }
+
+  fn open_track_file(&self, filename: &str) -> Result<TrackFileHandle> {
+    self.handles.get_or_open(filename, || {
+      let mut buf = PathBuf::from(filename);
+      buf.pop();
+      if !Path::is_dir(&buf) {
+        std::fs::create_dir_all(&buf)?;
+      }
+      let mut tf = TrackFile::new(filename)?;
+      tf.set_durability(self.durability);
+      if tf.created() {
+        let mut counters = self.counters.lock().unwrap();
+        counters.tracks += 1;
+        counters.bytes += TrackPointFile::header_size() as u64;
+      }
+      Ok(tf)
+    })
   }
 
   pub fn store_track(&self, pilot: &Pilot) -> Result<()> {
-    let mut pilot_track = self.get_pilot_track_file(pilot)?;
+    let pilot_track = self.get_pilot_track_file(pilot)?;
+    let mut pilot_track = pilot_track.lock().unwrap();
     let track_point = pilot.into();
+    let (count, size) = (pilot_track.count()?, pilot_track.size());
     pilot_track.append(&track_point)?;
+    self.count_growth(&pilot_track, count, size)
+  }
+
+  // Current positions of the pilots, to be written with write_batch
+  pub fn track_batch<'a>(&self, pilots: impl Iterator<Item = &'a Pilot>) -> TrackBatch {
+    pilots
+      .map(|pilot| (self.pilot_track_filename(pilot), vec![pilot.into()]))
+      .collect()
+  }
+
+  // Appends the points to their files, every file is written once
+  pub fn write_batch(&self, batch: TrackBatch) -> BatchStats {
+    let mut stats = BatchStats {
+      files: batch.len(),
+      points: batch.values().map(|points| points.len()).sum(),
+      ..Default::default()
+    };
+    let results: Vec<(Option<u64>, std::time::Duration)> = batch
+      .into_par_iter()
+      .map(|(filename, points)| {
+        let t = Instant::now();
+        let res = self.open_track_file(&filename).and_then(|tf| {
+          let mut tf = tf.lock().unwrap();
+          let (count, size) = (tf.count()?, tf.size());
+          let written = tf.append_many(&points)?;
+          self.count_growth(&tf, count, size)?;
+          Ok(written)
+        });
+        if let Err(err) = &res {
+          error!("error storing track {filename}: {err}");
+        }
+        (res.ok(), t.elapsed())
+      })
+      .collect();
+
+    let mut spent = std::time::Duration::ZERO;
+    for (written, elapsed) in results {
+      spent += elapsed;
+      match written {
+        Some(bytes) => stats.bytes_written += bytes,
+        None => stats.failed += 1,
+      }
+    }
+    if stats.files > 0 {
+      stats.append_latency = spent / stats.files as u32;
+    }
+    stats
+  }
+
+  // true if no points of the pilot are stored locally
+  pub fn track_missing(&self, pilot: &Pilot) -> bool {
+    let filename = self.pilot_track_filename(pilot);
+    std::fs::metadata(filename)
+      .map(|meta| meta.len() as usize <= size_of::<Header>())
+      .unwrap_or(true)
+  }
+
+  pub fn track_file_exists(&self, pilot: &Pilot) -> bool {
+    std::path::Path::new(&self.pilot_track_filename(pilot)).exists()
+  }
+
+  // Contents of the pilot's track file as archived, gzipped if asked to
+  pub fn read_track_file(&self, pilot: &Pilot, compress: bool) -> Result<Vec<u8>> {
+    let filename = self.pilot_track_filename(pilot);
+    let data = std::fs::read(filename)?;
+    if !compress {
+      return Ok(data);
+    }
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&data)?;
+    Ok(encoder.finish()?)
+  }
+
+  // Puts an archived track file back in place, gzipped or not
+  pub fn restore_track_file(&self, pilot: &Pilot, data: &[u8]) -> Result<()> {
+    let filename = self.pilot_track_filename(pilot);
+    let mut buf = PathBuf::from(&filename);
+    buf.pop();
+    std::fs::create_dir_all(&buf)?;
+    let tmp_name = format!("{filename}.tmp");
+    if data.starts_with(&GZIP_MAGIC) {
+      let mut raw = vec![];
+      GzDecoder::new(data).read_to_end(&mut raw)?;
+      std::fs::write(&tmp_name, raw)?;
+    } else {
+      std::fs::write(&tmp_name, data)?;
+    }
+    let replaced = read_header_prefix(&filename)
+      .ok()
+      .zip(std::fs::metadata(&filename).ok());
+    std::fs::rename(&tmp_name, &filename)?;
+    self.handles.remove(&filename);
+    // the file is not in use yet, archives of older versions are upgraded
+    let tf = TrackPointFile::repair(&filename)?;
+
+    let mut counters = self.counters.lock().unwrap();
+    match replaced {
+      Some((prefix, meta)) => {
+        counters.points -= counters.points.min(prefix.count);
+        counters.bytes -= counters.bytes.min(meta.len());
+      }
+      None => counters.tracks += 1,
+    }
+    counters.points += tf.count()?;
+    counters.bytes += std::fs::metadata(&filename)?.len();
     Ok(())
   }
 
+  fn stats_filename(filename: &str) -> String {
+    format!("{filename}.stats")
+  }
+
+  // Summary of the pilot's track, computed on demand and cached in a
+  // sidecar file until the track changes
+  pub fn get_track_stats(&self, pilot: &Pilot) -> Result<TrackStats> {
+    let filename = self.pilot_track_filename(pilot);
+    let stats_filename = Self::stats_filename(&filename);
+    let pilot_track = self.open_track_file(&filename)?;
+    let pilot_track = pilot_track.lock().unwrap();
+    let count = pilot_track.count()?;
+    let mtime = pilot_track.mtime()?.timestamp_millis();
+
+    let cached = std::fs::read(&stats_filename)
+      .ok()
+      .and_then(|raw| serde_json::from_slice::<StatsSidecar>(&raw).ok());
+    if let Some(sidecar) = cached {
+      if sidecar.count == count && sidecar.mtime == mtime {
+        return Ok(sidecar.stats);
+      }
+    }
+
+    let stats = TrackStats::compute(&pilot_track.read_all()?);
+    let sidecar = StatsSidecar {
+      count,
+      mtime,
+      stats,
+    };
+    match serde_json::to_vec(&sidecar) {
+      Ok(raw) => {
+        if let Err(err) = std::fs::write(&stats_filename, raw) {
+          error!("error caching track stats {stats_filename}: {err}");
+        }
+      }
+      Err(err) => error!("error serializing track stats: {err}"),
+    }
+    Ok(sidecar.stats)
+  }
+
   pub fn get_track_points(&self, pilot: &Pilot) -> Result<Vec<TrackPoint>> {
     let pilot_track = self.get_pilot_track_file(pilot)?;
+    let pilot_track = pilot_track.lock().unwrap();
     let points = pilot_track.read_all()?;
     Ok(points)
   }
+
+  // Up to len track points starting from pos, empty past the end of track
+  pub fn get_track_chunk(&self, pilot: &Pilot, pos: usize, len: usize) -> Result<Vec<TrackPoint>> {
+    let pilot_track = self.get_pilot_track_file(pilot)?;
+    let pilot_track = pilot_track.lock().unwrap();
+    if pos as u64 >= pilot_track.count()? {
+      return Ok(vec![]);
+    }
+    let points = pilot_track.read_multiple_at(pos, len)?;
+    Ok(points)
+  }
+
+  // Track points within the time range, decimated down to max_points if
+  // set. Only the part of the file within the range is read
+  pub fn get_track_points_range(
+    &self,
+    pilot: &Pilot,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    max_points: Option<usize>,
+  ) -> Result<Vec<TrackPoint>> {
+    let pilot_track = self.get_pilot_track_file(pilot)?;
+    let pilot_track = pilot_track.lock().unwrap();
+    let count = pilot_track.count()? as usize;
+
+    let start = match from {
+      Some(from) => lower_bound(&pilot_track, count, from.timestamp_millis())?,
+      None => 0,
+    };
+    let end = match to {
+      Some(to) => lower_bound(&pilot_track, count, to.timestamp_millis() + 1)?,
+      None => count,
+    };
+    if start >= end {
+      return Ok(vec![]);
+    }
+
+    let points = pilot_track.read_multiple_at(start, end - start)?;
+    match max_points {
+      Some(max_points) if points.len() > max_points => Ok(
+        decimate(points.len(), max_points)
+          .into_iter()
+          .map(|idx| points[idx].clone())
+          .collect(),
+      ),
+      _ => Ok(points),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env::temp_dir;
+
+  fn store(name: &str) -> (Store, PathBuf) {
+    let folder = temp_dir().join(format!("simwatch_tracks_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&folder);
+    let store = Store::new(
+      folder.to_str().unwrap(),
+      16,
+      std::time::Duration::from_secs(60),
+      Durability::Batch,
+    );
+    (store, folder)
+  }
+
+  // a pilot logged on an hour ago with a point a minute, moving east
+  // a degree a minute
+  fn fly(store: &Store, callsign: &str, points: i64) -> Pilot {
+    let start = Utc::now() - Duration::minutes(60);
+    let mut pilot = Pilot {
+      logon_time: start,
+      last_updated: start,
+      ..Pilot::test_default(callsign)
+    };
+    for i in 0..points {
+      pilot.position.lng = i as f64;
+      pilot.last_updated = start + Duration::minutes(i);
+      store.store_track(&pilot).unwrap();
+    }
+    pilot
+  }
+
+  #[test]
+  fn test_track_points_range() {
+    let (store, folder) = store("range");
+    let pilot = fly(&store, "AAA", 60);
+    let start = pilot.logon_time;
+
+    let all = store
+      .get_track_points_range(&pilot, None, None, None)
+      .unwrap();
+    assert_eq!(all.len(), 60);
+
+    let from = start + Duration::minutes(30);
+    let last = store
+      .get_track_points_range(&pilot, Some(from), None, None)
+      .unwrap();
+    assert_eq!(last.len(), 30);
+    assert_eq!(last[0].ts, from.timestamp_millis());
+
+    // both ends are inclusive
+    let to = start + Duration::minutes(39);
+    let range = store
+      .get_track_points_range(&pilot, Some(from), Some(to), None)
+      .unwrap();
+    assert_eq!(range.len(), 10);
+    assert_eq!(range[9].ts, to.timestamp_millis());
+
+    let range = store
+      .get_track_points_range(&pilot, Some(to), Some(from), None)
+      .unwrap();
+    assert!(range.is_empty());
+    let before = start - Duration::minutes(1);
+    let range = store
+      .get_track_points_range(&pilot, None, Some(before), None)
+      .unwrap();
+    assert!(range.is_empty());
+
+    let empty = Pilot {
+      logon_time: start,
+      ..Pilot::test_default("BBB")
+    };
+    let range = store
+      .get_track_points_range(&empty, None, None, Some(10))
+      .unwrap();
+    assert!(range.is_empty());
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_track_points_decimated() {
+    let (store, folder) = store("decimated");
+    let pilot = fly(&store, "AAA", 60);
+
+    let overview = store
+      .get_track_points_range(&pilot, None, None, Some(10))
+      .unwrap();
+    assert_eq!(overview.len(), 10);
+    assert_eq!(overview[0].lng, 0.0);
+    assert_eq!(overview[9].lng, 59.0);
+
+    // the last point is the one kept
+    let overview = store
+      .get_track_points_range(&pilot, None, None, Some(1))
+      .unwrap();
+    assert_eq!(overview.len(), 1);
+    assert_eq!(overview[0].lng, 59.0);
+
+    let overview = store
+      .get_track_points_range(&pilot, None, None, Some(0))
+      .unwrap();
+    assert!(overview.is_empty());
+
+    let overview = store
+      .get_track_points_range(&pilot, None, None, Some(100))
+      .unwrap();
+    assert_eq!(overview.len(), 60);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_track_stats() {
+    let (store, folder) = store("stats");
+    let mut pilot = fly(&store, "AAA", 60);
+
+    let stats = store.get_track_stats(&pilot).unwrap();
+    assert_eq!(stats.points, 60);
+    assert_eq!(stats.duration_secs, 59 * 60);
+    let sidecar = Store::stats_filename(&store.pilot_track_filename(&pilot));
+    assert!(Path::new(&sidecar).exists());
+    // served from the sidecar now
+    assert_eq!(store.get_track_stats(&pilot).unwrap(), stats);
+
+    // the sidecar is stale once the track is appended to
+    pilot.last_updated += Duration::minutes(1);
+    store.store_track(&pilot).unwrap();
+    let stats = store.get_track_stats(&pilot).unwrap();
+    assert_eq!(stats.points, 61);
+    assert_eq!(stats.duration_secs, 60 * 60);
+
+    // and ignored if it doesn't match the track
+    std::fs::write(&sidecar, "{}").unwrap();
+    assert_eq!(store.get_track_stats(&pilot).unwrap(), stats);
+
+    let empty = Pilot {
+      logon_time: pilot.logon_time,
+      ..Pilot::test_default("BBB")
+    };
+    assert_eq!(
+      store.get_track_stats(&empty).unwrap(),
+      TrackStats::default()
+    );
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_track_chunk() {
+    let (store, folder) = store("chunk");
+    let pilot = fly(&store, "AAA", 60);
+
+    let chunk = store.get_track_chunk(&pilot, 0, 20).unwrap();
+    assert_eq!(chunk.len(), 20);
+    assert_eq!(chunk[0].lng, 0.0);
+    // the last chunk is a short one
+    let chunk = store.get_track_chunk(&pilot, 50, 20).unwrap();
+    assert_eq!(chunk.len(), 10);
+    assert_eq!(chunk[0].lng, 50.0);
+    assert!(store.get_track_chunk(&pilot, 60, 20).unwrap().is_empty());
+    assert!(store.get_track_chunk(&pilot, 10, 0).unwrap().is_empty());
+
+    let empty = Pilot {
+      logon_time: pilot.logon_time,
+      ..Pilot::test_default("BBB")
+    };
+    assert!(store.get_track_chunk(&empty, 0, 20).unwrap().is_empty());
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_write_batch() {
+    let (store, folder) = store("batch");
+    let aaa = fly(&store, "AAA", 10);
+    let bbb = Pilot {
+      logon_time: aaa.logon_time,
+      ..Pilot::test_default("BBB")
+    };
+
+    let stats = store.write_batch(store.track_batch([&aaa, &bbb].into_iter()));
+    assert_eq!((stats.files, stats.points, stats.failed), (2, 2, 0));
+    // checksums make every point take more than its size
+    assert!(stats.write_amplification() > 1.0);
+    assert_eq!(store.get_track_points(&aaa).unwrap().len(), 11);
+    assert_eq!(store.get_track_points(&bbb).unwrap().len(), 1);
+
+    let stats = store.write_batch(TrackBatch::new());
+    assert_eq!((stats.files, stats.points), (0, 0));
+    assert_eq!(stats.write_amplification(), 0.0);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_counters() {
+    let (store, folder) = store("counters");
+    assert_eq!(store.counters(), StoreCounters::default());
+
+    let pilot = fly(&store, "AAA", 60);
+    let counters = store.counters();
+    assert_eq!((counters.tracks, counters.points), (1, 60));
+    let filename = store.pilot_track_filename(&pilot);
+    let len = std::fs::metadata(&filename).unwrap().len();
+    assert_eq!(counters.bytes, len);
+    // the points are stored encoded against the previous ones
+    assert!(counters.bytes < 60 * size_of::<TrackPoint>() as u64);
+
+    store.write_batch(store.track_batch([&pilot].into_iter()));
+    assert_eq!(store.counters().points, 61);
+
+    // opening a track creates an empty file
+    let empty = Pilot {
+      logon_time: pilot.logon_time,
+      ..Pilot::test_default("BBB")
+    };
+    store.get_track_points(&empty).unwrap();
+    let counters = store.counters();
+    assert_eq!((counters.tracks, counters.points), (2, 61));
+
+    // recounted from the files, nothing is left to compact
+    store.cleanup().unwrap();
+    assert_eq!(store.counters(), counters);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  #[test]
+  fn test_archive_roundtrip() {
+    let (store, folder) = store("archive");
+    let pilot = fly(&store, "AAA", 60);
+    let points = store.get_track_points(&pilot).unwrap();
+
+    for compress in [false, true] {
+      let data = store.read_track_file(&pilot, compress).unwrap();
+      assert_eq!(data.starts_with(&GZIP_MAGIC), compress);
+      std::fs::remove_dir_all(&folder).unwrap();
+      store.restore_track_file(&pilot, &data).unwrap();
+      assert_eq!(store.get_track_points(&pilot).unwrap(), points);
+    }
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+
+  // a straight track of 400 points 15 seconds apart ending an hour ago,
+  // the points of the given range of it
+  fn fly_old(store: &Store, pilot: &mut Pilot, points: std::ops::Range<i64>) {
+    let start = Utc::now() - Duration::minutes(61) - Duration::seconds(15 * 400);
+    for i in points {
+      pilot.position.lng = i as f64 * 0.001;
+      pilot.last_updated = start + Duration::seconds(15 * i);
+      store.store_track(pilot).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_compact() {
+    let (store, folder) = store("compact");
+    let mut pilot = Pilot {
+      logon_time: Utc::now() - Duration::hours(6),
+      ..Pilot::test_default("AAA")
+    };
+    let filename = store.pilot_track_filename(&pilot);
+
+    // too few points to bother
+    fly_old(&store, &mut pilot, 0..100);
+    assert_eq!(store.cleanup().unwrap().compacted_points, 0);
+    let header = TrackPointFile::new(&filename).unwrap().header().unwrap();
+    assert_eq!(header.compacted(), 0);
+
+    // the straight line is downsampled down to its ends
+    fly_old(&store, &mut pilot, 100..400);
+    let stats = store.cleanup().unwrap();
+    assert_eq!(stats.compacted_points, 398);
+    assert_eq!(store.get_track_points(&pilot).unwrap().len(), 2);
+    assert_eq!(store.counters().points, 2);
+
+    // nothing new to compact, the file is not rewritten
+    let modified = std::fs::metadata(&filename).unwrap().modified().unwrap();
+    assert_eq!(store.cleanup().unwrap().compacted_points, 0);
+    assert_eq!(
+      std::fs::metadata(&filename).unwrap().modified().unwrap(),
+      modified
+    );
+
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
 }
@@ -0,0 +1,107 @@
+use super::trackpoint::TrackPoint;
+use crate::service::camden;
+use geo::HaversineDistance;
+use geo_types::Point;
+use serde::{Deserialize, Serialize};
+
+// pilots are considered airborne at this ground speed, knots
+const AIRBORNE_SPEED: i32 = 60;
+const METERS_PER_NM: f64 = 1852.0;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrackStats {
+  pub distance_nm: f64,
+  pub max_altitude: i32,
+  pub max_groundspeed: i32,
+  pub airborne_secs: u64,
+  pub duration_secs: u64,
+  pub points: u64,
+}
+
+impl TrackStats {
+  pub fn compute(points: &[TrackPoint]) -> Self {
+    let mut stats = Self {
+      points: points.len() as u64,
+      ..Default::default()
+    };
+    for tp in points {
+      stats.max_altitude = stats.max_altitude.max(tp.alt);
+      stats.max_groundspeed = stats.max_groundspeed.max(tp.gs);
+    }
+    let mut distance = 0.0;
+    let mut airborne_ms = 0;
+    for pair in points.windows(2) {
+      let (prev, next) = (&pair[0], &pair[1]);
+      distance +=
+        Point::new(prev.lng, prev.lat).haversine_distance(&Point::new(next.lng, next.lat));
+      if prev.gs >= AIRBORNE_SPEED && next.gs >= AIRBORNE_SPEED {
+        airborne_ms += (next.ts - prev.ts).max(0);
+      }
+    }
+    stats.distance_nm = distance / METERS_PER_NM;
+    stats.airborne_secs = (airborne_ms / 1000) as u64;
+    if let (Some(first), Some(last)) = (points.first(), points.last()) {
+      stats.duration_secs = ((last.ts - first.ts).max(0) / 1000) as u64;
+    }
+    stats
+  }
+}
+
+impl From<TrackStats> for camden::TrackStats {
+  fn from(value: TrackStats) -> Self {
+    Self {
+      distance_nm: value.distance_nm,
+      max_altitude: value.max_altitude,
+      max_groundspeed: value.max_groundspeed,
+      airborne_secs: value.airborne_secs,
+      duration_secs: value.duration_secs,
+      points: value.points,
+    }
+  }
+}
+
+// Stats cached next to the track file, valid as long as the file has not
+// been written to since
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsSidecar {
+  pub count: u64,
+  pub mtime: i64,
+  pub stats: TrackStats,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tp(lng: f64, gs: i32, alt: i32, ts: i64) -> TrackPoint {
+    TrackPoint {
+      lat: 0.0,
+      lng,
+      alt,
+      hdg: 90,
+      gs,
+      ts,
+    }
+  }
+
+  #[test]
+  fn test_compute() {
+    assert_eq!(TrackStats::compute(&[]), TrackStats::default());
+
+    let points = [
+      tp(0.0, 0, 100, 0),
+      tp(0.0, 80, 100, 60_000),
+      tp(1.0, 300, 10000, 660_000),
+      tp(2.0, 320, 35000, 1_260_000),
+      tp(2.0, 20, 100, 1_860_000),
+    ];
+    let stats = TrackStats::compute(&points);
+    assert_eq!(stats.points, 5);
+    assert_eq!(stats.max_altitude, 35000);
+    assert_eq!(stats.max_groundspeed, 320);
+    assert_eq!(stats.airborne_secs, 1200);
+    assert_eq!(stats.duration_secs, 1860);
+    // a degree of longitude along the equator is 60nm
+    assert!((stats.distance_nm - 120.0).abs() < 0.5);
+  }
+}
@@ -1,4 +1,11 @@
-use crate::{moving::pilot::Pilot, service::camden};
+use crate::{
+  moving::pilot::Pilot,
+  service::camden,
+  trackfile::{get_varint, put_varint, TrackFileEntry},
+};
+
+// positions are stored with 1e-7 degree precision, about a centimeter
+const COORD_SCALE: f64 = 1e7;
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -21,6 +28,50 @@ impl PartialEq for TrackPoint {
   }
 }
 
+impl TrackPoint {
+  fn fields(&self) -> [i64; 6] {
+    [
+      self.ts,
+      (self.lat * COORD_SCALE).round() as i64,
+      (self.lng * COORD_SCALE).round() as i64,
+      self.alt as i64,
+      self.hdg as i64,
+      self.gs as i64,
+    ]
+  }
+
+  fn from_fields(fields: [i64; 6]) -> Self {
+    let [ts, lat, lng, alt, hdg, gs] = fields;
+    Self {
+      lat: lat as f64 / COORD_SCALE,
+      lng: lng as f64 / COORD_SCALE,
+      alt: alt as i32,
+      hdg: hdg as i16,
+      gs: gs as i32,
+      ts,
+    }
+  }
+}
+
+// Stored as varint deltas of the fields from the previous point, the
+// consecutive points of a track differ by a few units
+impl TrackFileEntry for TrackPoint {
+  fn encode(&self, prev: Option<&Self>, buf: &mut Vec<u8>) {
+    let base = prev.map_or([0; 6], Self::fields);
+    for (value, base) in self.fields().into_iter().zip(base) {
+      put_varint(buf, value.wrapping_sub(base));
+    }
+  }
+
+  fn decode(mut data: &[u8], prev: Option<&Self>) -> Option<Self> {
+    let mut fields = prev.map_or([0; 6], Self::fields);
+    for field in fields.iter_mut() {
+      *field = field.wrapping_add(get_varint(&mut data)?);
+    }
+    Some(Self::from_fields(fields))
+  }
+}
+
 impl From<TrackPoint> for camden::TrackPoint {
   fn from(value: TrackPoint) -> Self {
     Self {
@@ -46,3 +97,43 @@ impl From<&Pilot> for TrackPoint {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_encoding() {
+    let first = TrackPoint {
+      lat: 55.9726,
+      lng: 37.4146,
+      alt: 35000,
+      hdg: 270,
+      gs: 450,
+      ts: 1_700_000_000_000,
+    };
+    let second = TrackPoint {
+      lat: 55.97264,
+      lng: 37.41,
+      alt: 34980,
+      hdg: 269,
+      gs: 452,
+      ts: 1_700_000_015_000,
+    };
+
+    let mut buf = vec![];
+    first.encode(None, &mut buf);
+    assert!(buf.len() < std::mem::size_of::<TrackPoint>());
+    assert_eq!(TrackPoint::decode(&buf, None), Some(first.clone()));
+
+    // a point following another one takes a few bytes per field
+    buf.clear();
+    second.encode(Some(&first), &mut buf);
+    assert!(buf.len() < std::mem::size_of::<TrackPoint>() / 3);
+    let decoded = TrackPoint::decode(&buf, Some(&first)).unwrap();
+    assert_eq!(decoded, second);
+    assert_eq!(decoded.ts, second.ts);
+
+    assert_eq!(TrackPoint::decode(&buf[..3], Some(&first)), None);
+  }
+}
@@ -1,18 +1,42 @@
+use crate::config::Durability;
 use chrono::{DateTime, Utc};
+use log::warn;
 use std::{
   error::Error,
   fmt::{Debug, Display},
   fs::{File, OpenOptions},
-  io::{Seek, SeekFrom, Write},
+  io::Write,
   marker::PhantomData,
   mem::size_of,
   os::unix::prelude::FileExt,
   ptr::slice_from_raw_parts,
+  sync::atomic::{AtomicU64, Ordering},
 };
 use tonic::Status;
 
 pub type Result<T> = std::result::Result<T, TrackFileError>;
 
+const CHECKSUM_SIZE: usize = size_of::<u32>();
+// every this many entries one is encoded on its own, reads start from
+// the closest one
+const KEYFRAME_INTERVAL: usize = 64;
+
+// Corruption counters, exposed via metrics
+static RECOVERED_FILES: AtomicU64 = AtomicU64::new(0);
+static DROPPED_ENTRIES: AtomicU64 = AtomicU64::new(0);
+static CHECKSUM_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// files being rewritten get temporary names of their own
+static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+pub fn corruption_counters() -> [(&'static str, u64); 3] {
+  [
+    ("recovered_files", RECOVERED_FILES.load(Ordering::Relaxed)),
+    ("dropped_entries", DROPPED_ENTRIES.load(Ordering::Relaxed)),
+    ("checksum_errors", CHECKSUM_ERRORS.load(Ordering::Relaxed)),
+  ]
+}
+
 #[derive(Debug)]
 pub enum TrackFileError {
   IOError(std::io::Error),
@@ -20,6 +44,8 @@ pub enum TrackFileError {
   InvalidFileLength(usize, usize),
   InsufficientDataLength(usize),
   IndexError(usize),
+  ChecksumMismatch(usize),
+  UnsupportedVersion(u64),
 }
 
 impl Display for TrackFileError {
@@ -38,6 +64,12 @@ impl Display for TrackFileError {
       TrackFileError::IndexError(idx) => {
         write!(f, "Invalid index {idx} while reading track file data")
       }
+      TrackFileError::ChecksumMismatch(idx) => {
+        write!(f, "Track file corrupted, checksum mismatch at index {idx}")
+      }
+      TrackFileError::UnsupportedVersion(version) => {
+        write!(f, "Unsupported track file version {version}")
+      }
     }
   }
 }
@@ -56,12 +88,98 @@ impl From<TrackFileError> for Status {
   }
 }
 
+// How the entries are stored by a version of the file format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryLayout {
+  // as they are in memory
+  Raw,
+  // as they are in memory, followed by their checksums
+  Checksummed,
+  // variable length records of the entries encoded against the previous
+  // ones, see TrackFileEntry
+  Encoded,
+}
+
 pub trait TrackFileHeader: Sized + Clone + Default {
   fn check_magic(&self) -> bool;
   fn version(&self) -> u64;
   fn timestamp(&self) -> u64;
   fn count(&self) -> u64;
   fn inc(&mut self);
+  // used when rewriting the file, keeps the timestamp as is
+  fn set_count(&mut self, count: u64);
+  // used when upgrading files of older versions
+  fn set_version(&mut self, version: u64);
+  fn set_timestamp(&mut self, ts: u64);
+  fn entry_layout(version: u64) -> EntryLayout;
+  // size of the header written by the given version, for the headers
+  // grown since
+  fn size_of_version(_version: u64) -> usize {
+    size_of::<Self>()
+  }
+}
+
+// The fields every version of every header starts with
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct HeaderPrefix {
+  pub magic: u64,
+  pub version: u64,
+  pub ts: u64,
+  pub count: u64,
+}
+
+// The leading header fields of the file of any version, as is
+pub fn read_header_prefix(filename: &str) -> Result<HeaderPrefix> {
+  let file = File::open(filename)?;
+  let mut buf = vec![0; size_of::<HeaderPrefix>()];
+  file.read_exact_at(&mut buf, 0)?;
+  from_raw(&buf)
+}
+
+// Entries are stored encoded against the previous one, the first one and
+// every KEYFRAME_INTERVAL-th are encoded on their own. By default the
+// raw entries are stored
+pub trait TrackFileEntry: Clone + Sized + PartialEq {
+  fn encode(&self, _prev: Option<&Self>, buf: &mut Vec<u8>) {
+    buf.extend(to_raw(self));
+  }
+
+  fn decode(data: &[u8], _prev: Option<&Self>) -> Option<Self> {
+    from_raw(data).ok()
+  }
+}
+
+// LEB128 varints, the signed ones zigzag encoded so that small negative
+// values stay short
+pub fn put_uvarint(buf: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buf.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+pub fn get_uvarint(data: &mut &[u8]) -> Option<u64> {
+  let mut value = 0;
+  for shift in (0..64).step_by(7) {
+    let (&byte, rest) = data.split_first()?;
+    *data = rest;
+    value |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Some(value);
+    }
+  }
+  None
+}
+
+pub fn put_varint(buf: &mut Vec<u8>, value: i64) {
+  put_uvarint(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+pub fn get_varint(data: &mut &[u8]) -> Option<i64> {
+  let value = get_uvarint(data)?;
+  Some((value >> 1) as i64 ^ -((value & 1) as i64))
 }
 
 fn to_raw<T: Sized>(obj: &T) -> Vec<u8> {
@@ -75,90 +193,280 @@ fn from_raw<T: Sized + Clone>(data: &[u8]) -> std::result::Result<T, TrackFileEr
   if data.len() < size_of::<T>() {
     Err(TrackFileError::InsufficientDataLength(data.len()))
   } else {
-    let slice = data as *const [u8] as *const T;
-    let tp = unsafe { &*slice };
-    Ok(tp.clone())
+    // entries followed by checksums are not necessarily aligned
+    let ptr = data.as_ptr() as *const T;
+    let tp = unsafe { ptr.read_unaligned() };
+    Ok(tp)
   }
 }
 
-pub struct TrackFile<E: Clone + Sized + PartialEq, H: TrackFileHeader> {
+// the entry the idx-th one is encoded against
+fn base<E>(idx: usize, prev: Option<&E>) -> Option<&E> {
+  if idx.is_multiple_of(KEYFRAME_INTERVAL) {
+    None
+  } else {
+    prev
+  }
+}
+
+// Entries are stored as records of the encoded entry length, the encoded
+// entry and its checksum. The header count tells how many of them are
+// there, anything past them is left from an interrupted write
+pub struct TrackFile<E: TrackFileEntry, H: TrackFileHeader> {
   file: File,
   name: String,
-  phantom_e: PhantomData<E>,
+  durability: Durability,
+  // whether the file has been created by this handle
+  created: bool,
+  // offsets of the keyframes, the entries encoded on their own
+  keyframes: Vec<u64>,
+  // the last two entries along with their offsets, new ones are
+  // encoded against the last one
+  tail: Vec<(u64, E)>,
+  // end of the last entry
+  end: u64,
   phantom_h: PhantomData<H>,
 }
 
-impl<E: Clone + Sized + PartialEq, H: TrackFileHeader> TrackFile<E, H> {
+impl<E: TrackFileEntry, H: TrackFileHeader> TrackFile<E, H> {
+  // Opens the file, creating it if missing. Files of older versions are
+  // upgraded, damaged ones are refused until repaired
   pub fn new(filename: &str) -> Result<Self> {
+    let mut tf = Self::open(filename)?;
+    let header = tf.read_file_header()?;
+    if !header.check_magic() {
+      return Err(TrackFileError::InvalidMagicNumber);
+    }
+    if header.version() != H::default().version() {
+      tf.migrate(header.version())?;
+    }
+    let valid = tf.index()?;
+    if valid < tf.read_file_header()?.count() as usize {
+      return Err(TrackFileError::ChecksumMismatch(valid));
+    }
+    Ok(tf)
+  }
+
+  // Opens the file upgrading it to the current version and cutting it off
+  // at the first damaged entry. A write in progress looks just like one,
+  // thus nothing else may have the file open meanwhile
+  pub fn repair(filename: &str) -> Result<Self> {
+    let mut tf = Self::open(filename)?;
+    let header = tf.read_file_header()?;
+    if !header.check_magic() {
+      return Err(TrackFileError::InvalidMagicNumber);
+    }
+    if header.version() != H::default().version() {
+      tf.migrate(header.version())?;
+    }
+    let valid = tf.index()?;
+    let len = tf.file.metadata()?.len();
+    if valid < tf.read_file_header()?.count() as usize || tf.end != len {
+      tf.recover()?;
+    }
+    Ok(tf)
+  }
+
+  fn open(filename: &str) -> Result<Self> {
     let res = OpenOptions::new().write(true).read(true).open(filename);
 
-    let tf = match res {
-      Ok(file) => Self {
-        file,
-        name: filename.to_owned(),
-        phantom_e: PhantomData,
-        phantom_h: PhantomData,
-      },
+    let (file, created) = match res {
+      Ok(file) => (file, false),
       Err(err) => match err.kind() {
         std::io::ErrorKind::NotFound => {
-          let mut file = OpenOptions::new()
-            .create(true)
+          // only one of the handles racing to create the file writes the header
+          let res = OpenOptions::new()
+            .create_new(true)
             .write(true)
             .read(true)
-            .open(filename)?;
+            .open(filename);
+          let mut file = match res {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+              return Self::open(filename)
+            }
+            Err(err) => return Err(err.into()),
+          };
           let header = H::default();
           let raw_header = to_raw(&header);
           file.write_all(&raw_header)?;
-          Self {
-            file,
-            name: filename.to_owned(),
-            phantom_e: PhantomData,
-            phantom_h: PhantomData,
-          }
+          (file, true)
         }
         _ => return Err(err.into()),
       },
     };
-    tf.check()?;
-    Ok(tf)
+    Ok(Self {
+      file,
+      name: filename.to_owned(),
+      durability: Durability::default(),
+      created,
+      keyframes: vec![],
+      tail: vec![],
+      end: Self::header_size() as u64,
+      phantom_h: PhantomData,
+    })
+  }
+
+  pub fn created(&self) -> bool {
+    self.created
+  }
+
+  // Rewrites the file of the given version in the current format, the
+  // header fields added since get their defaults. Version 1 entries have
+  // no checksums, the later ones are cut off at the first invalid one.
+  // Files written by newer versions are refused rather than misread
+  fn migrate(&mut self, from: u64) -> Result<()> {
+    if from > H::default().version() {
+      return Err(TrackFileError::UnsupportedVersion(from));
+    }
+    let mut buf = vec![0; size_of::<HeaderPrefix>()];
+    self.file.read_at(&mut buf, 0)?;
+    let prefix: HeaderPrefix = from_raw(&buf)?;
+
+    let header_size = H::size_of_version(from);
+    let entry_size = match H::entry_layout(from) {
+      EntryLayout::Raw => size_of::<E>(),
+      EntryLayout::Checksummed => size_of::<E>() + CHECKSUM_SIZE,
+      EntryLayout::Encoded => return Err(TrackFileError::UnsupportedVersion(from)),
+    };
+    let meta = std::fs::metadata(&self.name)?;
+    let fit = (meta.len() as usize).saturating_sub(header_size) / entry_size;
+    let count = (prefix.count as usize).min(fit);
+
+    let mut buf = vec![0; count * entry_size];
+    self.file.read_at(&mut buf, header_size as u64)?;
+    let entries = buf
+      .chunks_exact(entry_size)
+      .take_while(|chunk| entry_size == size_of::<E>() || Self::checksum_valid(chunk))
+      .map(from_raw)
+      .collect::<Result<Vec<E>>>()?;
+
+    let mut header = H::default();
+    header.set_timestamp(prefix.ts);
+    self.write_entries(&header, &entries)
+  }
+
+  fn checksum_valid(buf: &[u8]) -> bool {
+    let (data, checksum) = buf.split_at(size_of::<E>());
+    crc32fast::hash(data).to_le_bytes() == checksum
   }
 
-  fn check(&self) -> Result<()> {
+  // Reads the entries counted in the header through, noting where the
+  // keyframes and the last entries are. Stops at the first damaged entry,
+  // returns the number of the intact ones
+  fn index(&mut self) -> Result<usize> {
     let header = self.read_file_header()?;
+    let start = Self::header_size() as u64;
+    let len = self.file.metadata()?.len();
+    let mut data = vec![0; len.saturating_sub(start) as usize];
+    self.file.read_exact_at(&mut data, start)?;
+
+    self.keyframes.clear();
+    self.tail.clear();
+    self.end = start;
+    let count = header.count() as usize;
+    for idx in 0..count {
+      let offset = (self.end - start) as usize;
+      let prev = base(idx, self.tail.last().map(|(_, e)| e));
+      let Ok((e, size)) = Self::decode_record(&data[offset..], idx, prev) else {
+        return Ok(idx);
+      };
+      self.push_tail(self.end, idx, e);
+      self.end += size as u64;
+    }
+    Ok(count)
+  }
+
+  fn push_tail(&mut self, offset: u64, idx: usize, e: E) {
+    if idx.is_multiple_of(KEYFRAME_INTERVAL) {
+      self.keyframes.push(offset);
+    }
+    self.tail.push((offset, e));
+    if self.tail.len() > 2 {
+      self.tail.remove(0);
+    }
+  }
+
+  // Cuts the file off at the first damaged entry, fixing the header
+  // count. Returns the number of entries dropped
+  pub fn recover(&mut self) -> Result<usize> {
+    let mut header = self.read_file_header()?;
     if !header.check_magic() {
-      Err(TrackFileError::InvalidMagicNumber)
-    } else {
-      let meta = std::fs::metadata(&self.name)?;
-      let expected_len = (header.count() as usize) * Self::entry_size() + Self::header_size();
-      let real_len = meta.len() as usize;
-      if real_len != expected_len {
-        Err(TrackFileError::InvalidFileLength(expected_len, real_len))
-      } else {
-        Ok(())
-      }
+      return Err(TrackFileError::InvalidMagicNumber);
     }
+    let count = header.count() as usize;
+    let valid = self.index()?;
+
+    self.file.set_len(self.end)?;
+    header.set_count(valid as u64);
+    self.write_file_header(&header)?;
+    self.file.sync_all()?;
+
+    let dropped = count - valid;
+    RECOVERED_FILES.fetch_add(1, Ordering::Relaxed);
+    DROPPED_ENTRIES.fetch_add(dropped as u64, Ordering::Relaxed);
+    warn!(
+      "track file {} recovered, {dropped} entries dropped",
+      self.name
+    );
+    Ok(dropped)
   }
 
-  fn make_entry_buf() -> Vec<u8> {
-    let mut buf = vec![];
-    buf.resize(Self::entry_size(), 0);
-    buf
+  fn encode_record(e: &E, prev: Option<&E>, buf: &mut Vec<u8>) {
+    let mut payload = vec![];
+    e.encode(prev, &mut payload);
+    put_uvarint(buf, payload.len() as u64);
+    buf.extend(&payload);
+    buf.extend(crc32fast::hash(&payload).to_le_bytes());
   }
 
-  fn make_header_buf() -> Vec<u8> {
+  // Decodes the record at the start of data, returns the entry along with
+  // the record size
+  fn decode_record(data: &[u8], pos: usize, prev: Option<&E>) -> Result<(E, usize)> {
+    let mut rest = data;
+    let len = get_uvarint(&mut rest).ok_or(TrackFileError::InsufficientDataLength(data.len()))?;
+    let len = len as usize;
+    if rest.len() < len.saturating_add(CHECKSUM_SIZE) {
+      return Err(TrackFileError::InsufficientDataLength(rest.len()));
+    }
+    let (payload, checksum) = rest.split_at(len);
+    if crc32fast::hash(payload).to_le_bytes() != checksum[..CHECKSUM_SIZE] {
+      CHECKSUM_ERRORS.fetch_add(1, Ordering::Relaxed);
+      return Err(TrackFileError::ChecksumMismatch(pos));
+    }
+    let e = E::decode(payload, prev).ok_or(TrackFileError::InsufficientDataLength(len))?;
+    Ok((e, data.len() - rest.len() + len + CHECKSUM_SIZE))
+  }
+
+  // the entry as it reads back once stored
+  fn stored(e: &E) -> E {
     let mut buf = vec![];
-    buf.resize(Self::header_size(), 0);
-    buf
+    e.encode(None, &mut buf);
+    E::decode(&buf, None).unwrap_or_else(|| e.clone())
   }
 
-  const fn entry_size() -> usize {
-    size_of::<E>()
+  fn make_header_buf() -> Vec<u8> {
+    vec![0; Self::header_size()]
   }
 
-  const fn header_size() -> usize {
+  pub const fn header_size() -> usize {
     size_of::<H>()
   }
 
+  // Size of the file up to the end of the last entry
+  pub fn size(&self) -> u64 {
+    self.end
+  }
+
+  pub fn header(&self) -> Result<H> {
+    self.read_file_header()
+  }
+
+  // Replaces the header as is, the count included
+  pub fn set_header(&mut self, header: &H) -> Result<()> {
+    self.write_file_header(header)
+  }
+
   fn read_file_header(&self) -> Result<H> {
     let mut buf = Self::make_header_buf();
     self.file.read_at(&mut buf, 0)?;
@@ -171,10 +479,14 @@ impl<E: Clone + Sized + PartialEq, H: TrackFileHeader> TrackFile<E, H> {
     Ok(())
   }
 
-  fn inc(&mut self) -> Result<()> {
-    let mut header = self.read_file_header()?;
-    header.inc();
-    self.write_file_header(&header)?;
+  pub fn set_durability(&mut self, durability: Durability) {
+    self.durability = durability;
+  }
+
+  fn sync(&self) -> Result<()> {
+    if self.durability != Durability::Os {
+      self.file.sync_data()?;
+    }
     Ok(())
   }
 
@@ -200,93 +512,165 @@ impl<E: Clone + Sized + PartialEq, H: TrackFileHeader> TrackFile<E, H> {
     Ok(())
   }
 
-  pub fn append(&mut self, e: &E) -> Result<()> {
-    let header = self.read_file_header()?;
-    let count = header.count() as usize;
-    let offset = if count < 2 {
-      // if less than 2 points exist, append only
-      0
-    } else {
-      let mut last_two = self.read_multiple_at(count - 2, 2)?;
-      let last = last_two.pop().unwrap();
-      let prev = last_two.pop().unwrap();
-      if last == prev && prev == *e {
-        // if the last two points are equal and the new one equals to them
-        // replace the last one, overwriting only timestamp
-        -(Self::entry_size() as i64)
+  // whether the entry replaces the last one instead of being appended:
+  // the last two entries are equal and so is the new one, only the
+  // latest of them is kept
+  fn replaces_last(tail: &[E], e: &E) -> bool {
+    let len = tail.len();
+    len >= 2 && tail[len - 1] == tail[len - 2] && tail[len - 2] == *e
+  }
+
+  // Returns the number of bytes written, the header included
+  pub fn append(&mut self, e: &E) -> Result<u64> {
+    self.append_many(std::slice::from_ref(e))
+  }
+
+  // Appends the entries following the rules of replaces_last. The entries
+  // are written before the header so that an interrupted write leaves a
+  // tail which is ignored and cut off by the recovery
+  // Returns the number of bytes written, the headers included
+  pub fn append_many(&mut self, entries: &[E]) -> Result<u64> {
+    if self.durability == Durability::Append && entries.len() > 1 {
+      let mut written = 0;
+      for e in entries {
+        written += self.append(e)?;
+      }
+      return Ok(written);
+    }
+
+    let mut header = self.read_file_header()?;
+    let stored = self.tail.len();
+    let mut tail: Vec<E> = self.tail.iter().map(|(_, e)| e.clone()).collect();
+    // the stored entries from this one on are rewritten
+    let mut first = stored;
+    for e in entries {
+      let e = Self::stored(e);
+      if Self::replaces_last(&tail, &e) {
+        first = first.min(tail.len() - 1);
+        tail.pop();
       } else {
-        // otherwise, append
-        0
+        header.inc();
       }
+      tail.push(e);
+    }
+
+    let offset = match self.tail.get(first) {
+      Some((offset, _)) => *offset,
+      None => self.end,
     };
+    let idx = header.count() as usize - (tail.len() - first);
+    let written = self.write_from(offset, idx, &tail[first..])?;
+    self.write_file_header(&header)?;
+    // the whole batch is committed at once
+    self.sync()?;
+    Ok(written + Self::header_size() as u64)
+  }
 
-    if offset == 0 {
-      self.inc()?
+  // Writes the entries over the ones stored from the offset on, the first
+  // of them becoming the idx-th entry. Returns the number of bytes written
+  fn write_from(&mut self, offset: u64, idx: usize, entries: &[E]) -> Result<u64> {
+    self.tail.retain(|(at, _)| *at < offset);
+    self.keyframes.retain(|at| *at < offset);
+    let mut data = vec![];
+    for (i, e) in entries.iter().enumerate() {
+      let start = data.len() as u64;
+      Self::encode_record(
+        e,
+        base(idx + i, self.tail.last().map(|(_, e)| e)),
+        &mut data,
+      );
+      self.push_tail(offset + start, idx + i, e.clone());
     }
+    self.file.write_all_at(&data, offset)?;
+    let end = offset + data.len() as u64;
+    if end < self.end {
+      self.file.set_len(end)?;
+    }
+    self.end = end;
+    Ok(data.len() as u64)
+  }
+
+  // Replaces all the entries of the file. The new contents are written
+  // to a temporary file first which is then moved over the original one
+  pub fn rewrite(&mut self, entries: &[E]) -> Result<()> {
+    let header = self.read_file_header()?;
+    self.write_entries(&header, entries)
+  }
+
+  fn write_entries(&mut self, header: &H, entries: &[E]) -> Result<()> {
+    let mut header = header.clone();
+    header.set_count(entries.len() as u64);
 
-    let data = to_raw(e);
-    self.file.seek(SeekFrom::End(offset))?;
-    self.file.write_all(&data)?;
+    let tmp_name = format!(
+      "{}.{}.tmp",
+      self.name,
+      TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    );
+    {
+      let mut tmp = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp_name)?;
+      let mut data = to_raw(&header);
+      let mut prev = None;
+      for (idx, e) in entries.iter().enumerate() {
+        Self::encode_record(e, base(idx, prev), &mut data);
+        prev = Some(e);
+      }
+      tmp.write_all(&data)?;
+      tmp.sync_all()?;
+    }
+    std::fs::rename(&tmp_name, &self.name)?;
+    self.file = OpenOptions::new().write(true).read(true).open(&self.name)?;
+    self.index()?;
     Ok(())
   }
 
   pub fn read_at(&self, pos: usize) -> Result<E> {
-    let header = self.read_file_header()?;
-    if pos as u64 >= header.count() {
-      Err(TrackFileError::IndexError(pos))
-    } else {
-      let mut buf = Self::make_entry_buf();
-      let offset = Self::header_size() + pos * Self::entry_size();
-      self.file.read_at(&mut buf, offset as u64)?;
-      let e = from_raw(&buf)?;
-      Ok(e)
-    }
+    self
+      .read_multiple_at(pos, 1)?
+      .pop()
+      .ok_or(TrackFileError::IndexError(pos))
   }
 
+  // Entries are decoded from the keyframe preceding pos on
   pub fn read_multiple_at(&self, pos: usize, len: usize) -> Result<Vec<E>> {
     let header = self.read_file_header()?;
     let count = header.count() as usize;
-    let mut len = len;
-
-    if pos + len > count {
-      len = count - pos;
-    }
-
+    let len = len.min(count.saturating_sub(pos));
     if len < 1 {
       return Ok(Vec::new());
     }
 
-    let mut buf = vec![];
-    let entry_len = Self::entry_size();
-    buf.resize(len * entry_len, 0);
-
-    let offset = Self::header_size() + pos * entry_len;
-    self.file.read_at(&mut buf, offset as u64)?;
+    let first = pos / KEYFRAME_INTERVAL;
+    let from = *self
+      .keyframes
+      .get(first)
+      .ok_or(TrackFileError::IndexError(pos))?;
+    let to = match self.keyframes.get((pos + len - 1) / KEYFRAME_INTERVAL + 1) {
+      Some(to) => *to,
+      None => self.end,
+    };
+    let mut data = vec![0; (to - from) as usize];
+    self.file.read_exact_at(&mut data, from)?;
 
-    let mut entries = vec![];
-    for idx in 0..len {
-      let start = idx * entry_len;
-      let end = (idx + 1) * entry_len;
-      let e = from_raw(&buf[start..end])?;
-      entries.push(e);
+    let mut entries = Vec::with_capacity(len);
+    let mut offset = 0;
+    let mut prev = None;
+    for idx in first * KEYFRAME_INTERVAL..pos + len {
+      let (e, size) = Self::decode_record(&data[offset..], idx, base(idx, prev.as_ref()))?;
+      offset += size;
+      if idx >= pos {
+        entries.push(e.clone());
+      }
+      prev = Some(e);
     }
-
     Ok(entries)
   }
 
   pub fn read_all(&self) -> Result<Vec<E>> {
-    let header = self.read_file_header()?;
-
-    let mut buf = Self::make_entry_buf();
-    let mut res = vec![];
-    for idx in 0..header.count() {
-      let idx = idx as usize;
-      let offset = Self::header_size() + idx * Self::entry_size();
-      self.file.read_at(&mut buf, offset as u64)?;
-      let tp = from_raw(&buf)?;
-      res.push(tp);
-    }
-    Ok(res)
+    self.read_multiple_at(0, self.count()? as usize)
   }
 }
 
@@ -299,7 +683,7 @@ pub mod tests {
     io::Read,
   };
 
-  const TRACK_VERSION: u64 = 1;
+  const TRACK_VERSION: u64 = 3;
   const TRACK_MAGIC_NUMBER: u64 = 0x119F3E5F006A42C8;
 
   #[derive(Debug, Clone)]
@@ -343,6 +727,26 @@ pub mod tests {
       self.ts = Utc::now().timestamp_millis() as u64;
       self.count += 1;
     }
+
+    fn set_count(&mut self, count: u64) {
+      self.count = count;
+    }
+
+    fn set_version(&mut self, version: u64) {
+      self.version = version;
+    }
+
+    fn set_timestamp(&mut self, ts: u64) {
+      self.ts = ts;
+    }
+
+    fn entry_layout(version: u64) -> EntryLayout {
+      match version {
+        1 => EntryLayout::Raw,
+        2 => EntryLayout::Checksummed,
+        _ => EntryLayout::Encoded,
+      }
+    }
   }
 
   #[derive(Clone, Debug)]
@@ -356,6 +760,18 @@ pub mod tests {
     }
   }
 
+  impl TrackFileEntry for Entry {}
+
+  // length, entry and checksum
+  const RECORD_SIZE: usize = 1 + size_of::<Entry>() + CHECKSUM_SIZE;
+
+  // an entry as stored by version 2
+  fn checksummed(e: &Entry) -> Vec<u8> {
+    let mut data = to_raw(e);
+    data.extend(crc32fast::hash(&data).to_le_bytes());
+    data
+  }
+
   fn vec_compare(v1: &[u8], v2: &[u8]) -> bool {
     v1.len() == v2.len() && v1.iter().zip(v2).all(|(i1, i2)| *i1 == *i2)
   }
@@ -379,18 +795,17 @@ pub mod tests {
     }
 
     let meta = fs::metadata(path).unwrap();
-    let expected_len = 3 * size_of::<Entry>() + size_of::<Header>();
+    let expected_len = 3 * RECORD_SIZE + size_of::<Header>();
     assert_eq!(expected_len, meta.len() as usize);
 
-    let mut raw = vec![];
-    raw.resize(size_of::<Header>(), 0);
+    let mut raw = vec![0; size_of::<Header>()];
 
     let mut f = File::open(path).unwrap();
-    f.read(&mut raw).unwrap();
+    f.read_exact(&mut raw).unwrap();
 
     let expected_raw = &[
       0xc8, 0x42, 0x6a, 0x00, 0x5f, 0x3e, 0x9f, 0x11, // magic
-      0x01, 0, 0, 0, 0, 0, 0, 0, // version
+      0x03, 0, 0, 0, 0, 0, 0, 0, // version
     ];
     assert!(vec_compare(&raw[..16], expected_raw));
 
@@ -399,4 +814,300 @@ pub mod tests {
 
     remove_file(path).unwrap();
   }
+
+  #[test]
+  fn test_rewrite() {
+    let path = temp_dir();
+    let path = path.join("track_rewrite.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    let mut tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+    for value in 0..5 {
+      tf.append(&Entry { value }).unwrap();
+    }
+    let mtime = tf.mtime().unwrap();
+    tf.rewrite(&[Entry { value: 0 }, Entry { value: 4 }])
+      .unwrap();
+    assert_eq!(tf.count().unwrap(), 2);
+    assert_eq!(tf.mtime().unwrap(), mtime);
+    tf.append(&Entry { value: 5 }).unwrap();
+
+    let tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+    let values: Vec<u32> = tf.read_all().unwrap().iter().map(|e| e.value).collect();
+    assert_eq!(values, vec![0, 4, 5]);
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_recover() {
+    let path = temp_dir();
+    let path = path.join("track_recover.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    {
+      let mut tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+      for value in 0..5 {
+        tf.append(&Entry { value }).unwrap();
+      }
+    }
+
+    // a partial write of the last entry
+    let f = OpenOptions::new().write(true).open(path).unwrap();
+    let len = f.metadata().unwrap().len();
+    f.set_len(len - 2).unwrap();
+
+    // refused as is, the tail is only cut off by a repair
+    let res: Result<TrackFile<Entry, Header>> = TrackFile::new(path);
+    assert!(matches!(res, Err(TrackFileError::ChecksumMismatch(4))));
+    let mut tf: TrackFile<Entry, Header> = TrackFile::repair(path).unwrap();
+    assert_eq!(tf.count().unwrap(), 4);
+    assert_eq!(
+      f.metadata().unwrap().len() as usize,
+      size_of::<Header>() + 4 * RECORD_SIZE
+    );
+
+    // a corrupted entry in the middle
+    let offset = size_of::<Header>() + 2 * RECORD_SIZE + 1;
+    f.write_at(&[0xff], offset as u64).unwrap();
+    assert!(matches!(
+      tf.read_at(2),
+      Err(TrackFileError::ChecksumMismatch(2))
+    ));
+    assert!(tf.read_all().is_err());
+
+    assert_eq!(tf.recover().unwrap(), 2);
+    let values: Vec<u32> = tf.read_all().unwrap().iter().map(|e| e.value).collect();
+    assert_eq!(values, vec![0, 1]);
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_upgrade() {
+    let path = temp_dir();
+    let path = path.join("track_upgrade.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    // version 1 files have no checksums, version 2 ones have the fixed
+    // size entries followed by them
+    for version in [1, 2] {
+      let mut header = Header::default();
+      header.set_version(version);
+      header.set_timestamp(1000);
+      header.set_count(3);
+      let mut data = to_raw(&header);
+      for value in 0..3 {
+        match version {
+          1 => data.extend(to_raw(&Entry { value })),
+          _ => data.extend(checksummed(&Entry { value })),
+        }
+      }
+      fs::write(path, data).unwrap();
+
+      // upgraded on open
+      let mut tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+      let values: Vec<u32> = tf.read_all().unwrap().iter().map(|e| e.value).collect();
+      assert_eq!(values, vec![0, 1, 2]);
+      let header = tf.read_file_header().unwrap();
+      assert_eq!(
+        (header.version(), header.timestamp()),
+        (TRACK_VERSION, 1000)
+      );
+      tf.append(&Entry { value: 3 }).unwrap();
+
+      let tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+      assert_eq!(tf.read_all().unwrap().len(), 4);
+      assert_eq!(
+        fs::metadata(path).unwrap().len() as usize,
+        size_of::<Header>() + 4 * RECORD_SIZE
+      );
+    }
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_upgrade_header() {
+    let path = temp_dir();
+    let path = path.join("track_upgrade_header.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    // version 2 track headers have no compaction watermark
+    let prefix = HeaderPrefix {
+      magic: 0x119F3E5F006A42C8,
+      version: 2,
+      ts: 1000,
+      count: 2,
+    };
+    let mut data = to_raw(&prefix);
+    for value in 0..2 {
+      data.extend(checksummed(&Entry { value }));
+    }
+    fs::write(path, data).unwrap();
+
+    type Upgraded = TrackFile<Entry, crate::track::header::Header>;
+    let tf = Upgraded::repair(path).unwrap();
+    let values: Vec<u32> = tf.read_all().unwrap().iter().map(|e| e.value).collect();
+    assert_eq!(values, vec![0, 1]);
+    let header = tf.header().unwrap();
+    assert_eq!((header.timestamp(), header.compacted()), (1000, 0));
+    assert_eq!(
+      fs::metadata(path).unwrap().len() as usize,
+      Upgraded::header_size() + 2 * RECORD_SIZE
+    );
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_unsupported_version() {
+    let path = temp_dir();
+    let path = path.join("track_unsupported.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    let mut header = Header::default();
+    header.set_version(TRACK_VERSION + 1);
+    fs::write(path, to_raw(&header)).unwrap();
+
+    let res: Result<TrackFile<Entry, Header>> = TrackFile::repair(path);
+    assert!(matches!(res, Err(TrackFileError::UnsupportedVersion(_))));
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_append_many() {
+    let path = temp_dir();
+    let path = path.join("track_append_many.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    let mut tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+    tf.append(&Entry { value: 1 }).unwrap();
+    tf.append_many(&[2, 2, 2, 3].map(|value| Entry { value }))
+      .unwrap();
+    let written = tf
+      .append_many(&[3, 3].map(|value| Entry { value }))
+      .unwrap();
+    // the last entry stored is replaced, nothing else is rewritten
+    assert_eq!(written as usize, RECORD_SIZE + size_of::<Header>());
+
+    let tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+    let values: Vec<u32> = tf.read_all().unwrap().iter().map(|e| e.value).collect();
+    assert_eq!(values, vec![1, 2, 2, 3, 3]);
+
+    remove_file(path).unwrap();
+  }
+
+  // equal regardless of the timestamp, like track points
+  #[derive(Clone, Debug)]
+  struct Stamped {
+    value: u32,
+    ts: u32,
+  }
+
+  impl PartialEq for Stamped {
+    fn eq(&self, other: &Self) -> bool {
+      self.value == other.value
+    }
+  }
+
+  impl TrackFileEntry for Stamped {}
+
+  #[test]
+  fn test_replace_last() {
+    let path = temp_dir();
+    let path = path.join("track_replace_last.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    let mut tf: TrackFile<Stamped, Header> = TrackFile::new(path).unwrap();
+    for (ts, value) in [1, 1, 1, 1, 2].into_iter().enumerate() {
+      tf.append(&Stamped {
+        value,
+        ts: ts as u32,
+      })
+      .unwrap();
+    }
+    tf.append_many(&[Stamped { value: 2, ts: 5 }, Stamped { value: 2, ts: 6 }])
+      .unwrap();
+
+    // the header count always matches the file length
+    let expected_len = 4 * (1 + size_of::<Stamped>() + CHECKSUM_SIZE) + size_of::<Header>();
+    assert_eq!(fs::metadata(path).unwrap().len() as usize, expected_len);
+
+    let tf: TrackFile<Stamped, Header> = TrackFile::new(path).unwrap();
+    assert_eq!(tf.count().unwrap(), 4);
+    let stamps: Vec<(u32, u32)> = tf
+      .read_all()
+      .unwrap()
+      .iter()
+      .map(|e| (e.value, e.ts))
+      .collect();
+    // the repeated points are squashed, the last one keeps the latest ts
+    assert_eq!(stamps, vec![(1, 0), (1, 3), (2, 4), (2, 6)]);
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_keyframes() {
+    let path = temp_dir();
+    let path = path.join("track_keyframes.bin");
+    let path = path.to_str().unwrap();
+    let _ = remove_file(path);
+
+    let mut tf: TrackFile<Entry, Header> = TrackFile::new(path).unwrap();
+    tf.append_many(&(0..100).map(|value| Entry { value }).collect::<Vec<_>>())
+      .unwrap();
+    for value in 100..200 {
+      tf.append(&Entry { value }).unwrap();
+    }
+    assert_eq!(tf.keyframes.len(), 4);
+
+    // read from the closest keyframe on, both before and after a reopen
+    for tf in [tf, TrackFile::new(path).unwrap()] {
+      assert_eq!(tf.read_at(0).unwrap().value, 0);
+      assert_eq!(tf.read_at(130).unwrap().value, 130);
+      assert_eq!(tf.read_at(199).unwrap().value, 199);
+      assert!(matches!(
+        tf.read_at(200),
+        Err(TrackFileError::IndexError(200))
+      ));
+      let values: Vec<u32> = tf
+        .read_multiple_at(60, 10)
+        .unwrap()
+        .iter()
+        .map(|e| e.value)
+        .collect();
+      assert_eq!(values, (60..70).collect::<Vec<_>>());
+      assert_eq!(tf.read_multiple_at(190, 20).unwrap().len(), 10);
+      assert_eq!(tf.read_all().unwrap().len(), 200);
+    }
+
+    remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_varint() {
+    let values = [0, 1, -1, 63, -64, 64, i32::MAX as i64, i64::MIN, i64::MAX];
+    let mut buf = vec![];
+    for value in values {
+      put_varint(&mut buf, value);
+    }
+    // small values of either sign take a byte
+    assert_eq!(buf[..5], [0, 2, 1, 126, 127]);
+    let mut data = buf.as_slice();
+    for value in values {
+      assert_eq!(get_varint(&mut data), Some(value));
+    }
+    assert_eq!(get_varint(&mut data), None);
+    assert_eq!(get_uvarint(&mut [0x80].as_slice()), None);
+  }
 }
@@ -1,5 +1,5 @@
 use geo_types::{Coord, Point as GeoPoint};
-use rstar::AABB;
+use rstar::{Envelope, AABB};
 use serde::Serialize;
 
 use crate::service::camden::{self, MapBounds};
@@ -22,6 +22,15 @@ impl From<Point> for GeoPoint {
   }
 }
 
+impl From<camden::Point> for Point {
+  fn from(val: camden::Point) -> Self {
+    Self {
+      lat: val.lat,
+      lng: val.lng,
+    }
+  }
+}
+
 impl From<Point> for camden::Point {
   fn from(value: Point) -> Self {
     Self {
@@ -71,6 +80,42 @@ impl rstar::Point for Point {
   }
 }
 
+// Splits a polyline wherever it crosses the antimeridian, closing and
+// reopening the parts at the map edges, so that clients never draw a line
+// across the whole map. Same edges as Rect::envelopes() uses.
+pub fn split_antimeridian(points: &[Point]) -> Vec<Vec<Point>> {
+  let mut parts = vec![];
+  let mut current: Vec<Point> = vec![];
+
+  for point in points.iter() {
+    if let Some(prev) = current.last().copied() {
+      let dlng = point.lng - prev.lng;
+      if dlng.abs() > 180.0 {
+        // going east over 180 means the next point has a negative lng
+        let (edge, next_edge, unwrapped) = if dlng < 0.0 {
+          (MAX_LNG, MIN_LNG, point.lng + 360.0)
+        } else {
+          (MIN_LNG, MAX_LNG, point.lng - 360.0)
+        };
+        let ratio = (edge - prev.lng) / (unwrapped - prev.lng);
+        let lat = prev.lat + (point.lat - prev.lat) * ratio;
+        current.push(Point { lat, lng: edge });
+        parts.push(current);
+        current = vec![Point {
+          lat,
+          lng: next_edge,
+        }];
+      }
+    }
+    current.push(*point);
+  }
+
+  if current.len() > 1 {
+    parts.push(current);
+  }
+  parts
+}
+
 #[derive(Debug, Serialize, Clone, Copy)]
 pub struct Rect {
   pub south_west: Point,
@@ -148,6 +193,18 @@ impl Rect {
       vec![AABB::from_corners(self.south_west, self.north_east)]
     }
   }
+
+  pub fn contains(&self, point: Point) -> bool {
+    self
+      .envelopes()
+      .iter()
+      .any(|env| env.contains_point(&point))
+  }
+
+  pub fn intersects(&self, south_west: Point, north_east: Point) -> bool {
+    let other = AABB::from_corners(south_west, north_east);
+    self.envelopes().iter().any(|env| env.intersects(&other))
+  }
 }
 
 impl From<MapBounds> for Rect {
@@ -221,6 +278,59 @@ pub mod tests {
     );
   }
 
+  #[test]
+  fn test_contains_wrapped() {
+    let rect = Rect::new(170.0, 0.0, -170.0, 10.0);
+    assert!(rect.contains(Point {
+      lat: 5.0,
+      lng: 175.0
+    }));
+    assert!(rect.contains(Point {
+      lat: 5.0,
+      lng: -175.0
+    }));
+    assert!(!rect.contains(Point { lat: 5.0, lng: 0.0 }));
+    assert!(rect.intersects(
+      Point {
+        lat: 8.0,
+        lng: -172.0
+      },
+      Point {
+        lat: 20.0,
+        lng: -160.0
+      }
+    ));
+    assert!(!rect.intersects(Point { lat: 0.0, lng: 0.0 }, Point { lat: 1.0, lng: 1.0 }));
+  }
+
+  #[test]
+  fn test_split_antimeridian() {
+    let points = vec![
+      Point {
+        lat: 0.0,
+        lng: 170.0,
+      },
+      Point {
+        lat: 10.0,
+        lng: -170.0,
+      },
+      Point {
+        lat: 12.0,
+        lng: -160.0,
+      },
+    ];
+    let parts = split_antimeridian(&points);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].len(), 2);
+    assert_eq!(parts[1].len(), 3);
+    assert_eq!(parts[0][1].lng, MAX_LNG);
+    assert_eq!(parts[1][0].lng, MIN_LNG);
+    assert!((parts[0][1].lat - 5.0).abs() < 0.01);
+
+    let points = vec![Point { lat: 0.0, lng: 0.0 }, Point { lat: 1.0, lng: 1.0 }];
+    assert_eq!(split_antimeridian(&points).len(), 1);
+  }
+
   #[test]
   fn test_nowrap() {
     let rect = Rect::new(0.0, 0.0, 10.0, 10.0);
@@ -1,5 +1,6 @@
-use std::{collections::HashMap, hash::Hash, ops::Deref};
+use std::{collections::HashMap, hash::Hash, ops::Deref, time::Duration};
 
+use crate::config;
 use chrono::{DateTime, Utc};
 use log::error;
 use tokio::sync::mpsc::Sender;
@@ -37,6 +38,22 @@ impl<T: Hash + Eq> Default for Counter<T> {
   }
 }
 
+pub fn http_client(cfg: &config::Http) -> reqwest::Result<reqwest::Client> {
+  let mut builder = reqwest::Client::builder()
+    .connect_timeout(cfg.connect_timeout)
+    .timeout(cfg.timeout)
+    .user_agent(&cfg.user_agent);
+  if let Some(proxy) = cfg.proxy.as_ref() {
+    builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+  }
+  builder.build()
+}
+
+pub fn from_millis(ms: i64) -> DateTime<Utc> {
+  let nsecs = (ms.rem_euclid(1000) * 1_000_000) as u32;
+  DateTime::from_timestamp(ms.div_euclid(1000), nsecs).unwrap_or_default()
+}
+
 pub fn seconds_since(t: DateTime<Utc>) -> f32 {
   let t2 = Utc::now();
   let d = (t2 - t).to_std();
@@ -47,6 +64,27 @@ pub fn seconds_since(t: DateTime<Utc>) -> f32 {
   }
 }
 
+// Exponential backoff delay for the given attempt (starting from 0) with up to
+// 50% of random jitter, capped at max
+pub fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+  let delay = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+  // there's no rand in deps, subsecond nanos are good enough for a jitter
+  let jitter = Utc::now().timestamp_subsec_nanos() as f64 / 1_000_000_000.0;
+  delay.mul_f64(1.0 + jitter * 0.5).min(max)
+}
+
+pub async fn proxy_requests<T>(mut stream: Streaming<T>, tx: Sender<T>) {
+  while let Some(msg) = stream.next().await {
+    if let Ok(msg) = msg {
+      let res = tx.send(msg).await;
+      if let Err(err) = res {
+        error!("error sending request via channel: {err:?}");
+        break;
+      }
+    }
+  }
+}
+
 #[cfg(test)]
 pub mod tests {
   use super::*;
@@ -61,16 +99,16 @@ pub mod tests {
     assert_eq!(*keys[0], "abc");
     assert_eq!(counter.get("abc").unwrap(), &2);
   }
-}
 
-pub async fn proxy_requests<T>(mut stream: Streaming<T>, tx: Sender<T>) {
-  while let Some(msg) = stream.next().await {
-    if let Ok(msg) = msg {
-      let res = tx.send(msg).await;
-      if let Err(err) = res {
-        error!("error sending request via channel: {err:?}");
-        break;
-      }
-    }
+  #[test]
+  fn test_backoff_delay() {
+    let base = Duration::from_secs(1);
+    let max = Duration::from_secs(60);
+    let d = backoff_delay(0, base, max);
+    assert!(d >= base && d <= base.mul_f64(1.5));
+    let d = backoff_delay(3, base, max);
+    assert!(d >= Duration::from_secs(8) && d <= Duration::from_secs(12));
+    assert_eq!(backoff_delay(10, base, max), max);
+    assert_eq!(backoff_delay(u32::MAX, base, max), max);
   }
 }
@@ -1,6 +1,7 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize};
 
+use super::metar::parse_miles;
 use crate::service::camden;
 
 const WHY_IS_IT_EVEN_CUSTOM_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
@@ -32,6 +33,31 @@ impl From<WindDirection> for camden::weather_info::WindDirection {
   }
 }
 
+// Visibility in statute miles, the API reports it either as a number or
+// as text like "10+" or "1/2"
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Visibility {
+  Miles(f64),
+  Text(String),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Cloud {
+  pub cover: String,
+  // feet above ground level
+  pub base: Option<u64>,
+}
+
+impl From<Cloud> for camden::CloudLayer {
+  fn from(value: Cloud) -> Self {
+    Self {
+      cover: value.cover,
+      base: value.base,
+    }
+  }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Metar {
   pub metar_id: u64,
@@ -54,6 +80,24 @@ pub struct Metar {
   pub wgst: Option<u64>,
   #[serde(rename(deserialize = "rawOb"))]
   pub raw_ob: String,
+  #[serde(default)]
+  pub visib: Option<Visibility>,
+  // altimeter setting, hPa
+  #[serde(default)]
+  pub altim: Option<f64>,
+  #[serde(default)]
+  pub clouds: Option<Vec<Cloud>>,
+  #[serde(default, rename(deserialize = "wxString"))]
+  pub wx_string: Option<String>,
+}
+
+impl Metar {
+  pub fn visibility_sm(&self) -> Option<f64> {
+    match self.visib.as_ref()? {
+      Visibility::Miles(miles) => Some(*miles),
+      Visibility::Text(text) => parse_miles(text.trim().trim_end_matches('+')),
+    }
+  }
 }
 
 #[cfg(test)]
@@ -0,0 +1,304 @@
+use super::ext_types::{Cloud, Metar, Visibility, WindDirection};
+use crate::service::camden;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const MPS_TO_KT: f64 = 1.94384;
+const METERS_PER_SM: f64 = 1609.344;
+const INHG_TO_HPA: f64 = 33.8639;
+// CAVOK and 9999 both stand for 10km or more
+const UNLIMITED_VISIBILITY_SM: f64 = 10.0;
+
+lazy_static! {
+  static ref STATION: Regex = Regex::from_str(r"^[A-Z][A-Z0-9]{3}$").unwrap();
+  static ref REPORT_TIME: Regex = Regex::from_str(r"^(\d{2})(\d{2})(\d{2})Z$").unwrap();
+  static ref WIND: Regex =
+    Regex::from_str(r"^(\d{3}|VRB)(\d{2,3})(?:G(\d{2,3}))?(KT|MPS)$").unwrap();
+  static ref TEMPERATURE: Regex = Regex::from_str(r"^(M?\d{2})/(M?\d{2})?$").unwrap();
+  static ref VISIBILITY_M: Regex = Regex::from_str(r"^(\d{4})(?:NDV)?$").unwrap();
+  static ref VISIBILITY_SM: Regex = Regex::from_str(r"^([PM])?(\d+(?:/\d+)?)SM$").unwrap();
+  static ref WHOLE_MILES: Regex = Regex::from_str(r"^\d$").unwrap();
+  static ref CLOUD: Regex =
+    Regex::from_str(r"^(FEW|SCT|BKN|OVC|VV)(\d{3}|///)(?:CB|TCU|///)?$").unwrap();
+  static ref QNH: Regex = Regex::from_str(r"^([QA])(\d{4})$").unwrap();
+  static ref PHENOMENA: Regex = Regex::from_str(
+    r"^(?:\+|-|VC)?(?:MI|PR|BC|DR|BL|SH|TS|FZ)?(?:DZ|RA|SN|SG|IC|PL|GR|GS|UP|BR|FG|FU|VA|DU|SA|HZ|PY|PO|SQ|FC|SS|DS)*$"
+  )
+  .unwrap();
+}
+
+// Ordered from the best to the worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FlightCategory {
+  Vfr,
+  Mvfr,
+  Ifr,
+  Lifr,
+}
+
+impl FlightCategory {
+  // FAA categories by the ceiling in feet and the visibility in statute
+  // miles, the worse of the two wins
+  pub fn compute(ceiling: Option<u64>, visibility: Option<f64>) -> Option<Self> {
+    if ceiling.is_none() && visibility.is_none() {
+      return None;
+    }
+    let by_ceiling = match ceiling {
+      Some(c) if c < 500 => Self::Lifr,
+      Some(c) if c < 1000 => Self::Ifr,
+      Some(c) if c <= 3000 => Self::Mvfr,
+      _ => Self::Vfr,
+    };
+    let by_visibility = match visibility {
+      Some(v) if v < 1.0 => Self::Lifr,
+      Some(v) if v < 3.0 => Self::Ifr,
+      Some(v) if v <= 5.0 => Self::Mvfr,
+      _ => Self::Vfr,
+    };
+    Some(by_ceiling.max(by_visibility))
+  }
+}
+
+impl From<FlightCategory> for camden::FlightCategory {
+  fn from(value: FlightCategory) -> Self {
+    match value {
+      FlightCategory::Vfr => camden::FlightCategory::Vfr,
+      FlightCategory::Mvfr => camden::FlightCategory::Mvfr,
+      FlightCategory::Ifr => camden::FlightCategory::Ifr,
+      FlightCategory::Lifr => camden::FlightCategory::Lifr,
+    }
+  }
+}
+
+// Ceiling is the lowest broken, overcast or obscured layer
+pub fn ceiling(clouds: &[Cloud]) -> Option<u64> {
+  clouds
+    .iter()
+    .filter(|c| matches!(c.cover.as_str(), "BKN" | "OVC" | "OVX" | "VV"))
+    .filter_map(|c| c.base)
+    .min()
+}
+
+// Report time is given as day of month and time, the month is the current
+// one unless the day is in the future
+fn parse_report_time(token: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  let caps = REPORT_TIME.captures(token)?;
+  let day: u32 = caps[1].parse().ok()?;
+  let hour: u32 = caps[2].parse().ok()?;
+  let minute: u32 = caps[3].parse().ok()?;
+
+  let (mut year, mut month) = (now.year(), now.month());
+  if day > now.day() {
+    (year, month) = if month == 1 {
+      (year - 1, 12)
+    } else {
+      (year, month - 1)
+    };
+  }
+  let dt = NaiveDate::from_ymd_opt(year, month, day)?
+    .and_hms_opt(hour, minute, 0)?
+    .and_utc();
+  // reports from the future are clock skew, clamp them to now
+  Some(dt.min(now + Duration::minutes(5)))
+}
+
+fn parse_temperature(value: &str) -> Option<f64> {
+  match value.strip_prefix('M') {
+    Some(value) => value.parse::<f64>().ok().map(|v| -v),
+    None => value.parse().ok(),
+  }
+}
+
+// Statute miles given as a whole number or a fraction
+pub fn parse_miles(value: &str) -> Option<f64> {
+  match value.split_once('/') {
+    Some((num, den)) => {
+      let den: f64 = den.parse().ok()?;
+      (den > 0.0).then_some(num.parse::<f64>().ok()? / den)
+    }
+    None => value.parse().ok(),
+  }
+}
+
+// Parses a raw metar, returns None if it doesn't look like one
+pub fn parse_raw(raw: &str, now: DateTime<Utc>) -> Option<Metar> {
+  let raw = raw.trim();
+  let mut tokens = raw
+    .split_whitespace()
+    .skip_while(|t| *t == "METAR" || *t == "SPECI");
+
+  let icao_id = tokens.next().filter(|t| STATION.is_match(t))?.to_owned();
+  let report_time = parse_report_time(tokens.next()?, now)?;
+
+  let mut metar = Metar {
+    metar_id: 0,
+    icao_id,
+    receipt_time: report_time,
+    report_time,
+    temp: None,
+    dewp: None,
+    wdir: None,
+    wspd: None,
+    wgst: None,
+    raw_ob: raw.to_owned(),
+    visib: None,
+    altim: None,
+    clouds: None,
+    wx_string: None,
+  };
+
+  let mut clouds = vec![];
+  let mut phenomena = vec![];
+  // whole miles preceding a fraction, as in 1 1/2SM
+  let mut whole_miles = None;
+
+  for token in tokens {
+    if token == "RMK" || token == "TEMPO" || token == "BECMG" {
+      break;
+    }
+
+    if WHOLE_MILES.is_match(token) {
+      whole_miles = token.parse::<f64>().ok();
+      continue;
+    }
+    let whole = whole_miles.take().unwrap_or(0.0);
+
+    if let Some(caps) = WIND.captures(token) {
+      let factor = if &caps[4] == "MPS" { MPS_TO_KT } else { 1.0 };
+      let speed = |v: &str| v.parse::<f64>().ok().map(|v| (v * factor).round() as u64);
+      metar.wdir = Some(match &caps[1] {
+        "VRB" => WindDirection::Variable("VRB".to_owned()),
+        deg => WindDirection::Degree(deg.parse().ok()?),
+      });
+      metar.wspd = speed(&caps[2]);
+      metar.wgst = caps.get(3).and_then(|g| speed(g.as_str()));
+    } else if token == "CAVOK" {
+      metar.visib = Some(Visibility::Miles(UNLIMITED_VISIBILITY_SM));
+    } else if let Some(caps) = VISIBILITY_M.captures(token) {
+      if metar.visib.is_none() {
+        let meters: f64 = caps[1].parse().ok()?;
+        let miles = if meters >= 9999.0 {
+          UNLIMITED_VISIBILITY_SM
+        } else {
+          meters / METERS_PER_SM
+        };
+        metar.visib = Some(Visibility::Miles(miles));
+      }
+    } else if let Some(caps) = VISIBILITY_SM.captures(token) {
+      if let Some(miles) = parse_miles(&caps[2]) {
+        metar.visib = Some(Visibility::Miles(whole + miles));
+      }
+    } else if let Some(caps) = CLOUD.captures(token) {
+      clouds.push(Cloud {
+        cover: caps[1].to_owned(),
+        base: caps[2].parse::<u64>().ok().map(|hundreds| hundreds * 100),
+      });
+    } else if matches!(token, "SKC" | "CLR" | "NSC" | "NCD") {
+      clouds.push(Cloud {
+        cover: "CLR".to_owned(),
+        base: None,
+      });
+    } else if let Some(caps) = TEMPERATURE.captures(token) {
+      metar.temp = parse_temperature(&caps[1]);
+      metar.dewp = caps.get(2).and_then(|d| parse_temperature(d.as_str()));
+    } else if let Some(caps) = QNH.captures(token) {
+      let value: f64 = caps[2].parse().ok()?;
+      metar.altim = Some(match &caps[1] {
+        "Q" => value,
+        _ => (value / 100.0 * INHG_TO_HPA * 10.0).round() / 10.0,
+      });
+    } else if token.len() >= 2 && PHENOMENA.is_match(token) {
+      phenomena.push(token);
+    }
+  }
+
+  if !clouds.is_empty() {
+    metar.clouds = Some(clouds);
+  }
+  if !phenomena.is_empty() {
+    metar.wx_string = Some(phenomena.join(" "));
+  }
+  Some(metar)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_raw() {
+    let now = DateTime::parse_from_rfc3339("2024-03-02T12:00:00Z")
+      .unwrap()
+      .with_timezone(&Utc);
+
+    let metar = parse_raw(
+      "EGLL 021150Z AUTO 24015G25KT 9999 -RA BKN040 OVC060 08/M01 Q1012 NOSIG",
+      now,
+    )
+    .unwrap();
+    assert_eq!(metar.icao_id, "EGLL");
+    assert_eq!(
+      metar.report_time,
+      DateTime::parse_from_rfc3339("2024-03-02T11:50:00Z").unwrap()
+    );
+    assert_eq!(metar.wdir, Some(WindDirection::Degree(240)));
+    assert_eq!(metar.wspd, Some(15));
+    assert_eq!(metar.wgst, Some(25));
+    assert_eq!(metar.temp, Some(8.0));
+    assert_eq!(metar.dewp, Some(-1.0));
+    assert_eq!(metar.visibility_sm(), Some(10.0));
+    assert_eq!(metar.altim, Some(1012.0));
+    assert_eq!(metar.wx_string.as_deref(), Some("-RA"));
+    let clouds = metar.clouds.unwrap();
+    assert_eq!(clouds.len(), 2);
+    assert_eq!(ceiling(&clouds), Some(4000));
+
+    // the day is in the future, the report is from the previous month
+    let metar = parse_raw("METAR UUEE 282330Z VRB02MPS CAVOK M05/M07 Q1020", now).unwrap();
+    assert_eq!(
+      metar.report_time,
+      DateTime::parse_from_rfc3339("2024-02-28T23:30:00Z").unwrap()
+    );
+    assert_eq!(metar.wdir, Some(WindDirection::Variable("VRB".to_owned())));
+    assert_eq!(metar.wspd, Some(4));
+    assert_eq!(metar.temp, Some(-5.0));
+    assert_eq!(metar.visibility_sm(), Some(10.0));
+
+    let metar = parse_raw(
+      "KJFK 021151Z 04008KT 1 1/2SM BR FG VV004 03/02 A2992 RMK AO2 SLP132",
+      now,
+    )
+    .unwrap();
+    assert_eq!(metar.visibility_sm(), Some(1.5));
+    assert_eq!(metar.altim, Some(1013.2));
+    assert_eq!(metar.wx_string.as_deref(), Some("BR FG"));
+    assert_eq!(ceiling(&metar.clouds.unwrap()), Some(400));
+
+    assert!(parse_raw("No METAR available for XXXX", now).is_none());
+    assert!(parse_raw("", now).is_none());
+  }
+
+  #[test]
+  fn test_flight_category() {
+    assert_eq!(FlightCategory::compute(None, None), None);
+    assert_eq!(
+      FlightCategory::compute(None, Some(10.0)),
+      Some(FlightCategory::Vfr)
+    );
+    assert_eq!(
+      FlightCategory::compute(Some(3000), Some(10.0)),
+      Some(FlightCategory::Mvfr)
+    );
+    assert_eq!(
+      FlightCategory::compute(Some(5000), Some(2.0)),
+      Some(FlightCategory::Ifr)
+    );
+    assert_eq!(
+      FlightCategory::compute(Some(800), Some(0.5)),
+      Some(FlightCategory::Lifr)
+    );
+  }
+}
@@ -1,25 +1,38 @@
 mod ext_types;
+pub mod metar;
+pub mod provider;
+pub mod sigmet;
+pub mod winds;
 
 use std::{
-  collections::HashMap,
-  sync::atomic::{AtomicUsize, Ordering},
+  collections::{HashMap, HashSet},
+  error::Error,
+  path::Path,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+  },
 };
 
-use self::ext_types::{Metar, WindDirection};
-use crate::service::camden;
+use self::{
+  ext_types::{Cloud, Metar, WindDirection},
+  metar::{ceiling, FlightCategory},
+  provider::{build_provider, WeatherProvider},
+};
+use crate::{config, service::camden};
+use arc_swap::ArcSwap;
 use chrono::{DateTime, Duration, Utc};
+use futures_util::{stream, StreamExt};
 use log::{debug, error, info};
 use reqwest::Client;
-use serde::Serialize;
-use tokio::{
-  join,
-  sync::RwLock,
-  time::{sleep, Duration as TDuration},
-};
+use serde::{Deserialize, Serialize};
+use tokio::{join, sync::RwLock, time::sleep};
+use tracing::{info_span, Instrument};
 
-const BASE_API: &str = "https://aviationweather.gov/cgi-bin/data";
+// wind shift in degrees worth a notification
+const WIND_SHIFT_THRESHOLD: u64 = 30;
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WeatherInfo {
   pub temperature: Option<f64>,
   pub dew_point: Option<f64>,
@@ -28,11 +41,72 @@ pub struct WeatherInfo {
   pub wind_direction: Option<WindDirection>,
   pub raw: String,
   pub ts: DateTime<Utc>,
+  // statute miles
+  pub visibility: Option<f64>,
+  pub clouds: Vec<Cloud>,
+  // hPa
+  pub qnh: Option<f64>,
+  // present weather, e.g. "-RA BR"
+  pub weather: Option<String>,
+  pub flight_category: Option<FlightCategory>,
+}
+
+impl WeatherInfo {
+  // direction in degrees and speed in knots, unless the wind is variable
+  pub fn wind(&self) -> Option<(u64, u64)> {
+    match (&self.wind_direction, self.wind_speed) {
+      (Some(WindDirection::Degree(direction)), Some(speed)) => Some((*direction, speed)),
+      _ => None,
+    }
+  }
+
+  // Whether the change from the previous observation is worth drawing the
+  // user's attention to
+  pub fn significant_change(&self, prev: &WeatherInfo) -> bool {
+    if self.flight_category != prev.flight_category {
+      return true;
+    }
+    match (&self.wind_direction, &prev.wind_direction) {
+      (Some(WindDirection::Degree(cur)), Some(WindDirection::Degree(prev))) => {
+        let shift = cur.abs_diff(*prev) % 360;
+        shift.min(360 - shift) > WIND_SHIFT_THRESHOLD
+      }
+      _ => false,
+    }
+  }
+}
+
+// A refreshed METAR of an airport visible on the map
+#[derive(Debug, Clone)]
+pub struct WeatherChange {
+  // compound id of the airport
+  pub airport_id: String,
+  pub icao: String,
+  pub wx: WeatherInfo,
+  pub significant: bool,
+}
+
+impl From<WeatherChange> for camden::WeatherChange {
+  fn from(value: WeatherChange) -> Self {
+    Self {
+      icao: value.icao,
+      wx: Some(value.wx.into()),
+      significant_change: value.significant,
+    }
+  }
 }
 
 impl From<Metar> for WeatherInfo {
   fn from(value: Metar) -> Self {
+    let visibility = value.visibility_sm();
+    let clouds = value.clouds.unwrap_or_default();
+    let flight_category = FlightCategory::compute(ceiling(&clouds), visibility);
     Self {
+      visibility,
+      clouds,
+      qnh: value.altim,
+      weather: value.wx_string,
+      flight_category,
       temperature: value.temp,
       dew_point: value.dewp,
       wind_speed: value.wspd,
@@ -54,6 +128,14 @@ impl From<WeatherInfo> for camden::WeatherInfo {
       raw: value.raw,
       ts: value.ts.timestamp_millis() as u64,
       wind_direction: value.wind_direction.map(|v| v.into()),
+      visibility: value.visibility,
+      clouds: value.clouds.into_iter().map(|c| c.into()).collect(),
+      qnh: value.qnh,
+      weather: value.weather,
+      flight_category: value
+        .flight_category
+        .map(|fc| camden::FlightCategory::from(fc) as i32)
+        .unwrap_or_default(),
     }
   }
 }
@@ -65,71 +147,230 @@ struct BlackListItem {
 }
 
 impl BlackListItem {
-  pub fn new() -> Self {
+  pub fn new(duration: Duration) -> Self {
     Self {
       set_at: Utc::now(),
-      duration: Duration::seconds(3600),
+      duration,
     }
   }
 
-  pub fn double(&self) -> Self {
+  pub fn double(&self, max: Duration) -> Self {
     Self {
       set_at: Utc::now(),
-      duration: self.duration * 2,
+      duration: (self.duration * 2).min(max),
     }
   }
 
+  pub fn expires_at(&self) -> DateTime<Utc> {
+    self.set_at + self.duration
+  }
+
   pub fn expired(&self) -> bool {
-    let now = Utc::now();
-    now > self.set_at + self.duration
+    Utc::now() > self.expires_at()
+  }
+
+  // An entry not renewed for blacklist_max after expiring is forgotten,
+  // so that a location failing again starts over with the initial duration
+  pub fn forgotten(&self, now: DateTime<Utc>, max: Duration) -> bool {
+    now > self.expires_at() + max
+  }
+}
+
+// A location no weather is requested for until expires_at
+#[derive(Debug, Clone)]
+pub struct BlacklistEntry {
+  pub icao: String,
+  pub blacklisted_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl From<BlacklistEntry> for camden::WeatherBlacklistEntry {
+  fn from(value: BlacklistEntry) -> Self {
+    Self {
+      icao: value.icao,
+      blacklisted_at: value.blacklisted_at.timestamp_millis() as u64,
+      expires_at: value.expires_at.timestamp_millis() as u64,
+    }
   }
 }
 
 #[derive(Debug)]
 pub struct WeatherManager {
-  metar_ttl: Duration,
+  // replaced on config reload, providers and blacklist periods are kept
+  cfg: ArcSwap<config::Weather>,
+  cache_path: String,
+  blacklist_initial: Duration,
+  blacklist_max: Duration,
+  client: Client,
+  providers: Vec<Box<dyn WeatherProvider>>,
+  // fetch errors by provider, in the same order as providers
+  provider_errors: Vec<AtomicU64>,
   cache: RwLock<HashMap<String, WeatherInfo>>,
+  // locations requested on demand, waiting to be loaded in one batch
+  requested: RwLock<HashSet<String>>,
   blacklist: RwLock<HashMap<String, BlackListItem>>,
   apireq_num: AtomicUsize,
 }
 
 impl WeatherManager {
-  pub fn new(metar_ttl: Duration) -> Self {
+  pub fn new(cfg: config::Weather, cache_path: &str, client: Client) -> Self {
+    let providers: Vec<_> = cfg.providers().iter().map(build_provider).collect();
+    let blacklist_max = Duration::from_std(cfg.blacklist_max).unwrap_or(Duration::days(1));
+    let blacklist_initial = Duration::from_std(cfg.blacklist_initial).unwrap_or(Duration::hours(1));
     Self {
-      metar_ttl,
+      cache_path: cache_path.to_owned(),
+      client,
+      provider_errors: providers.iter().map(|_| AtomicU64::new(0)).collect(),
+      providers,
+      blacklist_initial: blacklist_initial.min(blacklist_max),
+      blacklist_max,
+      cfg: ArcSwap::from_pointee(cfg),
       cache: Default::default(),
+      requested: Default::default(),
       blacklist: Default::default(),
       apireq_num: AtomicUsize::new(0),
     }
   }
 
+  // Drops all the cached weather and blacklisted locations,
+  // returns the number of cached entries removed
+  pub async fn flush(&self) -> usize {
+    let mut cache = self.cache.write().await;
+    let count = cache.len();
+    cache.clear();
+    self.blacklist.write().await.clear();
+    count
+  }
+
+  // Restores the cache stored by save_cache skipping the expired entries,
+  // returns the number of entries restored
+  pub async fn load_cache(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    if !Path::new(&self.cache_path).is_file() {
+      return Ok(0);
+    }
+    let raw = std::fs::read_to_string(&self.cache_path)?;
+    let stored: HashMap<String, WeatherInfo> = serde_json::from_str(&raw)?;
+    let now = Utc::now();
+    let mut cache = self.cache.write().await;
+    let mut count = 0;
+    for (location, wx) in stored {
+      if now - wx.ts < self.ttl() {
+        cache.insert(location, wx);
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  // Stores the cache on disk, returns the number of entries stored
+  pub async fn save_cache(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let (raw, count) = {
+      let cache = self.cache.read().await;
+      (serde_json::to_string(&*cache)?, cache.len())
+    };
+    // written aside and renamed so that a crash never leaves a partial file
+    let tmp = format!("{}.tmp", self.cache_path);
+    std::fs::write(&tmp, raw)?;
+    std::fs::rename(&tmp, &self.cache_path)?;
+    Ok(count)
+  }
+
+  pub fn ttl(&self) -> Duration {
+    Duration::from_std(self.cfg.load().ttl).unwrap_or(Duration::minutes(30))
+  }
+
+  // ttl, refresh interval and batching take effect right away
+  pub fn reload(&self, cfg: config::Weather) {
+    self.cfg.store(Arc::new(cfg));
+  }
+
+  // Queues the locations to be loaded with the next batch
+  pub async fn request(&self, locations: impl IntoIterator<Item = String>) {
+    self.requested.write().await.extend(locations);
+  }
+
+  pub async fn take_requested(&self) -> Vec<String> {
+    let mut requested = self.requested.write().await;
+    std::mem::take(&mut *requested).into_iter().collect()
+  }
+
   pub fn request_num(&self) -> usize {
     self.apireq_num.load(Ordering::Relaxed)
   }
 
+  pub fn provider_errors(&self) -> Vec<(&str, u64)> {
+    self
+      .providers
+      .iter()
+      .zip(self.provider_errors.iter())
+      .map(|(provider, errors)| (provider.name(), errors.load(Ordering::Relaxed)))
+      .collect()
+  }
+
+  // Locations currently blacklisted, the expired entries are not listed
+  pub async fn blacklist(&self) -> Vec<BlacklistEntry> {
+    let blacklist = self.blacklist.read().await;
+    let mut entries: Vec<BlacklistEntry> = blacklist
+      .iter()
+      .filter(|(_, item)| !item.expired())
+      .map(|(icao, item)| BlacklistEntry {
+        icao: icao.clone(),
+        blacklisted_at: item.set_at,
+        expires_at: item.expires_at(),
+      })
+      .collect();
+    entries.sort_by(|a, b| a.icao.cmp(&b.icao));
+    entries
+  }
+
+  pub async fn blacklist_size(&self) -> usize {
+    let blacklist = self.blacklist.read().await;
+    blacklist.values().filter(|item| !item.expired()).count()
+  }
+
+  // Removes the locations from the blacklist, returns the number of
+  // entries removed
+  pub async fn unblacklist<'a>(&self, locations: impl IntoIterator<Item = &'a str>) -> usize {
+    let mut blacklist = self.blacklist.write().await;
+    let mut count = 0;
+    for location in locations {
+      if blacklist.remove(location).is_some() {
+        info!("location {location} removed from the blacklist");
+        count += 1;
+      }
+    }
+    count
+  }
+
+  async fn prune_blacklist(&self) {
+    let now = Utc::now();
+    let mut blacklist = self.blacklist.write().await;
+    blacklist.retain(|_, item| !item.forgotten(now, self.blacklist_max));
+  }
+
   async fn has_valid_cache_for(&self, location: &str) -> bool {
     let cache = self.cache.read().await;
     let value = cache.get(location);
     if let Some(value) = value {
       let now = Utc::now();
       let delta = now - value.ts;
-      delta < self.metar_ttl
+      delta < self.ttl()
     } else {
       false
     }
   }
 
   pub async fn run(&self) {
-    let sleep_time = TDuration::from_secs(300);
     info!("starting weather update loop");
     loop {
       let expired = {
         let cache = self.cache.read().await;
         let mut expired = vec![];
         let now = Utc::now();
+        let ttl = self.ttl();
         for (key, wx) in cache.iter() {
           let delta = now - wx.ts;
-          if delta >= self.metar_ttl {
+          if delta >= ttl {
             expired.push(key.clone());
           }
         }
@@ -145,6 +386,13 @@ impl WeatherManager {
         self.preload(locations).await;
       }
 
+      self.prune_blacklist().await;
+      match self.save_cache().await {
+        Ok(count) => debug!("{count} weather entries stored in {}", self.cache_path),
+        Err(err) => error!("error storing weather cache: {err}"),
+      }
+
+      let sleep_time = self.cfg.load().refresh_interval;
       sleep(sleep_time).await;
     }
   }
@@ -180,32 +428,54 @@ impl WeatherManager {
       return;
     }
 
-    let locations = locations.join(",");
-    info!("preloading weather for {locations}");
-
-    let path = format!("{BASE_API}/metar.php?ids={locations}&format=json");
-    let client = Client::new();
-
-    self.inc_apireq();
-    let res = client.get(path).send().await;
-
-    if let Err(err) = res {
-      error!("error loading wx data: {err}");
-      return;
+    let cfg = self.cfg.load_full();
+    let chunks: Vec<String> = locations
+      .chunks(cfg.max_batch_size.max(1))
+      .map(|chunk| chunk.join(","))
+      .collect();
+    let mut fetches = stream::iter(chunks)
+      .map(|chunk| self.fetch_chunk(chunk))
+      .buffer_unordered(cfg.max_concurrency.max(1));
+
+    while let Some(metars) = fetches.next().await {
+      let mut cache = self.cache.write().await;
+      for metar in metars {
+        let loc = metar.icao_id.clone();
+        cache.insert(loc, metar.into());
+      }
     }
+  }
 
-    let res = res.unwrap().json::<Vec<Metar>>().await;
-    if let Err(err) = res {
-      error!("error parsing wx data: {err}");
-      return;
-    }
+  // locations are comma separated
+  async fn fetch_chunk(&self, locations: String) -> Vec<Metar> {
+    info!("preloading weather for {locations}");
+    self.fetch(&locations).await.unwrap_or_default()
+  }
 
-    let metars = res.unwrap();
-    let mut cache = self.cache.write().await;
-    for metar in metars {
-      let loc = metar.icao_id.clone();
-      cache.insert(loc, metar.into());
+  // Tries the providers in order, returns None if all of them have failed
+  async fn fetch(&self, locations: &str) -> Option<Vec<Metar>> {
+    for (provider, errors) in self.providers.iter().zip(self.provider_errors.iter()) {
+      self.inc_apireq();
+      let res = provider
+        .fetch(&self.client, locations)
+        .instrument(info_span!(
+          "weather_fetch",
+          provider = provider.name(),
+          locations
+        ))
+        .await;
+      match res {
+        Ok(metars) => return Some(metars),
+        Err(err) => {
+          errors.fetch_add(1, Ordering::Relaxed);
+          error!(
+            "[{}] error loading {locations} wx data: {err}",
+            provider.name()
+          );
+        }
+      }
     }
+    None
   }
 
   async fn get_cache(&self, location: &str) -> Option<WeatherInfo> {
@@ -216,7 +486,7 @@ impl WeatherManager {
     };
     let now = Utc::now();
     let delta = now - value.ts;
-    if delta > self.metar_ttl {
+    if delta > self.ttl() {
       None
     } else {
       Some(value)
@@ -232,24 +502,7 @@ impl WeatherManager {
 
     info!("collecting weather for {location} from remote api");
 
-    let path = format!("{BASE_API}/metar.php?ids={location}&format=json");
-    let client = Client::new();
-
-    self.inc_apireq();
-    let res = client.get(path).send().await;
-
-    if let Err(err) = res {
-      error!("error loading {location} wx data: {err}");
-      return None;
-    }
-
-    let metar = res.unwrap().json::<Vec<Metar>>().await;
-    if let Err(err) = metar {
-      error!("error parsing {location} wx data: {err}");
-      return None;
-    }
-
-    let metar = metar.unwrap().get(0).cloned();
+    let metar = self.fetch(location).await?.first().cloned();
     if let Some(metar) = metar {
       Some(metar.into())
     } else {
@@ -258,8 +511,8 @@ impl WeatherManager {
 
       let blitem = blacklist.get(location);
       let blitem = match blitem {
-        Some(blitem) => blitem.double(),
-        None => BlackListItem::new(),
+        Some(blitem) => blitem.double(self.blacklist_max),
+        None => BlackListItem::new(self.blacklist_initial),
       };
       debug!("blacklisting {location} for {}", blitem.duration);
       blacklist.insert(location.to_owned(), blitem);
@@ -283,3 +536,133 @@ impl WeatherManager {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn wx(raw: &str, ts: DateTime<Utc>) -> WeatherInfo {
+    WeatherInfo {
+      temperature: Some(10.0),
+      dew_point: None,
+      wind_speed: Some(5),
+      wind_gust: None,
+      wind_direction: Some(WindDirection::Variable("VRB".to_owned())),
+      raw: raw.to_owned(),
+      ts,
+      visibility: Some(10.0),
+      clouds: vec![],
+      qnh: Some(1013.0),
+      weather: None,
+      flight_category: Some(FlightCategory::Vfr),
+    }
+  }
+
+  #[test]
+  fn test_significant_change() {
+    let now = Utc::now();
+    let prev = WeatherInfo {
+      wind_direction: Some(WindDirection::Degree(350)),
+      ..wx("EGLL 021150Z", now)
+    };
+
+    let cur = WeatherInfo {
+      wind_direction: Some(WindDirection::Degree(10)),
+      ..wx("EGLL 021220Z", now)
+    };
+    assert!(!cur.significant_change(&prev));
+
+    let cur = WeatherInfo {
+      wind_direction: Some(WindDirection::Degree(30)),
+      ..wx("EGLL 021220Z", now)
+    };
+    assert!(cur.significant_change(&prev));
+
+    let cur = WeatherInfo {
+      wind_direction: Some(WindDirection::Degree(350)),
+      flight_category: Some(FlightCategory::Ifr),
+      ..wx("EGLL 021220Z", now)
+    };
+    assert!(cur.significant_change(&prev));
+
+    // variable wind can't be compared
+    assert!(!wx("EGLL 021220Z", now).significant_change(&prev));
+  }
+
+  #[tokio::test]
+  async fn test_cache_persistence() {
+    let path = std::env::temp_dir().join(format!("weather-cache-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let cfg = config::Weather::default();
+
+    let manager = WeatherManager::new(cfg.clone(), path, Client::new());
+    assert_eq!(manager.load_cache().await.unwrap(), 0);
+    {
+      let mut cache = manager.cache.write().await;
+      cache.insert("EGLL".into(), wx("EGLL 021150Z", Utc::now()));
+      cache.insert(
+        "LFPG".into(),
+        wx("LFPG 021150Z", Utc::now() - Duration::hours(2)),
+      );
+    }
+    assert_eq!(manager.save_cache().await.unwrap(), 2);
+
+    // the expired entry is not restored
+    let manager = WeatherManager::new(cfg, path, Client::new());
+    assert_eq!(manager.load_cache().await.unwrap(), 1);
+    let restored = manager.get_cache("EGLL").await.unwrap();
+    assert_eq!(restored.raw, "EGLL 021150Z");
+    assert_eq!(restored.flight_category, Some(FlightCategory::Vfr));
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_blacklist() {
+    let cfg = config::Weather::default();
+    let manager = WeatherManager::new(cfg, "/nonexistent", Client::new());
+    let now = Utc::now();
+    {
+      let mut blacklist = manager.blacklist.write().await;
+      blacklist.insert("EGLL".into(), BlackListItem::new(Duration::hours(1)));
+      blacklist.insert("LFPG".into(), BlackListItem::new(Duration::hours(2)));
+      // expired, but not forgotten yet
+      blacklist.insert(
+        "EDDF".into(),
+        BlackListItem {
+          set_at: now - Duration::hours(2),
+          duration: Duration::hours(1),
+        },
+      );
+      blacklist.insert(
+        "LEMD".into(),
+        BlackListItem {
+          set_at: now - Duration::days(3),
+          duration: Duration::hours(1),
+        },
+      );
+    }
+
+    let entries = manager.blacklist().await;
+    let icaos: Vec<&str> = entries.iter().map(|e| e.icao.as_str()).collect();
+    assert_eq!(icaos, vec!["EGLL", "LFPG"]);
+    assert_eq!(manager.blacklist_size().await, 2);
+
+    manager.prune_blacklist().await;
+    assert_eq!(manager.blacklist.read().await.len(), 3);
+
+    assert_eq!(manager.unblacklist(["EGLL", "KJFK"]).await, 1);
+    assert!(!manager.is_blacklisted("EGLL").await);
+    assert!(manager.is_blacklisted("LFPG").await);
+  }
+
+  #[test]
+  fn test_blacklist_double() {
+    let max = Duration::hours(6);
+    let mut item = BlackListItem::new(Duration::hours(1));
+    for expected in [2, 4, 6, 6] {
+      item = item.double(max);
+      assert_eq!(item.duration, Duration::hours(expected));
+    }
+  }
+}
@@ -0,0 +1,80 @@
+use super::{ext_types::Metar, metar::parse_raw};
+use crate::config::{WeatherProviderCfg, WeatherProviderKind};
+use chrono::Utc;
+use reqwest::Client;
+use std::{error::Error, fmt::Debug};
+
+pub type ProviderResult = Result<Vec<Metar>, Box<dyn Error + Send + Sync>>;
+
+#[tonic::async_trait]
+pub trait WeatherProvider: Debug + Send + Sync {
+  // provider name used in logs and metrics
+  fn name(&self) -> &str;
+  // locations are comma separated icao codes, the ones with no metar
+  // available are missing from the result
+  async fn fetch(&self, client: &Client, locations: &str) -> ProviderResult;
+}
+
+// aviationweather.gov data API
+#[derive(Debug)]
+pub struct AviationWeather {
+  base_url: String,
+}
+
+impl AviationWeather {
+  pub fn new(base_url: &str) -> Self {
+    Self {
+      base_url: base_url.trim_end_matches('/').to_owned(),
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl WeatherProvider for AviationWeather {
+  fn name(&self) -> &str {
+    "aviationweather"
+  }
+
+  async fn fetch(&self, client: &Client, locations: &str) -> ProviderResult {
+    let path = format!("{}/metar.php?ids={locations}&format=json", self.base_url);
+    let response = client.get(path).send().await?.error_for_status()?;
+    let metars = response.json::<Vec<Metar>>().await?;
+    Ok(metars)
+  }
+}
+
+// VATSIM metar service, responds with raw metars one per line
+#[derive(Debug)]
+pub struct VatsimMetar {
+  url: String,
+}
+
+impl VatsimMetar {
+  pub fn new(url: &str) -> Self {
+    Self {
+      url: url.to_owned(),
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl WeatherProvider for VatsimMetar {
+  fn name(&self) -> &str {
+    "vatsim"
+  }
+
+  async fn fetch(&self, client: &Client, locations: &str) -> ProviderResult {
+    let path = format!("{}?id={locations}", self.url);
+    let response = client.get(path).send().await?.error_for_status()?;
+    let text = response.text().await?;
+    let now = Utc::now();
+    Ok(text.lines().filter_map(|raw| parse_raw(raw, now)).collect())
+  }
+}
+
+pub fn build_provider(cfg: &WeatherProviderCfg) -> Box<dyn WeatherProvider> {
+  match cfg.kind {
+    WeatherProviderKind::AviationWeather => Box::new(AviationWeather::new(&cfg.url)),
+    WeatherProviderKind::Vatsim => Box::new(VatsimMetar::new(&cfg.url)),
+  }
+}
@@ -0,0 +1,191 @@
+use crate::{
+  service::camden,
+  types::{Point, Rect},
+};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use std::error::Error;
+
+#[derive(Debug, Deserialize)]
+struct Coord {
+  lat: f64,
+  lon: f64,
+}
+
+// International SIGMET as served by the aviationweather.gov data API
+#[derive(Debug, Deserialize)]
+struct ISigmet {
+  #[serde(rename(deserialize = "firId"))]
+  fir_id: String,
+  #[serde(default, rename(deserialize = "seriesId"))]
+  series_id: Option<String>,
+  #[serde(rename(deserialize = "validTimeFrom"))]
+  valid_time_from: i64,
+  #[serde(rename(deserialize = "validTimeTo"))]
+  valid_time_to: i64,
+  #[serde(default)]
+  hazard: Option<String>,
+  #[serde(default)]
+  qualifier: Option<String>,
+  #[serde(default)]
+  base: Option<i32>,
+  #[serde(default)]
+  top: Option<i32>,
+  #[serde(default, rename(deserialize = "rawSigmet"))]
+  raw_sigmet: String,
+  #[serde(default)]
+  coords: Vec<Coord>,
+}
+
+// A hazard area drawn on the map: turbulence, icing, thunderstorms,
+// volcanic ash and so on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hazard {
+  pub id: String,
+  pub fir: String,
+  pub hazard: String,
+  pub qualifier: String,
+  // feet
+  pub base: Option<i32>,
+  pub top: Option<i32>,
+  pub valid_from: DateTime<Utc>,
+  pub valid_to: DateTime<Utc>,
+  pub raw: String,
+  pub polygon: Vec<Point>,
+}
+
+impl Hazard {
+  pub fn bounds(&self) -> Rect {
+    let mut rect = Rect::new(f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for p in self.polygon.iter() {
+      rect.south_west.lat = rect.south_west.lat.min(p.lat);
+      rect.south_west.lng = rect.south_west.lng.min(p.lng);
+      rect.north_east.lat = rect.north_east.lat.max(p.lat);
+      rect.north_east.lng = rect.north_east.lng.max(p.lng);
+    }
+    rect
+  }
+
+  pub fn expired(&self, now: DateTime<Utc>) -> bool {
+    self.valid_to <= now
+  }
+}
+
+impl TryFrom<ISigmet> for Hazard {
+  type Error = ();
+
+  // SIGMETs with no area can't be drawn and are skipped
+  fn try_from(value: ISigmet) -> Result<Self, Self::Error> {
+    if value.coords.is_empty() {
+      return Err(());
+    }
+    let valid_from = DateTime::from_timestamp(value.valid_time_from, 0).ok_or(())?;
+    let valid_to = DateTime::from_timestamp(value.valid_time_to, 0).ok_or(())?;
+    let series = value.series_id.unwrap_or_default();
+    Ok(Self {
+      id: format!("{}:{}:{}", value.fir_id, series, value.valid_time_from),
+      fir: value.fir_id,
+      hazard: value.hazard.unwrap_or_default(),
+      qualifier: value.qualifier.unwrap_or_default(),
+      base: value.base,
+      top: value.top,
+      valid_from,
+      valid_to,
+      raw: value.raw_sigmet,
+      polygon: value
+        .coords
+        .into_iter()
+        .map(|c| Point {
+          lat: c.lat,
+          lng: c.lon,
+        })
+        .collect(),
+    })
+  }
+}
+
+impl From<Hazard> for camden::Hazard {
+  fn from(value: Hazard) -> Self {
+    Self {
+      id: value.id,
+      fir: value.fir,
+      hazard: value.hazard,
+      qualifier: value.qualifier,
+      base: value.base,
+      top: value.top,
+      valid_from: value.valid_from.timestamp_millis() as u64,
+      valid_to: value.valid_to.timestamp_millis() as u64,
+      raw: value.raw,
+      polygon: value.polygon.into_iter().map(|p| p.into()).collect(),
+    }
+  }
+}
+
+pub fn parse_hazards(raw: &str) -> Result<Vec<Hazard>, serde_json::Error> {
+  let sigmets: Vec<ISigmet> = serde_json::from_str(raw)?;
+  Ok(
+    sigmets
+      .into_iter()
+      .filter_map(|s| Hazard::try_from(s).ok())
+      .collect(),
+  )
+}
+
+pub async fn fetch_hazards(
+  client: &Client,
+  url: &str,
+) -> Result<Vec<Hazard>, Box<dyn Error + Send + Sync>> {
+  let response = client.get(url).send().await?.error_for_status()?;
+  let raw = response.text().await?;
+  Ok(parse_hazards(&raw)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_hazards() {
+    let raw = r#"[
+      {
+        "icaoId": "EGRR", "firId": "EGTT", "firName": "LONDON", "receiptTime": "2024-03-02 11:50:00",
+        "validTimeFrom": 1709380800, "validTimeTo": 1709395200, "seriesId": "3",
+        "hazard": "TURB", "qualifier": "SEV", "base": 25000, "top": 38000, "geom": "AREA",
+        "coords": [{"lat": 50.0, "lon": -2.0}, {"lat": 52.5, "lon": 1.0}, {"lat": 49.0, "lon": 1.5}],
+        "rawSigmet": "EGTT SIGMET 3 VALID 021200/021600 EGRR- SEV TURB"
+      },
+      {
+        "icaoId": "RJTD", "firId": "RJJJ", "validTimeFrom": 1709380800, "validTimeTo": 1709395200,
+        "hazard": "TC", "coords": [], "rawSigmet": "RJJJ SIGMET"
+      }
+    ]"#;
+    let hazards = parse_hazards(raw).unwrap();
+    assert_eq!(hazards.len(), 1);
+
+    let hazard = &hazards[0];
+    assert_eq!(hazard.id, "EGTT:3:1709380800");
+    assert_eq!(hazard.hazard, "TURB");
+    assert_eq!(hazard.top, Some(38000));
+    assert_eq!(hazard.polygon.len(), 3);
+
+    let bounds = hazard.bounds();
+    assert_eq!(
+      bounds.south_west,
+      Point {
+        lat: 49.0,
+        lng: -2.0
+      }
+    );
+    assert_eq!(
+      bounds.north_east,
+      Point {
+        lat: 52.5,
+        lng: 1.5
+      }
+    );
+
+    assert!(!hazard.expired(hazard.valid_from));
+    assert!(hazard.expired(hazard.valid_to));
+  }
+}
@@ -0,0 +1,325 @@
+use crate::{config, service::camden, types::Rect};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info};
+use reqwest::Client;
+use serde::Deserialize;
+use std::{
+  collections::{BTreeSet, HashMap},
+  error::Error,
+};
+use tokio::sync::RwLock;
+
+// Flight levels and the GFS pressure levels at the same altitude in the
+// standard atmosphere
+const LEVELS: [(u32, u32); 8] = [
+  (50, 850),
+  (100, 700),
+  (180, 500),
+  (240, 400),
+  (300, 300),
+  (340, 250),
+  (390, 200),
+  (450, 150),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Cell {
+  level: u32,
+  lat: i32,
+  lng: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindVector {
+  pub lat: f64,
+  pub lng: f64,
+  // degrees true the wind blows from
+  pub direction: u32,
+  // knots
+  pub speed: f64,
+}
+
+#[derive(Debug)]
+struct CachedWind {
+  wind: WindVector,
+  fetched_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct Forecast {
+  current: HashMap<String, serde_json::Value>,
+}
+
+// The API responds with a single object if a single location is requested
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Forecasts {
+  Many(Vec<Forecast>),
+  One(Forecast),
+}
+
+// The pressure level closest to the flight level, hPa
+pub fn pressure_level(fl: u32) -> u32 {
+  LEVELS
+    .iter()
+    .min_by_key(|(level_fl, _)| level_fl.abs_diff(fl))
+    .map(|(_, hpa)| *hpa)
+    .unwrap()
+}
+
+fn grid_range(min: f64, max: f64, step: f64) -> std::ops::RangeInclusive<i32> {
+  (min / step).ceil() as i32..=(max / step).floor() as i32
+}
+
+// Envelopes split at the antimeridian stop just short of it
+fn antimeridian(lng: f64) -> f64 {
+  if lng.abs() > 179.999 {
+    180.0f64.copysign(lng)
+  } else {
+    lng
+  }
+}
+
+// Grid nodes within the rect as (lat, lng) indices. If there are more than
+// max_points nodes, every n-th one is taken in both directions so that the
+// coarser grid shares the nodes with the finer one
+fn grid_cells(rect: &Rect, step: f64, max_points: usize) -> Vec<(i32, i32)> {
+  let lat_range = grid_range(rect.south_west.lat, rect.north_east.lat, step);
+  let lng_ranges: Vec<_> = rect
+    .envelopes()
+    .iter()
+    .map(|env| {
+      grid_range(
+        antimeridian(env.lower().lng),
+        antimeridian(env.upper().lng),
+        step,
+      )
+    })
+    .collect();
+
+  let lat_count = lat_range.clone().count();
+  let lng_count: usize = lng_ranges.iter().map(|r| r.clone().count()).sum();
+  let total = lat_count * lng_count;
+  let stride = if total > max_points && max_points > 0 {
+    ((total as f64 / max_points as f64).sqrt().ceil() as i32).max(1)
+  } else {
+    1
+  };
+
+  let max_lng = (180.0 / step).round() as i32;
+  let mut cells = BTreeSet::new();
+  for lat in lat_range.filter(|lat| lat % stride == 0) {
+    for lng_range in lng_ranges.iter() {
+      for lng in lng_range.clone().filter(|lng| lng % stride == 0) {
+        // 180 and -180 are the same meridian
+        let lng = if lng == max_lng { -max_lng } else { lng };
+        cells.insert((lat, lng));
+      }
+    }
+  }
+  cells.into_iter().collect()
+}
+
+// Wind direction and speed at the level for every forecast, in the order
+// the locations were requested
+fn parse_forecasts(raw: &str, level: u32) -> Result<Vec<Option<(u32, f64)>>, serde_json::Error> {
+  let forecasts = match serde_json::from_str::<Forecasts>(raw)? {
+    Forecasts::Many(forecasts) => forecasts,
+    Forecasts::One(forecast) => vec![forecast],
+  };
+  let speed_key = format!("wind_speed_{level}hPa");
+  let direction_key = format!("wind_direction_{level}hPa");
+  Ok(
+    forecasts
+      .iter()
+      .map(|f| {
+        let speed = f.current.get(&speed_key)?.as_f64()?;
+        let direction = f.current.get(&direction_key)?.as_f64()?;
+        Some((direction.round() as u32 % 360, speed))
+      })
+      .collect(),
+  )
+}
+
+// GFS winds aloft on a regular grid, fetched from the Open-Meteo API
+// on demand and cached per grid node and pressure level
+#[derive(Debug)]
+pub struct WindsAloft {
+  cfg: config::Winds,
+  client: Client,
+  cache: RwLock<HashMap<Cell, CachedWind>>,
+}
+
+impl WindsAloft {
+  pub fn new(cfg: config::Winds, client: Client) -> Self {
+    Self {
+      cfg,
+      client,
+      cache: Default::default(),
+    }
+  }
+
+  // Winds within the rect at the pressure level closest to the flight
+  // level, returns the level along with the wind vectors
+  pub async fn get(
+    &self,
+    rect: &Rect,
+    fl: u32,
+  ) -> Result<(u32, Vec<WindVector>), Box<dyn Error + Send + Sync>> {
+    let level = pressure_level(fl);
+    let step = self.cfg.grid_step;
+    let cells: Vec<Cell> = grid_cells(rect, step, self.cfg.max_points)
+      .into_iter()
+      .map(|(lat, lng)| Cell { level, lat, lng })
+      .collect();
+
+    let ttl = Duration::from_std(self.cfg.ttl).unwrap_or(Duration::hours(1));
+    let missing: Vec<Cell> = {
+      let now = Utc::now();
+      let cache = self.cache.read().await;
+      cells
+        .iter()
+        .filter(|cell| cache.get(cell).is_none_or(|c| now - c.fetched_at >= ttl))
+        .copied()
+        .collect()
+    };
+
+    if !missing.is_empty() {
+      debug!(
+        "fetching winds aloft for {} grid nodes at {level}hPa",
+        missing.len()
+      );
+      for batch in missing.chunks(self.cfg.batch_size.max(1)) {
+        let winds = self.fetch(level, batch).await?;
+        let now = Utc::now();
+        let mut cache = self.cache.write().await;
+        for (cell, wind) in batch.iter().zip(winds) {
+          if let Some(wind) = wind {
+            cache.insert(
+              *cell,
+              CachedWind {
+                wind,
+                fetched_at: now,
+              },
+            );
+          }
+        }
+      }
+    }
+
+    let cache = self.cache.read().await;
+    let winds = cells
+      .iter()
+      .filter_map(|cell| cache.get(cell).map(|c| c.wind))
+      .collect();
+    Ok((level, winds))
+  }
+
+  async fn fetch(
+    &self,
+    level: u32,
+    cells: &[Cell],
+  ) -> Result<Vec<Option<WindVector>>, Box<dyn Error + Send + Sync>> {
+    let step = self.cfg.grid_step;
+    let position = |cell: &Cell| (cell.lat as f64 * step, cell.lng as f64 * step);
+    let lats: Vec<String> = cells.iter().map(|c| position(c).0.to_string()).collect();
+    let lngs: Vec<String> = cells.iter().map(|c| position(c).1.to_string()).collect();
+
+    info!("loading winds aloft for {} grid nodes", cells.len());
+    let response = self
+      .client
+      .get(&self.cfg.url)
+      .query(&[
+        ("latitude", lats.join(",")),
+        ("longitude", lngs.join(",")),
+        (
+          "current",
+          format!("wind_speed_{level}hPa,wind_direction_{level}hPa"),
+        ),
+        ("wind_speed_unit", "kn".to_owned()),
+      ])
+      .send()
+      .await?
+      .error_for_status()?;
+    let raw = response.text().await?;
+    let winds = parse_forecasts(&raw, level)?;
+    if winds.len() != cells.len() {
+      return Err(format!("{} forecasts for {} locations", winds.len(), cells.len()).into());
+    }
+
+    Ok(
+      cells
+        .iter()
+        .zip(winds)
+        .map(|(cell, wind)| {
+          let (lat, lng) = position(cell);
+          wind.map(|(direction, speed)| WindVector {
+            lat,
+            lng,
+            direction,
+            speed,
+          })
+        })
+        .collect(),
+    )
+  }
+}
+
+impl From<WindVector> for camden::WindVector {
+  fn from(value: WindVector) -> Self {
+    Self {
+      position: Some(camden::Point {
+        lat: value.lat,
+        lng: value.lng,
+      }),
+      direction: value.direction,
+      speed: value.speed,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pressure_level() {
+    assert_eq!(pressure_level(0), 850);
+    assert_eq!(pressure_level(180), 500);
+    assert_eq!(pressure_level(350), 250);
+    assert_eq!(pressure_level(600), 150);
+  }
+
+  #[test]
+  fn test_grid_cells() {
+    let rect = Rect::new(-1.0, 49.0, 11.0, 56.0);
+    let cells = grid_cells(&rect, 5.0, 100);
+    assert_eq!(
+      cells,
+      vec![(10, 0), (10, 1), (10, 2), (11, 0), (11, 1), (11, 2)]
+    );
+
+    // every other node is taken
+    let cells = grid_cells(&rect, 5.0, 2);
+    assert_eq!(cells, vec![(10, 0), (10, 2)]);
+
+    // across the antimeridian, 180 is folded into -180
+    let rect = Rect::new(170.0, 0.0, -170.0, 1.0);
+    let cells = grid_cells(&rect, 5.0, 100);
+    assert_eq!(cells, vec![(0, -36), (0, -35), (0, -34), (0, 34), (0, 35)]);
+  }
+
+  #[test]
+  fn test_parse_forecasts() {
+    let raw = r#"[
+      {"latitude": 50.0, "longitude": 0.0, "current": {"time": "2024-03-02T12:00", "wind_speed_250hPa": 95.3, "wind_direction_250hPa": 265}},
+      {"latitude": 55.0, "longitude": 0.0, "current": {"time": "2024-03-02T12:00", "wind_speed_250hPa": null, "wind_direction_250hPa": null}}
+    ]"#;
+    let winds = parse_forecasts(raw, 250).unwrap();
+    assert_eq!(winds, vec![Some((265, 95.3)), None]);
+
+    let raw = r#"{"latitude": 50.0, "longitude": 0.0, "current": {"wind_speed_250hPa": 10, "wind_direction_250hPa": 360}}"#;
+    let winds = parse_forecasts(raw, 250).unwrap();
+    assert_eq!(winds, vec![Some((0, 10.0))]);
+  }
+}